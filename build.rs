@@ -0,0 +1,77 @@
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    let out = env::var("OUT_DIR").unwrap();
+    write_crc_tables(&Path::new(&out).join("crc_tables.rs"));
+
+    // The accelerated CRC-32 implementations need intrinsics from newer
+    // compilers than the rest of the crate, so they're only enabled when the
+    // compiler has them: x86_64's PCLMULQDQ since Rust 1.27, and aarch64's
+    // CRC-32 instructions since Rust 1.80.
+    println!("cargo:rustc-check-cfg=cfg(flate2_pclmul_crc)");
+    println!("cargo:rustc-check-cfg=cfg(flate2_arm_crc)");
+    let minor = rustc_minor_version().unwrap_or(0);
+    if minor >= 27 {
+        println!("cargo:rustc-cfg=flate2_pclmul_crc");
+    }
+    if minor >= 80 {
+        println!("cargo:rustc-cfg=flate2_arm_crc");
+    }
+}
+
+// Writes the slice-by-8 tables for the bit-reflected gzip polynomial, where
+// TABLES[0] is the usual byte-at-a-time table and TABLES[k][n] is the CRC of
+// byte `n` followed by `k` zero bytes.
+fn write_crc_tables(path: &Path) {
+    let mut tables = [[0u32; 256]; 8];
+    for n in 0..256 {
+        let mut crc = n as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+        tables[0][n] = crc;
+    }
+    for k in 1..8 {
+        for n in 0..256 {
+            let prev = tables[k - 1][n];
+            tables[k][n] = (prev >> 8) ^ tables[0][(prev & 0xff) as usize];
+        }
+    }
+
+    let mut out = String::from("[\n");
+    for table in tables.iter() {
+        out.push_str("    [\n");
+        for row in table.chunks(6) {
+            out.push_str("       ");
+            for crc in row {
+                out.push_str(&format!(" 0x{:08x},", crc));
+            }
+            out.push_str("\n");
+        }
+        out.push_str("    ],\n");
+    }
+    out.push_str("]\n");
+    File::create(path).unwrap().write_all(out.as_bytes()).unwrap();
+}
+
+fn rustc_minor_version() -> Option<u32> {
+    let rustc = env::var("RUSTC").unwrap_or("rustc".to_string());
+    let output = match Command::new(rustc).arg("--version").output() {
+        Ok(output) => output,
+        Err(..) => return None,
+    };
+    let version = match String::from_utf8(output.stdout) {
+        Ok(version) => version,
+        Err(..) => return None,
+    };
+    // "rustc 1.xx.y (...)"
+    let mut pieces = version.split('.');
+    if pieces.next() != Some("rustc 1") {
+        return None
+    }
+    pieces.next().and_then(|minor| minor.parse().ok())
+}
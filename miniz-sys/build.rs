@@ -1,8 +1,60 @@
 extern crate gcc;
 
 use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
 
 fn main() {
-    gcc::compile_library("libminiz.a", &["miniz.c"]);
-    println!("cargo:root={}", env::var("OUT_DIR").unwrap());
+    // The vendored miniz.c is left as released upstream, and flate2's
+    // changes to it are applied from miniz.patch before compiling.
+    let out = env::var("OUT_DIR").unwrap();
+    let source = read("miniz.c");
+    let patched = apply_patch(&source, &read("miniz.patch"));
+    let dst = Path::new(&out).join("miniz.c");
+    File::create(&dst).unwrap().write_all(patched.as_bytes()).unwrap();
+
+    gcc::compile_library("libminiz.a", &[dst.to_str().unwrap()]);
+    println!("cargo:rerun-if-changed=miniz.c");
+    println!("cargo:rerun-if-changed=miniz.patch");
+    println!("cargo:root={}", out);
+}
+
+fn read(path: &str) -> String {
+    let mut s = String::new();
+    File::open(path).unwrap().read_to_string(&mut s).unwrap();
+    s
+}
+
+// Applies a unified diff of a single file, as written by `diff -u`. Every
+// hunk has to apply exactly at the lines it names, or the build fails.
+fn apply_patch(source: &str, patch: &str) -> String {
+    let old = source.lines().collect::<Vec<_>>();
+    let mut new = Vec::with_capacity(old.len());
+    let mut pos = 0;
+    for line in patch.lines().skip_while(|l| !l.starts_with("@@ ")) {
+        if line.starts_with("@@ ") {
+            // "@@ -start,len +start,len @@", where lines count from 1.
+            let start = line[4..].split(|c| c == ',' || c == ' ')
+                .next()
+                .and_then(|n| n.parse::<usize>().ok())
+                .expect("malformed hunk in miniz.patch");
+            assert!(start > pos, "miniz.patch hunks out of order");
+            new.extend_from_slice(&old[pos..start - 1]);
+            pos = start - 1;
+        } else if line.starts_with('+') {
+            new.push(&line[1..]);
+        } else if line.starts_with('-') || line.starts_with(' ') {
+            assert!(pos < old.len() && old[pos] == &line[1..],
+                    "miniz.patch doesn't apply at line {}", pos + 1);
+            if line.starts_with(' ') {
+                new.push(old[pos]);
+            }
+            pos += 1;
+        }
+    }
+    new.extend_from_slice(&old[pos..]);
+    let mut out = new.join("\n");
+    out.push('\n');
+    out
 }
@@ -25,6 +25,10 @@ pub const MZ_PARAM_ERROR: c_int = -10000;
 pub const MZ_DEFLATED: c_int = 8;
 pub const MZ_DEFAULT_WINDOW_BITS: c_int = 15;
 pub const MZ_DEFAULT_STRATEGY: c_int = 0;
+pub const MZ_FILTERED: c_int = 1;
+pub const MZ_HUFFMAN_ONLY: c_int = 2;
+pub const MZ_RLE: c_int = 3;
+pub const MZ_FIXED: c_int = 4;
 
 #[repr(C)]
 pub struct mz_stream {
@@ -66,12 +70,39 @@ extern {
     pub fn mz_deflate(stream: *mut mz_stream, flush: c_int) -> c_int;
     pub fn mz_deflateEnd(stream: *mut mz_stream) -> c_int;
     pub fn mz_deflateReset(stream: *mut mz_stream) -> c_int;
+    pub fn mz_deflateSetDictionary(stream: *mut mz_stream,
+                                   dictionary: *const u8,
+                                   dict_len: c_uint)
+                                   -> c_int;
+    pub fn mz_deflateParams(stream: *mut mz_stream,
+                            level: c_int,
+                            strategy: c_int)
+                            -> c_int;
+    pub fn mz_deflateCopy(dest: *mut mz_stream,
+                          source: *mut mz_stream)
+                          -> c_int;
 
     pub fn mz_inflateInit2(stream: *mut mz_stream,
                            window_bits: c_int)
                            -> c_int;
     pub fn mz_inflate(stream: *mut mz_stream, flush: c_int) -> c_int;
     pub fn mz_inflateEnd(stream: *mut mz_stream) -> c_int;
+    pub fn mz_inflateCopy(dest: *mut mz_stream,
+                          source: *mut mz_stream)
+                          -> c_int;
+    pub fn mz_inflateSetDictionary(stream: *mut mz_stream,
+                                   dictionary: *const u8,
+                                   dict_len: c_uint)
+                                   -> c_int;
+    pub fn mz_inflateGetDictionary(stream: *mut mz_stream,
+                                   dictionary: *mut u8,
+                                   dict_len: *mut c_uint)
+                                   -> c_int;
+    pub fn mz_inflatePrime(stream: *mut mz_stream,
+                           bits: c_int,
+                           value: c_int)
+                           -> c_int;
 
+    pub fn mz_adler32(adler: c_ulong, ptr: *const u8, len: size_t) -> c_ulong;
     pub fn mz_crc32(crc: c_ulong, ptr: *const u8, len: size_t) -> c_ulong;
 }
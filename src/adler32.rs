@@ -0,0 +1,310 @@
+//! A pure Rust implementation of the Adler-32 checksum used by zlib streams
+
+use std::io::prelude::*;
+use std::io;
+
+// The largest prime below 2^16.
+const BASE: u32 = 65521;
+
+// The most bytes that can be summed before `b` might overflow 32 bits, rounded
+// down to a multiple of the number of lanes.
+const NMAX: usize = 5552 / LANES * LANES;
+
+const LANES: usize = 16;
+
+/// The Adler-32 checksum of a stream of bytes, as found in the trailer of a
+/// zlib stream.
+///
+/// The two running sums are only reduced modulo 65521 every few thousand
+/// bytes, and in between the bytes are added up 16 at a time in a way the
+/// compiler can vectorize.
+///
+/// # Examples
+///
+/// ```
+/// use flate2::Adler32;
+///
+/// let mut adler = Adler32::new();
+/// adler.update(b"Wiki");
+/// adler.update(b"pedia");
+/// assert_eq!(adler.sum(), 0x11e60398);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+    amt: u64,
+}
+
+/// A wrapper around a [`Read`] that calculates the Adler-32 checksum of the
+/// data read.
+///
+/// [`Read`]: https://doc.rust-lang.org/std/io/trait.Read.html
+#[derive(Debug)]
+pub struct Adler32Reader<R> {
+    inner: R,
+    adler: Adler32,
+}
+
+/// A wrapper around a [`Write`] that calculates the Adler-32 checksum of the
+/// data written.
+///
+/// [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
+#[derive(Debug)]
+pub struct Adler32Writer<W> {
+    inner: W,
+    adler: Adler32,
+}
+
+impl Adler32 {
+    /// Creates the checksum of no data, which is 1.
+    pub fn new() -> Adler32 {
+        Adler32 { a: 1, b: 0, amt: 0 }
+    }
+
+    /// Returns the checksum of the data so far.
+    pub fn sum(&self) -> u32 {
+        self.b << 16 | self.a
+    }
+
+    /// Returns the number of bytes that have been checksummed.
+    pub fn amount(&self) -> u64 {
+        self.amt
+    }
+
+    /// Updates the checksum with the bytes in `data`.
+    pub fn update(&mut self, data: &[u8]) {
+        self.amt = self.amt.wrapping_add(data.len() as u64);
+        for block in data.chunks(NMAX) {
+            let lanes = block.len() / LANES * LANES;
+            self.update_lanes(&block[..lanes]);
+            for &byte in block[lanes..].iter() {
+                self.a += byte as u32;
+                self.b += self.a;
+            }
+            self.a %= BASE;
+            self.b %= BASE;
+        }
+    }
+
+    // Sums a block of at most `NMAX` bytes, a multiple of `LANES` long, in
+    // separate lanes which are then combined.
+    fn update_lanes(&mut self, block: &[u8]) {
+        let mut a = [0u32; LANES];
+        let mut b = [0u32; LANES];
+        for group in block.chunks(LANES) {
+            for i in 0..LANES {
+                a[i] += group[i] as u32;
+                b[i] += a[i];
+            }
+        }
+
+        // Byte `i` of every group is weighted by the number of groups from it
+        // to the end in `b[i]`, and needs to be weighted by the number of
+        // bytes instead.
+        let len = block.len() as u64;
+        let mut sum = 0;
+        let mut weighted = (self.b as u64) + len * (self.a as u64);
+        for i in 0..LANES {
+            sum += a[i] as u64;
+            weighted += LANES as u64 * b[i] as u64;
+            weighted -= i as u64 * a[i] as u64;
+        }
+        self.a = ((self.a as u64 + sum) % BASE as u64) as u32;
+        self.b = (weighted % BASE as u64) as u32;
+    }
+
+    /// Resets the checksum to that of no data.
+    pub fn reset(&mut self) {
+        *self = Adler32::new();
+    }
+
+    /// Combines the checksum with the checksum of the subsequent block of
+    /// bytes, the same way zlib's `adler32_combine` does.
+    pub fn combine(&mut self, additional: &Adler32) {
+        let base = BASE as u64;
+        let rem = additional.amt % base;
+        let a1 = self.a as u64;
+        let a = a1 + additional.a as u64 + base - 1;
+        let b = rem * a1 % base + self.b as u64 + additional.b as u64 +
+                base - rem;
+        self.a = (a % base) as u32;
+        self.b = (b % base) as u32;
+        self.amt = self.amt.wrapping_add(additional.amt);
+    }
+}
+
+impl<R: Read> Adler32Reader<R> {
+    /// Creates a new reader checksumming the data read from `r`.
+    pub fn new(r: R) -> Adler32Reader<R> {
+        Adler32Reader {
+            inner: r,
+            adler: Adler32::new(),
+        }
+    }
+}
+
+impl<R> Adler32Reader<R> {
+    /// Returns the checksum of the data read so far.
+    pub fn adler32(&self) -> &Adler32 {
+        &self.adler
+    }
+
+    /// Consumes this reader, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Acquires a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Acquires a mutable reference to the underlying reader.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Resets the checksum.
+    pub fn reset(&mut self) {
+        self.adler.reset();
+    }
+}
+
+impl<R: Read> Read for Adler32Reader<R> {
+    fn read(&mut self, into: &mut [u8]) -> io::Result<usize> {
+        let amt = try!(self.inner.read(into));
+        self.adler.update(&into[..amt]);
+        Ok(amt)
+    }
+}
+
+impl<R: BufRead> BufRead for Adler32Reader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+    fn consume(&mut self, amt: usize) {
+        if let Ok(data) = self.inner.fill_buf() {
+            self.adler.update(&data[..amt]);
+        }
+        self.inner.consume(amt);
+    }
+}
+
+impl<W: Write> Adler32Writer<W> {
+    /// Creates a new writer checksumming the data written to `w`.
+    pub fn new(w: W) -> Adler32Writer<W> {
+        Adler32Writer {
+            inner: w,
+            adler: Adler32::new(),
+        }
+    }
+}
+
+impl<W> Adler32Writer<W> {
+    /// Returns the checksum of the data written so far.
+    pub fn adler32(&self) -> &Adler32 {
+        &self.adler
+    }
+
+    /// Consumes this writer, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Resets the checksum.
+    pub fn reset(&mut self) {
+        self.adler.reset();
+    }
+}
+
+impl<W: Write> Write for Adler32Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let amt = try!(self.inner.write(buf));
+        self.adler.update(&buf[..amt]);
+        Ok(amt)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+
+    // The checksum straight from its definition, a byte at a time.
+    fn reference(data: &[u8]) -> u32 {
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in data {
+            a = (a + byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        b << 16 | a
+    }
+
+    #[test]
+    fn matches_backend() {
+        let mut rng = thread_rng();
+        let data = rng.gen_iter::<u8>().take(100_000).collect::<Vec<_>>();
+        let ones = vec![0xff; 100_000];
+        let lens = [0, 1, 15, 16, 17, 5551, 5552, 5553, 65536, 100_000];
+        for &len in lens.iter() {
+            for data in [&data, &ones].iter() {
+                let mut adler = Adler32::new();
+                adler.update(&data[..len]);
+                assert_eq!(adler.sum(), reference(&data[..len]));
+                assert_eq!(adler.amount(), len as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn combine() {
+        let data = thread_rng().gen_iter::<u8>().take(30_000)
+            .collect::<Vec<_>>();
+        for &split in [0, 1, 7000, 30_000].iter() {
+            let (first, second) = data.split_at(split);
+            let mut a = Adler32::new();
+            a.update(first);
+            let mut b = Adler32::new();
+            b.update(second);
+            a.combine(&b);
+            assert_eq!(a.sum(), reference(&data));
+            assert_eq!(a.amount(), data.len() as u64);
+        }
+        let mut a = Adler32::new();
+        a.update(&data);
+        a.reset();
+        assert_eq!(a, Adler32::new());
+    }
+
+    #[test]
+    fn reader_writer() {
+        let data = thread_rng().gen_iter::<u8>().take(10_000)
+            .collect::<Vec<_>>();
+        let mut r = Adler32Reader::new(&data[..]);
+        r.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(r.adler32().sum(), reference(&data));
+
+        let mut w = Adler32Writer::new(Vec::new());
+        w.write_all(&data).unwrap();
+        assert_eq!(w.adler32().sum(), reference(&data));
+        assert_eq!(w.into_inner(), data);
+    }
+}
@@ -0,0 +1,1189 @@
+//! Implementations of the deflate algorithm that streams can be built on.
+//!
+//! Every [`Compress`] and [`Decompress`] stream runs on top of a [`Backend`].
+//! By default this is the one selected by the crate's features (zlib if the
+//! `zlib` feature is enabled, then miniz_oxide if `rust_backend` is, then
+//! miniz), but each backend which was compiled in is also available as a
+//! value of its own, and a different one can be picked for any stream through
+//! [`CompressBuilder::backend`] and [`DecompressBuilder::backend`]. This way
+//! several backends can be used in the same program.
+//!
+//! The traits in this module can also be implemented outside of this crate to
+//! plug in another implementation altogether.
+//!
+//! [`Compress`]: ../struct.Compress.html
+//! [`Decompress`]: ../struct.Decompress.html
+//! [`Backend`]: trait.Backend.html
+//! [`CompressBuilder::backend`]: ../struct.CompressBuilder.html#method.backend
+//! [`DecompressBuilder::backend`]: ../struct.DecompressBuilder.html#method.backend
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use {BlockInfo, Compression, Flush, Strategy};
+use ffi::Budget;
+
+/// An implementation of deflate compression and decompression.
+///
+/// A backend creates the streams doing the actual work, and reports which of
+/// the optional features of zlib it supports so that builders can reject
+/// parameters it can't honor.
+///
+/// A backend which lacks a feature still has to refuse it when asked, as
+/// described for each method here and on [`DeflateStream`] and
+/// [`InflateStream`], so that it fails loudly instead of quietly producing
+/// something different from the other backends.
+///
+/// [`DeflateStream`]: trait.DeflateStream.html
+/// [`InflateStream`]: trait.InflateStream.html
+pub trait Backend: fmt::Debug + Sync {
+    /// Returns whether window sizes other than 15 bits are supported.
+    fn supports_window_bits(&self) -> bool;
+
+    /// Returns whether memory levels other than 9 are supported.
+    fn supports_mem_level(&self) -> bool;
+
+    /// Returns whether preset dictionaries are supported.
+    ///
+    /// If not, `set_dictionary` on the backend's streams must return
+    /// `ReturnCode::StreamError`.
+    fn supports_dictionary(&self) -> bool;
+
+    /// Returns whether decompression streams support `Flush::Block`.
+    ///
+    /// If not, `inflate` must return `ReturnCode::StreamError` without
+    /// consuming any input when given `Flush::Block`, rather than treating it
+    /// as another flush mode.
+    ///
+    /// The default implementation returns `false`.
+    fn supports_block_flush(&self) -> bool {
+        false
+    }
+
+    /// Returns whether streams can be created with a [`MemoryLimit`].
+    ///
+    /// If not, `deflate_init` and `inflate_init` must fail with
+    /// `io::ErrorKind::InvalidInput` when given a limit, rather than ignoring
+    /// it.
+    ///
+    /// The default implementation returns `false`.
+    ///
+    /// [`MemoryLimit`]: struct.MemoryLimit.html
+    fn supports_memory_limit(&self) -> bool {
+        false
+    }
+
+    /// Returns an estimate of the memory a compression stream allocates with
+    /// the given window size and memory level, if the backend can tell.
+    ///
+    /// The default implementation returns `None`.
+    fn deflate_memory(&self, _window_bits: u8, _mem_level: u8)
+                      -> Option<usize> {
+        None
+    }
+
+    /// Returns an estimate of the memory a decompression stream allocates
+    /// with the given window size, if the backend can tell.
+    ///
+    /// The default implementation returns `None`.
+    fn inflate_memory(&self, _window_bits: u8) -> Option<usize> {
+        None
+    }
+
+    /// Creates a new compression stream.
+    ///
+    /// The stream produces a zlib header and trailer around the deflate data
+    /// if `zlib_header` is set, and raw deflate data otherwise. The window
+    /// size is between 9 and 15 bits, and the memory level between 1 and 9.
+    /// If a `limit` is given, everything the stream allocates is charged to
+    /// it, and allocations which would exceed it fail.
+    ///
+    /// Builders only pass a window size or memory level other than the
+    /// default to backends which claim to support it, but a backend given
+    /// parameters it can't honor should fail with
+    /// `io::ErrorKind::InvalidInput` rather than substitute its own.
+    fn deflate_init(&self,
+                    level: Compression,
+                    zlib_header: bool,
+                    window_bits: u8,
+                    mem_level: u8,
+                    strategy: Strategy,
+                    limit: Option<&MemoryLimit>)
+                    -> io::Result<Box<dyn DeflateStream>>;
+
+    /// Creates a new decompression stream, which expects a zlib header if
+    /// `zlib_header` is set and raw deflate data otherwise. A `limit` is
+    /// handled the same way as for `deflate_init`.
+    fn inflate_init(&self,
+                    zlib_header: bool,
+                    window_bits: u8,
+                    limit: Option<&MemoryLimit>)
+                    -> io::Result<Box<dyn InflateStream>>;
+
+    /// Updates the CRC-32 checksum `crc` with `data`.
+    fn crc32(&self, crc: u32, data: &[u8]) -> u32;
+}
+
+/// A compression stream created by a [`Backend`].
+///
+/// Dropping the stream releases everything it allocated.
+///
+/// [`Backend`]: trait.Backend.html
+pub trait DeflateStream: fmt::Debug + Send + Sync {
+    /// Compresses as much of `input` into `output` as possible, the same way
+    /// zlib's `deflate` does.
+    ///
+    /// A backend which can't stop compressing at a block boundary returns
+    /// `ReturnCode::StreamError` for `Flush::Block`.
+    fn deflate(&mut self, input: &[u8], output: &mut [u8], flush: Flush)
+               -> Progress;
+
+    /// Resets the stream to its state right after it was created.
+    fn reset(&mut self) -> ReturnCode;
+
+    /// Changes the compression level and strategy, the same way zlib's
+    /// `deflateParams` does.
+    fn set_params(&mut self, level: Compression, strategy: Strategy)
+                  -> ReturnCode;
+
+    /// Installs a preset dictionary, the same way zlib's
+    /// `deflateSetDictionary` does.
+    fn set_dictionary(&mut self, dictionary: &[u8]) -> ReturnCode;
+
+    /// Returns the message describing the last error, if any.
+    fn message(&self) -> Option<String>;
+
+    /// Creates an independent copy of this stream in its current state, the
+    /// same way zlib's `deflateCopy` does.
+    ///
+    /// The default implementation returns an error, for backends which can't
+    /// duplicate a stream.
+    fn try_clone(&self) -> io::Result<Box<dyn DeflateStream>> {
+        Err(no_clone())
+    }
+
+    /// Returns the number of bytes the stream currently has allocated.
+    ///
+    /// The default implementation returns `None`, for backends which don't
+    /// keep track of this.
+    fn memory_usage(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// A decompression stream created by a [`Backend`].
+///
+/// Dropping the stream releases everything it allocated.
+///
+/// [`Backend`]: trait.Backend.html
+pub trait InflateStream: fmt::Debug + Send + Sync {
+    /// Decompresses as much of `input` into `output` as possible, the same way
+    /// zlib's `inflate` does.
+    ///
+    /// `Flush::Block` is only passed on to backends which claim to support
+    /// it, see [`Backend::supports_block_flush`].
+    ///
+    /// [`Backend::supports_block_flush`]: trait.Backend.html#method.supports_block_flush
+    fn inflate(&mut self, input: &[u8], output: &mut [u8], flush: Flush)
+               -> Progress;
+
+    /// Resets the stream to its state right after it was created, except that
+    /// it now expects a zlib header only if `zlib_header` is set.
+    fn reset(&mut self, zlib_header: bool) -> ReturnCode;
+
+    /// Installs a preset dictionary, the same way zlib's
+    /// `inflateSetDictionary` does.
+    fn set_dictionary(&mut self, dictionary: &[u8]) -> ReturnCode;
+
+    /// Returns the message describing the last error, if any.
+    ///
+    /// Where a problem with the input has an equivalent in zlib, the message
+    /// should be zlib's, since that's what `DataError::kind` is derived from.
+    /// Anything else is reported as `DataErrorKind::Other`.
+    fn message(&self) -> Option<String>;
+
+    /// Creates an independent copy of this stream in its current state, the
+    /// same way zlib's `inflateCopy` does.
+    ///
+    /// The default implementation returns an error, for backends which can't
+    /// duplicate a stream.
+    fn try_clone(&self) -> io::Result<Box<dyn InflateStream>> {
+        Err(no_clone())
+    }
+
+    /// Returns where the last call to `inflate` stopped, as zlib reports in
+    /// the `data_type` field of its stream.
+    ///
+    /// The default implementation returns `None`, for backends which don't
+    /// track this.
+    fn block_info(&self) -> Option<BlockInfo> {
+        None
+    }
+
+    /// Returns the most recent output, up to the size of the window, the same
+    /// way zlib's `inflateGetDictionary` does.
+    ///
+    /// The default implementation returns an error, for backends which don't
+    /// expose their window.
+    fn window(&self) -> io::Result<Vec<u8>> {
+        Err(no_window())
+    }
+
+    /// Inserts the low `bits` bits of `value` into the input, the same way
+    /// zlib's `inflatePrime` does.
+    ///
+    /// The default implementation returns `ReturnCode::StreamError`, for
+    /// backends which can't do this.
+    fn prime(&mut self, _bits: u8, _value: u8) -> ReturnCode {
+        ReturnCode::StreamError
+    }
+
+    /// Returns the number of bytes the stream currently has allocated.
+    ///
+    /// The default implementation returns `None`, for backends which don't
+    /// keep track of this.
+    fn memory_usage(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// A budget for the memory allocated by compression and decompression
+/// streams.
+///
+/// A limit can be shared by any number of streams, through
+/// [`CompressBuilder::memory_limit`] and [`DecompressBuilder::memory_limit`],
+/// and clones of it refer to the same budget. Once the streams together hold
+/// as much memory as the limit allows, further allocations fail and the
+/// stream which needed them reports an error instead of aborting the program.
+///
+/// [`CompressBuilder::memory_limit`]: ../struct.CompressBuilder.html#method.memory_limit
+/// [`DecompressBuilder::memory_limit`]: ../struct.DecompressBuilder.html#method.memory_limit
+///
+/// # Examples
+///
+/// ```
+/// use flate2::{CompressBuilder, Compression, DecompressBuilder, MemoryLimit};
+///
+/// // at most 1 MiB for both streams together
+/// let limit = MemoryLimit::new(1 << 20);
+/// let compress = CompressBuilder::new(Compression::default())
+///     .memory_limit(limit.clone())
+///     .build(true);
+/// let decompress = DecompressBuilder::new()
+///     .memory_limit(limit.clone())
+///     .build(true);
+/// assert!(limit.used() <= limit.limit());
+/// ```
+#[derive(Clone, Debug)]
+pub struct MemoryLimit {
+    budget: Arc<Budget>,
+}
+
+impl MemoryLimit {
+    /// Creates a new budget of `bytes` bytes.
+    pub fn new(bytes: usize) -> MemoryLimit {
+        MemoryLimit {
+            budget: Arc::new(Budget {
+                limit: bytes,
+                used: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Returns the number of bytes the budget allows.
+    pub fn limit(&self) -> usize {
+        self.budget.limit
+    }
+
+    /// Returns the number of bytes currently allocated by all streams sharing
+    /// this budget.
+    pub fn used(&self) -> usize {
+        self.budget.used.load(Ordering::SeqCst)
+    }
+}
+
+/// The outcome of a single `deflate` or `inflate` call.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Progress {
+    /// The number of input bytes consumed.
+    pub consumed: usize,
+    /// The number of output bytes written.
+    pub written: usize,
+    /// The result of the call.
+    pub code: ReturnCode,
+}
+
+/// The result of an operation on a stream, mirroring zlib's return codes.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ReturnCode {
+    /// Progress was made (`Z_OK`).
+    Ok,
+    /// The end of the stream was reached (`Z_STREAM_END`).
+    StreamEnd,
+    /// A preset dictionary with the given Adler-32 checksum is needed to go
+    /// on decompressing (`Z_NEED_DICT`).
+    NeedDict(u32),
+    /// No progress was possible (`Z_BUF_ERROR`).
+    BufError,
+    /// The stream is in a state where the operation isn't allowed
+    /// (`Z_STREAM_ERROR`).
+    StreamError,
+    /// The input data is corrupt, or a dictionary doesn't match
+    /// (`Z_DATA_ERROR`).
+    DataError,
+    /// Memory couldn't be allocated (`Z_MEM_ERROR`).
+    MemError,
+    /// Any other code.
+    Other(i32),
+}
+
+fn no_clone() -> io::Error {
+    io::Error::new(io::ErrorKind::Other,
+                   "this compression backend can't duplicate a stream")
+}
+
+fn no_window() -> io::Error {
+    io::Error::new(io::ErrorKind::Other,
+                   "this compression backend can't return its window")
+}
+
+/// Returns the backend that streams use unless another one is configured.
+#[cfg(feature = "zlib")]
+pub fn default() -> &'static dyn Backend {
+    &Zlib
+}
+
+/// Returns the backend that streams use unless another one is configured.
+#[cfg(all(not(feature = "zlib"), feature = "rust_backend"))]
+pub fn default() -> &'static dyn Backend {
+    &MinizOxide
+}
+
+/// Returns the backend that streams use unless another one is configured.
+#[cfg(all(not(feature = "zlib"), not(feature = "rust_backend")))]
+pub fn default() -> &'static dyn Backend {
+    &Miniz
+}
+
+// Implements `Backend` for `$name` on top of the miniz-style functions in
+// `ffi::$imp`.
+#[cfg(any(feature = "zlib", feature = "miniz-sys"))]
+macro_rules! ffi_backend {
+    ($(#[$attr:meta])* pub struct $name:ident => $imp:ident;) => (
+        $(#[$attr])*
+        #[derive(Copy, Clone, Debug)]
+        pub struct $name;
+
+        impl Backend for $name {
+            fn supports_window_bits(&self) -> bool {
+                ::ffi::$imp::SUPPORTS_WINDOW_BITS
+            }
+
+            fn supports_mem_level(&self) -> bool {
+                ::ffi::$imp::SUPPORTS_MEM_LEVEL
+            }
+
+            fn supports_dictionary(&self) -> bool {
+                ::ffi::$imp::SUPPORTS_DICTIONARY
+            }
+
+            fn supports_block_flush(&self) -> bool {
+                ::ffi::$imp::SUPPORTS_BLOCK_FLUSH
+            }
+
+            fn supports_memory_limit(&self) -> bool {
+                ::ffi::$imp::SUPPORTS_MEMORY_LIMIT
+            }
+
+            fn deflate_memory(&self, window_bits: u8, mem_level: u8)
+                              -> Option<usize> {
+                Some(::ffi::$imp::deflate_memory(window_bits, mem_level))
+            }
+
+            fn inflate_memory(&self, window_bits: u8) -> Option<usize> {
+                Some(::ffi::$imp::inflate_memory(window_bits))
+            }
+
+            fn deflate_init(&self,
+                            level: Compression,
+                            zlib_header: bool,
+                            window_bits: u8,
+                            mem_level: u8,
+                            strategy: Strategy,
+                            limit: Option<&MemoryLimit>)
+                            -> io::Result<Box<dyn DeflateStream>> {
+                let budget = try!($imp::budget(limit));
+                $imp::Deflate::new(level, zlib_header, window_bits, mem_level,
+                                   strategy, budget)
+                    .map(|s| Box::new(s) as Box<dyn DeflateStream>)
+            }
+
+            fn inflate_init(&self,
+                            zlib_header: bool,
+                            window_bits: u8,
+                            limit: Option<&MemoryLimit>)
+                            -> io::Result<Box<dyn InflateStream>> {
+                let budget = try!($imp::budget(limit));
+                $imp::Inflate::new(zlib_header, window_bits, budget)
+                    .map(|s| Box::new(s) as Box<dyn InflateStream>)
+            }
+
+            fn crc32(&self, crc: u32, data: &[u8]) -> u32 {
+                $imp::crc32(crc, data)
+            }
+        }
+
+        mod $imp {
+            use std::ffi::CStr;
+            use std::io;
+            use std::sync::Arc;
+
+            use libc::{c_char, c_int, c_uint, c_ulong, size_t};
+
+            use {BlockInfo, Compression, Flush, Strategy};
+            use ffi::$imp as raw;
+            use ffi::Budget;
+            use super::{DeflateStream, InflateStream, MemoryLimit, Progress};
+            use super::{ReturnCode, no_clone, no_window};
+
+            #[derive(Debug)]
+            pub struct Deflate {
+                stream: raw::StreamWrapper,
+            }
+
+            #[derive(Debug)]
+            pub struct Inflate {
+                stream: raw::StreamWrapper,
+                window_bits: u8,
+            }
+
+            // The streams own their state and only ever touch it through
+            // `&mut self`.
+            unsafe impl Send for Deflate {}
+            unsafe impl Sync for Deflate {}
+            unsafe impl Send for Inflate {}
+            unsafe impl Sync for Inflate {}
+
+            impl Deflate {
+                pub fn new(level: Compression,
+                           zlib_header: bool,
+                           window_bits: u8,
+                           mem_level: u8,
+                           strategy: Strategy,
+                           budget: Option<Arc<Budget>>)
+                           -> io::Result<Deflate> {
+                    let mut stream = raw::StreamWrapper::new(budget);
+                    let bits = signed_bits(window_bits, zlib_header);
+                    let rc = unsafe {
+                        raw::mz_deflateInit2(&mut *stream,
+                                             level.level() as c_int,
+                                             raw::MZ_DEFLATED,
+                                             bits,
+                                             mem_level as c_int,
+                                             strategy as c_int)
+                    };
+                    try!(init_result(rc));
+                    Ok(Deflate { stream: stream })
+                }
+            }
+
+            impl DeflateStream for Deflate {
+                fn deflate(&mut self,
+                           input: &[u8],
+                           output: &mut [u8],
+                           flush: Flush) -> Progress {
+                    let raw = &mut *self.stream;
+                    set_buffers(raw, input, output);
+                    let rc = unsafe { raw::mz_deflate(raw, flush as c_int) };
+                    progress(raw, input, output, rc)
+                }
+
+                fn reset(&mut self) -> ReturnCode {
+                    let rc = unsafe { raw::mz_deflateReset(&mut *self.stream) };
+                    return_code(&self.stream, rc)
+                }
+
+                fn set_params(&mut self, level: Compression,
+                              strategy: Strategy) -> ReturnCode {
+                    // zlib may try to compress pending input here, so give it
+                    // a valid but empty output buffer to guarantee nothing is
+                    // written anywhere.
+                    let raw = &mut *self.stream;
+                    let mut empty = [0u8; 1];
+                    set_buffers(raw, &[], &mut empty[..0]);
+                    let rc = unsafe {
+                        raw::mz_deflateParams(raw, level.level() as c_int,
+                                              strategy as c_int)
+                    };
+                    return_code(raw, rc)
+                }
+
+                fn set_dictionary(&mut self, dictionary: &[u8]) -> ReturnCode {
+                    let rc = unsafe {
+                        raw::mz_deflateSetDictionary(&mut *self.stream,
+                                                     dictionary.as_ptr(),
+                                                     dictionary.len() as c_uint)
+                    };
+                    return_code(&self.stream, rc)
+                }
+
+                fn message(&self) -> Option<String> {
+                    message(&self.stream)
+                }
+
+                fn try_clone(&self) -> io::Result<Box<dyn DeflateStream>> {
+                    let (stream, rc) = self.stream.copy(|dest, source| unsafe {
+                        raw::mz_deflateCopy(dest, source)
+                    });
+                    try!(copy_result(rc));
+                    Ok(Box::new(Deflate { stream: stream }))
+                }
+
+                fn memory_usage(&self) -> Option<usize> {
+                    self.stream.memory_usage()
+                }
+            }
+
+            impl Drop for Deflate {
+                fn drop(&mut self) {
+                    unsafe {
+                        raw::mz_deflateEnd(&mut *self.stream);
+                    }
+                }
+            }
+
+            impl Inflate {
+                pub fn new(zlib_header: bool,
+                           window_bits: u8,
+                           budget: Option<Arc<Budget>>)
+                           -> io::Result<Inflate> {
+                    let mut stream = raw::StreamWrapper::new(budget);
+                    let bits = signed_bits(window_bits, zlib_header);
+                    let rc = unsafe {
+                        raw::mz_inflateInit2(&mut *stream, bits)
+                    };
+                    try!(init_result(rc));
+                    Ok(Inflate {
+                        stream: stream,
+                        window_bits: window_bits,
+                    })
+                }
+            }
+
+            impl InflateStream for Inflate {
+                fn inflate(&mut self,
+                           input: &[u8],
+                           output: &mut [u8],
+                           flush: Flush) -> Progress {
+                    let raw = &mut *self.stream;
+                    set_buffers(raw, input, output);
+                    let rc = unsafe { raw::mz_inflate(raw, flush as c_int) };
+                    progress(raw, input, output, rc)
+                }
+
+                fn reset(&mut self, zlib_header: bool) -> ReturnCode {
+                    let bits = signed_bits(self.window_bits, zlib_header);
+                    let rc = unsafe {
+                        raw::mz_inflateReset2(&mut *self.stream, bits)
+                    };
+                    return_code(&self.stream, rc)
+                }
+
+                fn set_dictionary(&mut self, dictionary: &[u8]) -> ReturnCode {
+                    let rc = unsafe {
+                        raw::mz_inflateSetDictionary(&mut *self.stream,
+                                                     dictionary.as_ptr(),
+                                                     dictionary.len() as c_uint)
+                    };
+                    return_code(&self.stream, rc)
+                }
+
+                fn message(&self) -> Option<String> {
+                    message(&self.stream)
+                }
+
+                fn try_clone(&self) -> io::Result<Box<dyn InflateStream>> {
+                    let (stream, rc) = self.stream.copy(|dest, source| unsafe {
+                        raw::mz_inflateCopy(dest, source)
+                    });
+                    try!(copy_result(rc));
+                    Ok(Box::new(Inflate {
+                        stream: stream,
+                        window_bits: self.window_bits,
+                    }))
+                }
+
+                fn block_info(&self) -> Option<BlockInfo> {
+                    if !raw::SUPPORTS_BLOCK_FLUSH {
+                        return None
+                    }
+                    let data_type = self.stream.data_type;
+                    Some(BlockInfo {
+                        unused_bits: (data_type & 7) as u8,
+                        last_block: data_type & 64 != 0,
+                        block_boundary: data_type & 128 != 0,
+                    })
+                }
+
+                fn window(&self) -> io::Result<Vec<u8>> {
+                    let mut window = vec![0; 1 << self.window_bits];
+                    let mut len = 0;
+                    let rc = unsafe {
+                        raw::mz_inflateGetDictionary(
+                            &*self.stream as *const _ as *mut _,
+                            window.as_mut_ptr(),
+                            &mut len)
+                    };
+                    if rc != raw::MZ_OK {
+                        return Err(no_window())
+                    }
+                    window.truncate(len as usize);
+                    Ok(window)
+                }
+
+                fn prime(&mut self, bits: u8, value: u8) -> ReturnCode {
+                    let rc = unsafe {
+                        raw::mz_inflatePrime(&mut *self.stream,
+                                             bits as c_int,
+                                             value as c_int)
+                    };
+                    return_code(&self.stream, rc)
+                }
+
+                fn memory_usage(&self) -> Option<usize> {
+                    self.stream.memory_usage()
+                }
+            }
+
+            impl Drop for Inflate {
+                fn drop(&mut self) {
+                    unsafe {
+                        raw::mz_inflateEnd(&mut *self.stream);
+                    }
+                }
+            }
+
+            pub fn budget(limit: Option<&MemoryLimit>)
+                          -> io::Result<Option<Arc<Budget>>> {
+                match limit {
+                    Some(_) if !raw::SUPPORTS_MEMORY_LIMIT => {
+                        Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                           "memory limits are not supported \
+                                            by this compression backend"))
+                    }
+                    Some(limit) => Ok(Some(limit.budget.clone())),
+                    None => Ok(None),
+                }
+            }
+
+            pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+                unsafe {
+                    raw::mz_crc32(crc as c_ulong,
+                                  data.as_ptr(),
+                                  data.len() as size_t) as u32
+                }
+            }
+
+            // Negative window bits ask for raw deflate data.
+            fn signed_bits(window_bits: u8, zlib_header: bool) -> c_int {
+                let bits = window_bits as c_int;
+                if zlib_header { bits } else { -bits }
+            }
+
+            fn set_buffers(raw: &mut raw::mz_stream,
+                           input: &[u8],
+                           output: &mut [u8]) {
+                raw.next_in = input.as_ptr() as *mut u8;
+                raw.avail_in = input.len() as c_uint;
+                raw.next_out = output.as_mut_ptr();
+                raw.avail_out = output.len() as c_uint;
+            }
+
+            // The stream's own total counters might be only 32 bits wide, so
+            // work out what happened from how far its buffers moved.
+            fn progress(raw: &raw::mz_stream,
+                        input: &[u8],
+                        output: &[u8],
+                        rc: c_int) -> Progress {
+                Progress {
+                    consumed: raw.next_in as usize - input.as_ptr() as usize,
+                    written: raw.next_out as usize - output.as_ptr() as usize,
+                    code: return_code(raw, rc),
+                }
+            }
+
+            fn return_code(raw: &raw::mz_stream, rc: c_int) -> ReturnCode {
+                match rc {
+                    raw::MZ_OK => ReturnCode::Ok,
+                    raw::MZ_STREAM_END => ReturnCode::StreamEnd,
+                    raw::MZ_NEED_DICT => ReturnCode::NeedDict(raw.adler as u32),
+                    raw::MZ_BUF_ERROR => ReturnCode::BufError,
+                    raw::MZ_STREAM_ERROR => ReturnCode::StreamError,
+                    raw::MZ_DATA_ERROR => ReturnCode::DataError,
+                    raw::MZ_MEM_ERROR => ReturnCode::MemError,
+                    c => ReturnCode::Other(c as i32),
+                }
+            }
+
+            fn init_result(rc: c_int) -> io::Result<()> {
+                match rc {
+                    raw::MZ_OK => Ok(()),
+                    raw::MZ_MEM_ERROR => {
+                        Err(io::Error::new(io::ErrorKind::Other,
+                                           "out of memory"))
+                    }
+                    _ => {
+                        Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                           "invalid compression stream \
+                                            parameters"))
+                    }
+                }
+            }
+
+            fn copy_result(rc: c_int) -> io::Result<()> {
+                match rc {
+                    raw::MZ_OK => Ok(()),
+                    raw::MZ_MEM_ERROR => {
+                        Err(io::Error::new(io::ErrorKind::Other,
+                                           "out of memory"))
+                    }
+                    _ => Err(no_clone()),
+                }
+            }
+
+            fn message(raw: &raw::mz_stream) -> Option<String> {
+                if raw.msg.is_null() {
+                    return None
+                }
+                let msg = unsafe { CStr::from_ptr(raw.msg as *const c_char) };
+                Some(msg.to_string_lossy().into_owned())
+            }
+        }
+    )
+}
+
+#[cfg(feature = "rust_backend")]
+pub use oxide::MinizOxide;
+
+#[cfg(feature = "zlib")]
+ffi_backend! {
+    /// The system zlib library, available with the `zlib` feature.
+    pub struct Zlib => zlib;
+}
+
+#[cfg(feature = "miniz-sys")]
+ffi_backend! {
+    /// The bundled miniz library, available with the default `miniz-sys`
+    /// feature.
+    ///
+    /// miniz always uses a 32KiB window and a fixed amount of memory for its
+    /// internal state.
+    pub struct Miniz => miniz;
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::cmp;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use {Compression, Compress, DataErrorKind, Decompress, Flush, Status};
+    use Strategy;
+    use {CompressBuilder, DecompressBuilder};
+    use super::{Backend, DeflateStream, InflateStream, MemoryLimit};
+
+    pub(crate) fn backends() -> Vec<&'static dyn Backend> {
+        let mut backends: Vec<&'static dyn Backend> = Vec::new();
+        #[cfg(feature = "zlib")]
+        backends.push(&super::Zlib);
+        #[cfg(feature = "rust_backend")]
+        backends.push(&super::MinizOxide);
+        #[cfg(feature = "miniz-sys")]
+        backends.push(&super::Miniz);
+        backends
+    }
+
+    fn compress(c: &mut Compress, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + 64);
+        let status = c.compress_vec(data, &mut out, Flush::Finish).unwrap();
+        assert_eq!(status, Status::StreamEnd);
+        out
+    }
+
+    fn decompress(d: &mut Decompress, data: &[u8], len: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(len);
+        let status = d.decompress_vec(data, &mut out, Flush::Finish).unwrap();
+        assert_eq!(status, Status::StreamEnd);
+        out
+    }
+
+    #[test]
+    fn roundtrip_across_backends() {
+        let data = b"hello hello hello, backends!".iter().cycle().take(2800)
+            .cloned().collect::<Vec<u8>>();
+        for &b1 in backends().iter() {
+            for &b2 in backends().iter() {
+                for &zlib_header in [false, true].iter() {
+                    let mut c = CompressBuilder::new(Compression::default())
+                        .backend(b1)
+                        .build(zlib_header)
+                        .unwrap();
+                    let mut d = DecompressBuilder::new()
+                        .backend(b2)
+                        .build(zlib_header)
+                        .unwrap();
+                    let compressed = compress(&mut c, &data);
+                    let out = decompress(&mut d, &compressed, data.len());
+                    assert!(out == data, "{:?} -> {:?}", b1, b2);
+
+                    c.reset();
+                    d.reset(zlib_header);
+                    assert!(compress(&mut c, &data) == compressed);
+                    let out = decompress(&mut d, &compressed, data.len());
+                    assert!(out == data);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dictionary_across_backends() {
+        let dict = b"the quick brown fox jumps over the lazy dog, ".iter()
+            .cycle().take(300).cloned().collect::<Vec<u8>>();
+        let data = b"the lazy dog jumps over the quick brown fox";
+        for &b1 in backends().iter() {
+            for &b2 in backends().iter() {
+                for &zlib_header in [false, true].iter() {
+                    let mut c = CompressBuilder::new(Compression::default())
+                        .backend(b1)
+                        .build(zlib_header)
+                        .unwrap();
+                    let plain = compress(&mut c, data);
+                    let mut c = CompressBuilder::new(Compression::default())
+                        .backend(b1)
+                        .dictionary(&dict[..])
+                        .build(zlib_header)
+                        .unwrap();
+                    let compressed = compress(&mut c, data);
+                    // The dictionary has to have been used for matches, not
+                    // just recorded in the header.
+                    let header = if zlib_header {4} else {0};
+                    assert!(compressed.len() + 10 < plain.len() + header,
+                            "{:?}: {} vs {}", b1, compressed.len(),
+                            plain.len());
+
+                    let mut d = DecompressBuilder::new()
+                        .backend(b2)
+                        .dictionary(&dict[..])
+                        .build(zlib_header)
+                        .unwrap();
+                    let out = decompress(&mut d, &compressed, data.len());
+                    assert!(out == &data[..], "{:?} -> {:?}", b1, b2);
+                }
+
+                // Only the end of a dictionary longer than the window counts.
+                let dict = (0..40000u32).map(|i| (i * i % 251) as u8)
+                    .collect::<Vec<u8>>();
+                let data = &dict[dict.len() - 1000..];
+                let mut c = CompressBuilder::new(Compression::default())
+                    .backend(b1)
+                    .dictionary(&dict[..])
+                    .build(false)
+                    .unwrap();
+                let compressed = compress(&mut c, data);
+                assert!(compressed.len() < 100, "{:?}", b1);
+                let mut d = DecompressBuilder::new()
+                    .backend(b2)
+                    .dictionary(&dict[..])
+                    .build(false)
+                    .unwrap();
+                let out = decompress(&mut d, &compressed, data.len());
+                assert!(out == data, "{:?} -> {:?}", b1, b2);
+            }
+        }
+    }
+
+    #[test]
+    fn window() {
+        let data = (0..100000u64).map(|i| (i * i % 251) as u8)
+            .collect::<Vec<u8>>();
+        for &b in backends().iter() {
+            let mut c = CompressBuilder::new(Compression::default())
+                .backend(b)
+                .build(false)
+                .unwrap();
+            let compressed = compress(&mut c, &data);
+            let mut d = DecompressBuilder::new()
+                .backend(b)
+                .build(false)
+                .unwrap();
+            assert!(d.window().unwrap().is_empty(), "{:?}", b);
+            let mut out = Vec::with_capacity(data.len());
+            for chunk in compressed.chunks(1000) {
+                d.decompress_vec(chunk, &mut out, Flush::None).unwrap();
+                let start = out.len() - cmp::min(out.len(), 1 << 15);
+                assert!(d.window().unwrap() == &out[start..], "{:?}", b);
+            }
+            assert!(out == data);
+        }
+    }
+
+    #[test]
+    fn resume_at_block_boundaries() {
+        let data = (0..100000u64).map(|i| (i * i % 251) as u8)
+            .collect::<Vec<u8>>();
+        let mut c = Compress::new(Compression::default(), false);
+        let mut compressed = Vec::with_capacity(200000);
+        for chunk in data.chunks(20000) {
+            c.compress_vec(chunk, &mut compressed, Flush::Sync).unwrap();
+        }
+        c.compress_vec(&[], &mut compressed, Flush::Finish).unwrap();
+
+        for &b in backends().iter().filter(|b| b.supports_block_flush()) {
+            let mut d = DecompressBuilder::new()
+                .backend(b)
+                .build(false)
+                .unwrap();
+            let mut out = Vec::with_capacity(data.len());
+            let mut resumed = 0;
+            loop {
+                let pos = d.total_in() as usize;
+                let status = d.decompress_vec(&compressed[pos..], &mut out,
+                                              Flush::Block).unwrap();
+                if status == Status::StreamEnd {
+                    break
+                }
+                let info = d.block_info().unwrap();
+                if !info.block_boundary || info.last_block {
+                    continue
+                }
+
+                // Pick up from here with a fresh stream, given only the
+                // window and the unused bits of the last byte.
+                let mut d2 = DecompressBuilder::new()
+                    .backend(b)
+                    .build(false)
+                    .unwrap();
+                let pos = d.total_in() as usize;
+                let bits = info.unused_bits;
+                if bits > 0 {
+                    d2.prime(bits, compressed[pos - 1] >> (8 - bits)).unwrap();
+                }
+                d2.set_dictionary(&d.window().unwrap()).unwrap();
+                let mut rest = Vec::with_capacity(data.len());
+                let status = d2.decompress_vec(&compressed[pos..], &mut rest,
+                                               Flush::Finish).unwrap();
+                assert_eq!(status, Status::StreamEnd);
+                assert!(rest == &data[out.len()..], "{:?}", b);
+                resumed += 1;
+            }
+            assert!(out == data);
+            assert!(resumed > 3, "{:?}: resumed {} times", b, resumed);
+        }
+    }
+
+    #[test]
+    fn capabilities() {
+        for &b in backends().iter() {
+            let builder = CompressBuilder::new(Compression::default())
+                .backend(b)
+                .window_bits(9);
+            assert_eq!(builder.build(true).is_ok(), b.supports_window_bits());
+            let builder = DecompressBuilder::new()
+                .backend(b)
+                .dictionary(&b"dict"[..]);
+            assert_eq!(builder.build(false).is_ok(), b.supports_dictionary());
+        }
+    }
+
+    #[test]
+    fn unsupported_features_are_refused() {
+        let data = b"refused, refused, refused".to_vec();
+        for &b in backends().iter() {
+            let res = CompressBuilder::new(Compression::default())
+                .backend(b)
+                .memory_limit(MemoryLimit::new(1 << 30))
+                .build(true);
+            match res {
+                Ok(..) => assert!(b.supports_memory_limit(), "{:?}", b),
+                Err(e) => {
+                    assert!(!b.supports_memory_limit(), "{:?}", b);
+                    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+                }
+            }
+
+            let mut c = CompressBuilder::new(Compression::default())
+                .backend(b)
+                .build(false)
+                .unwrap();
+            let compressed = compress(&mut c, &data);
+            let mut d = DecompressBuilder::new()
+                .backend(b)
+                .build(false)
+                .unwrap();
+            let mut out = Vec::with_capacity(data.len());
+            let res = d.decompress_vec(&compressed, &mut out, Flush::Block);
+            assert_eq!(res.is_ok(), b.supports_block_flush(), "{:?}", b);
+            if res.is_err() {
+                assert_eq!(d.total_in(), 0);
+                assert!(out.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn raw_dictionary_after_full_flush() {
+        let first = b"a first part which nothing else refers back to".to_vec();
+        let dict = b"the quick brown fox jumps over the lazy dog, ".iter()
+            .cycle().take(300).cloned().collect::<Vec<u8>>();
+        let second = b"the lazy dog jumps over the quick brown fox";
+        for &b1 in backends().iter().filter(|b| b.supports_dictionary()) {
+            let mut c = CompressBuilder::new(Compression::default())
+                .backend(b1)
+                .build(false)
+                .unwrap();
+            let mut compressed = Vec::with_capacity(1000);
+            c.compress_vec(&first, &mut compressed, Flush::Full).unwrap();
+            let split = compressed.len();
+            c.set_dictionary(&dict).unwrap();
+            c.compress_vec(second, &mut compressed, Flush::Finish).unwrap();
+            assert!(compressed.len() - split < second.len(), "{:?}", b1);
+
+            for &b2 in backends().iter().filter(|b| b.supports_dictionary()) {
+                let mut d = DecompressBuilder::new()
+                    .backend(b2)
+                    .build(false)
+                    .unwrap();
+                let mut out = Vec::with_capacity(100);
+                d.decompress_vec(&compressed[..split], &mut out, Flush::None)
+                    .unwrap();
+                assert!(out == first, "{:?} -> {:?}", b1, b2);
+                d.set_dictionary(&dict).unwrap();
+                let rest = decompress(&mut d, &compressed[split..], 100);
+                assert!(rest == &second[..], "{:?} -> {:?}", b1, b2);
+            }
+        }
+    }
+
+    #[test]
+    fn error_kinds() {
+        let data = b"hello hello hello, backends!".iter().cycle().take(2800)
+            .cloned().collect::<Vec<u8>>();
+        for &b in backends().iter() {
+            let mut c = CompressBuilder::new(Compression::default())
+                .backend(b)
+                .build(true)
+                .unwrap();
+            let compressed = compress(&mut c, &data);
+            let cases: [(&[u8], DataErrorKind); 3] = [
+                (&compressed[..compressed.len() / 2], DataErrorKind::Truncated),
+                (&[0x78, 0x9d], DataErrorKind::Header),
+                (&[0x78, 0x9c, 0x07], DataErrorKind::BlockType),
+            ];
+            for &(input, kind) in cases.iter() {
+                let mut d = DecompressBuilder::new()
+                    .backend(b)
+                    .build(true)
+                    .unwrap();
+                let mut out = Vec::with_capacity(data.len());
+                let err = d.decompress_vec(input, &mut out, Flush::Finish)
+                    .unwrap_err();
+                // miniz_oxide can't say what was wrong with a block.
+                if kind == DataErrorKind::BlockType &&
+                   format!("{:?}", b) == "MinizOxide" {
+                    assert_eq!(err.kind(), DataErrorKind::Other);
+                } else {
+                    assert_eq!(err.kind(), kind, "{:?}: {:?}", b, err);
+                }
+            }
+
+            let mut corrupt = compressed.clone();
+            let n = corrupt.len();
+            corrupt[n - 1] ^= 1;
+            let mut d = DecompressBuilder::new()
+                .backend(b)
+                .build(true)
+                .unwrap();
+            let mut out = Vec::with_capacity(data.len());
+            let err = d.decompress_vec(&corrupt, &mut out, Flush::Finish)
+                .unwrap_err();
+            assert_eq!(err.kind(), DataErrorKind::Checksum, "{:?}", b);
+        }
+    }
+
+    #[test]
+    fn crc32() {
+        for &b in backends().iter() {
+            assert_eq!(b.crc32(0, b"123456789"), 0xcbf43926);
+            assert_eq!(b.crc32(b.crc32(0, b"1234"), b"56789"), 0xcbf43926);
+        }
+    }
+
+    // Delegates to the default backend, counting the streams created.
+    #[derive(Debug)]
+    struct Counting {
+        streams: AtomicUsize,
+    }
+
+    static COUNTING: Counting = Counting { streams: AtomicUsize::new(0) };
+
+    impl Backend for Counting {
+        fn supports_window_bits(&self) -> bool {
+            super::default().supports_window_bits()
+        }
+
+        fn supports_mem_level(&self) -> bool {
+            super::default().supports_mem_level()
+        }
+
+        fn supports_dictionary(&self) -> bool {
+            super::default().supports_dictionary()
+        }
+
+        fn deflate_init(&self,
+                        level: Compression,
+                        zlib_header: bool,
+                        window_bits: u8,
+                        mem_level: u8,
+                        strategy: Strategy,
+                        limit: Option<&MemoryLimit>)
+                        -> io::Result<Box<dyn DeflateStream>> {
+            self.streams.fetch_add(1, Ordering::SeqCst);
+            super::default().deflate_init(level, zlib_header, window_bits,
+                                          mem_level, strategy, limit)
+        }
+
+        fn inflate_init(&self,
+                        zlib_header: bool,
+                        window_bits: u8,
+                        limit: Option<&MemoryLimit>)
+                        -> io::Result<Box<dyn InflateStream>> {
+            self.streams.fetch_add(1, Ordering::SeqCst);
+            super::default().inflate_init(zlib_header, window_bits, limit)
+        }
+
+        fn crc32(&self, crc: u32, data: &[u8]) -> u32 {
+            super::default().crc32(crc, data)
+        }
+    }
+
+    #[test]
+    fn custom_backend() {
+        let data = b"a custom backend".iter().cycle().take(320)
+            .cloned().collect::<Vec<u8>>();
+        let mut c = CompressBuilder::new(Compression::best())
+            .backend(&COUNTING)
+            .build(true)
+            .unwrap();
+        let mut d = DecompressBuilder::new()
+            .backend(&COUNTING)
+            .build(true)
+            .unwrap();
+        assert_eq!(COUNTING.streams.load(Ordering::SeqCst), 2);
+
+        let compressed = compress(&mut c, &data);
+        assert!(decompress(&mut d, &compressed, data.len()) == data);
+    }
+}
@@ -0,0 +1,828 @@
+//! Blocked gzip (BGZF), as used by SAM/BAM, VCF and tabix
+//!
+//! A BGZF file is a series of gzip members, each holding at most 64 KiB of
+//! compressed and uncompressed data and recording its own compressed size in
+//! a "BC" subfield of the header's extra field. It ends with an empty member
+//! serving as an end-of-file marker. Since every member can be decompressed
+//! on its own, any position in the uncompressed data can be reached through a
+//! [`VirtualOffset`], which combines the offset of a member in the file with
+//! an offset into its uncompressed data.
+//!
+//! A BGZF file is a valid gzip file, so it can also be read with any of the
+//! multi-member gzip decoders in this crate.
+//!
+//! [`VirtualOffset`]: struct.VirtualOffset.html
+//!
+//! # Examples
+//!
+//! ```
+//! use std::io::prelude::*;
+//! use std::io::{Cursor, SeekFrom};
+//! use flate2::Compression;
+//! use flate2::bgzf;
+//!
+//! let mut w = bgzf::Writer::new(Vec::new(), Compression::default());
+//! w.write_all(b"chr1\t100\n").unwrap();
+//! let offset = w.virtual_offset();
+//! w.write_all(b"chr2\t200\n").unwrap();
+//! let file = w.finish().unwrap();
+//!
+//! let mut r = bgzf::Reader::new(Cursor::new(file));
+//! r.seek(SeekFrom::Start(offset.into())).unwrap();
+//! let mut line = String::new();
+//! r.read_line(&mut line).unwrap();
+//! assert_eq!(line, "chr2\t200\n");
+//! ```
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use std::sync::mpsc::{self, Receiver};
+
+use {Compression, DecompressFormat, Format};
+use {compress_to_vec, decompress_to_vec};
+use crc::Crc;
+use gz;
+use parallel::Pool;
+
+/// The largest block, header and trailer included.
+const MAX_BLOCK_SIZE: usize = 64 * 1024;
+
+/// The most data the writer puts into a block, which leaves room for the
+/// block to be stored uncompressed if it doesn't compress.
+const MAX_BLOCK_DATA: usize = 0xff00;
+
+/// The length of the header written for every block.
+const HEADER_LEN: usize = 18;
+
+const EOF_MARKER: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00,
+    0x42, 0x43, 0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00,
+];
+
+/// A position in the uncompressed data of a BGZF file
+///
+/// The upper 48 bits are the offset of a block in the compressed file, and
+/// the lower 16 bits are an offset into the block's uncompressed data. This
+/// converts to and from the `u64` used by BAM and tabix indexes, and by the
+/// `Seek` implementation of [`Reader`].
+///
+/// [`Reader`]: struct.Reader.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VirtualOffset(u64);
+
+/// A writer compressing data into the BGZF format
+///
+/// Data is collected into blocks of a little less than 64 KiB, each of which
+/// is written to the underlying writer `W` as a separate gzip member. The
+/// end-of-file marker is written by [`finish`], or when the writer is
+/// dropped.
+///
+/// [`finish`]: #method.finish
+#[derive(Debug)]
+pub struct Writer<W: Write> {
+    inner: Option<W>,
+    level: Compression,
+    block: Vec<u8>,
+    // Compressed output that hasn't been written yet.
+    buf: Vec<u8>,
+    // The compressed and uncompressed sizes of the blocks emitted so far.
+    offset: u64,
+    total: u64,
+    index: GziIndex,
+    finished: bool,
+}
+
+/// A reader decompressing data in the BGZF format
+///
+/// Blocks are decompressed one at a time as they're read, or ahead of time
+/// on a pool of threads if the reader is created with [`with_threads`].
+/// When the underlying reader implements `Seek`, so does this reader, with
+/// the positions being [`VirtualOffset`]s converted to `u64`.
+///
+/// [`with_threads`]: #method.with_threads
+/// [`VirtualOffset`]: struct.VirtualOffset.html
+#[derive(Debug)]
+pub struct Reader<R: Read> {
+    inner: R,
+    pool: Option<Pool>,
+    // The offset in `inner` of the next block to be read.
+    offset: u64,
+    // Blocks being decompressed by the pool, in order.
+    pending: VecDeque<Pending>,
+    block: Block,
+    pos: usize,
+}
+
+/// The index of a BGZF file, as stored in the `.gzi` files written by
+/// `bgzip -i`
+///
+/// The index maps the start of every block in the file but the first to its
+/// offset in the uncompressed data, so that a position in the uncompressed
+/// data can be translated into a [`VirtualOffset`].
+///
+/// [`VirtualOffset`]: struct.VirtualOffset.html
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct GziIndex {
+    entries: Vec<(u64, u64)>,
+}
+
+#[derive(Debug)]
+struct Block {
+    offset: u64,
+    next: u64,
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    offset: u64,
+    next: u64,
+    data: Receiver<io::Result<Vec<u8>>>,
+}
+
+impl VirtualOffset {
+    /// Creates a virtual offset from the offset of a block in the compressed
+    /// file and an offset into the block's uncompressed data.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `compressed` doesn't fit in 48 bits.
+    pub fn new(compressed: u64, uncompressed: u16) -> VirtualOffset {
+        assert!(compressed < 1 << 48, "compressed offset out of range");
+        VirtualOffset(compressed << 16 | uncompressed as u64)
+    }
+
+    /// Returns the offset of the block in the compressed file.
+    pub fn compressed(&self) -> u64 {
+        self.0 >> 16
+    }
+
+    /// Returns the offset into the block's uncompressed data.
+    pub fn uncompressed(&self) -> u16 {
+        self.0 as u16
+    }
+}
+
+impl From<u64> for VirtualOffset {
+    fn from(offset: u64) -> VirtualOffset {
+        VirtualOffset(offset)
+    }
+}
+
+impl From<VirtualOffset> for u64 {
+    fn from(offset: VirtualOffset) -> u64 {
+        offset.0
+    }
+}
+
+impl<W: Write> Writer<W> {
+    /// Creates a new writer which will compress blocks at the given level
+    /// and write them to `w`.
+    pub fn new(w: W, level: Compression) -> Writer<W> {
+        Writer {
+            inner: Some(w),
+            level: level,
+            block: Vec::with_capacity(MAX_BLOCK_DATA),
+            buf: Vec::new(),
+            offset: 0,
+            total: 0,
+            index: GziIndex::new(),
+            finished: false,
+        }
+    }
+
+    /// Returns the virtual offset at which the next byte written will be
+    /// found.
+    pub fn virtual_offset(&self) -> VirtualOffset {
+        VirtualOffset::new(self.offset, self.block.len() as u16)
+    }
+
+    /// Returns the index of the blocks emitted so far.
+    ///
+    /// The index is complete once the writer has been finished or flushed.
+    pub fn index(&self) -> &GziIndex {
+        &self.index
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Note that mutation of the writer may result in surprising results if
+    /// this writer is continued to be used.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.as_mut().unwrap()
+    }
+
+    /// Attempt to finish this output stream, writing out the last block and
+    /// the end-of-file marker.
+    ///
+    /// Note that this function can only be used once data has finished being
+    /// written to the output stream. After this function is called then
+    /// further calls to `write` may result in a panic.
+    ///
+    /// # Errors
+    ///
+    /// This function will perform I/O to complete this stream, and any I/O
+    /// errors which occur will be returned from this function.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        if !self.finished {
+            try!(self.emit());
+            self.buf.extend_from_slice(&EOF_MARKER);
+            self.offset += EOF_MARKER.len() as u64;
+            self.finished = true;
+        }
+        self.write_buf()
+    }
+
+    /// Finish this stream, returning the underlying writer.
+    ///
+    /// # Errors
+    ///
+    /// This function will perform I/O to complete this stream, and any I/O
+    /// errors which occur will be returned from this function.
+    pub fn finish(mut self) -> io::Result<W> {
+        try!(self.try_finish());
+        Ok(self.inner.take().unwrap())
+    }
+
+    // Compresses the current block, if there's anything in it.
+    fn emit(&mut self) -> io::Result<()> {
+        if self.block.len() == 0 {
+            return Ok(())
+        }
+        if self.offset > 0 {
+            self.index.entries.push((self.offset, self.total));
+        }
+        let block = try!(encode_block(&self.block, self.level));
+        self.offset += block.len() as u64;
+        self.total += self.block.len() as u64;
+        self.buf.extend_from_slice(&block);
+        self.block.clear();
+        Ok(())
+    }
+
+    fn write_buf(&mut self) -> io::Result<()> {
+        while self.buf.len() > 0 {
+            let n = try!(self.inner.as_mut().unwrap().write(&self.buf));
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into())
+            }
+            self.buf.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        assert!(!self.finished);
+        try!(self.write_buf());
+        // A full block is only compressed once more data comes, so that a
+        // failure doesn't leave data taken from `buf` behind.
+        if self.block.len() == MAX_BLOCK_DATA {
+            try!(self.emit());
+        }
+        let n = cmp::min(buf.len(), MAX_BLOCK_DATA - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.finished {
+            try!(self.emit());
+        }
+        try!(self.write_buf());
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for Writer<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+impl<R: Read> Reader<R> {
+    /// Creates a new reader which will decompress the blocks read from `r`.
+    pub fn new(r: R) -> Reader<R> {
+        Reader {
+            inner: r,
+            pool: None,
+            offset: 0,
+            pending: VecDeque::new(),
+            block: Block {
+                offset: 0,
+                next: 0,
+                data: Vec::new(),
+            },
+            pos: 0,
+        }
+    }
+
+    /// Creates a new reader which will read ahead of the data being returned
+    /// and decompress blocks on `threads` threads in the background.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `threads` is zero.
+    pub fn with_threads(r: R, threads: usize) -> Reader<R> {
+        assert!(threads > 0, "a reader needs at least one thread");
+        let mut reader = Reader::new(r);
+        reader.pool = Some(Pool::new(threads));
+        reader
+    }
+
+    /// Returns the virtual offset of the next byte to be read.
+    pub fn virtual_offset(&self) -> VirtualOffset {
+        if self.pos < self.block.data.len() {
+            VirtualOffset::new(self.block.offset, self.pos as u16)
+        } else {
+            VirtualOffset::new(self.block.next, 0)
+        }
+    }
+
+    /// Acquires a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Acquires a mutable reference to the underlying reader.
+    ///
+    /// Note that mutation of the reader may result in surprising results if
+    /// this reader is continued to be used.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Consumes this reader, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    // Reads and decompresses the next block, returning `false` at the end of
+    // the input.
+    fn next_block(&mut self) -> io::Result<bool> {
+        let depth = match self.pool {
+            Some(ref pool) => 2 * pool.threads(),
+            None => 0,
+        };
+        while self.pending.len() < depth {
+            let offset = self.offset;
+            let raw = match read_block(&mut self.inner) {
+                Ok(Some(raw)) => raw,
+                Ok(None) => break,
+                Err(e) => {
+                    // Reported once the blocks before it have been read.
+                    let (tx, rx) = mpsc::channel();
+                    drop(tx.send(Err(e)));
+                    self.pending.push_back(Pending {
+                        offset: offset,
+                        next: offset,
+                        data: rx,
+                    });
+                    break
+                }
+            };
+            self.offset += raw.len() as u64;
+            let (tx, rx) = mpsc::channel();
+            self.pool.as_ref().unwrap().run(move || {
+                drop(tx.send(decode_block(&raw)));
+            });
+            self.pending.push_back(Pending {
+                offset: offset,
+                next: self.offset,
+                data: rx,
+            });
+        }
+
+        let (offset, next, data) = match self.pending.pop_front() {
+            Some(pending) => {
+                let data = match pending.data.recv() {
+                    Ok(result) => try!(result),
+                    Err(_) => {
+                        return Err(io::Error::new(io::ErrorKind::Other,
+                                                  "decompression thread \
+                                                   panicked"))
+                    }
+                };
+                (pending.offset, pending.next, data)
+            }
+            None => {
+                let offset = self.offset;
+                let raw = match try!(read_block(&mut self.inner)) {
+                    Some(raw) => raw,
+                    None => return Ok(false),
+                };
+                self.offset += raw.len() as u64;
+                (offset, self.offset, try!(decode_block(&raw)))
+            }
+        };
+        self.block = Block {
+            offset: offset,
+            next: next,
+            data: data,
+        };
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, into: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let data = try!(self.fill_buf());
+            let n = cmp::min(data.len(), into.len());
+            into[..n].copy_from_slice(&data[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read> BufRead for Reader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // Empty blocks, like the end-of-file marker, are skipped.
+        while self.pos == self.block.data.len() {
+            if !try!(self.next_block()) {
+                break
+            }
+        }
+        Ok(&self.block.data[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = cmp::min(self.pos + amt, self.block.data.len());
+    }
+}
+
+impl<R: Read + Seek> Seek for Reader<R> {
+    /// Seeks to a virtual offset, returning it again.
+    ///
+    /// Only `SeekFrom::Start` is supported, as virtual offsets can't be
+    /// added to or subtracted from.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => VirtualOffset::from(offset),
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "BGZF readers can only seek to a \
+                                           virtual offset"))
+            }
+        };
+        self.pending.clear();
+        try!(self.inner.seek(SeekFrom::Start(offset.compressed())));
+        self.offset = offset.compressed();
+        self.block = Block {
+            offset: self.offset,
+            next: self.offset,
+            data: Vec::new(),
+        };
+        self.pos = 0;
+        try!(self.next_block());
+        if offset.uncompressed() as usize > self.block.data.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "virtual offset is past the end of \
+                                       its block"))
+        }
+        self.pos = offset.uncompressed() as usize;
+        Ok(offset.into())
+    }
+}
+
+impl GziIndex {
+    /// Creates an empty index, which is the index of a file with at most one
+    /// block.
+    pub fn new() -> GziIndex {
+        GziIndex { entries: Vec::new() }
+    }
+
+    /// Builds the index of the BGZF file read from `r`, reading only the
+    /// block headers and trailers.
+    pub fn build<R: Read>(mut r: R) -> io::Result<GziIndex> {
+        let mut index = GziIndex::new();
+        let (mut offset, mut total) = (0, 0);
+        while let Some(raw) = try!(read_block(&mut r)) {
+            let isize = read_le_u32(&raw[raw.len() - 4..]) as u64;
+            if offset > 0 && isize > 0 {
+                index.entries.push((offset, total));
+            }
+            offset += raw.len() as u64;
+            total += isize;
+        }
+        Ok(index)
+    }
+
+    /// Reads an index in the `.gzi` format.
+    pub fn read_from<R: Read>(mut r: R) -> io::Result<GziIndex> {
+        let mut buf = [0; 8];
+        try!(r.read_exact(&mut buf));
+        let count = read_le_u64(&buf);
+        let mut index = GziIndex::new();
+        for _ in 0..count {
+            try!(r.read_exact(&mut buf));
+            let compressed = read_le_u64(&buf);
+            try!(r.read_exact(&mut buf));
+            index.entries.push((compressed, read_le_u64(&buf)));
+        }
+        Ok(index)
+    }
+
+    /// Writes this index in the `.gzi` format.
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        try!(w.write_all(&le_u64(self.entries.len() as u64)));
+        for &(compressed, uncompressed) in self.entries.iter() {
+            try!(w.write_all(&le_u64(compressed)));
+            try!(w.write_all(&le_u64(uncompressed)));
+        }
+        Ok(())
+    }
+
+    /// Returns the offsets of the blocks in the compressed file and in the
+    /// uncompressed data, in order.
+    pub fn entries(&self) -> &[(u64, u64)] {
+        &self.entries
+    }
+
+    /// Translates an offset in the uncompressed data into a virtual offset.
+    ///
+    /// Returns `None` if the offset is too far past the last block in the
+    /// index to be expressed as a virtual offset.
+    pub fn virtual_offset(&self, uncompressed: u64)
+                          -> Option<VirtualOffset> {
+        let i = match self.entries.binary_search_by_key(&uncompressed,
+                                                         |e| e.1) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+        let (compressed, start) = match i {
+            0 => (0, 0),
+            i => self.entries[i - 1],
+        };
+        let within = uncompressed - start;
+        if within > u16::max_value() as u64 {
+            return None
+        }
+        Some(VirtualOffset::new(compressed, within as u16))
+    }
+}
+
+// Compresses `data` into a complete block.
+fn encode_block(data: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    let mut deflated = try!(compress_to_vec(data, Format::Raw, level));
+    if HEADER_LEN + deflated.len() + 8 > MAX_BLOCK_SIZE {
+        deflated = try!(compress_to_vec(data, Format::Raw,
+                                        Compression::none()));
+    }
+    let bsize = HEADER_LEN + deflated.len() + 8 - 1;
+    let extra = vec![b'B', b'C', 2, 0, bsize as u8, (bsize >> 8) as u8];
+    let mut block = gz::builder_header(gz::Builder::new().extra(extra),
+                                       level);
+    debug_assert_eq!(block.len(), HEADER_LEN);
+    block.extend_from_slice(&deflated);
+
+    let mut crc = Crc::new();
+    crc.update(data);
+    block.extend_from_slice(&le_u32(crc.sum()));
+    block.extend_from_slice(&le_u32(data.len() as u32));
+    Ok(block)
+}
+
+// Decompresses a block read by `read_block`, checking its trailer.
+fn decode_block(raw: &[u8]) -> io::Result<Vec<u8>> {
+    let mut rest = raw;
+    try!(gz::read_gz_header(&mut rest));
+    if rest.len() < 8 {
+        return Err(invalid_block())
+    }
+    let (deflated, trailer) = rest.split_at(rest.len() - 8);
+    let data = try!(decompress_to_vec(deflated, DecompressFormat::Raw,
+                                      Some(MAX_BLOCK_SIZE)));
+    let mut crc = Crc::new();
+    crc.update(&data);
+    if read_le_u32(&trailer[..4]) != crc.sum() ||
+       read_le_u32(&trailer[4..]) != data.len() as u32 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "corrupt BGZF block does not have a \
+                                   matching checksum"))
+    }
+    Ok(data)
+}
+
+// Reads a complete compressed block, using the size recorded in its header,
+// or returns `None` at the end of the input.
+fn read_block<R: Read>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut raw = vec![0; 12];
+    let mut n = 0;
+    while n < raw.len() {
+        match r.read(&mut raw[n..]) {
+            Ok(0) if n == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(m) => n += m,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if raw[0] != 0x1f || raw[1] != 0x8b || raw[2] != 8 ||
+       raw[3] & gz::FEXTRA == 0 {
+        return Err(invalid_block())
+    }
+    let xlen = (raw[10] as usize) | (raw[11] as usize) << 8;
+    raw.resize(12 + xlen, 0);
+    try!(r.read_exact(&mut raw[12..]));
+
+    // Look for the BC subfield among those in the extra field.
+    let mut bsize = None;
+    let mut extra = &raw[12..];
+    while extra.len() >= 4 {
+        let len = (extra[2] as usize) | (extra[3] as usize) << 8;
+        if extra.len() < 4 + len {
+            break
+        }
+        if extra[0] == b'B' && extra[1] == b'C' && len == 2 {
+            bsize = Some((extra[4] as usize) | (extra[5] as usize) << 8);
+        }
+        extra = &extra[4 + len..];
+    }
+    let size = match bsize {
+        Some(bsize) if bsize + 1 >= raw.len() + 8 => bsize + 1,
+        _ => return Err(invalid_block()),
+    };
+    let len = raw.len();
+    raw.resize(size, 0);
+    try!(r.read_exact(&mut raw[len..]));
+    Ok(Some(raw))
+}
+
+fn invalid_block() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid BGZF block header")
+}
+
+fn le_u32(n: u32) -> [u8; 4] {
+    [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
+
+fn le_u64(n: u64) -> [u8; 8] {
+    let mut b = [0; 8];
+    for (i, b) in b.iter_mut().enumerate() {
+        *b = (n >> (8 * i)) as u8;
+    }
+    b
+}
+
+fn read_le_u32(b: &[u8]) -> u32 {
+    (b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 |
+        (b[3] as u32) << 24
+}
+
+fn read_le_u64(b: &[u8]) -> u64 {
+    b.iter().rev().fold(0, |n, &b| n << 8 | b as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::{Cursor, SeekFrom};
+
+    use rand::{thread_rng, Rng};
+
+    use Compression;
+    use read::MultiGzDecoder;
+    use super::*;
+
+    fn data() -> Vec<u8> {
+        let mut rng = thread_rng();
+        let mut data = Vec::new();
+        for i in 0..20_000 {
+            let line = format!("chr{}\t{}\t{}\n", i % 22 + 1, i * 100,
+                               rng.gen::<u16>() % 4);
+            data.extend_from_slice(line.as_bytes());
+        }
+        data.extend(rng.gen_iter::<u8>().take(100_000));
+        data
+    }
+
+    fn write(data: &[u8]) -> (Vec<u8>, Vec<(VirtualOffset, usize)>,
+                              GziIndex) {
+        let mut w = Writer::new(Vec::new(), Compression::default());
+        let mut offsets = Vec::new();
+        let mut pos = 0;
+        for chunk in data.chunks(7919) {
+            offsets.push((w.virtual_offset(), pos));
+            w.write_all(chunk).unwrap();
+            pos += chunk.len();
+        }
+        w.flush().unwrap();
+        let index = w.index().clone();
+        (w.finish().unwrap(), offsets, index)
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = data();
+        let (file, _, _) = write(&data);
+        assert_eq!(&file[file.len() - 28..], &EOF_MARKER[..]);
+
+        let mut out = Vec::new();
+        Reader::new(&file[..]).read_to_end(&mut out).unwrap();
+        assert!(out == data);
+
+        let mut out = Vec::new();
+        MultiGzDecoder::new(&file[..]).unwrap().read_to_end(&mut out)
+            .unwrap();
+        assert!(out == data);
+    }
+
+    #[test]
+    fn empty() {
+        let file = Writer::new(Vec::new(), Compression::default())
+            .finish()
+            .unwrap();
+        assert_eq!(file, &EOF_MARKER[..]);
+        let mut out = Vec::new();
+        Reader::new(&file[..]).read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"");
+    }
+
+    #[test]
+    fn threads() {
+        let data = data();
+        let (file, _, _) = write(&data);
+        for &threads in [1, 3].iter() {
+            let mut out = Vec::new();
+            Reader::with_threads(&file[..], threads).read_to_end(&mut out)
+                .unwrap();
+            assert!(out == data);
+        }
+    }
+
+    #[test]
+    fn seek() {
+        let data = data();
+        let (file, offsets, _) = write(&data);
+        let mut r = Reader::with_threads(Cursor::new(file), 2);
+        for &(offset, pos) in offsets.iter().rev() {
+            assert_eq!(r.seek(SeekFrom::Start(offset.into())).unwrap(),
+                       u64::from(offset));
+            let mut buf = [0; 100];
+            r.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &data[pos..pos + 100]);
+        }
+        assert!(r.seek(SeekFrom::Current(0)).is_err());
+    }
+
+    #[test]
+    fn virtual_offsets() {
+        let data = data();
+        let (file, offsets, _) = write(&data);
+        let mut r = Reader::new(&file[..]);
+        let mut pos = 0;
+        for &(offset, start) in offsets.iter() {
+            let mut buf = vec![0; start - pos];
+            r.read_exact(&mut buf).unwrap();
+            pos = start;
+            assert_eq!(r.virtual_offset(), offset);
+        }
+    }
+
+    #[test]
+    fn gzi() {
+        let data = data();
+        let (file, _, index) = write(&data);
+        assert!(index.entries().len() > 1);
+        assert_eq!(GziIndex::build(&file[..]).unwrap(), index);
+
+        let mut gzi = Vec::new();
+        index.write_to(&mut gzi).unwrap();
+        assert_eq!(gzi.len(), 8 + 16 * index.entries().len());
+        assert_eq!(GziIndex::read_from(&gzi[..]).unwrap(), index);
+
+        let mut r = Reader::new(Cursor::new(file));
+        for &pos in [0, 1, 65279, 65280, 100_000, data.len() - 10].iter() {
+            let offset = index.virtual_offset(pos as u64).unwrap();
+            r.seek(SeekFrom::Start(offset.into())).unwrap();
+            let mut buf = [0; 10];
+            r.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &data[pos..pos + 10]);
+        }
+    }
+
+    #[test]
+    fn corrupt() {
+        let (mut file, _, _) = write(b"hello world");
+        file[20] ^= 0xff;
+        let mut out = Vec::new();
+        assert!(Reader::new(&file[..]).read_to_end(&mut out).is_err());
+        assert!(Reader::new(&b"not bgzf"[..]).read_to_end(&mut out)
+            .is_err());
+    }
+}
@@ -1,18 +1,27 @@
-//! Simple CRC bindings backed by miniz.c
+//! CRC-32 checksums, as used by the gzip format
+//!
+//! The checksum is computed in Rust rather than by the compression backend.
+//! Large buffers are folded with carry-less multiplication on x86_64 CPUs
+//! with PCLMULQDQ, and use the CRC-32 instructions on aarch64 CPUs that have
+//! them, as long as the compiler is new enough to provide the intrinsics for
+//! them (see the build script). Everything else uses a slice-by-8 table
+//! lookup.
 
+use std::hash::Hasher;
 use std::io::prelude::*;
 use std::io;
-use libc;
 
-use ffi;
-
-/// The CRC calculated by a [`CrcReader`].
+/// The CRC calculated by a [`CrcReader`] or [`CrcWriter`].
+///
+/// `Crc` also implements [`Hasher`], with `finish` returning the CRC.
 ///
 /// [`CrcReader`]: struct.CrcReader.html
-#[derive(Debug)]
+/// [`CrcWriter`]: struct.CrcWriter.html
+/// [`Hasher`]: https://doc.rust-lang.org/std/hash/trait.Hasher.html
+#[derive(Clone, Debug)]
 pub struct Crc {
-    crc: libc::c_ulong,
-    amt: u32,
+    crc: u32,
+    amt: u64,
 }
 
 /// A wrapper around a [`Read`] that calculates the CRC.
@@ -24,29 +33,43 @@ pub struct CrcReader<R> {
     crc: Crc,
 }
 
+/// A wrapper around a [`Write`] that calculates the CRC.
+///
+/// [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
+#[derive(Debug)]
+pub struct CrcWriter<W> {
+    inner: W,
+    crc: Crc,
+}
+
 impl Crc {
     /// Create a new CRC.
     pub fn new() -> Crc {
         Crc { crc: 0, amt: 0 }
     }
 
-    /// bla
+    /// Create a CRC from a checksum and the number of bytes it covers, such as
+    /// one computed elsewhere, so that it can be passed to `combine`.
+    pub fn from_parts(sum: u32, amount: u64) -> Crc {
+        Crc { crc: sum, amt: amount }
+    }
+
+    /// The current value of the CRC.
     pub fn sum(&self) -> u32 {
-        self.crc as u32
+        self.crc
     }
 
     /// The number of bytes that have been used to calculate the CRC.
-    /// This value is only accurate if the amount is lower than 2^32.
-    pub fn amount(&self) -> u32 {
+    ///
+    /// The gzip trailer only stores this modulo 2^32.
+    pub fn amount(&self) -> u64 {
         self.amt
     }
 
     /// Update the CRC with the bytes in `data`.
     pub fn update(&mut self, data: &[u8]) {
-        self.amt = self.amt.wrapping_add(data.len() as u32);
-        self.crc = unsafe {
-            ffi::mz_crc32(self.crc, data.as_ptr(), data.len() as libc::size_t)
-        };
+        self.amt = self.amt.wrapping_add(data.len() as u64);
+        self.crc = update(self.crc, data);
     }
 
     /// Reset the CRC.
@@ -57,12 +80,8 @@ impl Crc {
 
     /// Combine the CRC with the CRC for the subsequent block of bytes.
     pub fn combine(&mut self, additional_crc: &Crc) {
-        self.crc = unsafe {
-            ffi::mz_crc32_combine(self.crc as ::libc::c_ulong,
-                                  additional_crc.crc as ::libc::c_ulong,
-                                  additional_crc.amt as ::libc::off_t)
-        };
-        self.amt += additional_crc.amt;
+        self.crc = combine(self.crc, additional_crc.crc, additional_crc.amt);
+        self.amt = self.amt.wrapping_add(additional_crc.amt);
     }
 }
 
@@ -122,3 +141,332 @@ impl<R: BufRead> BufRead for CrcReader<R> {
         self.inner.consume(amt);
     }
 }
+
+impl Hasher for Crc {
+    fn write(&mut self, bytes: &[u8]) {
+        self.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        self.sum() as u64
+    }
+}
+
+impl<W: Write> CrcWriter<W> {
+    /// Create a new CrcWriter.
+    pub fn new(w: W) -> CrcWriter<W> {
+        CrcWriter {
+            inner: w,
+            crc: Crc::new(),
+        }
+    }
+}
+
+impl<W> CrcWriter<W> {
+    /// Get the Crc for this CrcWriter.
+    pub fn crc(&self) -> &Crc {
+        &self.crc
+    }
+
+    /// Get the writer that is wrapped by this CrcWriter.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Get the writer that is wrapped by this CrcWriter by reference.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the writer that is wrapped by this CrcWriter.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Reset the Crc in this CrcWriter.
+    pub fn reset(&mut self) {
+        self.crc.reset();
+    }
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let amt = try!(self.inner.write(buf));
+        self.crc.update(&buf[..amt]);
+        Ok(amt)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Updates `crc` with `data`, picking the fastest implementation the CPU
+// supports. The accelerated versions only pay off on longer buffers.
+fn update(crc: u32, data: &[u8]) -> u32 {
+    #[cfg(all(target_arch = "x86_64", flate2_pclmul_crc))]
+    {
+        if data.len() >= 128 &&
+           is_x86_feature_detected!("pclmulqdq") &&
+           is_x86_feature_detected!("sse4.1") {
+            return unsafe { pclmul::update(crc, data) }
+        }
+    }
+    #[cfg(all(target_arch = "aarch64", flate2_arm_crc))]
+    {
+        if data.len() >= 8 && ::std::arch::is_aarch64_feature_detected!("crc") {
+            return unsafe { aarch64::update(crc, data) }
+        }
+    }
+    update_slice8(crc, data)
+}
+
+// TABLES[0] is the usual byte-at-a-time table for the reflected polynomial,
+// and TABLES[k][n] is the CRC of byte `n` followed by `k` zero bytes. They're
+// generated by the build script.
+static TABLES: [[u32; 256]; 8] = include!(concat!(env!("OUT_DIR"),
+                                                 "/crc_tables.rs"));
+
+fn update_slice8(crc: u32, mut data: &[u8]) -> u32 {
+    let t = &TABLES;
+    let mut crc = !crc;
+    while data.len() >= 8 {
+        let lo = crc ^ ((data[0] as u32) | (data[1] as u32) << 8 |
+                        (data[2] as u32) << 16 | (data[3] as u32) << 24);
+        crc = t[7][(lo & 0xff) as usize] ^
+              t[6][((lo >> 8) & 0xff) as usize] ^
+              t[5][((lo >> 16) & 0xff) as usize] ^
+              t[4][(lo >> 24) as usize] ^
+              t[3][data[4] as usize] ^
+              t[2][data[5] as usize] ^
+              t[1][data[6] as usize] ^
+              t[0][data[7] as usize];
+        data = &data[8..];
+    }
+    for &byte in data {
+        crc = t[0][((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// Returns the CRC of the data covered by `crc1` followed by `len2` bytes with
+// the CRC `crc2`. Appending `len2` bytes multiplies the CRC by x^(8 * len2)
+// modulo the polynomial, which takes a squaring per bit of `len2`.
+fn combine(crc1: u32, crc2: u32, len2: u64) -> u32 {
+    // x^8, to start with, in the same bit-reflected form as the CRC itself,
+    // where x^0 is the top bit.
+    let mut power = 1 << (31 - 8);
+    let mut factor = 1 << 31;
+    let mut n = len2;
+    while n != 0 {
+        if n & 1 == 1 {
+            factor = multiply(factor, power);
+        }
+        power = multiply(power, power);
+        n >>= 1;
+    }
+    multiply(factor, crc1) ^ crc2
+}
+
+// Multiplies `a` and `b` modulo the polynomial, both bit-reflected. `a` must
+// not be zero.
+fn multiply(a: u32, mut b: u32) -> u32 {
+    let mut m = 1 << 31;
+    let mut p = 0;
+    loop {
+        if a & m != 0 {
+            p ^= b;
+            if a & (m - 1) == 0 {
+                return p
+            }
+        }
+        m >>= 1;
+        b = if b & 1 == 1 { 0xedb88320 ^ (b >> 1) } else { b >> 1 };
+    }
+}
+
+// Folding with carry-less multiplication, following Intel's "Fast CRC
+// Computation for Generic Polynomials Using PCLMULQDQ Instruction" with the
+// constants for the bit-reflected gzip polynomial. SSE4.2's `crc32`
+// instruction is no help here, as it uses the Castagnoli polynomial.
+#[cfg(all(target_arch = "x86_64", flate2_pclmul_crc))]
+mod pclmul {
+    use std::arch::x86_64::*;
+
+    // x^(4*128+32) mod P, x^(4*128-32) mod P, x^(128+32) mod P,
+    // x^(128-32) mod P and x^64 mod P, all bit-reflected.
+    const K1: i64 = 0x154442bd4;
+    const K2: i64 = 0x1c6e41596;
+    const K3: i64 = 0x1751997d0;
+    const K4: i64 = 0x0ccaa009e;
+    const K5: i64 = 0x163cd6124;
+
+    // The polynomial itself, and floor(x^64 / P), for the Barrett reduction.
+    const P: i64 = 0x1db710641;
+    const MU: i64 = 0x1f7011641;
+
+    #[target_feature(enable = "pclmulqdq", enable = "sse2",
+                     enable = "sse4.1")]
+    pub unsafe fn update(crc: u32, mut data: &[u8]) -> u32 {
+        debug_assert!(data.len() >= 64);
+
+        // Fold four 128-bit lanes at a time, with the initial CRC mixed
+        // into the first one.
+        let mut x3 = load(&mut data);
+        let mut x2 = load(&mut data);
+        let mut x1 = load(&mut data);
+        let mut x0 = load(&mut data);
+        x3 = _mm_xor_si128(x3, _mm_cvtsi32_si128(!crc as i32));
+
+        let k1k2 = _mm_set_epi64x(K2, K1);
+        while data.len() >= 64 {
+            x3 = fold(x3, load(&mut data), k1k2);
+            x2 = fold(x2, load(&mut data), k1k2);
+            x1 = fold(x1, load(&mut data), k1k2);
+            x0 = fold(x0, load(&mut data), k1k2);
+        }
+
+        // Fold the lanes into one, then any remaining 128-bit blocks.
+        let k3k4 = _mm_set_epi64x(K4, K3);
+        let mut x = fold(x3, x2, k3k4);
+        x = fold(x, x1, k3k4);
+        x = fold(x, x0, k3k4);
+        while data.len() >= 16 {
+            x = fold(x, load(&mut data), k3k4);
+        }
+
+        // Reduce 128 bits to 64 bits.
+        let low32 = _mm_set_epi32(0, 0, 0, !0);
+        let x = _mm_xor_si128(_mm_clmulepi64_si128(x, k3k4, 0x10),
+                              _mm_srli_si128(x, 8));
+        let x = _mm_xor_si128(
+            _mm_clmulepi64_si128(_mm_and_si128(x, low32),
+                                 _mm_set_epi64x(0, K5),
+                                 0x00),
+            _mm_srli_si128(x, 4));
+
+        // Barrett reduction of the last 64 bits to the 32-bit CRC.
+        let pmu = _mm_set_epi64x(MU, P);
+        let t1 = _mm_clmulepi64_si128(_mm_and_si128(x, low32), pmu, 0x10);
+        let t2 = _mm_clmulepi64_si128(_mm_and_si128(t1, low32), pmu, 0x00);
+        let crc = !(_mm_extract_epi32(_mm_xor_si128(x, t2), 1) as u32);
+
+        super::update_slice8(crc, data)
+    }
+
+    #[target_feature(enable = "pclmulqdq", enable = "sse2")]
+    unsafe fn fold(x: __m128i, next: __m128i, keys: __m128i) -> __m128i {
+        let lo = _mm_clmulepi64_si128(x, keys, 0x00);
+        let hi = _mm_clmulepi64_si128(x, keys, 0x11);
+        _mm_xor_si128(_mm_xor_si128(next, lo), hi)
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn load(data: &mut &[u8]) -> __m128i {
+        let x = _mm_loadu_si128(data.as_ptr() as *const __m128i);
+        *data = &data[16..];
+        x
+    }
+}
+
+#[cfg(all(target_arch = "aarch64", flate2_arm_crc))]
+mod aarch64 {
+    use std::arch::aarch64::{__crc32b, __crc32d};
+
+    #[target_feature(enable = "crc")]
+    pub unsafe fn update(crc: u32, data: &[u8]) -> u32 {
+        let mut crc = !crc;
+        let mut words = data.chunks_exact(8);
+        for word in &mut words {
+            let mut n = 0;
+            for (i, &byte) in word.iter().enumerate() {
+                n |= (byte as u64) << (8 * i);
+            }
+            crc = __crc32d(crc, n);
+        }
+        for &byte in words.remainder() {
+            crc = __crc32b(crc, byte);
+        }
+        !crc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::Hasher;
+    use std::io::prelude::*;
+
+    use rand::{thread_rng, Rng};
+
+    use backend;
+    use super::*;
+
+    fn reference(data: &[u8]) -> u32 {
+        backend::default().crc32(0, data)
+    }
+
+    #[test]
+    fn matches_backend() {
+        let data = thread_rng().gen_iter::<u8>().take(10_000)
+            .collect::<Vec<_>>();
+        let lens = [0, 1, 7, 8, 9, 63, 64, 127, 128, 129, 200, 1000, 10_000];
+        for &len in lens.iter() {
+            for &start in [0, 1, 5].iter() {
+                let data = &data[start..len.max(start)];
+                let expected = reference(data);
+                let mut crc = Crc::new();
+                crc.update(data);
+                assert_eq!(crc.sum(), expected);
+                assert_eq!(update_slice8(0, data), expected);
+                let split = data.len() / 3;
+                let partial = update_slice8(0, &data[..split]);
+                assert_eq!(update(partial, &data[split..]), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn writer_and_hasher() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+        let mut w = CrcWriter::new(Vec::new());
+        w.write_all(data).unwrap();
+        assert_eq!(w.crc().sum(), 0x414fa339);
+        assert_eq!(w.crc().amount(), data.len() as u64);
+        assert_eq!(w.into_inner(), &data[..]);
+
+        let mut hasher = Crc::new();
+        hasher.write(&data[..10]);
+        hasher.write(&data[10..]);
+        assert_eq!(hasher.finish(), 0x414fa339);
+    }
+
+    #[test]
+    fn from_parts() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+        let (first, second) = data.split_at(20);
+        let mut crc = Crc::new();
+        crc.update(first);
+        let mut rest = Crc::new();
+        rest.update(second);
+        crc.combine(&Crc::from_parts(rest.sum(), second.len() as u64));
+        assert_eq!(crc.sum(), 0x414fa339);
+        assert_eq!(crc.amount(), data.len() as u64);
+
+        let big = Crc::from_parts(0, 5 << 30);
+        assert_eq!(big.amount(), 5 << 30);
+    }
+
+    #[test]
+    fn combine_long() {
+        // Lengths past 4 GiB aren't truncated, checked against zlib's
+        // crc32_combine64.
+        let mut crc = Crc::from_parts(0x414fa339, 43);
+        crc.combine(&Crc::from_parts(0x12345678, (1 << 32) + 7));
+        assert_eq!(crc.sum(), 0x65681e9d);
+        assert_eq!(crc.amount(), (1 << 32) + 50);
+
+        let mut crc = Crc::from_parts(0x414fa339, 43);
+        crc.combine(&Crc::from_parts(0x12345678, 7));
+        assert_eq!(crc.sum(), 0x494c2579);
+    }
+}
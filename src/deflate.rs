@@ -11,7 +11,8 @@ use tokio_io::{AsyncRead, AsyncWrite};
 
 use bufreader::BufReader;
 use zio;
-use {Compress, Decompress};
+use {Compress, CompressBuilder, Decompress, DecompressBuilder, Flush};
+use Strategy;
 
 /// A DEFLATE encoder, or compressor.
 ///
@@ -68,6 +69,15 @@ pub struct DecoderReader<R> {
 pub struct DecoderReaderBuf<R> {
     obj: R,
     data: Decompress,
+    recover: bool,
+    // Whether damaged data was hit and input is being skipped until the next
+    // full flush point.
+    syncing: bool,
+    damaged: bool,
+    lost: u64,
+    // Passed to the decompressor while there's input left, which is
+    // `Flush::Block` while `zran` indexes the stream.
+    flush: Flush,
 }
 
 /// A DEFLATE decoder, or decompressor.
@@ -93,6 +103,51 @@ impl<W: Write> EncoderWriter<W> {
         }
     }
 
+    /// Creates a new encoder which will write compressed data to the stream
+    /// given, using the compression parameters configured in `builder`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the parameters can't be honored, as described in
+    /// `CompressBuilder::build`.
+    pub fn new_with_builder(w: W, builder: CompressBuilder)
+                            -> io::Result<EncoderWriter<W>> {
+        let data = try!(builder.build(false));
+        Ok(EncoderWriter {
+            inner: zio::Writer::new(w, data),
+        })
+    }
+
+    /// Creates a new encoder which will write compressed data to the stream
+    /// given, primed with a preset dictionary.
+    ///
+    /// The same dictionary must be provided to the decoder.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend doesn't support preset dictionaries.
+    pub fn new_with_dictionary(w: W, level: ::Compression, dictionary: &[u8])
+                               -> io::Result<EncoderWriter<W>> {
+        let builder = CompressBuilder::new(level).dictionary(dictionary);
+        EncoderWriter::new_with_builder(w, builder)
+    }
+
+    /// Changes the compression level and strategy used for data written from
+    /// now on.
+    ///
+    /// All data written so far is first flushed to the underlying writer, as
+    /// with `flush`, except that the underlying writer itself is not flushed.
+    ///
+    /// # Errors
+    ///
+    /// This function will perform I/O to flush pending data, and any I/O
+    /// errors which occur will be returned from this function.
+    pub fn set_params(&mut self, level: ::Compression, strategy: Strategy)
+                      -> io::Result<()> {
+        try!(self.inner.flush_data());
+        self.inner.data.set_params(level, strategy)
+    }
+
     /// Acquires a reference to the underlying writer.
     pub fn get_ref(&self) -> &W {
         self.inner.get_ref()
@@ -124,7 +179,7 @@ impl<W: Write> EncoderWriter<W> {
     /// errors which occur will be returned from this function.
     pub fn reset(&mut self, w: W) -> io::Result<W> {
         try!(self.inner.finish());
-        self.inner.data.reset();
+        try!(self.inner.data.try_reset());
         Ok(self.inner.replace(w))
     }
 
@@ -237,6 +292,36 @@ impl<R: Read> EncoderReader<R> {
             inner: EncoderReaderBuf::new(BufReader::new(r), level),
         }
     }
+
+    /// Creates a new encoder which will read uncompressed data from the given
+    /// stream and emit the compressed stream, using the compression
+    /// parameters configured in `builder`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the parameters can't be honored, as described in
+    /// `CompressBuilder::build`.
+    pub fn new_with_builder(r: R, builder: CompressBuilder)
+                            -> io::Result<EncoderReader<R>> {
+        let r = BufReader::new(r);
+        EncoderReaderBuf::new_with_builder(r, builder).map(|inner| {
+            EncoderReader { inner: inner }
+        })
+    }
+
+    /// Creates a new encoder which will read uncompressed data from the given
+    /// stream, primed with a preset dictionary.
+    ///
+    /// The same dictionary must be provided to the decoder.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend doesn't support preset dictionaries.
+    pub fn new_with_dictionary(r: R, level: ::Compression, dictionary: &[u8])
+                               -> io::Result<EncoderReader<R>> {
+        let builder = CompressBuilder::new(level).dictionary(dictionary);
+        EncoderReader::new_with_builder(r, builder)
+    }
 }
 
 impl<R> EncoderReader<R> {
@@ -250,9 +335,20 @@ impl<R> EncoderReader<R> {
     ///
     /// Note that there may be currently buffered data when this function is
     /// called, and in that case the buffered data is discarded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the compression stream can't be reset. Use `try_reset`
+    /// to get an error instead.
     pub fn reset(&mut self, r: R) -> R {
-        self.inner.data.reset();
-        self.inner.obj.reset(r)
+        self.try_reset(r).expect("failed to reset compression stream")
+    }
+
+    /// Like `reset`, but returns an error if the compression stream
+    /// can't be reset, in which case the input stream isn't replaced.
+    pub fn try_reset(&mut self, r: R) -> io::Result<R> {
+        try!(self.inner.data.try_reset());
+        Ok(self.inner.obj.reset(r))
     }
 
     /// Acquires a reference to the underlying reader
@@ -330,6 +426,37 @@ impl<R: BufRead> EncoderReaderBuf<R> {
             data: Compress::new(level, false),
         }
     }
+
+    /// Creates a new encoder which will read uncompressed data from the given
+    /// stream and emit the compressed stream, using the compression
+    /// parameters configured in `builder`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the parameters can't be honored, as described in
+    /// `CompressBuilder::build`.
+    pub fn new_with_builder(r: R, builder: CompressBuilder)
+                            -> io::Result<EncoderReaderBuf<R>> {
+        let data = try!(builder.build(false));
+        Ok(EncoderReaderBuf {
+            obj: r,
+            data: data,
+        })
+    }
+
+    /// Creates a new encoder which will read uncompressed data from the given
+    /// stream, primed with a preset dictionary.
+    ///
+    /// The same dictionary must be provided to the decoder.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend doesn't support preset dictionaries.
+    pub fn new_with_dictionary(r: R, level: ::Compression, dictionary: &[u8])
+                               -> io::Result<EncoderReaderBuf<R>> {
+        let builder = CompressBuilder::new(level).dictionary(dictionary);
+        EncoderReaderBuf::new_with_builder(r, builder)
+    }
 }
 
 impl<R> EncoderReaderBuf<R> {
@@ -340,9 +467,20 @@ impl<R> EncoderReaderBuf<R> {
     /// the input stream with the one provided, returning the previous input
     /// stream. Future data read from this encoder will be the compressed
     /// version of `r`'s data.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the compression stream can't be reset. Use `try_reset`
+    /// to get an error instead.
     pub fn reset(&mut self, r: R) -> R {
-        self.data.reset();
-        mem::replace(&mut self.obj, r)
+        self.try_reset(r).expect("failed to reset compression stream")
+    }
+
+    /// Like `reset`, but returns an error if the compression stream
+    /// can't be reset, in which case the input stream isn't replaced.
+    pub fn try_reset(&mut self, r: R) -> io::Result<R> {
+        try!(self.data.try_reset());
+        Ok(mem::replace(&mut self.obj, r))
     }
 
     /// Acquires a reference to the underlying reader
@@ -423,6 +561,33 @@ impl<R: Read> DecoderReader<R> {
             inner: DecoderReaderBuf::new(BufReader::with_buf(buf, r))
         }
     }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, using the decompression parameters configured in `builder`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the parameters can't be honored, as described in
+    /// `DecompressBuilder::build`.
+    pub fn new_with_builder(r: R, builder: DecompressBuilder)
+                            -> io::Result<DecoderReader<R>> {
+        let r = BufReader::new(r);
+        DecoderReaderBuf::new_with_builder(r, builder).map(|inner| {
+            DecoderReader { inner: inner }
+        })
+    }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, using the preset dictionary the data was compressed with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend doesn't support preset dictionaries.
+    pub fn new_with_dictionary(r: R, dictionary: &[u8])
+                               -> io::Result<DecoderReader<R>> {
+        let builder = DecompressBuilder::new().dictionary(dictionary);
+        DecoderReader::new_with_builder(r, builder)
+    }
 }
 
 impl<R> DecoderReader<R> {
@@ -436,9 +601,35 @@ impl<R> DecoderReader<R> {
     ///
     /// Note that there may be currently buffered data when this function is
     /// called, and in that case the buffered data is discarded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the decompression stream can't be reset. Use `try_reset`
+    /// to get an error instead.
     pub fn reset(&mut self, r: R) -> R {
-        self.inner.data = Decompress::new(false);
-        self.inner.obj.reset(r)
+        self.try_reset(r).expect("failed to reset decompression stream")
+    }
+
+    /// Like `reset`, but returns an error if the decompression stream
+    /// can't be reset, in which case the input stream isn't replaced.
+    pub fn try_reset(&mut self, r: R) -> io::Result<R> {
+        try!(self.inner.try_reset_data());
+        Ok(self.inner.obj.reset(r))
+    }
+
+    /// Configures whether this decoder recovers from damaged input.
+    ///
+    /// See `bufread::DeflateDecoder::set_recover` for details.
+    pub fn set_recover(&mut self, recover: bool) {
+        self.inner.set_recover(recover)
+    }
+
+    /// Returns the number of compressed bytes skipped while recovering from
+    /// damaged input.
+    ///
+    /// See `bufread::DeflateDecoder::lost_bytes` for details.
+    pub fn lost_bytes(&self) -> u64 {
+        self.inner.lost_bytes()
     }
 
     /// Acquires a reference to the underlying stream
@@ -511,8 +702,46 @@ impl<R: BufRead> DecoderReaderBuf<R> {
         DecoderReaderBuf {
             obj: r,
             data: Decompress::new(false),
+            recover: false,
+            syncing: false,
+            damaged: false,
+            lost: 0,
+            flush: Flush::None,
         }
     }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, using the decompression parameters configured in `builder`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the parameters can't be honored, as described in
+    /// `DecompressBuilder::build`.
+    pub fn new_with_builder(r: R, builder: DecompressBuilder)
+                            -> io::Result<DecoderReaderBuf<R>> {
+        let data = try!(builder.build(false));
+        Ok(DecoderReaderBuf {
+            obj: r,
+            data: data,
+            recover: false,
+            syncing: false,
+            damaged: false,
+            lost: 0,
+            flush: Flush::None,
+        })
+    }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, using the preset dictionary the data was compressed with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend doesn't support preset dictionaries.
+    pub fn new_with_dictionary(r: R, dictionary: &[u8])
+                               -> io::Result<DecoderReaderBuf<R>> {
+        let builder = DecompressBuilder::new().dictionary(dictionary);
+        DecoderReaderBuf::new_with_builder(r, builder)
+    }
 }
 
 impl<R> DecoderReaderBuf<R> {
@@ -523,17 +752,69 @@ impl<R> DecoderReaderBuf<R> {
     /// input stream with the one provided, returning the previous input
     /// stream. Future data read from this decoder will be the decompressed
     /// version of `r`'s data.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the decompression stream can't be reset. Use `try_reset`
+    /// to get an error instead.
     pub fn reset(&mut self, r: R) -> R {
-        self.data = Decompress::new(false);
-        mem::replace(&mut self.obj, r)
+        self.try_reset(r).expect("failed to reset decompression stream")
+    }
+
+    /// Like `reset`, but returns an error if the decompression stream
+    /// can't be reset, in which case the input stream isn't replaced.
+    pub fn try_reset(&mut self, r: R) -> io::Result<R> {
+        try!(self.try_reset_data());
+        Ok(mem::replace(&mut self.obj, r))
     }
 
     /// Resets the state of this decoder's data
     ///
     /// This will reset the internal state of this decoder. It will continue
     /// reading from the same stream.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the decompression stream can't be reset. Use
+    /// `try_reset_data` to get an error instead.
     pub fn reset_data(&mut self) {
-        self.data = Decompress::new(false);
+        self.try_reset_data().expect("failed to reset decompression stream")
+    }
+
+    /// Like `reset_data`, but returns an error if the decompression stream
+    /// can't be reset.
+    pub fn try_reset_data(&mut self) -> io::Result<()> {
+        try!(self.data.try_reset(false));
+        self.syncing = false;
+        self.damaged = false;
+        self.lost = 0;
+        Ok(())
+    }
+
+    /// Configures whether this decoder recovers from damaged input.
+    ///
+    /// By default damaged input causes reads to fail with an error of kind
+    /// `InvalidData`. When recovering, the decoder instead skips input until
+    /// the next point where the compressor used `Flush::Full` (see
+    /// `Decompress::sync`) and carries on decompressing from there. If there
+    /// is no such point the rest of the input is skipped and the stream ends
+    /// early. Either way the decompressed data around the damage is missing,
+    /// and `lost_bytes` reports how much compressed input was skipped.
+    pub fn set_recover(&mut self, recover: bool) {
+        self.recover = recover;
+    }
+
+    /// Returns the number of compressed bytes skipped while recovering from
+    /// damaged input.
+    ///
+    /// Each time damage is found this counts all input from the start of the
+    /// read that ran into it up to the next full flush point, but not the
+    /// 4-byte `00 00 ff ff` marker which ends that flush point. Whatever the
+    /// read managed to decompress before noticing the damage is still
+    /// returned, so some of the counted bytes may have contributed to the
+    /// output.
+    pub fn lost_bytes(&self) -> u64 {
+        self.lost
     }
 
     /// Acquires a reference to the underlying stream
@@ -570,7 +851,46 @@ impl<R> DecoderReaderBuf<R> {
 
 impl<R: BufRead> Read for DecoderReaderBuf<R> {
     fn read(&mut self, into: &mut [u8]) -> io::Result<usize> {
-        zio::read(&mut self.obj, &mut self.data, into)
+        loop {
+            if self.syncing {
+                let (found, consumed) = {
+                    let input = try!(self.obj.fill_buf());
+                    if input.is_empty() {
+                        return Ok(0)
+                    }
+                    let before = self.data.total_in();
+                    let found = try!(self.data.sync(input));
+                    (found, (self.data.total_in() - before) as usize)
+                };
+                self.obj.consume(consumed);
+                self.lost += consumed as u64;
+                if found {
+                    // Part of the marker may have been consumed by an earlier
+                    // read which wasn't counted.
+                    self.lost = self.lost.saturating_sub(4);
+                    self.syncing = false;
+                }
+                continue
+            }
+
+            let before = self.data.total_out();
+            let before_in = self.data.total_in();
+            match zio::read_with(&mut self.obj, &mut self.data, into,
+                                 self.flush) {
+                Err(ref e) if self.recover &&
+                              e.kind() == io::ErrorKind::InvalidData => {
+                    self.syncing = true;
+                    self.damaged = true;
+                    self.lost += self.data.total_in() - before_in;
+                    // Hand out whatever was decompressed before the damage.
+                    let n = (self.data.total_out() - before) as usize;
+                    if n > 0 {
+                        return Ok(n)
+                    }
+                }
+                ret => return ret,
+            }
+        }
     }
 }
 
@@ -578,6 +898,19 @@ impl<R: BufRead> Read for DecoderReaderBuf<R> {
 impl<R: AsyncRead + BufRead> AsyncRead for DecoderReaderBuf<R> {
 }
 
+// Whether `r` has skipped damaged input, after which checksums covering the
+// whole stream can't match.
+pub fn damaged<R>(r: &DecoderReaderBuf<R>) -> bool {
+    r.damaged
+}
+
+// Makes `r` return from every read at the next deflate block boundary, and
+// gives access to its decompressor, for `zran` to index the stream with.
+pub fn stop_at_blocks<R>(r: &mut DecoderReaderBuf<R>) -> &mut Decompress {
+    r.flush = Flush::Block;
+    &mut r.data
+}
+
 impl<W: BufRead + Write> Write for DecoderReaderBuf<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.get_mut().write(buf)
@@ -606,6 +939,33 @@ impl<W: Write> DecoderWriter<W> {
         }
     }
 
+    /// Creates a new decoder which will write uncompressed data to the stream,
+    /// using the decompression parameters configured in `builder`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the parameters can't be honored, as described in
+    /// `DecompressBuilder::build`.
+    pub fn new_with_builder(w: W, builder: DecompressBuilder)
+                            -> io::Result<DecoderWriter<W>> {
+        let data = try!(builder.build(false));
+        Ok(DecoderWriter {
+            inner: zio::Writer::new(w, data),
+        })
+    }
+
+    /// Creates a new decoder which will write uncompressed data to the stream,
+    /// using the preset dictionary the data was compressed with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend doesn't support preset dictionaries.
+    pub fn new_with_dictionary(w: W, dictionary: &[u8])
+                               -> io::Result<DecoderWriter<W>> {
+        let builder = DecompressBuilder::new().dictionary(dictionary);
+        DecoderWriter::new_with_builder(w, builder)
+    }
+
     /// Acquires a reference to the underlying writer.
     pub fn get_ref(&self) -> &W {
         self.inner.get_ref()
@@ -636,7 +996,7 @@ impl<W: Write> DecoderWriter<W> {
     /// returns an error then that will be returned from this function.
     pub fn reset(&mut self, w: W) -> io::Result<W> {
         try!(self.inner.finish());
-        self.inner.data = Decompress::new(false);
+        try!(self.inner.data.try_reset(false));
         Ok(self.inner.replace(w))
     }
 
@@ -730,12 +1090,12 @@ mod tests {
     use rand::{thread_rng, Rng};
 
     use deflate::{EncoderWriter, EncoderReader, DecoderReader, DecoderWriter};
-    use Compression::Default;
+    use {Compress, Compression, Flush};
 
     #[test]
     fn roundtrip() {
         let mut real = Vec::new();
-        let mut w = EncoderWriter::new(Vec::new(), Default);
+        let mut w = EncoderWriter::new(Vec::new(), Compression::default());
         let v = thread_rng().gen_iter::<u8>().take(1024).collect::<Vec<_>>();
         for _ in 0..200 {
             let to_write = &v[..thread_rng().gen_range(0, v.len())];
@@ -752,7 +1112,9 @@ mod tests {
     #[test]
     fn drop_writes() {
         let mut data = Vec::new();
-        EncoderWriter::new(&mut data, Default).write_all(b"foo").unwrap();
+        EncoderWriter::new(&mut data, Compression::default())
+            .write_all(b"foo")
+            .unwrap();
         let mut r = DecoderReader::new(&data[..]);
         let mut ret = Vec::new();
         r.read_to_end(&mut ret).unwrap();
@@ -762,7 +1124,7 @@ mod tests {
     #[test]
     fn total_in() {
         let mut real = Vec::new();
-        let mut w = EncoderWriter::new(Vec::new(), Default);
+        let mut w = EncoderWriter::new(Vec::new(), Compression::default());
         let v = thread_rng().gen_iter::<u8>().take(1024).collect::<Vec<_>>();
         for _ in 0..200 {
             let to_write = &v[..thread_rng().gen_range(0, v.len())];
@@ -790,7 +1152,8 @@ mod tests {
                     .gen_iter::<u8>()
                     .take(1024 * 1024)
                     .collect::<Vec<_>>();
-        let mut r = DecoderReader::new(EncoderReader::new(&v[..], Default));
+        let r = EncoderReader::new(&v[..], Compression::default());
+        let mut r = DecoderReader::new(r);
         let mut ret = Vec::new();
         r.read_to_end(&mut ret).unwrap();
         assert_eq!(ret, v);
@@ -802,7 +1165,8 @@ mod tests {
                     .gen_iter::<u8>()
                     .take(1024 * 1024)
                     .collect::<Vec<_>>();
-        let mut w = EncoderWriter::new(DecoderWriter::new(Vec::new()), Default);
+        let mut w = EncoderWriter::new(DecoderWriter::new(Vec::new()),
+                                       Compression::default());
         w.write_all(&v).unwrap();
         let w = w.finish().unwrap().finish().unwrap();
         assert!(w == v);
@@ -814,13 +1178,13 @@ mod tests {
                     .gen_iter::<u8>()
                     .take(1024 * 1024)
                     .collect::<Vec<_>>();
-        let mut w = EncoderWriter::new(Vec::new(), Default);
+        let mut w = EncoderWriter::new(Vec::new(), Compression::default());
         w.write_all(&v).unwrap();
         let a = w.reset(Vec::new()).unwrap();
         w.write_all(&v).unwrap();
         let b = w.finish().unwrap();
 
-        let mut w = EncoderWriter::new(Vec::new(), Default);
+        let mut w = EncoderWriter::new(Vec::new(), Compression::default());
         w.write_all(&v).unwrap();
         let c = w.finish().unwrap();
         assert!(a == b && b == c);
@@ -833,12 +1197,12 @@ mod tests {
                     .take(1024 * 1024)
                     .collect::<Vec<_>>();
         let (mut a, mut b, mut c) = (Vec::new(), Vec::new(), Vec::new());
-        let mut r = EncoderReader::new(&v[..], Default);
+        let mut r = EncoderReader::new(&v[..], Compression::default());
         r.read_to_end(&mut a).unwrap();
         r.reset(&v[..]);
         r.read_to_end(&mut b).unwrap();
 
-        let mut r = EncoderReader::new(&v[..], Default);
+        let mut r = EncoderReader::new(&v[..], Compression::default());
         r.read_to_end(&mut c).unwrap();
         assert!(a == b && b == c);
     }
@@ -849,7 +1213,7 @@ mod tests {
                     .gen_iter::<u8>()
                     .take(1024 * 1024)
                     .collect::<Vec<_>>();
-        let mut w = EncoderWriter::new(Vec::new(), Default);
+        let mut w = EncoderWriter::new(Vec::new(), Compression::default());
         w.write_all(&v).unwrap();
         let data = w.finish().unwrap();
 
@@ -863,6 +1227,11 @@ mod tests {
             let mut r = DecoderReader::new(&data[..]);
             r.read_to_end(&mut c).unwrap();
             assert!(a == b && b == c && c == v);
+
+            let mut d = Vec::new();
+            r.try_reset(&data).unwrap();
+            r.read_to_end(&mut d).unwrap();
+            assert!(d == v);
         }
 
         {
@@ -882,7 +1251,7 @@ mod tests {
     #[test]
     fn zero_length_read_with_data() {
         let m = vec![3u8; 128 * 1024 + 1];
-        let mut c = EncoderReader::new(&m[..], ::Compression::Default);
+        let mut c = EncoderReader::new(&m[..], ::Compression::default());
 
         let mut result = Vec::new();
         c.read_to_end(&mut result).unwrap();
@@ -897,7 +1266,8 @@ mod tests {
         ::quickcheck::quickcheck(test as fn(_) -> _);
 
         fn test(v: Vec<u8>) -> bool {
-            let mut r = DecoderReader::new(EncoderReader::new(&v[..], Default));
+            let r = EncoderReader::new(&v[..], Compression::default());
+            let mut r = DecoderReader::new(r);
             let mut v2 = Vec::new();
             r.read_to_end(&mut v2).unwrap();
             v == v2
@@ -909,9 +1279,41 @@ mod tests {
         ::quickcheck::quickcheck(test as fn(_) -> _);
 
         fn test(v: Vec<u8>) -> bool {
-            let mut w = EncoderWriter::new(DecoderWriter::new(Vec::new()), Default);
+            let mut w = EncoderWriter::new(DecoderWriter::new(Vec::new()),
+                                           Compression::default());
             w.write_all(&v).unwrap();
             v == w.finish().unwrap().finish().unwrap()
         }
     }
+
+    #[test]
+    fn recover() {
+        let mut c = Compress::new(Compression::default(), false);
+        let mut data = Vec::with_capacity(1024);
+        c.compress_vec(b"one one one ", &mut data, Flush::Full).unwrap();
+        let flush_point = data.len();
+        c.compress_vec(b"two two two ", &mut data, Flush::Full).unwrap();
+        let second_point = data.len();
+        c.compress_vec(b"three three", &mut data, Flush::Finish).unwrap();
+        // an invalid block type
+        data[flush_point] = 0x06;
+
+        let mut d = DecoderReader::new(&data[..]);
+        assert!(d.read_to_end(&mut Vec::new()).is_err());
+
+        let mut d = DecoderReader::new(&data[..]);
+        d.set_recover(true);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"one one one three three");
+        // everything from the start of the failed read up to the marker
+        assert_eq!(d.lost_bytes(), second_point as u64 - 4);
+
+        // without a later flush point the stream just ends early
+        let mut d = DecoderReader::new(&data[..second_point - 1]);
+        d.set_recover(true);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"one one one ");
+    }
 }
@@ -0,0 +1,877 @@
+//! The dictzip format, used by DICT dictionary servers
+//!
+//! A dictzip file is an ordinary gzip file whose input was compressed in
+//! chunks of a fixed size, with a full flush after each of them, so that
+//! decompression can start afresh at the beginning of any chunk. The
+//! compressed size of every chunk is listed in an "RA" (random access)
+//! subfield of the header's extra field, which lets a [`Reader`] find and
+//! decompress only the chunks holding the data asked for.
+//!
+//! [`Reader`]: struct.Reader.html
+//!
+//! # Examples
+//!
+//! ```
+//! use std::io::prelude::*;
+//! use std::io::{Cursor, SeekFrom};
+//! use flate2::{Compression, GzBuilder};
+//! use flate2::dictzip;
+//!
+//! let mut w = dictzip::Builder::new()
+//!     .header(GzBuilder::new().filename("words"))
+//!     .write(Vec::new(), Compression::default())
+//!     .unwrap();
+//! for i in 0..10000 {
+//!     write!(w, "{:05}\n", i).unwrap();
+//! }
+//! let file = w.finish().unwrap();
+//!
+//! let mut r = dictzip::Reader::new(Cursor::new(file)).unwrap();
+//! r.seek(SeekFrom::Start(6 * 4321)).unwrap();
+//! let mut word = [0; 5];
+//! r.read_exact(&mut word).unwrap();
+//! assert_eq!(&word, b"04321");
+//! ```
+
+use std::cmp;
+use std::fmt;
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+
+use {Compress, Compression, Decompress, DecompressFormat, Flush, Format};
+use {GzHeader, Status};
+use crc::Crc;
+use gz;
+
+/// The chunk size used by the `dictzip` tool, which is small enough for
+/// every chunk to compress to less than 64 KiB.
+const DEFAULT_CHUNK_SIZE: usize = 58315;
+
+/// The most chunks that fit in the extra field of the header.
+const MAX_CHUNKS: usize = (0xffff - 4 - 6) / 2;
+
+/// The most chunks a seekable writer can reserve room for, leaving space for
+/// the subfield padding out the rest of the table.
+const MAX_RESERVED_CHUNKS: usize = MAX_CHUNKS - 2;
+
+/// The offset of the extra field in the gzip header.
+const EXTRA_OFFSET: u64 = 12;
+
+/// A builder for a dictzip [`Writer`], configuring the chunk size and the
+/// gzip header.
+///
+/// [`Writer`]: struct.Writer.html
+#[derive(Debug)]
+pub struct Builder {
+    chunk_size: usize,
+    header: gz::Builder,
+    size_hint: Option<u64>,
+}
+
+/// A writer compressing data into the dictzip format
+///
+/// The header at the start of the file lists the sizes of all chunks, so a
+/// writer created with [`Builder::write`] or [`Writer::new`] writes nothing
+/// to the underlying writer `W` until it is finished, keeping the whole
+/// compressed file in memory until then.
+///
+/// A writer created with [`Builder::write_seekable`] instead writes a header
+/// with room for the chunk table up front, writes every chunk out as soon as
+/// it's compressed, and seeks back to fill in the table once finished.
+///
+/// [`Builder::write`]: struct.Builder.html#method.write
+/// [`Builder::write_seekable`]: struct.Builder.html#method.write_seekable
+/// [`Writer::new`]: #method.new
+#[derive(Debug)]
+pub struct Writer<W: Write> {
+    inner: Option<W>,
+    // The header, until it's been built at the end of a buffered file.
+    header: Option<gz::Builder>,
+    level: Compression,
+    chunk_size: usize,
+    compress: Compress,
+    // The output not written yet, and where the current chunk starts in it.
+    // Everything before the current chunk is ready to be written out.
+    data: Vec<u8>,
+    chunk_start: usize,
+    chunk_in: usize,
+    sizes: Vec<u16>,
+    crc: Crc,
+    // How many bytes have been written since the start of the header.
+    written: u64,
+    // For seekable writers, the chunks the header has room for, and how to
+    // fill in the table once finished.
+    reserved: Option<usize>,
+    patch: Option<Patch<W>>,
+    finished: bool,
+}
+
+// Overwrites the given bytes the given distance before the position of a
+// seekable writer, which is only known to be `Seek` where it's created.
+struct Patch<W>(fn(&mut W, u64, &[u8]) -> io::Result<()>);
+
+/// A reader decompressing a dictzip file, which can seek to any position in
+/// the decompressed data
+///
+/// Only the chunks holding the data being read are decompressed, and the
+/// last one is kept around for the reads after it.
+#[derive(Debug)]
+pub struct Reader<R: Read + Seek> {
+    inner: R,
+    header: GzHeader,
+    chunk_size: usize,
+    // The offset of every chunk in the file, and of the end of the last one.
+    offsets: Vec<u64>,
+    length: u64,
+    position: u64,
+    chunk: Option<(usize, Vec<u8>)>,
+}
+
+impl Builder {
+    /// Creates a new builder with the chunk size of the `dictzip` tool and
+    /// an empty gzip header.
+    pub fn new() -> Builder {
+        Builder {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            header: gz::Builder::new(),
+            size_hint: None,
+        }
+    }
+
+    /// Configures the amount of input compressed as one chunk.
+    ///
+    /// Smaller chunks make reading small pieces of data faster, but compress
+    /// less well, and there can be no more than 32762 of them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero or more than 58315, the most that is
+    /// guaranteed to compress to less than 64 KiB.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Builder {
+        assert!(chunk_size > 0 && chunk_size <= DEFAULT_CHUNK_SIZE,
+                "chunk size out of range");
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Configures the gzip header to emit, as built by a `GzBuilder`.
+    ///
+    /// Its extra field is replaced by the one listing the chunks.
+    pub fn header(mut self, header: gz::Builder) -> Builder {
+        self.header = header;
+        self
+    }
+
+    /// Configures how much data a seekable writer will be given, so that
+    /// the header only has room for the chunks it needs.
+    ///
+    /// Without a hint, room is made for as many chunks as fit, which takes
+    /// up 64 KiB. Writing more data than hinted fails once the table is
+    /// full. Writers which aren't seekable ignore the hint.
+    pub fn size_hint(mut self, len: u64) -> Builder {
+        self.size_hint = Some(len);
+        self
+    }
+
+    /// Consumes this builder, creating a writer in the process.
+    ///
+    /// The data written to the returned writer will be compressed at the
+    /// given level and then written out to the supplied parameter `w`. As
+    /// `w` can't seek, nothing is written to it until the writer is finished.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the compression stream can't be set up.
+    pub fn write<W: Write>(self, w: W, lvl: Compression)
+                           -> io::Result<Writer<W>> {
+        self.into_writer(w, lvl, None)
+    }
+
+    /// Consumes this builder, creating a writer which streams the compressed
+    /// chunks to `w` as they're done.
+    ///
+    /// The header is written first with room for the chunk table, which is
+    /// filled in by seeking back once the writer is finished. The file is
+    /// written from the position `w` is at, and `w` is left at its end.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the compression stream can't be set up.
+    pub fn write_seekable<W: Write + Seek>(self, w: W, lvl: Compression)
+                                          -> io::Result<Writer<W>> {
+        let reserved = match self.size_hint {
+            Some(len) => {
+                let chunks = (len + self.chunk_size as u64 - 1) /
+                    self.chunk_size as u64;
+                cmp::max(cmp::min(chunks, MAX_RESERVED_CHUNKS as u64), 1)
+                    as usize
+            }
+            None => MAX_RESERVED_CHUNKS,
+        };
+        self.into_writer(w, lvl, Some((reserved, Patch(patch::<W>))))
+    }
+
+    fn into_writer<W: Write>(self, w: W, lvl: Compression,
+                             seekable: Option<(usize, Patch<W>)>)
+                             -> io::Result<Writer<W>> {
+        let compress = try!(Compress::try_new_with_format(lvl, Format::Raw));
+        let mut writer = Writer {
+            inner: Some(w),
+            header: Some(self.header),
+            level: lvl,
+            chunk_size: self.chunk_size,
+            compress: compress,
+            data: Vec::new(),
+            chunk_start: 0,
+            chunk_in: 0,
+            sizes: Vec::new(),
+            crc: Crc::new(),
+            written: 0,
+            reserved: None,
+            patch: None,
+            finished: false,
+        };
+        if let Some((reserved, patch)) = seekable {
+            writer.reserved = Some(reserved);
+            writer.patch = Some(patch);
+            writer.data = writer.build_header();
+            writer.chunk_start = writer.data.len();
+        }
+        Ok(writer)
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+impl<W: Write> Writer<W> {
+    /// Creates a new writer with the default chunk size and header, which
+    /// will compress at the given level and write to `w`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the compression stream can't be set up.
+    pub fn new(w: W, level: Compression) -> io::Result<Writer<W>> {
+        Builder::new().write(w, level)
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Note that mutation of the writer may result in surprising results if
+    /// this writer is continued to be used.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.as_mut().unwrap()
+    }
+
+    /// Attempt to finish this output stream, writing out the rest of the
+    /// file and filling in the chunk table.
+    ///
+    /// Note that this function can only be used once data has finished being
+    /// written to the output stream. After this function is called then
+    /// further calls to `write` may result in a panic.
+    ///
+    /// # Errors
+    ///
+    /// This function will perform I/O to complete this stream, and any I/O
+    /// errors which occur will be returned from this function.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        if !self.finished {
+            try!(self.compress(&[], Flush::Finish));
+            try!(self.end_chunk());
+            self.finished = true;
+
+            if self.patch.is_none() {
+                let mut file = self.build_header();
+                file.extend_from_slice(&self.data);
+                self.data = file;
+            }
+            let (sum, amt) = (self.crc.sum(), self.crc.amount() as u32);
+            self.data.extend_from_slice(&[
+                sum as u8, (sum >> 8) as u8, (sum >> 16) as u8,
+                (sum >> 24) as u8, amt as u8, (amt >> 8) as u8,
+                (amt >> 16) as u8, (amt >> 24) as u8,
+            ]);
+            self.chunk_start = self.data.len();
+        }
+        try!(self.write_data());
+        if let Some(patch) = self.patch.as_ref().map(|p| p.0) {
+            let extra = self.extra_field();
+            try!(patch(self.inner.as_mut().unwrap(),
+                       self.written - EXTRA_OFFSET,
+                       &extra));
+            self.patch = None;
+        }
+        Ok(())
+    }
+
+    /// Finish this stream, returning the underlying writer.
+    ///
+    /// # Errors
+    ///
+    /// This function will perform I/O to complete this stream, and any I/O
+    /// errors which occur will be returned from this function.
+    pub fn finish(mut self) -> io::Result<W> {
+        try!(self.try_finish());
+        Ok(self.inner.take().unwrap())
+    }
+
+    fn compress(&mut self, input: &[u8], flush: Flush) -> io::Result<()> {
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            let rest = &input[consumed..];
+            self.data.reserve(cmp::max(Compress::bound(rest.len(),
+                                                       Format::Raw),
+                                       1024));
+            let status = try!(self.compress.compress_vec(rest, &mut self.data,
+                                                         flush));
+            let done = if flush == Flush::Finish {
+                status == Status::StreamEnd
+            } else {
+                (self.compress.total_in() - start) as usize == input.len() &&
+                    self.data.len() < self.data.capacity()
+            };
+            if done {
+                return Ok(())
+            }
+        }
+    }
+
+    fn end_chunk(&mut self) -> io::Result<()> {
+        let size = self.data.len() - self.chunk_start;
+        if size > 0xffff {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      "dictzip chunk compressed to more than \
+                                       64 KiB"))
+        }
+        self.sizes.push(size as u16);
+        self.chunk_start = self.data.len();
+        self.chunk_in = 0;
+        Ok(())
+    }
+
+    // Writes out everything before the current chunk.
+    fn write_data(&mut self) -> io::Result<()> {
+        while self.chunk_start > 0 {
+            let n = try!(self.inner.as_mut().unwrap()
+                             .write(&self.data[..self.chunk_start]));
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into())
+            }
+            self.data.drain(..n);
+            self.chunk_start -= n;
+            self.written += n as u64;
+        }
+        Ok(())
+    }
+
+    fn build_header(&mut self) -> Vec<u8> {
+        let header = self.header.take().unwrap().extra(self.extra_field());
+        gz::builder_header(header, self.level)
+    }
+
+    // The extra field holding the RA subfield with the chunk table. For a
+    // seekable writer, it's followed by a subfield of zeros taking up the
+    // rest of the room reserved for the table, so the field's size never
+    // changes.
+    fn extra_field(&self) -> Vec<u8> {
+        let mut extra = vec![b'R', b'A'];
+        extra.extend_from_slice(&le_u16(6 + 2 * self.sizes.len()));
+        extra.extend_from_slice(&le_u16(1));
+        extra.extend_from_slice(&le_u16(self.chunk_size));
+        extra.extend_from_slice(&le_u16(self.sizes.len()));
+        for &size in self.sizes.iter() {
+            extra.extend_from_slice(&le_u16(size as usize));
+        }
+        if let Some(reserved) = self.reserved {
+            let padding = 2 * (reserved - self.sizes.len());
+            extra.extend_from_slice(b"ZP");
+            extra.extend_from_slice(&le_u16(padding));
+            extra.extend(vec![0; padding]);
+        }
+        extra
+    }
+
+    fn max_chunks(&self) -> usize {
+        self.reserved.unwrap_or(MAX_CHUNKS)
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        assert!(!self.finished);
+        if buf.len() == 0 {
+            return Ok(0)
+        }
+        // A full chunk is only closed once more data comes, so the last one
+        // is never empty.
+        if self.chunk_in == self.chunk_size {
+            if self.sizes.len() + 1 == self.max_chunks() {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "too much data for the dictzip \
+                                           chunk table"))
+            }
+            try!(self.compress(&[], Flush::Full));
+            try!(self.end_chunk());
+            if self.patch.is_some() {
+                try!(self.write_data());
+            }
+        }
+        let n = cmp::min(buf.len(), self.chunk_size - self.chunk_in);
+        try!(self.compress(&buf[..n], Flush::None));
+        self.crc.update(&buf[..n]);
+        self.chunk_in += n;
+        Ok(n)
+    }
+
+    /// Flushes the underlying writer, though only the chunks that are done
+    /// have been written to a seekable one, and nothing to any other before
+    /// the writer is finished.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for Writer<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+impl<W> fmt::Debug for Patch<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Patch")
+    }
+}
+
+fn patch<W: Write + Seek>(w: &mut W, back: u64, data: &[u8])
+                          -> io::Result<()> {
+    try!(w.seek(SeekFrom::Current(-(back as i64))));
+    try!(w.write_all(data));
+    try!(w.seek(SeekFrom::Current((back - data.len() as u64) as i64)));
+    Ok(())
+}
+
+impl<R: Read + Seek> Reader<R> {
+    /// Creates a new reader for the dictzip file read from `r`, reading its
+    /// header and chunk table.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file isn't a gzip file with a valid chunk
+    /// table.
+    pub fn new(mut r: R) -> io::Result<Reader<R>> {
+        try!(r.seek(SeekFrom::Start(0)));
+        let header = try!(gz::read_gz_header(&mut r));
+        let start = try!(r.seek(SeekFrom::Current(0)));
+        let (chunk_size, sizes) = match header.extra().and_then(chunk_table) {
+            Some(table) => table,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "gzip header has no dictzip chunk \
+                                           table"))
+            }
+        };
+        let mut offsets = vec![start];
+        for &size in sizes.iter() {
+            let last = offsets[offsets.len() - 1];
+            offsets.push(last + size as u64);
+        }
+
+        let mut reader = Reader {
+            inner: r,
+            header: header,
+            chunk_size: chunk_size,
+            offsets: offsets,
+            length: 0,
+            position: 0,
+            chunk: None,
+        };
+
+        // The trailer only records the length modulo 2^32, so it's worked
+        // out from the chunks instead, every one of which but the last is
+        // full, and the trailer is merely checked against it.
+        if let Some(last) = sizes.len().checked_sub(1) {
+            let data = try!(reader.read_chunk(last));
+            reader.length = last as u64 * chunk_size as u64 +
+                            data.len() as u64;
+            reader.chunk = Some((last, data));
+        }
+        try!(reader.inner.seek(SeekFrom::End(-4)));
+        let mut isize = [0; 4];
+        try!(reader.inner.read_exact(&mut isize));
+        let isize = isize.iter().rev().fold(0, |n, &b| n << 8 | b as u32);
+        if isize != reader.length as u32 {
+            return Err(corrupt())
+        }
+        Ok(reader)
+    }
+
+    /// Returns the header of the file.
+    pub fn header(&self) -> &GzHeader {
+        &self.header
+    }
+
+    /// Returns the amount of data in each chunk.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Returns the length of the decompressed data.
+    pub fn uncompressed_len(&self) -> u64 {
+        self.length
+    }
+
+    /// Acquires a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Acquires a mutable reference to the underlying reader.
+    ///
+    /// Note that mutation of the reader may result in surprising results if
+    /// this reader is continued to be used.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Consumes this reader, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    // Decompresses chunk `i`, unless it's the one decompressed last.
+    fn load(&mut self, i: usize) -> io::Result<()> {
+        match self.chunk {
+            Some((j, _)) if i == j => return Ok(()),
+            _ => {}
+        }
+        let data = try!(self.read_chunk(i));
+        let start = (i * self.chunk_size) as u64;
+        let len = cmp::min(self.chunk_size as u64, self.length - start);
+        if data.len() as u64 != len {
+            return Err(corrupt())
+        }
+        self.chunk = Some((i, data));
+        Ok(())
+    }
+
+    // Reads and decompresses chunk `i`, which can't hold more than a chunk's
+    // worth of data.
+    fn read_chunk(&mut self, i: usize) -> io::Result<Vec<u8>> {
+        try!(self.inner.seek(SeekFrom::Start(self.offsets[i])));
+        let mut raw = vec![0; (self.offsets[i + 1] - self.offsets[i]) as usize];
+        try!(self.inner.read_exact(&mut raw));
+
+        let mut d =
+            try!(Decompress::try_new_with_format(DecompressFormat::Raw));
+        let mut data = Vec::with_capacity(self.chunk_size + 1);
+        loop {
+            let before = d.total_in();
+            let status = try!(d.decompress_vec(&raw[before as usize..],
+                                               &mut data, Flush::None));
+            if status == Status::StreamEnd || d.total_in() == before ||
+               d.total_in() == raw.len() as u64 {
+                break
+            }
+        }
+        if data.len() > self.chunk_size {
+            return Err(corrupt())
+        }
+        Ok(data)
+    }
+}
+
+impl<R: Read + Seek> Read for Reader<R> {
+    fn read(&mut self, into: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.length || into.len() == 0 {
+            return Ok(0)
+        }
+        let i = (self.position / self.chunk_size as u64) as usize;
+        try!(self.load(i));
+        let data = &self.chunk.as_ref().unwrap().1;
+        let start = (self.position - (i * self.chunk_size) as u64) as usize;
+        let n = cmp::min(into.len(), data.len() - start);
+        into[..n].copy_from_slice(&data[start..start + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for Reader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.position = n;
+                return Ok(n)
+            }
+            SeekFrom::Current(n) => (self.position, n),
+            SeekFrom::End(n) => (self.length, n),
+        };
+        let target = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+        match target {
+            Some(target) => {
+                self.position = target;
+                Ok(target)
+            }
+            None => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   "invalid seek to a negative or \
+                                    overflowing position"))
+            }
+        }
+    }
+}
+
+// Finds the RA subfield in a header's extra field, returning the chunk size
+// and the compressed size of every chunk.
+fn chunk_table(mut extra: &[u8]) -> Option<(usize, Vec<u16>)> {
+    while extra.len() >= 4 {
+        let len = read_le_u16(&extra[2..]);
+        if extra.len() < 4 + len {
+            return None
+        }
+        let (field, rest) = extra[4..].split_at(len);
+        if &extra[..2] == b"RA" {
+            if len < 6 || read_le_u16(field) != 1 {
+                return None
+            }
+            let chunk_size = read_le_u16(&field[2..]);
+            let count = read_le_u16(&field[4..]);
+            if chunk_size == 0 || len != 6 + 2 * count {
+                return None
+            }
+            let sizes = field[6..].chunks(2)
+                .map(|b| read_le_u16(b) as u16)
+                .collect();
+            return Some((chunk_size, sizes))
+        }
+        extra = rest;
+    }
+    None
+}
+
+fn corrupt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt dictzip chunk")
+}
+
+fn le_u16(n: usize) -> [u8; 2] {
+    [n as u8, (n >> 8) as u8]
+}
+
+fn read_le_u16(b: &[u8]) -> usize {
+    (b[0] as usize) | (b[1] as usize) << 8
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::{self, Cursor, SeekFrom};
+
+    use rand::{thread_rng, Rng};
+
+    use {CompressBuilder, Compression, GzBuilder};
+    use backend;
+    use read::GzDecoder;
+    use super::*;
+
+    fn data() -> Vec<u8> {
+        let mut rng = thread_rng();
+        let mut data = Vec::new();
+        for i in 0..20_000 {
+            let line = format!("{}\t{}\n", i, rng.gen::<u16>());
+            data.extend_from_slice(line.as_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = data();
+        let mut w = Builder::new()
+            .chunk_size(10_000)
+            .header(GzBuilder::new().filename("data.txt"))
+            .write(Vec::new(), Compression::default())
+            .unwrap();
+        w.write_all(&data).unwrap();
+        let file = w.finish().unwrap();
+
+        let mut d = GzDecoder::new(&file[..]).unwrap();
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out == data);
+        assert_eq!(d.header().filename(), Some(&b"data.txt"[..]));
+        assert_eq!(&d.header().extra().unwrap()[..2], b"RA");
+
+        let mut r = Reader::new(Cursor::new(file)).unwrap();
+        assert_eq!(r.chunk_size(), 10_000);
+        assert_eq!(r.uncompressed_len(), data.len() as u64);
+        assert_eq!(r.header().filename(), Some(&b"data.txt"[..]));
+        for &pos in [0, 150_000, 9_990, 10_000, data.len() - 100].iter() {
+            r.seek(SeekFrom::Start(pos as u64)).unwrap();
+            let mut buf = [0; 100];
+            r.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &data[pos..pos + 100]);
+        }
+        r.seek(SeekFrom::End(-50)).unwrap();
+        let mut rest = Vec::new();
+        r.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, &data[data.len() - 50..]);
+        assert!(r.seek(SeekFrom::Current(-(data.len() as i64) - 1)).is_err());
+    }
+
+    #[test]
+    fn seekable() {
+        let data = data();
+        let mut w = Builder::new()
+            .chunk_size(10_000)
+            .header(GzBuilder::new().filename("data.txt"))
+            .size_hint(data.len() as u64)
+            .write_seekable(Cursor::new(Vec::new()), Compression::default())
+            .unwrap();
+        w.write_all(&data[..50_000]).unwrap();
+        assert!(w.get_ref().get_ref().len() > 1_000);
+        w.write_all(&data[50_000..]).unwrap();
+        let file = w.finish().unwrap().into_inner();
+
+        let mut d = GzDecoder::new(&file[..]).unwrap();
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out == data);
+        assert_eq!(d.header().filename(), Some(&b"data.txt"[..]));
+
+        let mut r = Reader::new(Cursor::new(file)).unwrap();
+        assert_eq!(r.uncompressed_len(), data.len() as u64);
+        assert_eq!(r.offsets.len(), (data.len() + 9_999) / 10_000 + 1);
+        r.seek(SeekFrom::Start(123_456)).unwrap();
+        let mut buf = [0; 100];
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &data[123_456..123_556]);
+    }
+
+    #[test]
+    fn seekable_table_full() {
+        let mut w = Builder::new()
+            .chunk_size(1000)
+            .size_hint(2500)
+            .write_seekable(Cursor::new(Vec::new()), Compression::default())
+            .unwrap();
+        w.write_all(&[1; 3000]).unwrap();
+        assert!(w.write(&[1]).is_err());
+        let file = w.finish().unwrap().into_inner();
+
+        let mut out = Vec::new();
+        Reader::new(Cursor::new(file)).unwrap().read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, &[1; 3000][..]);
+    }
+
+    #[test]
+    fn incompressible() {
+        let data = thread_rng().gen_iter::<u8>().take(100_000)
+            .collect::<Vec<_>>();
+        for &b in backend::tests::backends().iter() {
+            for &level in [0, 1, 6].iter() {
+                for &seekable in [false, true].iter() {
+                    let level = Compression::new(level);
+                    let builder = Builder::new().chunk_size(10_000);
+                    let mut w = if seekable {
+                        builder.write_seekable(Cursor::new(Vec::new()), level)
+                    } else {
+                        builder.write(Cursor::new(Vec::new()), level)
+                    }.unwrap();
+                    w.compress = CompressBuilder::new(level).backend(b)
+                        .build_format(Format::Raw)
+                        .unwrap();
+                    for chunk in data.chunks(30_000) {
+                        w.write_all(chunk).unwrap();
+                    }
+                    let file = w.finish().unwrap().into_inner();
+
+                    let mut r = Reader::new(Cursor::new(file)).unwrap();
+                    let mut out = Vec::new();
+                    r.read_to_end(&mut out).unwrap();
+                    assert!(out == data, "{:?} at level {:?}", b, level);
+                    r.seek(SeekFrom::Start(65_432)).unwrap();
+                    let mut buf = [0; 100];
+                    r.read_exact(&mut buf).unwrap();
+                    assert_eq!(&buf[..], &data[65_432..65_532]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn exact_chunks() {
+        let data = vec![7; 3000];
+        let mut w = Builder::new().chunk_size(1000)
+            .write(Vec::new(), Compression::fast())
+            .unwrap();
+        w.write_all(&data).unwrap();
+        let file = w.finish().unwrap();
+
+        let mut out = Vec::new();
+        let mut r = Reader::new(Cursor::new(file)).unwrap();
+        r.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(r.offsets.len(), 4);
+    }
+
+    #[test]
+    fn empty() {
+        let file = Writer::new(Vec::new(), Compression::default())
+            .unwrap()
+            .finish()
+            .unwrap();
+        let mut out = Vec::new();
+        GzDecoder::new(&file[..]).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"");
+        Reader::new(Cursor::new(file)).unwrap().read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, b"");
+    }
+
+    #[test]
+    fn length_from_chunks() {
+        let data = data();
+        let mut w = Builder::new().chunk_size(10_000)
+            .write(Vec::new(), Compression::default())
+            .unwrap();
+        w.write_all(&data).unwrap();
+        let file = w.finish().unwrap();
+        let r = Reader::new(Cursor::new(file.clone())).unwrap();
+        assert_eq!(r.uncompressed_len(), data.len() as u64);
+
+        // A trailer disagreeing with the chunks is caught up front, whether
+        // it claims too little data or too much.
+        for &delta in [1u8, 255].iter() {
+            let mut bad = file.clone();
+            let n = bad.len();
+            bad[n - 4] = bad[n - 4].wrapping_add(delta);
+            let err = Reader::new(Cursor::new(bad)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn not_dictzip() {
+        let mut w = GzBuilder::new().write(Vec::new(), Compression::default());
+        w.write_all(b"hello").unwrap();
+        let file = w.finish().unwrap();
+        assert!(Reader::new(Cursor::new(file)).is_err());
+    }
+}
@@ -1,17 +1,165 @@
-pub use self::imp::*;
+// Each C backend which is enabled gets its own module with the same
+// miniz-style API, and the default one is also exported at the top level.
+#[cfg(feature = "zlib")]
+pub use self::zlib as default;
+#[cfg(all(not(feature = "zlib"), feature = "miniz-sys"))]
+pub use self::miniz as default;
+
+pub use self::default::*;
+
+// With only the pure Rust backend there's no C library to take the constants
+// from, so these are zlib's values, which miniz shares.
+#[cfg(not(any(feature = "zlib", feature = "miniz-sys")))]
+pub mod default {
+    use libc::c_int;
+
+    pub const MZ_NO_FLUSH: c_int = 0;
+    pub const MZ_PARTIAL_FLUSH: c_int = 1;
+    pub const MZ_SYNC_FLUSH: c_int = 2;
+    pub const MZ_FULL_FLUSH: c_int = 3;
+    pub const MZ_FINISH: c_int = 4;
+    pub const MZ_BLOCK: c_int = 5;
+
+    pub const MZ_DEFAULT_WINDOW_BITS: c_int = 15;
+    pub const MZ_DEFAULT_STRATEGY: c_int = 0;
+    pub const MZ_FILTERED: c_int = 1;
+    pub const MZ_HUFFMAN_ONLY: c_int = 2;
+    pub const MZ_RLE: c_int = 3;
+    pub const MZ_FIXED: c_int = 4;
+}
+
+use std::sync::atomic::AtomicUsize;
+
+// The memory budget behind a `MemoryLimit`, shared by the streams charged to
+// it.
+#[derive(Debug)]
+pub struct Budget {
+    pub limit: usize,
+    pub used: AtomicUsize,
+}
+
+// Memory accounting for the C backends, whose streams allocate through `alloc`
+// and `free` with a pointer to their own `Accounting`. This keeps track of how
+// much each stream holds and charges it to an optional budget, which may be
+// shared with other streams.
+#[cfg(any(feature = "zlib", feature = "miniz-sys"))]
+pub mod memory {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use libc::{self, c_int, c_void, size_t};
+
+    use super::Budget;
+
+    #[derive(Debug)]
+    pub struct Accounting {
+        used: AtomicUsize,
+        budget: Option<Arc<Budget>>,
+        copying: Mutex<()>,
+    }
+
+    // Every allocation is prefixed with its size so that `free` knows how
+    // much is released, while keeping the rest aligned the way malloc does.
+    const HEADER: usize = 16;
+
+    impl Accounting {
+        pub fn new(budget: Option<Arc<Budget>>) -> Accounting {
+            Accounting {
+                used: AtomicUsize::new(0),
+                budget: budget,
+                copying: Mutex::new(()),
+            }
+        }
+
+        pub fn used(&self) -> usize {
+            self.used.load(Ordering::SeqCst)
+        }
+
+        pub fn budget(&self) -> Option<Arc<Budget>> {
+            self.budget.clone()
+        }
+    }
+
+    // Copying a stream allocates through the allocator of the source, so
+    // afterwards whatever `copy` allocated is moved over to `dest`. Copies of
+    // the same stream are serialized so that they can't be mixed up.
+    pub fn copy<F>(source: &Accounting, dest: &Accounting, copy: F) -> c_int
+        where F: FnOnce() -> c_int
+    {
+        let _lock = source.copying.lock().unwrap();
+        let before = source.used();
+        let rc = copy();
+        let taken = source.used() - before;
+        source.used.fetch_sub(taken, Ordering::SeqCst);
+        dest.used.fetch_add(taken, Ordering::SeqCst);
+        rc
+    }
+
+    pub unsafe fn alloc(opaque: *mut c_void, items: size_t, size: size_t)
+                        -> *mut c_void {
+        let accounting = &*(opaque as *const Accounting);
+        let total = match items.checked_mul(size)
+                               .and_then(|n| n.checked_add(HEADER)) {
+            Some(n) => n,
+            None => return 0 as *mut _,
+        };
+        if let Some(ref budget) = accounting.budget {
+            let used = budget.used.fetch_add(total, Ordering::SeqCst);
+            if used.checked_add(total).map_or(true, |n| n > budget.limit) {
+                budget.used.fetch_sub(total, Ordering::SeqCst);
+                return 0 as *mut _
+            }
+        }
+        let ptr = libc::malloc(total) as *mut usize;
+        if ptr.is_null() {
+            if let Some(ref budget) = accounting.budget {
+                budget.used.fetch_sub(total, Ordering::SeqCst);
+            }
+            return ptr as *mut c_void
+        }
+        *ptr = total;
+        accounting.used.fetch_add(total, Ordering::SeqCst);
+        (ptr as *mut u8).offset(HEADER as isize) as *mut c_void
+    }
+
+    pub unsafe fn free(opaque: *mut c_void, address: *mut c_void) {
+        if address.is_null() {
+            return
+        }
+        let accounting = &*(opaque as *const Accounting);
+        let ptr = (address as *mut u8).offset(-(HEADER as isize));
+        let total = *(ptr as *const usize);
+        accounting.used.fetch_sub(total, Ordering::SeqCst);
+        if let Some(ref budget) = accounting.budget {
+            budget.used.fetch_sub(total, Ordering::SeqCst);
+        }
+        libc::free(ptr as *mut c_void)
+    }
+}
 
 #[cfg(feature = "zlib")]
 #[allow(bad_style)]
-mod imp {
+pub mod zlib {
     extern crate libz_sys as z;
     use std::mem;
     use std::ops::{Deref, DerefMut};
-    use libc::{c_int, size_t, c_ulong, c_uint, c_char};
+    use std::sync::Arc;
+    use libc::{c_int, size_t, c_ulong, c_uint, c_char, c_void};
+
+    use super::Budget;
+    use super::memory::{self, Accounting};
 
     pub use self::z::*;
     pub use self::z::deflateEnd as mz_deflateEnd;
     pub use self::z::inflateEnd as mz_inflateEnd;
     pub use self::z::deflateReset as mz_deflateReset;
+    pub use self::z::deflateParams as mz_deflateParams;
+    pub use self::z::deflateCopy as mz_deflateCopy;
+    pub use self::z::inflateCopy as mz_inflateCopy;
+    pub use self::z::deflateSetDictionary as mz_deflateSetDictionary;
+    pub use self::z::inflateSetDictionary as mz_inflateSetDictionary;
+    pub use self::z::inflatePrime as mz_inflatePrime;
+    pub use self::z::inflateReset2 as mz_inflateReset2;
     pub use self::z::deflate as mz_deflate;
     pub use self::z::inflate as mz_inflate;
     pub use self::z::z_stream as mz_stream;
@@ -21,45 +169,75 @@ mod imp {
     pub use self::z::Z_DATA_ERROR as MZ_DATA_ERROR;
     pub use self::z::Z_DEFAULT_STRATEGY as MZ_DEFAULT_STRATEGY;
     pub use self::z::Z_DEFLATED as MZ_DEFLATED;
+    pub use self::z::Z_FILTERED as MZ_FILTERED;
     pub use self::z::Z_FINISH as MZ_FINISH;
+    pub use self::z::Z_FIXED as MZ_FIXED;
     pub use self::z::Z_FULL_FLUSH as MZ_FULL_FLUSH;
+    pub use self::z::Z_HUFFMAN_ONLY as MZ_HUFFMAN_ONLY;
+    pub use self::z::Z_MEM_ERROR as MZ_MEM_ERROR;
+    pub use self::z::Z_NEED_DICT as MZ_NEED_DICT;
     pub use self::z::Z_NO_FLUSH as MZ_NO_FLUSH;
     pub use self::z::Z_OK as MZ_OK;
     pub use self::z::Z_PARTIAL_FLUSH as MZ_PARTIAL_FLUSH;
+    pub use self::z::Z_RLE as MZ_RLE;
     pub use self::z::Z_STREAM_END as MZ_STREAM_END;
     pub use self::z::Z_SYNC_FLUSH as MZ_SYNC_FLUSH;
     pub use self::z::Z_STREAM_ERROR as MZ_STREAM_ERROR;
 
     pub const MZ_DEFAULT_WINDOW_BITS: c_int = 15;
 
-    pub unsafe extern fn mz_crc32(crc: c_ulong,
-                                  ptr: *const u8,
-                                  len: size_t) -> c_ulong {
-        z::crc32(crc, ptr, len as c_uint)
+    pub const SUPPORTS_WINDOW_BITS: bool = true;
+    pub const SUPPORTS_MEM_LEVEL: bool = true;
+    pub const SUPPORTS_DICTIONARY: bool = true;
+    pub const SUPPORTS_BLOCK_FLUSH: bool = true;
+    pub const SUPPORTS_MEMORY_LIMIT: bool = true;
+
+    // The sizes documented in zconf.h, plus a little for the state structures
+    // themselves.
+    pub fn deflate_memory(window_bits: u8, mem_level: u8) -> usize {
+        (1 << (window_bits + 2)) + (1 << (mem_level + 9)) + 8 * 1024
+    }
+
+    pub fn inflate_memory(window_bits: u8) -> usize {
+        (1 << window_bits) + 8 * 1024
     }
 
-    pub unsafe extern fn mz_crc32_combine(crc1: c_ulong,
-                                          crc2: c_ulong,
-                                          len2: z_off_t) -> c_ulong {
-          z::crc32_combine(crc1, crc2, len2)
+    // Available since zlib 1.2.7.1, but not bound by libz-sys.
+    extern "C" {
+        fn inflateGetDictionary(stream: *mut mz_stream,
+                                dictionary: *mut u8,
+                                len: *mut c_uint) -> c_int;
+    }
+
+    pub unsafe extern "C" fn mz_inflateGetDictionary(stream: *mut mz_stream,
+                                                     dictionary: *mut u8,
+                                                     len: *mut c_uint)
+                                                     -> c_int {
+        inflateGetDictionary(stream, dictionary, len)
+    }
+
+    pub unsafe extern "C" fn mz_crc32(crc: c_ulong,
+                                      ptr: *const u8,
+                                      len: size_t) -> c_ulong {
+        z::crc32(crc, ptr, len as c_uint)
     }
 
     const ZLIB_VERSION: &'static str = "1.2.8\0";
 
-    pub unsafe extern fn mz_deflateInit2(stream: *mut mz_stream,
-                                         level: c_int,
-                                         method: c_int,
-                                         window_bits: c_int,
-                                         mem_level: c_int,
-                                         strategy: c_int) -> c_int {
+    pub unsafe extern "C" fn mz_deflateInit2(stream: *mut mz_stream,
+                                             level: c_int,
+                                             method: c_int,
+                                             window_bits: c_int,
+                                             mem_level: c_int,
+                                             strategy: c_int) -> c_int {
         z::deflateInit2_(stream, level, method, window_bits, mem_level,
                          strategy,
                          ZLIB_VERSION.as_ptr() as *const c_char,
                          mem::size_of::<mz_stream>() as c_int)
     }
-    pub unsafe extern fn mz_inflateInit2(stream: *mut mz_stream,
-                                         window_bits: c_int)
-                                         -> c_int {
+    pub unsafe extern "C" fn mz_inflateInit2(stream: *mut mz_stream,
+                                             window_bits: c_int)
+                                             -> c_int {
         z::inflateInit2_(stream, window_bits,
                          ZLIB_VERSION.as_ptr() as *const c_char,
                          mem::size_of::<mz_stream>() as c_int)
@@ -67,6 +245,7 @@ mod imp {
 
     pub struct StreamWrapper{
         inner: Box<mz_stream>,
+        accounting: Box<Accounting>,
     }
 
     impl ::std::fmt::Debug for StreamWrapper{
@@ -77,10 +256,70 @@ mod imp {
 
     impl Default for StreamWrapper {
         fn default() -> StreamWrapper {
+            StreamWrapper::new(None)
+        }
+    }
+
+    impl StreamWrapper {
+        // Creates a stream whose allocations are charged to `budget`, if any.
+        pub fn new(budget: Option<Arc<Budget>>) -> StreamWrapper {
+            let accounting = Box::new(Accounting::new(budget));
+            let opaque = &*accounting as *const Accounting as *mut c_void;
+            // `zalloc` and `zfree` are non-nullable function pointers in the
+            // bindings, so the stream can't simply be zeroed.
             StreamWrapper {
-                inner: Box::new(unsafe{ mem::zeroed() })
+                inner: Box::new(mz_stream {
+                    next_in: 0 as *mut _,
+                    avail_in: 0,
+                    total_in: 0,
+                    next_out: 0 as *mut _,
+                    avail_out: 0,
+                    total_out: 0,
+                    msg: 0 as *mut _,
+                    state: 0 as *mut _,
+                    zalloc: zalloc,
+                    zfree: zfree,
+                    opaque: opaque,
+                    data_type: 0,
+                    adler: 0,
+                    reserved: 0,
+                }),
+                accounting: accounting,
             }
         }
+
+        // Returns the number of bytes currently allocated by the stream.
+        pub fn memory_usage(&self) -> Option<usize> {
+            Some(self.accounting.used())
+        }
+
+        // Copies this stream into a new one with `copy`, which is either
+        // `deflateCopy` or `inflateCopy`. zlib copies `opaque` along with
+        // everything else, so it's pointed at the new stream's accounting
+        // afterwards.
+        pub fn copy<F>(&self, copy: F) -> (StreamWrapper, c_int)
+            where F: FnOnce(*mut mz_stream, *mut mz_stream) -> c_int
+        {
+            let mut dest = StreamWrapper::new(self.accounting.budget());
+            let dest_ptr = &mut *dest.inner as *mut mz_stream;
+            let source = &*self.inner as *const mz_stream as *mut mz_stream;
+            let rc = memory::copy(&self.accounting, &dest.accounting, || {
+                copy(dest_ptr, source)
+            });
+            dest.inner.opaque = &*dest.accounting as *const Accounting
+                as *mut c_void;
+            (dest, rc)
+        }
+    }
+
+    unsafe extern "C" fn zalloc(opaque: *mut c_void,
+                                items: uInt,
+                                size: uInt) -> *mut c_void {
+        memory::alloc(opaque, items as size_t, size as size_t)
+    }
+
+    unsafe extern "C" fn zfree(opaque: *mut c_void, address: *mut c_void) {
+        memory::free(opaque, address)
     }
 
     impl Deref for StreamWrapper {
@@ -98,17 +337,41 @@ mod imp {
     }
 }
 
-#[cfg(not(feature = "zlib"))]
-mod imp {
+#[cfg(feature = "miniz-sys")]
+#[allow(bad_style)]
+pub mod miniz {
     extern crate miniz_sys;
     use std::mem;
     use std::ops::{Deref, DerefMut};
+    use std::sync::Arc;
 
-    use libc::{c_ulong, off_t};
+    use libc::{c_int, c_void, size_t};
     pub use self::miniz_sys::*;
 
+    use super::Budget;
+    use super::memory::{self, Accounting};
+
+    // miniz always uses a 32KiB window and a fixed amount of memory for its
+    // internal state.
+    pub const SUPPORTS_WINDOW_BITS: bool = false;
+    pub const SUPPORTS_MEM_LEVEL: bool = false;
+    pub const SUPPORTS_DICTIONARY: bool = true;
+    pub const SUPPORTS_BLOCK_FLUSH: bool = true;
+    pub const SUPPORTS_MEMORY_LIMIT: bool = true;
+
+    // The compressor and decompressor state are allocated in one piece each,
+    // with a size that doesn't depend on the parameters.
+    pub fn deflate_memory(_window_bits: u8, _mem_level: u8) -> usize {
+        320 * 1024
+    }
+
+    pub fn inflate_memory(_window_bits: u8) -> usize {
+        44 * 1024
+    }
+
     pub struct StreamWrapper {
         inner: mz_stream,
+        accounting: Box<Accounting>,
     }
 
     impl ::std::fmt::Debug for StreamWrapper{
@@ -119,117 +382,77 @@ mod imp {
 
     impl Default for StreamWrapper {
         fn default() -> StreamWrapper {
+            StreamWrapper::new(None)
+        }
+    }
+
+    impl StreamWrapper {
+        // Creates a stream whose allocations are charged to `budget`, if any.
+        pub fn new(budget: Option<Arc<Budget>>) -> StreamWrapper {
+            let accounting = Box::new(Accounting::new(budget));
+            let mut inner: mz_stream = unsafe { mem::zeroed() };
+            inner.zalloc = Some(zalloc);
+            inner.zfree = Some(zfree);
+            inner.opaque = &*accounting as *const Accounting as *mut c_void;
             StreamWrapper {
-                inner : unsafe{ mem::zeroed() }
+                inner: inner,
+                accounting: accounting,
             }
         }
-    }
 
-    impl Deref for StreamWrapper {
-        type Target = mz_stream;
+        // Returns the number of bytes currently allocated by the stream.
+        pub fn memory_usage(&self) -> Option<usize> {
+            Some(self.accounting.used())
+        }
 
-        fn deref(&self) -> &Self::Target {
-            &self.inner
+        // Copies this stream into a new one with `copy`, which is either
+        // `mz_deflateCopy` or `mz_inflateCopy`. Like zlib, these copy
+        // `opaque` along with everything else, so it's pointed at the new
+        // stream's accounting afterwards.
+        pub fn copy<F>(&self, copy: F) -> (StreamWrapper, c_int)
+            where F: FnOnce(*mut mz_stream, *mut mz_stream) -> c_int
+        {
+            let mut dest = StreamWrapper::new(self.accounting.budget());
+            let dest_ptr = &mut dest.inner as *mut mz_stream;
+            let source = &self.inner as *const mz_stream as *mut mz_stream;
+            let rc = memory::copy(&self.accounting, &dest.accounting, || {
+                copy(dest_ptr, source)
+            });
+            dest.inner.opaque = &*dest.accounting as *const Accounting
+                as *mut c_void;
+            (dest, rc)
         }
     }
 
-    impl DerefMut for StreamWrapper {
-        fn deref_mut(&mut self) -> &mut Self::Target {
-            &mut self.inner
-        }
+    extern "C" fn zalloc(opaque: *mut c_void,
+                         items: size_t,
+                         size: size_t) -> *mut c_void {
+        unsafe { memory::alloc(opaque, items, size) }
     }
 
-    pub unsafe extern fn mz_crc32_combine(crc1: c_ulong,
-                                          crc2: c_ulong,
-                                          len2: off_t) -> c_ulong {
-          crc32_combine_(crc1, crc2, len2)
+    extern "C" fn zfree(opaque: *mut c_void, address: *mut c_void) {
+        unsafe { memory::free(opaque, address) }
     }
 
-    // gf2_matrix_times, gf2_matrix_square and crc32_combine_ are ported from
-    // zlib.
+    impl Deref for StreamWrapper {
+        type Target = mz_stream;
 
-    fn gf2_matrix_times(mat: &[c_ulong; 32], mut vec: c_ulong) -> c_ulong {
-        let mut sum = 0;
-        let mut mat_pos = 0;
-        while vec != 0 {
-            if vec & 1 == 1 {
-                sum ^= mat[mat_pos];
-            }
-            vec >>= 1;
-            mat_pos += 1;
+        fn deref(&self) -> &Self::Target {
+            &self.inner
         }
-        sum
     }
 
-    fn gf2_matrix_square(square: &mut [c_ulong; 32], mat: &[c_ulong; 32]) {
-        for n in 0..32 {
-            square[n] = gf2_matrix_times(mat, mat[n]);
+    impl DerefMut for StreamWrapper {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.inner
         }
     }
 
-    fn crc32_combine_(mut crc1: c_ulong, crc2: c_ulong, mut len2: off_t) -> c_ulong {
-        let mut row;
-
-        let mut even = [0; 32]; /* even-power-of-two zeros operator */
-        let mut odd = [0; 32]; /* odd-power-of-two zeros operator */
-
-        /* degenerate case (also disallow negative lengths) */
-        if len2 <= 0 {
-            return crc1;
-        }
-
-        /* put operator for one zero bit in odd */
-        odd[0] = 0xedb88320;          /* CRC-32 polynomial */
-        row = 1;
-        for n in 1..32 {
-            odd[n] = row;
-            row <<= 1;
-        }
-
-        /* put operator for two zero bits in even */
-        gf2_matrix_square(&mut even, &odd);
-
-        /* put operator for four zero bits in odd */
-        gf2_matrix_square(&mut odd, &even);
-
-        /* apply len2 zeros to crc1 (first square will put the operator for one
-           zero byte, eight zero bits, in even) */
-        loop {
-            /* apply zeros operator for this bit of len2 */
-            gf2_matrix_square(&mut even, &odd);
-            if len2 & 1 == 1 {
-                crc1 = gf2_matrix_times(&even, crc1);
-            }
-            len2 >>= 1;
-
-            /* if no more bits set, then done */
-            if len2 == 0 {
-                break;
-            }
-
-            /* another iteration of the loop with odd and even swapped */
-            gf2_matrix_square(&mut odd, &even);
-            if len2 & 1 == 1 {
-                crc1 = gf2_matrix_times(&odd, crc1);
-            }
-            len2 >>= 1;
-
-            /* if no more bits set, then done */
-            if len2 == 0 {
-                break;
-            }
-        }
-
-        /* return combined crc */
-        crc1 ^= crc2;
-        crc1
+    // There's no way to reset an inflate stream into a different format
+    // other than starting over.
+    pub unsafe extern "C" fn mz_inflateReset2(stream: *mut mz_stream,
+                                              window_bits: c_int) -> c_int {
+        mz_inflateEnd(stream);
+        mz_inflateInit2(stream, window_bits)
     }
 }
-
-#[test]
-fn crc32_combine() {
-    let crc32 = unsafe {
-        imp::mz_crc32_combine(1, 2, 3)
-    };
-    assert_eq!(crc32, 29518389);
-}
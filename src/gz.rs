@@ -6,8 +6,7 @@ use std::cmp;
 use std::env;
 use std::ffi::CString;
 use std::io::prelude::*;
-use std::io;
-use std::mem;
+use std::io::{self, SeekFrom};
 use std::time;
 
 #[cfg(feature = "tokio")]
@@ -15,17 +14,28 @@ use futures::Poll;
 #[cfg(feature = "tokio")]
 use tokio_io::{AsyncRead, AsyncWrite};
 
-use {Compression, Compress};
+use {Compression, Compress, Decompress, DecompressFormat, Flush, Status};
+use Strategy;
 use bufreader::BufReader;
-use crc::{CrcReader, Crc};
+use crc::{CrcReader, CrcWriter, Crc};
 use deflate;
 use zio;
+use zran;
 
 static FHCRC: u8 = 1 << 1;
-static FEXTRA: u8 = 1 << 2;
+pub static FEXTRA: u8 = 1 << 2;
 static FNAME: u8 = 1 << 3;
 static FCOMMENT: u8 = 1 << 4;
 
+// The embedded index of flush points is kept in an extra subfield of an empty
+// member at the end of the file, which is followed by the count of points and
+// this magic number so it can be found from the end.
+const INDEX_ID: &'static [u8; 2] = b"ZX";
+const INDEX_MAGIC: &'static [u8; 4] = b"GZIX";
+const MAX_INDEX_POINTS: usize = (0xffff - 4 - 8 - 8) / 16;
+// An empty final block with fixed codes, and a trailer of zeros.
+const EMPTY_MEMBER_TAIL: [u8; 10] = [3, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
 /// A gzip streaming encoder
 ///
 /// This structure exposes a [`Write`] interface that will emit compressed data
@@ -38,6 +48,13 @@ pub struct EncoderWriter<W: Write> {
     crc: Crc,
     crc_bytes_written: usize,
     header: Vec<u8>,
+    header_len: u64,
+    flush_interval: Option<u64>,
+    // The (uncompressed, compressed) offsets of the full flushes so far.
+    flush_points: Vec<(u64, u64)>,
+    embed_index: bool,
+    // The member carrying the index, once it's been built.
+    index_member: Vec<u8>,
 }
 
 /// A gzip streaming encoder
@@ -118,6 +135,7 @@ pub struct DecoderReaderBuf<R> {
     inner: CrcReader<deflate::DecoderReaderBuf<R>>,
     header: Header,
     finished: bool,
+    recover: bool,
 }
 
 /// A gzip streaming decoder that decodes all members of a multistream
@@ -138,6 +156,49 @@ pub struct MultiDecoderReaderBuf<R> {
     inner: CrcReader<deflate::DecoderReaderBuf<R>>,
     header: Header,
     finished: bool,
+    recover: bool,
+    // Compressed bytes skipped in the members before the current one.
+    lost: u64,
+}
+
+/// A gzip streaming decoder
+///
+/// This structure exposes a [`Write`] interface that will consume compressed
+/// data and write the uncompressed data to the underlying writer `W`. The
+/// header is parsed as it is written, and the trailer is verified as soon as
+/// it has been written. Data following the trailer isn't consumed, so `write`
+/// returns `Ok(0)` for it.
+///
+/// [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
+#[derive(Debug)]
+pub struct DecoderWriter<W: Write> {
+    inner: zio::Writer<CrcWriter<W>, Decompress>,
+    state: DecoderState,
+    header: Option<Header>,
+    parser: HeaderParser,
+    // Trailer bytes collected so far.
+    buf: Vec<u8>,
+    multi: bool,
+}
+
+/// A gzip streaming decoder that decodes all members of a multistream
+///
+/// This structure exposes a [`Write`] interface that will consume all gzip
+/// members written to it and write the uncompressed data to the underlying
+/// writer `W`. `DecoderWriter` stops after the first member instead.
+///
+/// [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
+#[derive(Debug)]
+pub struct MultiDecoderWriter<W: Write> {
+    inner: DecoderWriter<W>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum DecoderState {
+    Header,
+    Body,
+    Trailer,
+    Done,
 }
 
 /// A structure representing the header of a gzip stream.
@@ -199,13 +260,33 @@ impl Builder {
     ///
     /// The data written to the returned encoder will be compressed and then
     /// written out to the supplied parameter `w`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the compression stream can't be set up. Use `try_write` to
+    /// get an error instead.
     pub fn write<W: Write>(self, w: W, lvl: Compression) -> EncoderWriter<W> {
-        EncoderWriter {
-            inner: zio::Writer::new(w, Compress::new(lvl, false)),
+        self.try_write(w, lvl)
+            .expect("failed to initialize compression stream")
+    }
+
+    /// Like `write`, but returns an error if the compression stream can't be
+    /// set up.
+    pub fn try_write<W: Write>(self, w: W, lvl: Compression)
+                               -> io::Result<EncoderWriter<W>> {
+        let compress = try!(Compress::try_new(lvl, false));
+        let header = self.into_header(lvl);
+        Ok(EncoderWriter {
+            inner: zio::Writer::new(w, compress),
             crc: Crc::new(),
-            header: self.into_header(lvl),
+            header_len: header.len() as u64,
+            header: header,
             crc_bytes_written: 0,
-        }
+            flush_interval: None,
+            flush_points: Vec::new(),
+            embed_index: false,
+            index_member: Vec::new(),
+        })
     }
 
     /// Consume this builder, creating a reader encoder in the process.
@@ -269,10 +350,14 @@ impl Builder {
         header[5] = (mtime >> 8) as u8;
         header[6] = (mtime >> 16) as u8;
         header[7] = (mtime >> 24) as u8;
-        header[8] = match lvl {
-            Compression::Best => 2,
-            Compression::Fast => 4,
-            _ => 0,
+        // The XFL byte follows zlib's convention: 2 when the slowest, densest
+        // level was used and 4 for the fastest levels.
+        header[8] = if lvl.level() >= Compression::best().level() {
+            2
+        } else if lvl.level() <= Compression::fast().level() {
+            4
+        } else {
+            0
         };
         header[9] = match env::consts::OS {
             "linux" => 3,
@@ -296,6 +381,73 @@ impl<W: Write> EncoderWriter<W> {
         Builder::new().write(w, level)
     }
 
+    /// Changes the compression level and strategy used for data written from
+    /// now on.
+    ///
+    /// All data written so far is first flushed to the underlying writer, as
+    /// with `flush`, except that the underlying writer itself is not flushed.
+    /// The header, which has already been emitted, is not updated.
+    ///
+    /// # Errors
+    ///
+    /// This function will perform I/O to flush pending data, and any I/O
+    /// errors which occur will be returned from this function.
+    pub fn set_params(&mut self, level: Compression, strategy: Strategy)
+                      -> io::Result<()> {
+        assert_eq!(self.crc_bytes_written, 0);
+        try!(self.write_header());
+        try!(self.inner.flush_data());
+        self.inner.data.set_params(level, strategy)
+    }
+
+    /// Makes the encoder issue a full flush after every `interval` bytes of
+    /// uncompressed data, recording the offsets at which they happen.
+    ///
+    /// Decompression can start afresh at each of these flush points, so the
+    /// output can be read from anywhere with a [`SeekableGzReader`] and an
+    /// index of them, as returned by [`index`] or embedded in the output with
+    /// [`set_embed_index`]. Every flush costs a little compression.
+    ///
+    /// [`SeekableGzReader`]: ../read/struct.SeekableGzReader.html
+    /// [`index`]: #method.index
+    /// [`set_embed_index`]: #method.set_embed_index
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn set_flush_interval(&mut self, interval: u64) {
+        assert!(interval > 0, "flush points need to be some distance apart");
+        self.flush_interval = Some(interval);
+    }
+
+    /// Returns the offsets of the full flushes issued so far, as pairs of the
+    /// offset in the uncompressed data and the offset in the output.
+    pub fn flush_points(&self) -> &[(u64, u64)] {
+        &self.flush_points
+    }
+
+    /// Returns an index of the flush points issued so far.
+    ///
+    /// Once the encoder has been finished with `try_finish`, this is the
+    /// index of the whole output, which can be saved to a separate file with
+    /// `Index::write_to`.
+    pub fn index(&self) -> zran::Index {
+        zran::Index::from_flush_points(DecompressFormat::Gzip,
+                                       self.inner.data.total_in(),
+                                       &self.flush_points)
+    }
+
+    /// Configures whether finishing the encoder appends the index of the
+    /// flush points to the output.
+    ///
+    /// The index is stored in the extra field of an empty gzip member after
+    /// the compressed data, so the output is still an ordinary gzip file. If
+    /// there are too many flush points to fit, only every other one (or
+    /// every fourth, and so on) is kept.
+    pub fn set_embed_index(&mut self, embed: bool) {
+        self.embed_index = embed;
+    }
+
     /// Acquires a reference to the underlying writer.
     pub fn get_ref(&self) -> &W {
         self.inner.get_ref()
@@ -329,7 +481,8 @@ impl<W: Write> EncoderWriter<W> {
         try!(self.inner.finish());
 
         while self.crc_bytes_written < 8 {
-            let (sum, amt) = (self.crc.sum() as u32, self.crc.amount());
+            let sum = self.crc.sum() as u32;
+            let amt = self.crc.amount() as u32;
             let buf = [(sum >> 0) as u8,
                        (sum >> 8) as u8,
                        (sum >> 16) as u8,
@@ -338,10 +491,20 @@ impl<W: Write> EncoderWriter<W> {
                        (amt >> 8) as u8,
                        (amt >> 16) as u8,
                        (amt >> 24) as u8];
-            let mut inner = self.inner.get_mut();
+            let inner = self.inner.get_mut();
             let n = try!(inner.write(&buf[self.crc_bytes_written..]));
             self.crc_bytes_written += n;
         }
+
+        if self.embed_index {
+            self.index_member = index_member(self.inner.data.total_in(),
+                                             &self.flush_points);
+            self.embed_index = false;
+        }
+        while self.index_member.len() > 0 {
+            let n = try!(self.inner.get_mut().write(&self.index_member));
+            self.index_member.drain(..n);
+        }
         Ok(())
     }
 
@@ -370,14 +533,48 @@ impl<W: Write> EncoderWriter<W> {
         }
         Ok(())
     }
+
+    // Issues a full flush and records where it happened.
+    fn add_flush_point(&mut self) -> io::Result<()> {
+        try!(self.inner.flush_data_with(Flush::Full));
+        let point = (self.inner.data.total_in(),
+                     self.header_len + self.inner.data.total_out());
+        self.flush_points.push(point);
+        Ok(())
+    }
 }
 
 impl<W: Write> Write for EncoderWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         assert_eq!(self.crc_bytes_written, 0);
         try!(self.write_header());
-        let n = try!(self.inner.write(buf));
+        let interval = match self.flush_interval {
+            Some(interval) => interval,
+            None => {
+                let n = try!(self.inner.write(buf));
+                self.crc.update(&buf[..n]);
+                return Ok(n)
+            }
+        };
+
+        // The interval may have been set, or shortened, after more data than
+        // it allows was already written, in which case that data gets its
+        // flush point right away.
+        let last = self.flush_points.last().map(|p| p.0).unwrap_or(0);
+        let since = self.inner.data.total_in() - last;
+        let left = match interval.saturating_sub(since) {
+            0 => {
+                try!(self.add_flush_point());
+                interval
+            }
+            left => left,
+        };
+        let len = cmp::min(buf.len() as u64, left) as usize;
+        let n = try!(self.inner.write(&buf[..len]));
         self.crc.update(&buf[..n]);
+        if n as u64 == left {
+            try!(self.add_flush_point());
+        }
         Ok(n)
     }
 
@@ -457,7 +654,7 @@ fn copy(into: &mut [u8], from: &[u8], pos: &mut usize) -> usize {
 }
 
 impl<R: Read> Read for EncoderReader<R> {
-    fn read(&mut self, mut into: &mut [u8]) -> io::Result<usize> {
+    fn read(&mut self, into: &mut [u8]) -> io::Result<usize> {
         self.inner.read(into)
     }
 }
@@ -576,6 +773,21 @@ impl<R> DecoderReader<R> {
         self.inner.header()
     }
 
+    /// Configures whether this decoder recovers from damaged input.
+    ///
+    /// See `bufread::GzDecoder::set_recover` for details.
+    pub fn set_recover(&mut self, recover: bool) {
+        self.inner.set_recover(recover)
+    }
+
+    /// Returns the number of compressed bytes skipped while recovering from
+    /// damaged input.
+    ///
+    /// See `bufread::GzDecoder::lost_bytes` for details.
+    pub fn lost_bytes(&self) -> u64 {
+        self.inner.lost_bytes()
+    }
+
     /// Acquires a reference to the underlying reader.
     pub fn get_ref(&self) -> &R {
         self.inner.get_ref().get_ref()
@@ -633,6 +845,21 @@ impl<R> MultiDecoderReader<R> {
         self.inner.header()
     }
 
+    /// Configures whether this decoder recovers from damaged input.
+    ///
+    /// See `bufread::MultiGzDecoder::set_recover` for details.
+    pub fn set_recover(&mut self, recover: bool) {
+        self.inner.set_recover(recover)
+    }
+
+    /// Returns the number of compressed bytes skipped while recovering from
+    /// damaged input.
+    ///
+    /// See `bufread::MultiGzDecoder::lost_bytes` for details.
+    pub fn lost_bytes(&self) -> u64 {
+        self.inner.lost_bytes()
+    }
+
     /// Acquires a reference to the underlying reader.
     pub fn get_ref(&self) -> &R {
         self.inner.get_ref().get_ref()
@@ -684,6 +911,7 @@ impl<R: BufRead> DecoderReaderBuf<R> {
             inner: CrcReader::new(flate),
             header: header,
             finished: false,
+            recover: false,
         });
     }
 
@@ -697,12 +925,21 @@ impl<R: BufRead> DecoderReaderBuf<R> {
 
             while len < buf.len() {
                 match try!(self.inner.get_mut().get_mut().read(&mut buf[len..])) {
+                    0 if self.recover => {
+                        self.finished = true;
+                        return Ok(())
+                    }
                     0 => return Err(corrupt()),
                     n => len += n,
                 }
             }
         }
 
+        // Once damaged data has been skipped the checksum can't match.
+        if self.recover && deflate::damaged(self.inner.get_ref()) {
+            self.finished = true;
+            return Ok(())
+        }
         let crc = ((buf[0] as u32) << 0) | ((buf[1] as u32) << 8) |
                   ((buf[2] as u32) << 16) |
                   ((buf[3] as u32) << 24);
@@ -712,7 +949,7 @@ impl<R: BufRead> DecoderReaderBuf<R> {
         if crc != self.inner.crc().sum() as u32 {
             return Err(corrupt());
         }
-        if amt != self.inner.crc().amount() {
+        if amt != self.inner.crc().amount() as u32 {
             return Err(corrupt());
         }
         self.finished = true;
@@ -726,6 +963,27 @@ impl<R> DecoderReaderBuf<R> {
         &self.header
     }
 
+    /// Configures whether this decoder recovers from damaged input.
+    ///
+    /// By default damaged input causes reads to fail with an error of kind
+    /// `InvalidData`. When recovering, damaged deflate data is skipped up to
+    /// the next full flush point as described in
+    /// `bufread::DeflateDecoder::set_recover`, and a missing trailer is
+    /// tolerated. The trailer's checksum is ignored once data has been skipped,
+    /// since it can't match what was decompressed. The header must still be
+    /// intact.
+    pub fn set_recover(&mut self, recover: bool) {
+        self.recover = recover;
+        self.inner.get_mut().set_recover(recover);
+    }
+
+    /// Returns the number of compressed bytes skipped while recovering from
+    /// damaged input, counted as described in
+    /// `bufread::DeflateDecoder::lost_bytes`.
+    pub fn lost_bytes(&self) -> u64 {
+        self.inner.get_ref().lost_bytes()
+    }
+
     /// Acquires a reference to the underlying reader.
     pub fn get_ref(&self) -> &R {
         self.inner.get_ref().get_ref()
@@ -757,6 +1015,12 @@ impl<R: BufRead> Read for DecoderReaderBuf<R> {
     }
 }
 
+// Makes `r` return from every read at the next deflate block boundary, and
+// gives access to its decompressor, for `zran` to index the member with.
+pub fn stop_at_blocks<R>(r: &mut DecoderReaderBuf<R>) -> &mut Decompress {
+    deflate::stop_at_blocks(r.inner.get_mut())
+}
+
 impl<R: BufRead + Write> Write for DecoderReaderBuf<R> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.get_mut().write(buf)
@@ -784,6 +1048,8 @@ impl<R: BufRead> MultiDecoderReaderBuf<R> {
             inner: CrcReader::new(flate),
             header: header,
             finished: false,
+            recover: false,
+            lost: 0,
         });
     }
 
@@ -797,6 +1063,10 @@ impl<R: BufRead> MultiDecoderReaderBuf<R> {
 
             while len < buf.len() {
                 match try!(self.inner.get_mut().get_mut().read(&mut buf[len..])) {
+                    0 if self.recover => {
+                        self.finished = true;
+                        return Ok(0)
+                    }
                     0 => return Err(corrupt()),
                     n => len += n,
                 }
@@ -809,10 +1079,12 @@ impl<R: BufRead> MultiDecoderReaderBuf<R> {
         let amt = ((buf[4] as u32) << 0) | ((buf[5] as u32) << 8) |
                   ((buf[6] as u32) << 16) |
                   ((buf[7] as u32) << 24);
-        if crc != self.inner.crc().sum() as u32 {
+        // Once damaged data has been skipped the checksum can't match.
+        let damaged = self.recover && deflate::damaged(self.inner.get_ref());
+        if !damaged && crc != self.inner.crc().sum() as u32 {
             return Err(corrupt());
         }
-        if amt != self.inner.crc().amount() {
+        if !damaged && amt != self.inner.crc().amount() as u32 {
             return Err(corrupt());
         }
         let remaining = match self.inner.get_mut().get_mut().fill_buf() {
@@ -828,9 +1100,10 @@ impl<R: BufRead> MultiDecoderReaderBuf<R> {
         };
 
         let next_header = try!(read_gz_header(self.inner.get_mut().get_mut()));
-        mem::replace(&mut self.header, next_header);
+        self.header = next_header;
+        self.lost += self.inner.get_ref().lost_bytes();
         self.inner.reset();
-        self.inner.get_mut().reset_data();
+        try!(self.inner.get_mut().try_reset_data());
 
         Ok(remaining)
     }
@@ -842,6 +1115,25 @@ impl<R> MultiDecoderReaderBuf<R> {
         &self.header
     }
 
+    /// Configures whether this decoder recovers from damaged input.
+    ///
+    /// This works as described in `bufread::GzDecoder::set_recover`, for
+    /// each member in turn: damaged deflate data is skipped up to the next
+    /// full flush point within the member, a member's checksum is ignored
+    /// once data has been skipped from it, and a missing trailer ends the
+    /// stream. The header of every member must still be intact.
+    pub fn set_recover(&mut self, recover: bool) {
+        self.recover = recover;
+        self.inner.get_mut().set_recover(recover);
+    }
+
+    /// Returns the number of compressed bytes skipped while recovering from
+    /// damaged input, in all members so far, counted as described in
+    /// `bufread::DeflateDecoder::lost_bytes`.
+    pub fn lost_bytes(&self) -> u64 {
+        self.lost + self.inner.get_ref().lost_bytes()
+    }
+
     /// Acquires a reference to the underlying reader.
     pub fn get_ref(&self) -> &R {
         self.inner.get_ref().get_ref()
@@ -886,6 +1178,206 @@ impl<R: BufRead + Write> Write for MultiDecoderReaderBuf<R> {
     }
 }
 
+impl<W: Write> DecoderWriter<W> {
+    /// Creates a new decoder which will write uncompressed data to the stream.
+    ///
+    /// When this decoder is dropped or unwrapped the final pieces of data will
+    /// be flushed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the decompression stream can't be set up.
+    pub fn new(w: W) -> io::Result<DecoderWriter<W>> {
+        let data = try!(Decompress::try_new(false));
+        Ok(DecoderWriter {
+            inner: zio::Writer::new(CrcWriter::new(w), data),
+            state: DecoderState::Header,
+            header: None,
+            parser: HeaderParser::new(),
+            buf: Vec::new(),
+            multi: false,
+        })
+    }
+
+    /// Returns the header associated with this stream, once it has been
+    /// written in full.
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.inner.get_ref().get_ref()
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Note that mutating the output/input state of the stream may corrupt this
+    /// object, so care must be taken when using this method.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.get_mut().get_mut()
+    }
+
+    /// Attempt to finish this output stream, writing out final chunks of data.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `UnexpectedEof` if the whole gzip member,
+    /// including its trailer, hasn't been written, and otherwise any I/O
+    /// errors from writing out the data.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        if self.state != DecoderState::Done {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "gzip stream ended before its trailer"))
+        }
+        self.inner.finish()
+    }
+
+    /// Consumes this decoder, flushing the output stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `try_finish`.
+    pub fn finish(mut self) -> io::Result<W> {
+        try!(self.try_finish());
+        Ok(self.inner.take_inner().into_inner())
+    }
+
+    // Consumes header bytes from `input`, returning how many were used.
+    fn write_header(&mut self, input: &[u8]) -> io::Result<usize> {
+        let n = match self.parser.parse(input) {
+            Ok(n) => n,
+            Err(msg) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+            }
+        };
+        if self.parser.is_done() {
+            let header = try!(read_gz_header(&mut self.parser.header()));
+            self.header = Some(header);
+            self.parser.reset();
+            self.state = DecoderState::Body;
+        }
+        Ok(n)
+    }
+
+    // Consumes trailer bytes from `input`, returning how many were used.
+    fn write_trailer(&mut self, input: &[u8]) -> io::Result<usize> {
+        let n = cmp::min(8 - self.buf.len(), input.len());
+        self.buf.extend_from_slice(&input[..n]);
+        if self.buf.len() == 8 {
+            let crc = self.inner.get_ref().crc();
+            if read_le_u32(&self.buf[..4]) != crc.sum() ||
+               read_le_u32(&self.buf[4..]) != crc.amount() as u32 {
+                return Err(corrupt())
+            }
+            self.buf.truncate(0);
+            self.state = DecoderState::Done;
+        }
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for DecoderWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut consumed = 0;
+        loop {
+            let input = &buf[consumed..];
+            match self.state {
+                DecoderState::Header if !input.is_empty() => {
+                    consumed += try!(self.write_header(input));
+                }
+                DecoderState::Body => {
+                    let (n, status) = try!(self.inner.write_with_status(input));
+                    consumed += n;
+                    if status != Status::StreamEnd {
+                        return Ok(consumed)
+                    }
+                    // Write out everything that was decompressed so the CRC
+                    // covers it before the trailer is checked.
+                    try!(self.inner.finish());
+                    self.state = DecoderState::Trailer;
+                }
+                DecoderState::Trailer if !input.is_empty() => {
+                    consumed += try!(self.write_trailer(input));
+                }
+                DecoderState::Done if self.multi && !input.is_empty() => {
+                    try!(self.inner.data.try_reset(false));
+                    self.inner.get_mut().reset();
+                    self.state = DecoderState::Header;
+                }
+                _ => return Ok(consumed),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> MultiDecoderWriter<W> {
+    /// Creates a new decoder which will write the uncompressed data of every
+    /// member to the stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the decompression stream can't be set up.
+    pub fn new(w: W) -> io::Result<MultiDecoderWriter<W>> {
+        let mut inner = try!(DecoderWriter::new(w));
+        inner.multi = true;
+        Ok(MultiDecoderWriter { inner: inner })
+    }
+
+    /// Returns the header of the member currently being decoded, once it has
+    /// been written in full.
+    pub fn header(&self) -> Option<&Header> {
+        self.inner.header()
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.inner.get_ref()
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Note that mutating the output/input state of the stream may corrupt this
+    /// object, so care must be taken when using this method.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.get_mut()
+    }
+
+    /// Attempt to finish this output stream, writing out final chunks of data.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `UnexpectedEof` if no member has been written
+    /// or the last one is incomplete, and otherwise any I/O errors from
+    /// writing out the data.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        self.inner.try_finish()
+    }
+
+    /// Consumes this decoder, flushing the output stream.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as `try_finish`.
+    pub fn finish(self) -> io::Result<W> {
+        self.inner.finish()
+    }
+}
+
+impl<W: Write> Write for MultiDecoderWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 impl Header {
     /// Returns the `filename` field of this gzip stream's header, if present.
     pub fn filename(&self) -> Option<&[u8]> {
@@ -933,8 +1425,248 @@ impl Header {
     }
 }
 
+/// Returns the header written by a default `Builder`, which is also used by
+/// `Compress` for `Format::Gzip`.
+pub fn default_header(lvl: Compression) -> Vec<u8> {
+    Builder::new().into_header(lvl)
+}
+
+/// Returns the header written by `builder`, for encoders outside this module.
+pub fn builder_header(builder: Builder, lvl: Compression) -> Vec<u8> {
+    builder.into_header(lvl)
+}
+
+/// Collects a gzip header as it arrives, checking each field once it's
+/// complete.
+///
+/// Only the bytes belonging to the header are taken, and each is looked at
+/// once, however finely the input is split up.
+#[derive(Clone, Debug)]
+pub struct HeaderParser {
+    buf: Vec<u8>,
+    field: HeaderField,
+    // The length `buf` will have at the end of a fixed size field.
+    need: usize,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum HeaderField {
+    Fixed,
+    ExtraLen,
+    Extra,
+    Name,
+    Comment,
+    Crc,
+    Done,
+}
+
+impl HeaderParser {
+    /// Creates a parser expecting the start of a header.
+    pub fn new() -> HeaderParser {
+        HeaderParser {
+            buf: Vec::new(),
+            field: HeaderField::Fixed,
+            need: 10,
+        }
+    }
+
+    /// Forgets the header parsed so far, to start on a new one.
+    pub fn reset(&mut self) {
+        self.buf.truncate(0);
+        self.field = HeaderField::Fixed;
+        self.need = 10;
+    }
+
+    /// Returns whether the whole header has been parsed.
+    pub fn is_done(&self) -> bool {
+        self.field == HeaderField::Done
+    }
+
+    /// Returns the header bytes collected so far.
+    pub fn header(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Consumes header bytes from `input`, returning how many were used.
+    ///
+    /// Returns a message describing the problem if the header is invalid.
+    pub fn parse(&mut self, input: &[u8]) -> Result<usize, &'static str> {
+        let mut consumed = 0;
+        while self.field != HeaderField::Done {
+            let rest = &input[consumed..];
+            let (n, complete) = match self.field {
+                HeaderField::Name | HeaderField::Comment => {
+                    match rest.iter().position(|b| *b == 0) {
+                        Some(i) => (i + 1, true),
+                        None => (rest.len(), false),
+                    }
+                }
+                _ => {
+                    let n = cmp::min(self.need - self.buf.len(), rest.len());
+                    (n, self.buf.len() + n == self.need)
+                }
+            };
+            self.buf.extend_from_slice(&rest[..n]);
+            consumed += n;
+            if self.field == HeaderField::Fixed {
+                // Validate what's there already so garbage is rejected early.
+                let b = &self.buf;
+                if b.len() > 0 && b[0] != 0x1f || b.len() > 1 && b[1] != 0x8b {
+                    return Err("incorrect header check")
+                }
+            }
+            if !complete {
+                break
+            }
+            try!(self.next_field());
+        }
+        Ok(consumed)
+    }
+
+    // Checks the field just completed and moves on to the next one present.
+    fn next_field(&mut self) -> Result<(), &'static str> {
+        let len = self.buf.len();
+        let flg = self.buf[3];
+        match self.field {
+            HeaderField::Fixed => {
+                if self.buf[2] != 8 {
+                    return Err("unknown compression method")
+                }
+                if flg & 0xe0 != 0 {
+                    return Err("unknown header flags set")
+                }
+            }
+            HeaderField::ExtraLen => {
+                let xlen = (self.buf[len - 2] as usize) |
+                           (self.buf[len - 1] as usize) << 8;
+                self.field = HeaderField::Extra;
+                self.need = len + xlen;
+                return Ok(())
+            }
+            HeaderField::Crc => {
+                let mut crc = Crc::new();
+                crc.update(&self.buf[..len - 2]);
+                let expected = (self.buf[len - 2] as u16) |
+                               (self.buf[len - 1] as u16) << 8;
+                if crc.sum() as u16 != expected {
+                    return Err("header crc mismatch")
+                }
+                self.field = HeaderField::Done;
+                return Ok(())
+            }
+            _ => {}
+        }
+
+        let field = self.field;
+        self.field = if field < HeaderField::ExtraLen && flg & FEXTRA != 0 {
+            HeaderField::ExtraLen
+        } else if field < HeaderField::Name && flg & FNAME != 0 {
+            HeaderField::Name
+        } else if field < HeaderField::Comment && flg & FCOMMENT != 0 {
+            HeaderField::Comment
+        } else if flg & FHCRC != 0 {
+            HeaderField::Crc
+        } else {
+            HeaderField::Done
+        };
+        self.need = len + 2;
+        Ok(())
+    }
+}
+
+// Builds the empty member carrying an index of flush points, thinning them out
+// if there are too many.
+fn index_member(length: u64, points: &[(u64, u64)]) -> Vec<u8> {
+    let mut step = 1;
+    while points.len() / step > MAX_INDEX_POINTS {
+        step *= 2;
+    }
+    let points = points.iter()
+        .enumerate()
+        .filter(|&(i, _)| (i + 1) % step == 0)
+        .map(|(_, point)| *point)
+        .collect::<Vec<_>>();
+    let count = points.len();
+
+    let mut extra = INDEX_ID.to_vec();
+    let len = 8 + 16 * count + 8;
+    extra.extend_from_slice(&[len as u8, (len >> 8) as u8]);
+    extra.extend_from_slice(&le_u64(length));
+    for &(uncompressed, compressed) in points.iter() {
+        extra.extend_from_slice(&le_u64(uncompressed));
+        extra.extend_from_slice(&le_u64(compressed));
+    }
+    extra.extend_from_slice(&le_u64(count as u64)[..4]);
+    extra.extend_from_slice(INDEX_MAGIC);
+
+    let mut member = Builder::new().extra(extra)
+        .into_header(Compression::none());
+    member.extend_from_slice(&EMPTY_MEMBER_TAIL);
+    member
+}
+
+/// Reads the index of flush points appended by an `EncoderWriter` from the
+/// end of `r`, returning the length of the uncompressed data and the points.
+pub fn read_index_member<R: Read + Seek>(r: &mut R)
+                                         -> io::Result<(u64, Vec<(u64, u64)>)> {
+    let end = try!(r.seek(SeekFrom::End(0)));
+    if end < 18 {
+        return Err(no_index())
+    }
+    try!(r.seek(SeekFrom::Start(end - 18)));
+    let mut tail = [0; 18];
+    try!(r.read_exact(&mut tail));
+    if &tail[4..8] != INDEX_MAGIC || &tail[8..] != &EMPTY_MEMBER_TAIL[..] {
+        return Err(no_index())
+    }
+    let count = read_le_u32(&tail[..4]) as u64;
+    let xlen = 4 + 8 + 16 * count + 8;
+    if xlen > 0xffff || 12 + xlen + 10 > end {
+        return Err(no_index())
+    }
+    try!(r.seek(SeekFrom::Start(end - 10 - xlen - 12)));
+    let mut member = vec![0; 12 + xlen as usize];
+    try!(r.read_exact(&mut member));
+
+    let header = try!(read_gz_header(&mut &member[..]));
+    let extra = match header.extra() {
+        Some(extra) if extra == &member[12..] => extra,
+        _ => return Err(no_index()),
+    };
+    if &extra[..2] != INDEX_ID {
+        return Err(no_index())
+    }
+    let length = read_le_u64(&extra[4..12]);
+    let points = extra[12..extra.len() - 8].chunks(16).map(|point| {
+        (read_le_u64(&point[..8]), read_le_u64(&point[8..]))
+    }).collect();
+    Ok((length, points))
+}
+
+fn le_u64(n: u64) -> [u8; 8] {
+    let mut b = [0; 8];
+    for (i, b) in b.iter_mut().enumerate() {
+        *b = (n >> (8 * i)) as u8;
+    }
+    b
+}
+
+fn read_le_u32(b: &[u8]) -> u32 {
+    (b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 |
+        (b[3] as u32) << 24
+}
+
+fn read_le_u64(b: &[u8]) -> u64 {
+    b.iter().rev().fold(0, |n, &b| n << 8 | b as u64)
+}
+
+fn no_index() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   "gzip file does not end with an index of flush points")
+}
+
 fn corrupt() -> io::Error {
-    io::Error::new(io::ErrorKind::InvalidInput,
+    io::Error::new(io::ErrorKind::InvalidData,
                    "corrupt gzip stream does not have a matching checksum")
 }
 
@@ -948,7 +1680,8 @@ fn read_le_u16<R: Read>(r: &mut R) -> io::Result<u16> {
     Ok((b[0] as u16) | ((b[1] as u16) << 8))
 }
 
-fn read_gz_header<R: Read>(r: &mut R) -> io::Result<Header> {
+/// Reads a gzip member's header from `r`, leaving it at the compressed data.
+pub fn read_gz_header<R: Read>(r: &mut R) -> io::Result<Header> {
     let mut crc_reader = CrcReader::new(r);
     let mut header = [0; 10];
     try!(crc_reader.read_exact(&mut header));
@@ -1027,13 +1760,21 @@ fn read_gz_header<R: Read>(r: &mut R) -> io::Result<Header> {
 mod tests {
     use std::io::prelude::*;
 
+    use std::io::{self, Cursor, SeekFrom};
+
     use super::{EncoderWriter, EncoderReader, DecoderReader, Builder};
-    use Compression::Default;
+    use super::{MultiDecoderReader, MAX_INDEX_POINTS};
+    use super::{DecoderWriter, MultiDecoderWriter};
+    use super::{index_member, read_index_member, FHCRC, FNAME};
+    use crc::Crc;
+    use {Compress, CompressBuilder, Compression, Flush, Format, Strategy};
+    use {backend, zio};
     use rand::{thread_rng, Rng};
+    use zran::{Index, SeekableGzReader};
 
     #[test]
     fn roundtrip() {
-        let mut e = EncoderWriter::new(Vec::new(), Default);
+        let mut e = EncoderWriter::new(Vec::new(), Compression::default());
         e.write_all(b"foo bar baz").unwrap();
         let inner = e.finish().unwrap();
         let mut d = DecoderReader::new(&inner[..]).unwrap();
@@ -1044,7 +1785,7 @@ mod tests {
 
     #[test]
     fn roundtrip_zero() {
-        let e = EncoderWriter::new(Vec::new(), Default);
+        let e = EncoderWriter::new(Vec::new(), Compression::default());
         let inner = e.finish().unwrap();
         let mut d = DecoderReader::new(&inner[..]).unwrap();
         let mut s = String::new();
@@ -1055,7 +1796,7 @@ mod tests {
     #[test]
     fn roundtrip_big() {
         let mut real = Vec::new();
-        let mut w = EncoderWriter::new(Vec::new(), Default);
+        let mut w = EncoderWriter::new(Vec::new(), Compression::default());
         let v = thread_rng().gen_iter::<u8>().take(1024).collect::<Vec<_>>();
         for _ in 0..200 {
             let to_write = &v[..thread_rng().gen_range(0, v.len())];
@@ -1075,8 +1816,8 @@ mod tests {
                     .gen_iter::<u8>()
                     .take(1024 * 1024)
                     .collect::<Vec<_>>();
-        let mut r = DecoderReader::new(EncoderReader::new(&v[..], Default))
-                        .unwrap();
+        let r = EncoderReader::new(&v[..], Compression::default());
+        let mut r = DecoderReader::new(r).unwrap();
         let mut res = Vec::new();
         r.read_to_end(&mut res).unwrap();
         assert!(res == v);
@@ -1089,7 +1830,7 @@ mod tests {
                     .filename("foo.rs")
                     .comment("bar")
                     .extra(vec![0, 1, 2, 3])
-                    .read(&r[..], Default);
+                    .read(&r[..], Compression::default());
         let mut d = DecoderReader::new(e).unwrap();
         assert_eq!(d.header().filename(), Some(&b"foo.rs"[..]));
         assert_eq!(d.header().comment(), Some(&b"bar"[..]));
@@ -1100,9 +1841,38 @@ mod tests {
 
     }
 
+    #[test]
+    fn xfl() {
+        let cases = [(0, 4), (1, 4), (2, 0), (6, 0), (8, 0), (9, 2)];
+        for &(level, xfl) in cases.iter() {
+            let e = EncoderWriter::new(Vec::new(), Compression::new(level));
+            let inner = e.finish().unwrap();
+            assert_eq!(inner[8], xfl);
+        }
+    }
+
+    #[test]
+    fn set_params() {
+        let text = "foo bar baz ".repeat(1000);
+        let mut e = EncoderWriter::new(Vec::new(), Compression::none());
+        e.write_all(text.as_bytes()).unwrap();
+        let stored = e.get_ref().len();
+        e.set_params(Compression::best(), Strategy::Default).unwrap();
+        assert!(e.get_ref().len() > stored);
+        let stored = e.get_ref().len();
+        e.write_all(text.as_bytes()).unwrap();
+        let inner = e.finish().unwrap();
+        assert!(inner.len() - stored < text.len() / 10);
+
+        let mut d = DecoderReader::new(&inner[..]).unwrap();
+        let mut s = String::new();
+        d.read_to_string(&mut s).unwrap();
+        assert_eq!(s, text.repeat(2));
+    }
+
     #[test]
     fn keep_reading_after_end() {
-        let mut e = EncoderWriter::new(Vec::new(), Default);
+        let mut e = EncoderWriter::new(Vec::new(), Compression::default());
         e.write_all(b"foo bar baz").unwrap();
         let inner = e.finish().unwrap();
         let mut d = DecoderReader::new(&inner[..]).unwrap();
@@ -1118,7 +1888,7 @@ mod tests {
         ::quickcheck::quickcheck(test as fn(_) -> _);
 
         fn test(v: Vec<u8>) -> bool {
-            let r = EncoderReader::new(&v[..], Default);
+            let r = EncoderReader::new(&v[..], Compression::default());
             let mut r = DecoderReader::new(r).unwrap();
             let mut v2 = Vec::new();
             r.read_to_end(&mut v2).unwrap();
@@ -1128,8 +1898,320 @@ mod tests {
 
     #[test]
     fn flush_after_write() {
-		let mut f = EncoderWriter::new(Vec::new(), Default);
+		let mut f = EncoderWriter::new(Vec::new(), Compression::default());
 		write!(f, "Hello world").unwrap();
 		f.flush().unwrap();
     }
+
+    #[test]
+    fn recover() {
+        let mut c = Compress::new_with_format(Compression::default(),
+                                              Format::Gzip);
+        let mut data = Vec::with_capacity(1024);
+        c.compress_vec(b"one one one ", &mut data, Flush::Full).unwrap();
+        let flush_point = data.len();
+        c.compress_vec(b"two two two ", &mut data, Flush::Full).unwrap();
+        c.compress_vec(b"three three", &mut data, Flush::Finish).unwrap();
+
+        let mut d = DecoderReader::new(&data[..]).unwrap();
+        d.set_recover(true);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"one one one two two two three three");
+        assert_eq!(d.lost_bytes(), 0);
+
+        // an invalid block type
+        data[flush_point] = 0x06;
+        let mut d = DecoderReader::new(&data[..]).unwrap();
+        d.set_recover(true);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"one one one three three");
+        assert!(d.lost_bytes() > 0);
+
+        // a missing trailer is only tolerated when recovering
+        let n = data.len() - 8;
+        data[flush_point] = 0x00;
+        let mut d = DecoderReader::new(&data[..n]).unwrap();
+        assert!(d.read_to_end(&mut Vec::new()).is_err());
+        let mut d = DecoderReader::new(&data[..n]).unwrap();
+        d.set_recover(true);
+        d.read_to_end(&mut Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn multi_recover() {
+        let mut data = Vec::with_capacity(1024);
+        let mut points = Vec::new();
+        let mut ends = Vec::new();
+        for &member in [&b"one "[..], b"two "].iter() {
+            let mut c = Compress::new_with_format(Compression::default(),
+                                                  Format::Gzip);
+            c.compress_vec(member, &mut data, Flush::Full).unwrap();
+            points.push(data.len());
+            c.compress_vec(b"lost ", &mut data, Flush::Full).unwrap();
+            c.compress_vec(member, &mut data, Flush::Finish).unwrap();
+            ends.push(data.len());
+        }
+
+        // an invalid block type in each member
+        for &point in points.iter() {
+            data[point] = 0x06;
+        }
+        let mut d = MultiDecoderReader::new(&data[..]).unwrap();
+        assert!(d.read_to_end(&mut Vec::new()).is_err());
+        let mut d = MultiDecoderReader::new(&data[..]).unwrap();
+        d.set_recover(true);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"one one two two ");
+
+        // the losses of both members are counted
+        let lost = d.lost_bytes();
+        let mut d = MultiDecoderReader::new(&data[..ends[0]]).unwrap();
+        d.set_recover(true);
+        d.read_to_end(&mut Vec::new()).unwrap();
+        assert!(d.lost_bytes() > 0 && d.lost_bytes() < lost);
+
+        // and a missing trailer ends the stream
+        let mut d = MultiDecoderReader::new(&data[..data.len() - 8]).unwrap();
+        d.set_recover(true);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"one one two two ");
+    }
+
+    #[test]
+    fn decode_writer() {
+        let v = thread_rng().gen_iter::<u8>().take(100_000)
+            .collect::<Vec<_>>();
+        let mut e = Builder::new().filename("foo.rs")
+            .write(Vec::new(), Compression::default());
+        e.write_all(&v).unwrap();
+        let gz = e.finish().unwrap();
+
+        // Feed it a byte at a time to cover every split of the header and
+        // trailer.
+        let mut d = DecoderWriter::new(Vec::new()).unwrap();
+        for (i, byte) in gz.iter().enumerate() {
+            if i == 10 {
+                assert!(d.header().is_none());
+            }
+            d.write_all(&[*byte]).unwrap();
+        }
+        assert_eq!(d.header().unwrap().filename(), Some(&b"foo.rs"[..]));
+        assert_eq!(d.finish().unwrap(), v);
+
+        let mut d = DecoderWriter::new(Vec::new()).unwrap();
+        d.write_all(&gz[..gz.len() - 1]).unwrap();
+        assert!(d.try_finish().is_err());
+
+        let mut corrupt = gz.clone();
+        let n = corrupt.len();
+        corrupt[n - 5] ^= 1;
+        let mut d = DecoderWriter::new(Vec::new()).unwrap();
+        assert!(d.write_all(&corrupt).is_err());
+
+        // Only the first member is consumed.
+        let mut twice = gz.clone();
+        twice.extend_from_slice(&gz);
+        let mut d = DecoderWriter::new(Vec::new()).unwrap();
+        let mut rest = &twice[..];
+        loop {
+            match d.write(rest).unwrap() {
+                0 => break,
+                n => rest = &rest[n..],
+            }
+        }
+        assert_eq!(rest.len(), gz.len());
+        assert_eq!(d.finish().unwrap(), v);
+    }
+
+    #[test]
+    fn multi_decode_writer() {
+        let mut gz = Vec::new();
+        let mut expected = Vec::new();
+        for name in ["a", "b", "c"].iter() {
+            let mut e = Builder::new().filename(*name)
+                .write(Vec::new(), Compression::default());
+            e.write_all(name.as_bytes()).unwrap();
+            gz.extend(e.finish().unwrap());
+            expected.extend_from_slice(name.as_bytes());
+        }
+
+        let mut d = MultiDecoderWriter::new(Vec::new()).unwrap();
+        for chunk in gz.chunks(7) {
+            d.write_all(chunk).unwrap();
+        }
+        assert_eq!(d.header().unwrap().filename(), Some(&b"c"[..]));
+        assert_eq!(d.finish().unwrap(), expected);
+
+        assert!(MultiDecoderWriter::new(Vec::new()).unwrap().finish().is_err());
+    }
+
+    #[test]
+    fn decode_writer_long_header() {
+        let name = vec![b'n'; 200_000];
+        let mut e = Builder::new()
+            .extra(vec![b'x'; 60_000])
+            .filename(name.clone())
+            .comment("a comment")
+            .write(Vec::new(), Compression::default());
+        e.write_all(b"body").unwrap();
+        let gz = e.finish().unwrap();
+
+        // Every byte of the header is parsed only once, however it's split.
+        let mut d = DecoderWriter::new(Vec::new()).unwrap();
+        for byte in gz.iter() {
+            d.write_all(&[*byte]).unwrap();
+        }
+        assert_eq!(d.header().unwrap().filename(), Some(&name[..]));
+        assert_eq!(d.header().unwrap().comment(), Some(&b"a comment"[..]));
+        assert_eq!(d.finish().unwrap(), b"body");
+
+        // A header CRC is checked once the header is complete.
+        let mut header = vec![0x1f, 0x8b, 8, FHCRC | FNAME, 0, 0, 0, 0, 0, 255];
+        header.extend_from_slice(b"name\0");
+        let crc = {
+            let mut crc = Crc::new();
+            crc.update(&header);
+            crc.sum()
+        };
+        header.push(crc as u8);
+        header.push((crc >> 8) as u8);
+        let mut e = EncoderWriter::new(Vec::new(), Compression::default());
+        e.write_all(b"body").unwrap();
+        header.extend_from_slice(&e.finish().unwrap()[10..]);
+        let mut d = DecoderWriter::new(Vec::new()).unwrap();
+        for chunk in header.chunks(3) {
+            d.write_all(chunk).unwrap();
+        }
+        assert_eq!(d.header().unwrap().filename(), Some(&b"name"[..]));
+        assert_eq!(d.finish().unwrap(), b"body");
+
+        header[15] ^= 1;
+        let mut d = DecoderWriter::new(Vec::new()).unwrap();
+        let err = d.write_all(&header).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decode_writer_bad_header() {
+        let bad = [0x1f, 0x8b, 7, 0, 0, 0, 0, 0, 0, 0];
+        let mut d = DecoderWriter::new(Vec::new()).unwrap();
+        let err = d.write_all(&bad).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut d = MultiDecoderWriter::new(Vec::new()).unwrap();
+        let err = d.write_all(b"not gzip").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn flush_points() {
+        let mut rng = thread_rng();
+        let mut data = Vec::new();
+        for i in 0..30_000 {
+            let line = format!("{} {}\n", i, rng.gen::<u32>());
+            data.extend_from_slice(line.as_bytes());
+        }
+
+        let mut e = EncoderWriter::new(Vec::new(), Compression::default());
+        e.set_flush_interval(64 * 1024);
+        e.set_embed_index(true);
+        for chunk in data.chunks(10007) {
+            e.write_all(chunk).unwrap();
+        }
+        e.try_finish().unwrap();
+        assert_eq!(e.flush_points().len(), data.len() / (64 * 1024));
+        let index = e.index();
+        let file = e.finish().unwrap();
+
+        let mut out = Vec::new();
+        DecoderReader::new(&file[..]).unwrap().read_to_end(&mut out).unwrap();
+        assert!(out == data);
+        out.clear();
+        MultiDecoderReader::new(&file[..]).unwrap().read_to_end(&mut out)
+            .unwrap();
+        assert!(out == data);
+
+        let mut cursor = Cursor::new(&file[..]);
+        assert_eq!(Index::read_embedded(&mut cursor).unwrap(), index);
+        let mut r = SeekableGzReader::with_embedded_index(cursor).unwrap();
+        for &pos in [300_000, 5, 64 * 1024, 200_000, data.len() - 9].iter() {
+            r.seek(SeekFrom::Start(pos as u64)).unwrap();
+            let mut buf = [0; 9];
+            r.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &data[pos..pos + 9]);
+        }
+
+        let plain = EncoderWriter::new(Vec::new(), Compression::default())
+            .finish()
+            .unwrap();
+        assert!(Index::read_embedded(&mut Cursor::new(plain)).is_err());
+    }
+
+    #[test]
+    fn flush_points_incompressible() {
+        let data = thread_rng().gen_iter::<u8>().take(1 << 20)
+            .collect::<Vec<_>>();
+        for &b in backend::tests::backends().iter() {
+            for &level in [0, 1, 6].iter() {
+                let level = Compression::new(level);
+                let mut e = EncoderWriter::new(Vec::new(), level);
+                let c = CompressBuilder::new(level).backend(b).build(false)
+                    .unwrap();
+                e.inner = zio::Writer::new(Vec::new(), c);
+                e.set_flush_interval(64 * 1024);
+                for chunk in data.chunks(200_000) {
+                    e.write_all(chunk).unwrap();
+                }
+                e.try_finish().unwrap();
+                let points = e.flush_points().to_vec();
+                let file = e.finish().unwrap();
+
+                assert_eq!(points.len(), data.len() / (64 * 1024));
+                for &(_, pos) in points.iter() {
+                    let pos = pos as usize;
+                    assert_eq!(&file[pos - 4..pos], &[0, 0, 0xff, 0xff][..],
+                               "{:?} at level {:?}", b, level);
+                }
+                let mut out = Vec::new();
+                DecoderReader::new(&file[..]).unwrap().read_to_end(&mut out)
+                    .unwrap();
+                assert!(out == data);
+            }
+        }
+    }
+
+    #[test]
+    fn late_flush_interval() {
+        let data = thread_rng().gen_iter::<u8>().take(100_000)
+            .collect::<Vec<_>>();
+        let mut e = EncoderWriter::new(Vec::new(), Compression::default());
+        e.write_all(&data[..50_000]).unwrap();
+        e.set_flush_interval(10_000);
+        e.write_all(&data[50_000..]).unwrap();
+        e.try_finish().unwrap();
+        let starts = e.flush_points().iter().map(|p| p.0).collect::<Vec<_>>();
+        assert_eq!(starts, [50_000, 60_000, 70_000, 80_000, 90_000, 100_000]);
+
+        let file = e.finish().unwrap();
+        let mut out = Vec::new();
+        DecoderReader::new(&file[..]).unwrap().read_to_end(&mut out).unwrap();
+        assert!(out == data);
+    }
+
+    #[test]
+    fn thinned_index() {
+        let points = (1..10_000u64).map(|i| (i * 100, i * 40))
+            .collect::<Vec<_>>();
+        let member = index_member(1_000_000, &points);
+        let (length, read) = read_index_member(&mut Cursor::new(member))
+            .unwrap();
+        assert_eq!(length, 1_000_000);
+        assert!(read.len() <= MAX_INDEX_POINTS);
+        assert!(read.len() > MAX_INDEX_POINTS / 2);
+        assert!(read.iter().all(|p| points.contains(p)));
+    }
 }
@@ -9,6 +9,15 @@
 //! [1]: https://code.google.com/p/miniz/
 //! [`libflate`]: https://docs.rs/crate/libflate/
 //!
+//! The `zlib` feature uses the system zlib instead, and the `rust_backend`
+//! feature uses [`miniz_oxide`], a port of miniz to Rust which doesn't need a
+//! C compiler. Either is usually combined with `default-features = false`,
+//! but backends can also be enabled side by side and chosen per stream
+//! through the [`backend`] module.
+//!
+//! [`miniz_oxide`]: https://docs.rs/miniz_oxide/
+//! [`backend`]: backend/index.html
+//!
 //! # Organization
 //!
 //! This crate consists mainly of two modules, [`read`] and [`write`]. Each
@@ -17,12 +26,16 @@
 //! whereas all types in the [`read`] module work on instances of [`Read`].
 //!
 //! Other various types are provided at the top-level of the crate for
-//! management and dealing with encoders/decoders.
+//! management and dealing with encoders/decoders. For data that is already in
+//! memory, [`compress_to_vec`] and [`decompress_to_vec`] do the whole job in
+//! one call.
 //!
 //! [`read`]: read/index.html
 //! [`write`]: write/index.html
 //! [`Read`]: https://doc.rust-lang.org/std/io/trait.Read.html
 //! [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
+//! [`compress_to_vec`]: fn.compress_to_vec.html
+//! [`decompress_to_vec`]: fn.decompress_to_vec.html
 //!
 //! # Helper traits
 //!
@@ -41,7 +54,7 @@
 //! [Tokio stack]: https://tokio.rs/
 //!
 //! ```toml
-//! flate2 = { version = "0.2", features = ["tokio"] }
+//! flate2 = { version = "0.3", features = ["tokio"] }
 //! ```
 //!
 //! All methods are internally capable of working with streams that may return
@@ -57,7 +70,7 @@
 //! time to perform I/O. If I/O streams are flushed before drop, however, then
 //! these operations will be a noop.
 
-#![doc(html_root_url = "https://docs.rs/flate2/0.2")]
+#![doc(html_root_url = "https://docs.rs/flate2/0.3")]
 #![deny(missing_docs)]
 #![deny(missing_debug_implementations)]
 #![allow(trivial_numeric_casts)]
@@ -79,9 +92,17 @@ use std::io;
 
 pub use gz::Builder as GzBuilder;
 pub use gz::Header as GzHeader;
-pub use mem::{Compress, Decompress, DataError, Status, Flush};
-pub use crc::{Crc, CrcReader};
+pub use parallel::Builder as ParallelGzBuilder;
+pub use mem::{Compress, CompressBuilder, Decompress, DecompressBuilder};
+pub use mem::{CompressError, CompressErrorKind, DataError, DataErrorKind};
+pub use mem::{BlockInfo, ZlibHeader, parse_zlib_header};
+pub use mem::{compress_to_vec, decompress_to_vec};
+pub use backend::MemoryLimit;
+pub use mem::{Status, Flush, Format, DecompressFormat, Strategy};
+pub use crc::{Crc, CrcReader, CrcWriter};
+pub use adler32::{Adler32, Adler32Reader, Adler32Writer};
 
+mod adler32;
 mod bufreader;
 mod crc;
 mod deflate;
@@ -89,8 +110,16 @@ mod ffi;
 mod gz;
 mod zio;
 mod mem;
+#[cfg(feature = "rust_backend")]
+mod oxide;
+mod parallel;
 mod zlib;
 
+pub mod backend;
+pub mod bgzf;
+pub mod dictzip;
+pub mod zran;
+
 /// Types which operate over [`Read`] streams, both encoders and decoders for
 /// various formats.
 ///
@@ -103,6 +132,7 @@ pub mod read {
     pub use gz::EncoderReader as GzEncoder;
     pub use gz::DecoderReader as GzDecoder;
     pub use gz::MultiDecoderReader as MultiGzDecoder;
+    pub use zran::SeekableGzReader;
 }
 
 /// Types which operate over [`Write`] streams, both encoders and decoders for
@@ -115,6 +145,9 @@ pub mod write {
     pub use zlib::EncoderWriter as ZlibEncoder;
     pub use zlib::DecoderWriter as ZlibDecoder;
     pub use gz::EncoderWriter as GzEncoder;
+    pub use gz::DecoderWriter as GzDecoder;
+    pub use gz::MultiDecoderWriter as MultiGzDecoder;
+    pub use parallel::EncoderWriter as ParallelGzEncoder;
 }
 
 /// Types which operate over [`BufRead`] streams, both encoders and decoders for
@@ -146,27 +179,60 @@ fn _assert_send_sync() {
     _assert_send_sync::<write::ZlibEncoder<Vec<u8>>>();
     _assert_send_sync::<write::ZlibDecoder<Vec<u8>>>();
     _assert_send_sync::<write::GzEncoder<Vec<u8>>>();
+    _assert_send_sync::<write::GzDecoder<Vec<u8>>>();
+    _assert_send_sync::<write::MultiGzDecoder<Vec<u8>>>();
+    _assert_send_sync::<write::ParallelGzEncoder<Vec<u8>>>();
+    _assert_send_sync::<read::SeekableGzReader<io::Cursor<Vec<u8>>>>();
 }
 
 /// When compressing data, the compression level can be specified by a value in
-/// this enum.
+/// this struct.
+///
+/// Levels range from 0 to 9, where 0 performs no compression at all and 9
+/// spends the most time searching for the smallest encoding. These are the same
+/// levels accepted by zlib's `deflateInit`.
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
-pub enum Compression {
+pub struct Compression(u32);
+
+impl Compression {
+    /// Creates a new description of the compression level with an explicitly
+    /// specified integer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `level` is greater than 9.
+    pub fn new(level: u32) -> Compression {
+        assert!(level <= 9, "invalid compression level: {}", level);
+        Compression(level)
+    }
+
     /// No compression is to be performed, this may actually inflate data
     /// slightly when encoding.
-    None = 0,
+    pub fn none() -> Compression {
+        Compression(0)
+    }
+
     /// Optimize for the best speed of encoding.
-    Fast = 1,
+    pub fn fast() -> Compression {
+        Compression(1)
+    }
+
     /// Optimize for the size of data being encoded.
-    Best = 9,
-    /// Choose the default compression, a balance between speed and size.
-    Default = 6,
+    pub fn best() -> Compression {
+        Compression(9)
+    }
+
+    /// Returns an integer representing the compression level, typically on a
+    /// scale of 0-9.
+    pub fn level(&self) -> u32 {
+        self.0
+    }
 }
 
-/// Default to Compression::Default.
+/// Default to a balance between speed and size, level 6.
 impl Default for Compression {
     fn default() -> Compression {
-        Compression::Default
+        Compression(6)
     }
 }
 
@@ -214,11 +280,10 @@ pub trait FlateWriteExt: Write + Sized {
         write::GzEncoder::new(self, lvl)
     }
 
-    // TODO: coming soon to a theater near you!
-    // /// Consume this writer to create a decompression stream of this stream.
-    // fn gz_decode(self) -> IoResult<write::GzDecoder<Self>> {
-    //     write::GzDecoder::new(self)
-    // }
+    /// Consume this writer to create a decompression stream of this stream.
+    fn gz_decode(self) -> io::Result<write::GzDecoder<Self>> {
+        write::GzDecoder::new(self)
+    }
 
     /// Consume this writer to create a compression stream at the specified
     /// compression level.
@@ -255,9 +320,9 @@ mod test {
     fn crazy() {
         let rdr = &mut b"foobar";
         let mut res = Vec::new();
-        rdr.gz_encode(Compression::Default)
-           .deflate_encode(Compression::Default)
-           .zlib_encode(Compression::Default)
+        rdr.gz_encode(Compression::default())
+           .deflate_encode(Compression::default())
+           .zlib_encode(Compression::default())
            .zlib_decode()
            .deflate_decode()
            .gz_decode()
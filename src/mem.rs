@@ -1,13 +1,16 @@
+use std::cmp;
 use std::error::Error;
 use std::fmt;
 use std::io;
-use std::marker;
 use std::slice;
 
-use libc::{c_int, c_uint};
-
+use Adler32;
 use Compression;
+use backend::{self, Backend, DeflateStream, InflateStream, MemoryLimit};
+use backend::ReturnCode;
+use crc::Crc;
 use ffi;
+use gz;
 
 /// Raw in-memory compression stream for blocks of data.
 ///
@@ -23,7 +26,10 @@ use ffi;
 /// [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
 #[derive(Debug)]
 pub struct Compress {
-    inner: Stream<DirCompress>,
+    inner: Stream<dyn DeflateStream>,
+    backend: &'static dyn Backend,
+    dictionary: Option<Vec<u8>>,
+    gzip: Option<GzipEncoder>,
 }
 
 /// Raw in-memory decompression stream for blocks of data.
@@ -40,29 +46,192 @@ pub struct Compress {
 /// [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
 #[derive(Debug)]
 pub struct Decompress {
-    inner: Stream<DirDecompress>,
+    inner: Stream<dyn InflateStream>,
+    backend: &'static dyn Backend,
+    // The format this stream was configured with, and the one actually being
+    // decoded, which differ until `Auto` has seen the first byte of input.
+    format: DecompressFormat,
+    detected: DecompressFormat,
+    gzip: GzipDecoder,
+    dictionary: Option<Vec<u8>>,
+    // How much of the full flush marker `sync` has seen so far, or before
+    // that, how much of it the input consumed by `decompress` ends with.
+    sync_matched: usize,
+    // After `sync` a zlib stream is decompressed as raw deflate data, so the
+    // bytes of its Adler-32 trailer that are still to be skipped once that
+    // data ends, and whether it has.
+    trailer_left: usize,
+    data_ended: bool,
+    // The first bytes of a zlib stream, enough to hold its header.
+    zlib_start: Vec<u8>,
+}
+
+/// A builder for a [`Compress`] stream with non-default parameters.
+///
+/// By default the builder produces the same stream as [`Compress::new`]: a 15
+/// bit window, a memory level of 9 and the default strategy, running on the
+/// default backend.
+///
+/// [`Compress`]: struct.Compress.html
+/// [`Compress::new`]: struct.Compress.html#method.new
+#[derive(Clone, Debug)]
+pub struct CompressBuilder {
+    level: Compression,
+    window_bits: u8,
+    mem_level: u8,
+    strategy: Strategy,
+    dictionary: Option<Vec<u8>>,
+    backend: &'static dyn Backend,
+    memory_limit: Option<MemoryLimit>,
+}
+
+/// A builder for a [`Decompress`] stream with non-default parameters.
+///
+/// By default the builder produces the same stream as [`Decompress::new`],
+/// which uses a 15 bit window and the default backend.
+///
+/// [`Decompress`]: struct.Decompress.html
+/// [`Decompress::new`]: struct.Decompress.html#method.new
+#[derive(Clone, Debug)]
+pub struct DecompressBuilder {
+    window_bits: u8,
+    dictionary: Option<Vec<u8>>,
+    backend: &'static dyn Backend,
+    memory_limit: Option<MemoryLimit>,
+}
+
+/// Tunes the compression algorithm for different kinds of input data.
+///
+/// The strategy only affects the compression ratio and speed, the output is
+/// always a valid deflate stream.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Strategy {
+    /// The default strategy, appropriate for most data.
+    Default = ffi::MZ_DEFAULT_STRATEGY as isize,
+
+    /// Tuned for data produced by a filter or predictor, which consists mostly
+    /// of small values with a somewhat random distribution.
+    Filtered = ffi::MZ_FILTERED as isize,
+
+    /// Only Huffman encoding is performed, no string matching takes place.
+    HuffmanOnly = ffi::MZ_HUFFMAN_ONLY as isize,
+
+    /// Match distances are limited to one, run-length encoding the input.
+    Rle = ffi::MZ_RLE as isize,
+
+    /// Dynamic Huffman codes are never used, which makes decoding simpler for
+    /// special applications.
+    Fixed = ffi::MZ_FIXED as isize,
+}
+
+/// The framing produced around the deflate data by a [`Compress`] stream.
+///
+/// [`Compress`]: struct.Compress.html
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// A raw deflate stream (RFC 1951) with no header or trailer.
+    Raw,
+
+    /// A zlib stream (RFC 1950), with a two byte header and an Adler-32
+    /// trailer.
+    Zlib,
+
+    /// A single gzip member (RFC 1952), with a minimal header and a CRC-32
+    /// trailer.
+    Gzip,
+}
+
+/// The framing expected around the deflate data by a [`Decompress`] stream.
+///
+/// [`Decompress`]: struct.Decompress.html
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DecompressFormat {
+    /// A raw deflate stream (RFC 1951) with no header or trailer.
+    Raw,
+
+    /// A zlib stream (RFC 1950).
+    Zlib,
+
+    /// A single gzip member (RFC 1952). The header and trailer are verified,
+    /// but their contents are otherwise ignored.
+    Gzip,
+
+    /// Either a zlib stream or a gzip member, detected from the first byte of
+    /// input.
+    Auto,
+}
+
+impl From<Format> for DecompressFormat {
+    fn from(format: Format) -> DecompressFormat {
+        match format {
+            Format::Raw => DecompressFormat::Raw,
+            Format::Zlib => DecompressFormat::Zlib,
+            Format::Gzip => DecompressFormat::Gzip,
+        }
+    }
+}
+
+// Writes the gzip header and trailer around the raw deflate data produced by
+// a `Compress`.
+#[derive(Clone, Debug)]
+struct GzipEncoder {
+    header: Vec<u8>,
+    // Header or trailer bytes which haven't been written out yet.
+    pending: Vec<u8>,
+    crc: Crc,
+    finished: bool,
+}
+
+// Parses and verifies the gzip header and trailer around the raw deflate data
+// consumed by a `Decompress`.
+#[derive(Clone, Debug)]
+struct GzipDecoder {
+    state: GzipState,
+    header: gz::HeaderParser,
+    // Trailer bytes collected so far.
+    buf: Vec<u8>,
+    crc: Crc,
+    // Whether the trailer can be verified, which isn't the case once data has
+    // been skipped by `Decompress::sync`.
+    check: bool,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum GzipState {
+    Header,
+    Body,
+    Trailer,
+    Done,
 }
 
+// A backend stream along with 64 bit totals, which are kept here since the
+// backend's own counters might be only 32 bits wide.
 #[derive(Debug)]
-struct Stream<D: Direction> {
-    stream_wrapper: ffi::StreamWrapper,
+struct Stream<S: ?Sized> {
+    raw: Box<S>,
     total_in: u64,
     total_out: u64,
-    _marker: marker::PhantomData<D>,
 }
 
-unsafe impl<D: Direction> Send for Stream<D> {}
-unsafe impl<D: Direction> Sync for Stream<D> {}
+impl<S: ?Sized> Stream<S> {
+    fn new(raw: Box<S>) -> Stream<S> {
+        Stream {
+            raw: raw,
+            total_in: 0,
+            total_out: 0,
+        }
+    }
 
-trait Direction {
-    unsafe fn destroy(stream: *mut ffi::mz_stream) -> c_int;
+    // Pairs a copy of the backend stream with the same totals.
+    fn with_raw<T: ?Sized>(&self, raw: Box<T>) -> Stream<T> {
+        Stream {
+            raw: raw,
+            total_in: self.total_in,
+            total_out: self.total_out,
+        }
+    }
 }
 
-#[derive(Debug)]
-enum DirCompress {}
-#[derive(Debug)]
-enum DirDecompress {}
-
 /// Values which indicate the form of flushing to be used when compressing or
 /// decompressing in-memory data.
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -101,12 +270,18 @@ pub enum Flush {
     /// the compressor, it may need to wait for the next block to be emitted.
     /// This is for advanced applications that need to control the emission of
     /// deflate blocks.
+    ///
+    /// When decompressing, this makes `decompress` return as soon as it
+    /// reaches a block boundary, which `Decompress::block_info` then reports.
+    /// The miniz_oxide backend doesn't support this for decompression.
     Block = ffi::MZ_BLOCK as isize,
 
     /// All output is flushed as with `Flush::Sync` and the compression state is
     /// reset so decompression can restart from this point if previous
     /// compressed data has been damaged or if random access is desired.
     ///
+    /// A decompressor finds these points with `Decompress::sync`.
+    ///
     /// Using this option too often can seriously degrade compression.
     Full = ffi::MZ_FULL_FLUSH as isize,
 
@@ -117,10 +292,118 @@ pub enum Flush {
     Finish = ffi::MZ_FINISH as isize,
 }
 
+/// Where a [`Decompress`] stream stopped within the deflate data, as returned
+/// by [`Decompress::block_info`].
+///
+/// Together with the bit offset and the window this is enough to resume
+/// decompression from the middle of a stream later on, which is how random
+/// access indexes over deflate data are built.
+///
+/// [`Decompress`]: struct.Decompress.html
+/// [`Decompress::block_info`]: struct.Decompress.html#method.block_info
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BlockInfo {
+    /// The number of bits of the last input byte consumed which are not part
+    /// of the data decoded so far, from 0 to 7.
+    pub unused_bits: u8,
+    /// Whether the block being decoded is the last one in the stream.
+    pub last_block: bool,
+    /// Whether decompression stopped right at a block boundary, either after
+    /// the end of a block or after the zlib header.
+    pub block_boundary: bool,
+}
+
+/// A structure representing the header of a zlib stream.
+///
+/// The header declares the window size the data was compressed with, a hint
+/// about the compression level, and whether a preset dictionary is needed to
+/// decompress it.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ZlibHeader {
+    cmf: u8,
+    flg: u8,
+    dictionary_id: Option<u32>,
+}
+
 /// Error returned when a decompression object finds that the input stream of
 /// bytes was not a valid input stream of bytes.
-#[derive(Debug)]
-pub struct DataError(());
+///
+/// The error describes what kind of problem was found, the message reported by
+/// the backend (if any) and how far into the compressed stream it was found.
+/// When converted to an `io::Error` it has the kind `InvalidData` and can be
+/// recovered with `get_ref` and `downcast_ref`.
+#[derive(Clone, Debug)]
+pub struct DataError {
+    kind: DataErrorKind,
+    message: Option<String>,
+    offset: u64,
+}
+
+/// The kinds of problems that can be found in a compressed stream.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DataErrorKind {
+    /// The zlib header is malformed or uses unsupported options.
+    Header,
+
+    /// A block has an invalid block type.
+    BlockType,
+
+    /// The length of a stored block doesn't match its one's complement.
+    StoredLength,
+
+    /// The code lengths describing a dynamic Huffman table are invalid.
+    CodeLengths,
+
+    /// An invalid literal/length or distance code was encountered.
+    Code,
+
+    /// A back reference points before the start of the decompressed data.
+    Distance,
+
+    /// The checksum or length in the stream's trailer doesn't match the
+    /// decompressed data.
+    Checksum,
+
+    /// The input ended before the end of the compressed stream.
+    Truncated,
+
+    /// The backend couldn't allocate the memory it needed to continue.
+    Memory,
+
+    /// The decompressed data is larger than the limit given to
+    /// `decompress_to_vec`.
+    OutputLimit,
+
+    /// Any other problem with the input, or an unexpected result from the
+    /// backend.
+    Other,
+}
+
+/// Error returned when a compression object fails to compress data.
+///
+/// Compression can't fail on any input, so this indicates that the stream was
+/// used incorrectly (for example by compressing more data after finishing it)
+/// or that the backend ran out of memory. When converted to an `io::Error` it
+/// has the kind `Other` and can be recovered with `get_ref` and
+/// `downcast_ref`.
+#[derive(Clone, Debug)]
+pub struct CompressError {
+    kind: CompressErrorKind,
+    message: Option<String>,
+}
+
+/// The kinds of failures reported by `CompressError`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CompressErrorKind {
+    /// The stream is in a state where the requested operation isn't allowed.
+    Stream,
+
+    /// The backend couldn't allocate the memory it needed to continue.
+    Memory,
+
+    /// The backend returned an unexpected result.
+    Other,
+}
 
 /// Possible status results of compressing some data or successfully
 /// decompressing a block of data.
@@ -149,6 +432,317 @@ pub enum Status {
     /// For decompression with zlib streams the adler-32 of the decompressed
     /// data has also been verified.
     StreamEnd,
+
+    /// Indicates that a zlib stream was compressed with a preset dictionary
+    /// which must be provided with `Decompress::set_dictionary` before
+    /// decompression can continue.
+    ///
+    /// The value is the Adler-32 checksum of the expected dictionary, as
+    /// recorded in the DICTID field of the zlib header.
+    NeedDictionary(u32),
+}
+
+impl Default for Strategy {
+    fn default() -> Strategy {
+        Strategy::Default
+    }
+}
+
+impl CompressBuilder {
+    /// Creates a new builder which will compress at the given level with
+    /// otherwise default parameters.
+    pub fn new(level: Compression) -> CompressBuilder {
+        CompressBuilder {
+            level: level,
+            window_bits: DEFAULT_WINDOW_BITS,
+            mem_level: DEFAULT_MEM_LEVEL,
+            strategy: Strategy::Default,
+            dictionary: None,
+            backend: backend::default(),
+            memory_limit: None,
+        }
+    }
+
+    /// Configures the base two logarithm of the window size, from 9 to 15.
+    ///
+    /// Larger windows compress better at the expense of memory, and data
+    /// compressed with a given window size can only be decompressed with a
+    /// window at least as large.
+    pub fn window_bits(mut self, bits: u8) -> CompressBuilder {
+        self.window_bits = bits;
+        self
+    }
+
+    /// Configures how much memory is allocated for the internal compression
+    /// state, from 1 (least memory, slowest) to 9 (most memory, fastest).
+    pub fn mem_level(mut self, mem_level: u8) -> CompressBuilder {
+        self.mem_level = mem_level;
+        self
+    }
+
+    /// Configures the strategy used to tune the compression algorithm.
+    pub fn strategy(mut self, strategy: Strategy) -> CompressBuilder {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Configures a preset dictionary to prime the compressor with.
+    ///
+    /// The dictionary should consist of strings that are likely to occur in
+    /// the data, with the most common ones at the end. The same dictionary
+    /// must be provided when decompressing. It is applied again whenever the
+    /// stream is reset.
+    pub fn dictionary<T: Into<Vec<u8>>>(mut self, dictionary: T)
+                                        -> CompressBuilder {
+        self.dictionary = Some(dictionary.into());
+        self
+    }
+
+    /// Configures the backend which does the actual compression, instead of
+    /// the default one.
+    ///
+    /// See the [`backend`] module for the available backends.
+    ///
+    /// [`backend`]: backend/index.html
+    pub fn backend(mut self, backend: &'static dyn Backend) -> CompressBuilder {
+        self.backend = backend;
+        self
+    }
+
+    /// Configures a budget that all memory allocated by the stream is charged
+    /// to.
+    ///
+    /// The budget may be shared with other streams. If it runs out while the
+    /// stream is being created, `build` fails, and if it runs out later on,
+    /// `compress` returns an error of kind `CompressErrorKind::Memory`.
+    pub fn memory_limit(mut self, limit: MemoryLimit) -> CompressBuilder {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    /// Consumes this builder, creating the compression stream.
+    ///
+    /// The `zlib_header` argument indicates whether the output data should
+    /// have a zlib header or not.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if a parameter is out of range
+    /// or if it can't be honored by the compression backend. The miniz
+    /// backend, for example, only supports a 15 bit window and a memory level
+    /// of 9, and the miniz_oxide backend doesn't support memory limits.
+    ///
+    /// Returns an error of kind `Other` if the memory limit doesn't leave
+    /// enough room for the stream's state.
+    pub fn build(self, zlib_header: bool) -> io::Result<Compress> {
+        self.build_format(if zlib_header {Format::Zlib} else {Format::Raw})
+    }
+
+    /// Consumes this builder, creating a compression stream which produces
+    /// output in the given format.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error in the same cases as `build`.
+    pub fn build_format(self, format: Format) -> io::Result<Compress> {
+        try!(check_window_bits(self.backend, self.window_bits));
+        if self.mem_level < 1 || self.mem_level > 9 {
+            return Err(bad_param("memory level must be between 1 and 9"))
+        }
+        if !self.backend.supports_mem_level() &&
+           self.mem_level != DEFAULT_MEM_LEVEL {
+            return Err(bad_param("the memory level can't be configured with \
+                                  this compression backend"))
+        }
+        let raw = try!(self.backend.deflate_init(self.level,
+                                                 format == Format::Zlib,
+                                                 self.window_bits,
+                                                 self.mem_level,
+                                                 self.strategy,
+                                                 self.memory_limit.as_ref()));
+        let mut c = Compress {
+            inner: Stream::new(raw),
+            backend: self.backend,
+            dictionary: None,
+            gzip: None,
+        };
+        if format == Format::Gzip {
+            let header = gz::default_header(self.level);
+            c.gzip = Some(GzipEncoder::new(header));
+        }
+        if let Some(dictionary) = self.dictionary {
+            try!(c.set_dictionary(&dictionary));
+            c.dictionary = Some(dictionary);
+        }
+        Ok(c)
+    }
+}
+
+impl DecompressBuilder {
+    /// Creates a new builder with default parameters.
+    pub fn new() -> DecompressBuilder {
+        DecompressBuilder {
+            window_bits: DEFAULT_WINDOW_BITS,
+            dictionary: None,
+            backend: backend::default(),
+            memory_limit: None,
+        }
+    }
+
+    /// Configures the base two logarithm of the window size, from 9 to 15.
+    ///
+    /// The window must be at least as large as the one used to compress the
+    /// data, otherwise decompression will fail.
+    pub fn window_bits(mut self, bits: u8) -> DecompressBuilder {
+        self.window_bits = bits;
+        self
+    }
+
+    /// Configures the preset dictionary the data was compressed with.
+    ///
+    /// For raw deflate streams the dictionary is installed immediately. For
+    /// zlib streams it is installed when the header asks for it, so
+    /// `Status::NeedDictionary` is never returned. It is applied again whenever
+    /// the stream is reset.
+    pub fn dictionary<T: Into<Vec<u8>>>(mut self, dictionary: T)
+                                        -> DecompressBuilder {
+        self.dictionary = Some(dictionary.into());
+        self
+    }
+
+    /// Configures the backend which does the actual decompression, instead of
+    /// the default one.
+    ///
+    /// See the [`backend`] module for the available backends.
+    ///
+    /// [`backend`]: backend/index.html
+    pub fn backend(mut self, backend: &'static dyn Backend)
+                   -> DecompressBuilder {
+        self.backend = backend;
+        self
+    }
+
+    /// Configures a budget that all memory allocated by the stream is charged
+    /// to.
+    ///
+    /// The budget may be shared with other streams. If it runs out while the
+    /// stream is being created, `build` fails, and if it runs out later on,
+    /// `decompress` returns an error of kind `DataErrorKind::Memory`.
+    pub fn memory_limit(mut self, limit: MemoryLimit) -> DecompressBuilder {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    /// Consumes this builder, creating the decompression stream.
+    ///
+    /// The `zlib_header` argument indicates whether the input data is expected
+    /// to have a zlib header or not.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if the window size is out of
+    /// range or if it or the memory limit can't be honored by the compression
+    /// backend, and an error of kind `Other` if the memory limit doesn't
+    /// leave enough room for the stream's state.
+    pub fn build(self, zlib_header: bool) -> io::Result<Decompress> {
+        self.build_format(if zlib_header {
+            DecompressFormat::Zlib
+        } else {
+            DecompressFormat::Raw
+        })
+    }
+
+    /// Consumes this builder, creating a decompression stream which expects
+    /// input in the given format.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error in the same cases as `build`.
+    pub fn build_format(self, format: DecompressFormat)
+                        -> io::Result<Decompress> {
+        try!(check_window_bits(self.backend, self.window_bits));
+        // `Auto` starts out as a raw stream, switching to zlib once a zlib
+        // header is seen.
+        let zlib_header = format == DecompressFormat::Zlib;
+        let raw = try!(self.backend.inflate_init(zlib_header,
+                                                 self.window_bits,
+                                                 self.memory_limit.as_ref()));
+        let mut d = Decompress {
+            inner: Stream::new(raw),
+            backend: self.backend,
+            format: format,
+            detected: format,
+            gzip: GzipDecoder::new(),
+            dictionary: None,
+            sync_matched: 0,
+            trailer_left: 0,
+            data_ended: false,
+            zlib_start: Vec::new(),
+        };
+        if let Some(dictionary) = self.dictionary {
+            if !self.backend.supports_dictionary() {
+                return Err(no_dictionary())
+            }
+            d.dictionary = Some(dictionary);
+            try!(d.install_raw_dictionary());
+        }
+        Ok(d)
+    }
+}
+
+const DEFAULT_WINDOW_BITS: u8 = ffi::MZ_DEFAULT_WINDOW_BITS as u8;
+const DEFAULT_MEM_LEVEL: u8 = 9;
+
+// One step of the same search as zlib's `syncsearch`, returning how much of the
+// 00 00 ff ff marker has been matched after `byte`. On a mismatch only a run
+// of zeros can start a new one.
+fn sync_step(matched: usize, byte: u8) -> usize {
+    if byte == (if matched < 2 {0} else {0xff}) {
+        matched + 1
+    } else if byte != 0 {
+        0
+    } else {
+        4 - matched
+    }
+}
+
+fn bad_param(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn check_window_bits(backend: &dyn Backend, bits: u8) -> io::Result<()> {
+    if bits < 9 || bits > 15 {
+        return Err(bad_param("window bits must be between 9 and 15"))
+    }
+    if !backend.supports_window_bits() && bits != DEFAULT_WINDOW_BITS {
+        return Err(bad_param("the window size can't be configured with this \
+                              compression backend"))
+    }
+    Ok(())
+}
+
+fn no_dictionary() -> io::Error {
+    bad_param("preset dictionaries are not supported by this compression \
+               backend")
+}
+
+fn reset_result(rc: ReturnCode) -> io::Result<()> {
+    match rc {
+        ReturnCode::Ok => Ok(()),
+        ReturnCode::MemError => {
+            Err(io::Error::new(io::ErrorKind::Other, "out of memory"))
+        }
+        rc => {
+            Err(io::Error::new(io::ErrorKind::Other,
+                               format!("failed to reset stream: {:?}", rc)))
+        }
+    }
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut adler = Adler32::new();
+    adler.update(data);
+    adler.sum()
 }
 
 impl Compress {
@@ -157,29 +751,99 @@ impl Compress {
     /// The `level` argument here indicates what level of compression is going
     /// to be performed, and the `zlib_header` argument indicates whether the
     /// output data should have a zlib header or not.
+    ///
+    /// To configure the window size, memory level or strategy use a
+    /// [`CompressBuilder`] instead.
+    ///
+    /// [`CompressBuilder`]: struct.CompressBuilder.html
+    ///
+    /// # Panics
+    ///
+    /// Panics if the backend fails to set up the stream, for example because
+    /// it runs out of memory. Use `try_new` to get an error instead.
     pub fn new(level: Compression, zlib_header: bool) -> Compress {
-        unsafe {
-            let mut state = ffi::StreamWrapper::default();
-            let ret = ffi::mz_deflateInit2(&mut *state,
-                                           level as c_int,
-                                           ffi::MZ_DEFLATED,
-                                           if zlib_header {
-                                               ffi::MZ_DEFAULT_WINDOW_BITS
-                                           } else {
-                                               -ffi::MZ_DEFAULT_WINDOW_BITS
-                                           },
-                                           9,
-                                           ffi::MZ_DEFAULT_STRATEGY);
-            debug_assert_eq!(ret, 0);
-            Compress {
-                inner: Stream {
-                    stream_wrapper: state,
-                    total_in: 0,
-                    total_out: 0,
-                    _marker: marker::PhantomData,
-                },
-            }
+        Compress::try_new(level, zlib_header)
+            .expect("failed to initialize compression stream")
+    }
+
+    /// Like `new`, but returns an error if the backend fails to set up the
+    /// stream.
+    pub fn try_new(level: Compression, zlib_header: bool)
+                   -> io::Result<Compress> {
+        CompressBuilder::new(level).build(zlib_header)
+    }
+
+    /// Creates a new object ready for compressing data that it's given,
+    /// producing output in the given format.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the backend fails to set up the stream. Use
+    /// `try_new_with_format` to get an error instead.
+    pub fn new_with_format(level: Compression, format: Format) -> Compress {
+        Compress::try_new_with_format(level, format)
+            .expect("failed to initialize compression stream")
+    }
+
+    /// Like `new_with_format`, but returns an error if the backend fails to
+    /// set up the stream.
+    pub fn try_new_with_format(level: Compression, format: Format)
+                               -> io::Result<Compress> {
+        CompressBuilder::new(level).build_format(format)
+    }
+
+    /// Returns the largest amount of output that compressing `input_len`
+    /// bytes in the given format can produce, similar to zlib's
+    /// `deflateBound`.
+    ///
+    /// The bound holds for every backend, compression level, strategy, window
+    /// size and memory level, so none of those need to be given, and for
+    /// zlib streams there's room for a preset dictionary's checksum in the
+    /// header. It only covers a stream compressed with `Flush::None` and
+    /// finished with `Flush::Finish`, though. Each `Flush::Partial`,
+    /// `Flush::Sync` or `Flush::Full` along the way ends a block early and
+    /// can add up to 10 bytes that aren't included, which callers flushing
+    /// mid-stream have to allow for themselves.
+    pub fn bound(input_len: usize, format: Format) -> usize {
+        let len = input_len;
+        // zlib's conservative bound, which it also uses itself for any but
+        // the default parameters, and miniz's bound, which allows for more
+        // overhead on short inputs.
+        let zlib = len + ((len + 7) >> 3) + ((len + 63) >> 6) + 5;
+        let miniz = cmp::max(128 + len / 100 * 110 + 110,
+                             128 + len + (len / (31 * 1024) + 1) * 5);
+        let framing = match format {
+            Format::Raw => 0,
+            Format::Zlib => 2 + 4 + 4,
+            Format::Gzip => 10 + 8,
+        };
+        cmp::max(zlib, miniz) + framing
+    }
+
+    /// Returns an estimate of the memory that a compression stream with the
+    /// given window size and memory level allocates on the default backend.
+    ///
+    /// For zlib this is about `(1 << (window_bits + 2)) + (1 << (mem_level +
+    /// 9))` bytes, whereas miniz and miniz_oxide always need a little over
+    /// 300 KiB. Estimates for other backends are available through
+    /// [`Backend::deflate_memory`].
+    ///
+    /// [`Backend::deflate_memory`]: backend/trait.Backend.html#method.deflate_memory
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if `window_bits` isn't between
+    /// 9 and 15, or `mem_level` isn't between 1 and 9.
+    pub fn memory_estimate(window_bits: u8, mem_level: u8)
+                           -> io::Result<usize> {
+        if window_bits < 9 || window_bits > 15 {
+            return Err(bad_param("window bits must be between 9 and 15"))
         }
+        if mem_level < 1 || mem_level > 9 {
+            return Err(bad_param("memory level must be between 1 and 9"))
+        }
+        let backend = backend::default();
+        Ok(backend.deflate_memory(window_bits, mem_level).unwrap_or(0))
     }
 
     /// Returns the total number of input bytes which have been processed by
@@ -197,12 +861,120 @@ impl Compress {
     /// Quickly resets this compressor without having to reallocate anything.
     ///
     /// This is equivalent to dropping this object and then creating a new one.
+    ///
+    /// If a dictionary was configured through `CompressBuilder::dictionary` it
+    /// is installed again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the backend fails to reset the stream or to reinstall the
+    /// dictionary. Use `try_reset` to get an error instead.
     pub fn reset(&mut self) {
-        let rc = unsafe { ffi::mz_deflateReset(&mut *self.inner.stream_wrapper) };
-        assert_eq!(rc, ffi::MZ_OK);
+        self.try_reset().expect("failed to reset compression stream")
+    }
+
+    /// Like `reset`, but returns an error if the backend fails to reset the
+    /// stream or to reinstall the dictionary.
+    pub fn try_reset(&mut self) -> io::Result<()> {
+        try!(reset_result(self.inner.raw.reset()));
 
         self.inner.total_in = 0;
         self.inner.total_out = 0;
+
+        if let Some(ref mut gzip) = self.gzip {
+            gzip.reset();
+        }
+        if let Some(dictionary) = self.dictionary.take() {
+            let ret = self.set_dictionary(&dictionary);
+            self.dictionary = Some(dictionary);
+            try!(ret);
+        }
+        Ok(())
+    }
+
+    /// Creates an independent copy of this compressor, including all of its
+    /// internal state, similar to zlib's `deflateCopy`.
+    ///
+    /// Both objects can then be given different input. This makes it possible
+    /// to compress a common prefix only once and continue with several
+    /// different suffixes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stream can't be duplicated, either because
+    /// memory ran out or because the backend doesn't support it (the
+    /// miniz_oxide backend doesn't).
+    ///
+    /// The copy is charged to the same memory limit as this stream, if any.
+    pub fn try_clone(&self) -> io::Result<Compress> {
+        let raw = try!(self.inner.raw.try_clone());
+        Ok(Compress {
+            inner: self.inner.with_raw(raw),
+            backend: self.backend,
+            dictionary: self.dictionary.clone(),
+            gzip: self.gzip.clone(),
+        })
+    }
+
+    /// Returns the number of bytes currently allocated by this stream, or
+    /// `None` if the backend doesn't keep track of it (miniz_oxide doesn't).
+    pub fn memory_usage(&self) -> Option<usize> {
+        self.inner.raw.memory_usage()
+    }
+
+    /// Installs a preset dictionary, priming the compressor with data that is
+    /// likely to occur in the input.
+    ///
+    /// For zlib streams this must be called before any data is compressed,
+    /// and the zlib header will then record the dictionary's checksum. For raw
+    /// deflate streams it may be called at any time, typically right after a
+    /// `Flush::Full`.
+    ///
+    /// Returns the Adler-32 checksum of the dictionary.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the dictionary can't be installed at this point in
+    /// the stream, or if the compression backend doesn't support preset
+    /// dictionaries.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) -> io::Result<u32> {
+        if !self.backend.supports_dictionary() {
+            return Err(no_dictionary())
+        }
+        if self.inner.raw.set_dictionary(dictionary) != ReturnCode::Ok {
+            return Err(bad_param("a dictionary can't be installed at this \
+                                  point in the stream"))
+        }
+        Ok(adler32(dictionary))
+    }
+
+    /// Changes the compression level and strategy used for all data that is
+    /// compressed from now on, similar to zlib's `deflateParams`.
+    ///
+    /// The new parameters only take effect on a block boundary, so all input
+    /// given to `compress` so far must have been flushed first with
+    /// `Flush::Sync` or `Flush::Full`. Unlike `deflateParams`, this method
+    /// doesn't flush pending input itself, as it has nowhere to write the
+    /// output to. The flush has to be repeated with no input for as long as
+    /// it fills the output buffer, like any other. Data compressed with
+    /// different parameters can be decompressed as usual.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if input is still pending
+    /// inside the compressor or if the stream has already been finished. The
+    /// stream is left unchanged, so it can go on with the old parameters.
+    pub fn set_params(&mut self, level: Compression, strategy: Strategy)
+                      -> io::Result<()> {
+        match self.inner.raw.set_params(level, strategy) {
+            ReturnCode::Ok => Ok(()),
+            ReturnCode::BufError => {
+                Err(bad_param("pending input must be flushed before changing \
+                               compression parameters"))
+            }
+            _ => Err(bad_param("compression parameters can't be changed at \
+                                this point in the stream")),
+        }
     }
 
     /// Compresses the input data into the output, consuming only as much
@@ -212,34 +984,74 @@ impl Compress {
     ///
     /// To learn how much data was consumed or how much output was produced, use
     /// the `total_in` and `total_out` functions before/after this is called.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `CompressError` if the stream can't be used this way any more
+    /// (for example because it was already finished with different input), or
+    /// if the backend runs out of memory.
     pub fn compress(&mut self,
                     input: &[u8],
                     output: &mut [u8],
                     flush: Flush)
-                    -> Status {
-        let raw = &mut *self.inner.stream_wrapper;
-        raw.next_in = input.as_ptr() as *mut _;
-        raw.avail_in = input.len() as c_uint;
-        raw.next_out = output.as_mut_ptr();
-        raw.avail_out = output.len() as c_uint;
-
-        let rc = unsafe { ffi::mz_deflate(raw, flush as c_int) };
+                    -> Result<Status, CompressError> {
+        let written = match self.gzip {
+            Some(ref mut gzip) => gzip.write_pending(output),
+            None => return self.deflate(input, output, flush),
+        };
+        self.inner.total_out += written as u64;
+        let output = &mut output[written..];
+        {
+            let gzip = self.gzip.as_ref().unwrap();
+            if gzip.finished && gzip.pending.is_empty() {
+                return Ok(Status::StreamEnd)
+            }
+            if !gzip.pending.is_empty() {
+                return Ok(Status::Ok)
+            }
+        }
 
-        // Unfortunately the total counters provided by zlib might be only
-        // 32 bits wide and overflow while processing large amounts of data.
-        self.inner.total_in += (raw.next_in as usize -
-                                input.as_ptr() as usize) as u64;
-        self.inner.total_out += (raw.next_out as usize -
-                                 output.as_ptr() as usize) as u64;
+        let (before_in, before_out) = (self.total_in(), self.total_out());
+        let status = try!(self.deflate(input, output, flush));
+        let consumed = (self.total_in() - before_in) as usize;
+        let produced = (self.total_out() - before_out) as usize;
 
-        match rc {
-            ffi::MZ_OK => Status::Ok,
-            ffi::MZ_BUF_ERROR => Status::BufError,
-            ffi::MZ_STREAM_END => Status::StreamEnd,
-            c => panic!("unknown return code: {}", c),
+        let gzip = self.gzip.as_mut().unwrap();
+        gzip.crc.update(&input[..consumed]);
+        if status != Status::StreamEnd {
+            return Ok(status)
+        }
+        gzip.finish();
+        let written = gzip.write_pending(&mut output[produced..]);
+        self.inner.total_out += written as u64;
+        if gzip.pending.is_empty() {
+            Ok(Status::StreamEnd)
+        } else {
+            Ok(Status::Ok)
         }
     }
 
+    fn deflate(&mut self, input: &[u8], output: &mut [u8], flush: Flush)
+               -> Result<Status, CompressError> {
+        let progress = self.inner.raw.deflate(input, output, flush);
+        self.inner.total_in += progress.consumed as u64;
+        self.inner.total_out += progress.written as u64;
+
+        let kind = match progress.code {
+            ReturnCode::Ok => return Ok(Status::Ok),
+            ReturnCode::BufError => return Ok(Status::BufError),
+            ReturnCode::StreamEnd => return Ok(Status::StreamEnd),
+            ReturnCode::StreamError => CompressErrorKind::Stream,
+            ReturnCode::MemError => CompressErrorKind::Memory,
+            c => {
+                let msg = format!("unknown return code: {:?}", c);
+                return Err(CompressError::new(CompressErrorKind::Other,
+                                              Some(msg)))
+            }
+        };
+        Err(CompressError::new(kind, self.inner.raw.message()))
+    }
+
     /// Compresses the input data into the extra space of the output, consuming
     /// only as much input as needed and writing as much output as possible.
     ///
@@ -252,7 +1064,7 @@ impl Compress {
                         input: &[u8],
                         output: &mut Vec<u8>,
                         flush: Flush)
-                        -> Status {
+                        -> Result<Status, CompressError> {
         let cap = output.capacity();
         let len = output.len();
 
@@ -274,25 +1086,86 @@ impl Decompress {
     ///
     /// The `zlib_header` argument indicates whether the input data is expected
     /// to have a zlib header or not.
-    pub fn new(zlib_header: bool) -> Decompress {
-        unsafe {
-            let mut state = ffi::StreamWrapper::default();
-            let ret = ffi::mz_inflateInit2(&mut *state,
-                                           if zlib_header {
-                                               ffi::MZ_DEFAULT_WINDOW_BITS
-                                           } else {
-                                               -ffi::MZ_DEFAULT_WINDOW_BITS
-                                           });
-            debug_assert_eq!(ret, 0);
-            Decompress {
-                inner: Stream {
-                    stream_wrapper: state,
-                    total_in: 0,
-                    total_out: 0,
-                    _marker: marker::PhantomData,
-                },
-            }
+    ///
+    /// To configure the window size use a [`DecompressBuilder`] instead.
+    ///
+    /// [`DecompressBuilder`]: struct.DecompressBuilder.html
+    ///
+    /// # Panics
+    ///
+    /// Panics if the backend fails to set up the stream, for example because
+    /// it runs out of memory. Use `try_new` to get an error instead.
+    pub fn new(zlib_header: bool) -> Decompress {
+        Decompress::try_new(zlib_header)
+            .expect("failed to initialize decompression stream")
+    }
+
+    /// Like `new`, but returns an error if the backend fails to set up the
+    /// stream.
+    pub fn try_new(zlib_header: bool) -> io::Result<Decompress> {
+        DecompressBuilder::new().build(zlib_header)
+    }
+
+    /// Creates a new object ready for decompressing data in the given format.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the backend fails to set up the stream. Use
+    /// `try_new_with_format` to get an error instead.
+    pub fn new_with_format(format: DecompressFormat) -> Decompress {
+        Decompress::try_new_with_format(format)
+            .expect("failed to initialize decompression stream")
+    }
+
+    /// Like `new_with_format`, but returns an error if the backend fails to
+    /// set up the stream.
+    pub fn try_new_with_format(format: DecompressFormat)
+                               -> io::Result<Decompress> {
+        DecompressBuilder::new().build_format(format)
+    }
+
+    /// Returns an estimate of the memory that a decompression stream with the
+    /// given window size allocates on the default backend.
+    ///
+    /// For zlib this is a little more than the window itself, whereas miniz
+    /// and miniz_oxide always need about 44 KiB. Estimates for other backends
+    /// are available through [`Backend::inflate_memory`].
+    ///
+    /// [`Backend::inflate_memory`]: backend/trait.Backend.html#method.inflate_memory
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidInput` if `window_bits` isn't between
+    /// 9 and 15.
+    pub fn memory_estimate(window_bits: u8) -> io::Result<usize> {
+        if window_bits < 9 || window_bits > 15 {
+            return Err(bad_param("window bits must be between 9 and 15"))
+        }
+        Ok(backend::default().inflate_memory(window_bits).unwrap_or(0))
+    }
+
+    /// Returns the format of the data being decompressed.
+    ///
+    /// For `DecompressFormat::Auto` this is `None` until enough input has been
+    /// seen to tell the formats apart.
+    pub fn format(&self) -> Option<Format> {
+        match self.detected {
+            DecompressFormat::Raw => Some(Format::Raw),
+            DecompressFormat::Zlib => Some(Format::Zlib),
+            DecompressFormat::Gzip => Some(Format::Gzip),
+            DecompressFormat::Auto => None,
+        }
+    }
+
+    /// Returns the header of the zlib stream being decompressed, once it has
+    /// been read.
+    ///
+    /// Returns `None` before that, and for streams in other formats.
+    pub fn zlib_header(&self) -> Option<ZlibHeader> {
+        if self.detected != DecompressFormat::Zlib {
+            return None
         }
+        parse_zlib_header(&self.zlib_start).ok()
     }
 
     /// Returns the total number of input bytes which have been processed by
@@ -327,33 +1200,298 @@ impl Decompress {
     /// If the input data to this instance of `Decompress` is not a valid
     /// zlib/deflate stream then this function may return an instance of
     /// `DataError` to indicate that the stream of input bytes is corrupted.
+    /// With `Flush::Finish` an error of kind `DataErrorKind::Truncated` is also
+    /// returned if the input ends before the stream does.
     pub fn decompress(&mut self,
                       input: &[u8],
                       output: &mut [u8],
                       flush: Flush)
                       -> Result<Status, DataError> {
-        let raw = &mut *self.inner.stream_wrapper;
-        raw.next_in = input.as_ptr() as *mut u8;
-        raw.avail_in = input.len() as c_uint;
-        raw.next_out = output.as_mut_ptr();
-        raw.avail_out = output.len() as c_uint;
+        if flush == Flush::Block && !self.backend.supports_block_flush() {
+            let msg = "Flush::Block is not supported by this compression \
+                       backend";
+            return Err(DataError::new(DataErrorKind::Other,
+                                      Some(msg.to_string()),
+                                      self.inner.total_in))
+        }
+        if self.detected == DecompressFormat::Auto {
+            // The first byte of a zlib header always has 8 in its low four
+            // bits, so it can't be mistaken for gzip's 0x1f.
+            match input.first() {
+                Some(&0x1f) => {
+                    self.detected = DecompressFormat::Gzip;
+                    if let Err(e) = self.install_raw_dictionary() {
+                        return Err(self.setup_error(e))
+                    }
+                }
+                Some(_) => {
+                    if let Err(e) = self._reset(true) {
+                        return Err(self.setup_error(e))
+                    }
+                    self.detected = DecompressFormat::Zlib;
+                }
+                None => return self.no_input(output, flush),
+            }
+        }
+        let before_in = self.inner.total_in;
+        let ret = match self.detected {
+            DecompressFormat::Gzip => {
+                self.decompress_gzip(input, output, flush)
+            }
+            _ if self.trailer_left > 0 || self.data_ended => {
+                self.inflate_synced_zlib(input, output, flush)
+            }
+            _ => self.inflate(input, output, flush),
+        };
+
+        // A marker may have started in input the backend consumed before it
+        // noticed damaged data, so `sync` picks up any partial match here.
+        let consumed = cmp::min((self.inner.total_in - before_in) as usize,
+                                input.len());
+        let (mut matched, tail) = match consumed.checked_sub(3) {
+            Some(start) => (0, &input[start..consumed]),
+            None => (self.sync_matched, &input[..consumed]),
+        };
+        for &byte in tail {
+            matched = sync_step(matched, byte) % 4;
+        }
+        self.sync_matched = matched;
+        ret
+    }
+
+    fn decompress_gzip(&mut self,
+                       mut input: &[u8],
+                       output: &mut [u8],
+                       flush: Flush)
+                       -> Result<Status, DataError> {
+        let before_in = self.inner.total_in;
+        if self.gzip.state == GzipState::Header {
+            let offset = self.inner.total_in;
+            let consumed = try!(self.gzip.read_header(input, offset));
+            self.inner.total_in += consumed as u64;
+            input = &input[consumed..];
+            if self.gzip.state == GzipState::Header {
+                return self.gzip_progress(before_in, output, flush)
+            }
+        }
+
+        if self.gzip.state == GzipState::Body {
+            let (start_in, start_out) = (self.total_in(), self.total_out());
+            let status = try!(self.inflate(input, output, flush));
+            let consumed = (self.total_in() - start_in) as usize;
+            let produced = (self.total_out() - start_out) as usize;
+            self.gzip.crc.update(&output[..produced]);
+            input = &input[consumed..];
+            if status != Status::StreamEnd {
+                return Ok(status)
+            }
+            self.gzip.state = GzipState::Trailer;
+        }
 
-        let rc = unsafe { ffi::mz_inflate(raw, flush as c_int) };
+        if self.gzip.state == GzipState::Trailer {
+            let offset = self.inner.total_in;
+            let consumed = try!(self.gzip.read_trailer(input, offset));
+            self.inner.total_in += consumed as u64;
+            if self.gzip.state == GzipState::Trailer {
+                return self.gzip_progress(before_in, output, flush)
+            }
+        }
+        Ok(Status::StreamEnd)
+    }
 
-        // Unfortunately the total counters provided by zlib might be only
-        // 32 bits wide and overflow while processing large amounts of data.
-        self.inner.total_in += (raw.next_in as usize -
-                                input.as_ptr() as usize) as u64;
-        self.inner.total_out += (raw.next_out as usize -
-                                 output.as_ptr() as usize) as u64;
+    // Reports the status after the gzip framing consumed all of the input
+    // without reaching the end of the header or trailer.
+    fn gzip_progress(&self, before_in: u64, output: &[u8], flush: Flush)
+                     -> Result<Status, DataError> {
+        if self.inner.total_in > before_in && flush != Flush::Finish {
+            Ok(Status::Ok)
+        } else {
+            self.no_input(output, flush)
+        }
+    }
 
-        match rc {
-            ffi::MZ_DATA_ERROR |
-            ffi::MZ_STREAM_ERROR => Err(DataError(())),
-            ffi::MZ_OK => Ok(Status::Ok),
-            ffi::MZ_BUF_ERROR => Ok(Status::BufError),
-            ffi::MZ_STREAM_END => Ok(Status::StreamEnd),
-            c => panic!("unknown return code: {}", c),
+    fn no_input(&self, output: &[u8], flush: Flush)
+                -> Result<Status, DataError> {
+        if flush == Flush::Finish && output.len() > 0 {
+            Err(DataError::new(DataErrorKind::Truncated,
+                               None,
+                               self.inner.total_in))
+        } else {
+            Ok(Status::BufError)
+        }
+    }
+
+    fn inflate(&mut self, input: &[u8], output: &mut [u8], flush: Flush)
+               -> Result<Status, DataError> {
+        let mut progress = self.inner.raw.inflate(input, output, flush);
+
+        // If the stream asks for the dictionary we were configured with then
+        // install it and keep going.
+        if let ReturnCode::NeedDict(_) = progress.code {
+            if let Some(ref dictionary) = self.dictionary {
+                progress.code = self.inner.raw.set_dictionary(dictionary);
+                if progress.code == ReturnCode::Ok {
+                    let more = self.inner.raw.inflate(
+                        &input[progress.consumed..],
+                        &mut output[progress.written..],
+                        flush);
+                    progress.consumed += more.consumed;
+                    progress.written += more.written;
+                    progress.code = more.code;
+                }
+            }
+        }
+
+        if self.detected == DecompressFormat::Zlib &&
+           self.inner.total_in == self.zlib_start.len() as u64 &&
+           self.zlib_start.len() < 6 {
+            let n = cmp::min(progress.consumed, 6 - self.zlib_start.len());
+            self.zlib_start.extend_from_slice(&input[..n]);
+        }
+        self.inner.total_in += progress.consumed as u64;
+        self.inner.total_out += progress.written as u64;
+
+        match progress.code {
+            ReturnCode::DataError |
+            ReturnCode::StreamError => {
+                Err(DataError::from_message(self.inner.raw.message(),
+                                            self.inner.total_in))
+            }
+            ReturnCode::Ok => Ok(Status::Ok),
+            // With no more input coming and room left for output, the only
+            // reason inflate can't make progress is that the input was cut
+            // short.
+            ReturnCode::BufError if flush == Flush::Finish &&
+                                    progress.consumed == input.len() &&
+                                    progress.written < output.len() => {
+                Err(DataError::new(DataErrorKind::Truncated,
+                                   None,
+                                   self.inner.total_in))
+            }
+            ReturnCode::BufError => Ok(Status::BufError),
+            ReturnCode::StreamEnd => Ok(Status::StreamEnd),
+            ReturnCode::NeedDict(adler) => Ok(Status::NeedDictionary(adler)),
+            ReturnCode::MemError => {
+                Err(DataError::new(DataErrorKind::Memory,
+                                   None,
+                                   self.inner.total_in))
+            }
+            c => {
+                let msg = format!("unknown return code: {:?}", c);
+                Err(DataError::new(DataErrorKind::Other,
+                                   Some(msg),
+                                   self.inner.total_in))
+            }
+        }
+    }
+
+    // Decompresses the raw deflate data a zlib stream continues with after
+    // `sync`, and then skips its trailer, which can't be checked.
+    fn inflate_synced_zlib(&mut self,
+                           input: &[u8],
+                           output: &mut [u8],
+                           flush: Flush)
+                           -> Result<Status, DataError> {
+        let mut consumed = 0;
+        if !self.data_ended {
+            let before = self.inner.total_in;
+            match try!(self.inflate(input, output, flush)) {
+                Status::StreamEnd => self.data_ended = true,
+                status => return Ok(status),
+            }
+            consumed = (self.inner.total_in - before) as usize;
+        }
+        let n = cmp::min(self.trailer_left, input.len() - consumed);
+        self.trailer_left -= n;
+        self.inner.total_in += n as u64;
+        if self.trailer_left == 0 {
+            Ok(Status::StreamEnd)
+        } else if n > 0 {
+            Ok(Status::Ok)
+        } else {
+            Ok(Status::BufError)
+        }
+    }
+
+    /// Returns where the last call to `decompress` stopped within the deflate
+    /// data, or `None` if the backend doesn't report it (the miniz_oxide
+    /// backend doesn't).
+    ///
+    /// With `Flush::Block` decompression stops at every block boundary, at
+    /// which point the deflate stream can later be resumed from the bit
+    /// position given here, as long as the window is restored too.
+    pub fn block_info(&self) -> Option<BlockInfo> {
+        self.inner.raw.block_info()
+    }
+
+    /// Returns the window of the decompressor, the most recent output of up
+    /// to 32 KiB which later data may refer back to, similar to zlib's
+    /// `inflateGetDictionary`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend doesn't expose its window.
+    pub fn window(&self) -> io::Result<Vec<u8>> {
+        self.inner.raw.window()
+    }
+
+    /// Inserts the low `bits` bits of `value` into the input ahead of the
+    /// next call to `decompress`, similar to zlib's `inflatePrime`.
+    ///
+    /// This allows a raw deflate stream to be resumed from a position in the
+    /// middle of a byte, as reported by [`block_info`], by priming the stream
+    /// with the bits of that byte which haven't been decoded yet.
+    ///
+    /// [`block_info`]: #method.block_info
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `bits` is more than 8, if the stream can't be
+    /// primed at this point, or if the backend doesn't support priming (the
+    /// miniz_oxide backend doesn't).
+    pub fn prime(&mut self, bits: u8, value: u8) -> io::Result<()> {
+        if bits > 8 {
+            return Err(bad_param("at most 8 bits can be primed at once"))
+        }
+        match self.inner.raw.prime(bits, value & ((1u16 << bits) - 1) as u8) {
+            ReturnCode::Ok => Ok(()),
+            _ => Err(bad_param("the stream can't be primed at this point, or \
+                                the backend doesn't support it")),
+        }
+    }
+
+    /// Returns the number of bytes currently allocated by this stream, or
+    /// `None` if the backend doesn't keep track of it (miniz_oxide doesn't).
+    pub fn memory_usage(&self) -> Option<usize> {
+        self.inner.raw.memory_usage()
+    }
+
+    /// Installs the preset dictionary that the data was compressed with.
+    ///
+    /// For zlib streams this must be called after `decompress` returns
+    /// `Status::NeedDictionary`, and the dictionary's checksum must match the
+    /// one that was requested. For raw deflate streams it may be called at any
+    /// time, typically right after creating or resetting this object.
+    ///
+    /// Returns the Adler-32 checksum of the dictionary.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the dictionary doesn't match the one the stream was
+    /// compressed with, if it can't be installed at this point in the stream,
+    /// or if the compression backend doesn't support preset dictionaries.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) -> io::Result<u32> {
+        if !self.backend.supports_dictionary() {
+            return Err(no_dictionary())
+        }
+        match self.inner.raw.set_dictionary(dictionary) {
+            ReturnCode::Ok => Ok(adler32(dictionary)),
+            ReturnCode::DataError => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   "dictionary does not match the stream"))
+            }
+            _ => Err(bad_param("a dictionary can't be installed at this point \
+                                in the stream")),
         }
     }
 
@@ -391,6 +1529,66 @@ impl Decompress {
         }
     }
 
+    /// Skips input until the next point where the compressor used
+    /// `Flush::Full`, and restarts decompression from there.
+    ///
+    /// This is the equivalent of zlib's `inflateSync`, and is used to recover
+    /// from damaged data. It scans `input` for the empty stored block which a
+    /// full flush emits, consuming bytes (as reported by `total_in`) up to and
+    /// including it. Returns `true` if one was found, in which case
+    /// `decompress` can be called again with the rest of the input. Otherwise
+    /// all of `input` was consumed and `sync` should be called again with more
+    /// data; a partial marker at the end of `input` is remembered. So is one
+    /// at the end of the input consumed by the last call to `decompress`, as
+    /// the backend may have read part of a marker before noticing the damage
+    /// preceding it.
+    ///
+    /// Data decompressed after a restart can't be checked against the zlib or
+    /// gzip trailer, so those checks are skipped for the rest of the stream.
+    /// The trailer is still consumed, so `total_in` ends up just past the
+    /// stream as usual.
+    /// Nothing is ever found in a stream which wasn't compressed with full
+    /// flushes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend fails to restart the stream once a
+    /// marker has been found.
+    pub fn sync(&mut self, input: &[u8]) -> Result<bool, DataError> {
+        let mut matched = self.sync_matched;
+        let mut consumed = 0;
+        while consumed < input.len() && matched < 4 {
+            matched = sync_step(matched, input[consumed]);
+            consumed += 1;
+        }
+        self.inner.total_in += consumed as u64;
+        if matched < 4 {
+            self.sync_matched = matched;
+            return Ok(false)
+        }
+
+        self.sync_matched = 0;
+        let (total_in, total_out) = (self.inner.total_in, self.inner.total_out);
+        if let Err(e) = self._reset(false) {
+            return Err(self.setup_error(e))
+        }
+        self.inner.total_in = total_in;
+        self.inner.total_out = total_out;
+        if self.detected == DecompressFormat::Auto {
+            self.detected = DecompressFormat::Raw;
+        }
+        if self.detected == DecompressFormat::Zlib {
+            self.trailer_left = 4;
+            self.data_ended = false;
+        }
+        if self.gzip.state == GzipState::Header {
+            self.gzip.header.reset();
+        }
+        self.gzip.state = GzipState::Body;
+        self.gzip.check = false;
+        Ok(true)
+    }
+
     /// Performs the equivalent of replacing this decompression state with a
     /// freshly allocated copy.
     ///
@@ -398,72 +1596,609 @@ impl Decompress {
     /// previously existing resources.
     ///
     /// The argument provided here indicates whether the reset state will
-    /// attempt to decode a zlib header first or not.
+    /// attempt to decode a zlib header first or not. The window size and
+    /// dictionary this stream was created with are retained.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the backend fails to reset the stream or to reinstall the
+    /// dictionary. Use `try_reset` to get an error instead.
     pub fn reset(&mut self, zlib_header: bool) {
-        self._reset(zlib_header);
+        self.try_reset(zlib_header)
+            .expect("failed to reset decompression stream")
     }
 
-    #[cfg(feature = "zlib")]
-    fn _reset(&mut self, zlib_header: bool) {
-        let bits = if zlib_header {
-            ffi::MZ_DEFAULT_WINDOW_BITS
+    /// Like `reset`, but returns an error if the backend fails to reset the
+    /// stream or to reinstall the dictionary.
+    pub fn try_reset(&mut self, zlib_header: bool) -> io::Result<()> {
+        self.try_reset_format(if zlib_header {
+            DecompressFormat::Zlib
         } else {
-            -ffi::MZ_DEFAULT_WINDOW_BITS
-        };
-        unsafe {
-            ffi::inflateReset2(&mut *self.inner.stream_wrapper, bits);
+            DecompressFormat::Raw
+        })
+    }
+
+    /// Like `reset`, but the reset state will expect input in the given
+    /// format.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as `reset`. Use `try_reset_format` to
+    /// get an error instead.
+    pub fn reset_format(&mut self, format: DecompressFormat) {
+        self.try_reset_format(format)
+            .expect("failed to reset decompression stream")
+    }
+
+    /// Like `reset_format`, but returns an error if the backend fails to
+    /// reset the stream or to reinstall the dictionary.
+    pub fn try_reset_format(&mut self, format: DecompressFormat)
+                            -> io::Result<()> {
+        try!(self._reset(format == DecompressFormat::Zlib));
+        self.format = format;
+        self.detected = format;
+        self.gzip.reset();
+        self.sync_matched = 0;
+        self.trailer_left = 0;
+        self.data_ended = false;
+        self.zlib_start.truncate(0);
+        self.install_raw_dictionary()
+    }
+
+    /// Creates an independent copy of this decompressor, including all of its
+    /// internal state, similar to zlib's `inflateCopy`.
+    ///
+    /// This can be used to take a snapshot of the stream before feeding it
+    /// data speculatively, and to go back to that snapshot afterwards.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stream can't be duplicated, either because
+    /// memory ran out or because the backend doesn't support it (the
+    /// miniz_oxide backend doesn't).
+    ///
+    /// The copy is charged to the same memory limit as this stream, if any.
+    pub fn try_clone(&self) -> io::Result<Decompress> {
+        let raw = try!(self.inner.raw.try_clone());
+        Ok(Decompress {
+            inner: self.inner.with_raw(raw),
+            backend: self.backend,
+            format: self.format,
+            detected: self.detected,
+            gzip: self.gzip.clone(),
+            dictionary: self.dictionary.clone(),
+            sync_matched: self.sync_matched,
+            trailer_left: self.trailer_left,
+            data_ended: self.data_ended,
+            zlib_start: self.zlib_start.clone(),
+        })
+    }
+
+    // Raw deflate data needs its dictionary up front, whereas zlib streams ask
+    // for it once the header has been read.
+    fn install_raw_dictionary(&mut self) -> io::Result<()> {
+        match self.detected {
+            DecompressFormat::Raw | DecompressFormat::Gzip => {}
+            DecompressFormat::Zlib | DecompressFormat::Auto => return Ok(()),
         }
+        if let Some(dictionary) = self.dictionary.take() {
+            let ret = self.set_dictionary(&dictionary);
+            self.dictionary = Some(dictionary);
+            try!(ret);
+        }
+        Ok(())
+    }
+
+    fn _reset(&mut self, zlib_header: bool) -> io::Result<()> {
+        try!(reset_result(self.inner.raw.reset(zlib_header)));
         self.inner.total_out = 0;
         self.inner.total_in = 0;
+        Ok(())
     }
 
-    #[cfg(not(feature = "zlib"))]
-    fn _reset(&mut self, zlib_header: bool) {
-        *self = Decompress::new(zlib_header);
+    // Reports a failure to set the stream up for the detected format as
+    // corrupt input, since that's all `decompress` can return.
+    fn setup_error(&self, e: io::Error) -> DataError {
+        DataError::new(DataErrorKind::Other,
+                       Some(e.to_string()),
+                       self.inner.total_in)
+    }
+}
+
+impl ZlibHeader {
+    /// Returns the CMF byte, which holds the compression method and the
+    /// window size.
+    pub fn cmf(&self) -> u8 {
+        self.cmf
+    }
+
+    /// Returns the FLG byte, which holds the check bits, the FDICT flag and
+    /// the compression level.
+    pub fn flg(&self) -> u8 {
+        self.flg
+    }
+
+    /// Returns the base-2 logarithm of the window size, from 8 to 15.
+    pub fn window_bits(&self) -> u8 {
+        (self.cmf >> 4) + 8
+    }
+
+    /// Returns the size of the window the data was compressed with, in
+    /// bytes.
+    pub fn window_size(&self) -> usize {
+        1 << self.window_bits()
+    }
+
+    /// Returns the FLEVEL field, a hint about how the data was compressed:
+    /// 0 for the fastest compression, 1 for fast, 2 for the default and 3
+    /// for the best compression.
+    pub fn level(&self) -> u8 {
+        self.flg >> 6
+    }
+
+    /// Returns whether the FDICT flag is set, meaning a preset dictionary is
+    /// needed to decompress the data.
+    pub fn has_dictionary(&self) -> bool {
+        self.flg & 0x20 != 0
+    }
+
+    /// Returns the DICTID field, the Adler-32 checksum of the preset
+    /// dictionary, if the FDICT flag is set.
+    pub fn dictionary_id(&self) -> Option<u32> {
+        self.dictionary_id
+    }
+
+    /// Returns the length of the header, which is 2 bytes, or 6 with a
+    /// DICTID.
+    pub fn header_len(&self) -> usize {
+        if self.has_dictionary() { 6 } else { 2 }
+    }
+}
+
+/// Parses the zlib header at the start of `data`, without decompressing
+/// anything.
+///
+/// # Errors
+///
+/// Returns an error of kind `DataErrorKind::Truncated` if `data` ends before
+/// the header does, and of kind `DataErrorKind::Header` if the header is
+/// invalid, with the same message zlib would report.
+///
+/// # Examples
+///
+/// ```
+/// use flate2::parse_zlib_header;
+///
+/// let header = parse_zlib_header(&[0x78, 0x9c]).unwrap();
+/// assert_eq!(header.window_size(), 32 * 1024);
+/// assert_eq!(header.level(), 2);
+/// assert_eq!(header.dictionary_id(), None);
+/// assert!(parse_zlib_header(&[0x1f, 0x8b]).is_err());
+/// ```
+pub fn parse_zlib_header(data: &[u8]) -> Result<ZlibHeader, DataError> {
+    if data.len() < 2 {
+        return Err(DataError::new(DataErrorKind::Truncated, None,
+                                  data.len() as u64))
+    }
+    let (cmf, flg) = (data[0], data[1]);
+    let invalid = |msg: &str| {
+        Err(DataError::new(DataErrorKind::Header, Some(msg.to_string()), 0))
+    };
+    if ((cmf as u16) << 8 | flg as u16) % 31 != 0 {
+        return invalid("incorrect header check")
+    }
+    if cmf & 0x0f != 8 {
+        return invalid("unknown compression method")
+    }
+    if cmf >> 4 > 7 {
+        return invalid("invalid window size")
+    }
+    let dictionary_id = if flg & 0x20 != 0 {
+        if data.len() < 6 {
+            return Err(DataError::new(DataErrorKind::Truncated, None,
+                                      data.len() as u64))
+        }
+        Some(data[2..6].iter().fold(0, |n, &b| n << 8 | b as u32))
+    } else {
+        None
+    };
+    Ok(ZlibHeader {
+        cmf: cmf,
+        flg: flg,
+        dictionary_id: dictionary_id,
+    })
+}
+
+/// Compresses all of `data` in the given format, returning the compressed
+/// bytes.
+///
+/// The output buffer is grown as needed, so this is the equivalent of feeding
+/// `data` to a `Compress` stream with `Flush::Finish` until it returns
+/// `Status::StreamEnd`.
+///
+/// # Examples
+///
+/// ```
+/// use flate2::{compress_to_vec, decompress_to_vec};
+/// use flate2::{Compression, DecompressFormat, Format};
+///
+/// let data = b"hello, hello, hello, hello!";
+/// let compressed = compress_to_vec(data, Format::Gzip, Compression::best())
+///     .unwrap();
+/// let decompressed = decompress_to_vec(&compressed, DecompressFormat::Gzip,
+///                                      Some(1024)).unwrap();
+/// assert_eq!(decompressed, &data[..]);
+/// ```
+///
+/// # Errors
+///
+/// Returns an error if the compression stream can't be created or the
+/// backend runs out of memory.
+pub fn compress_to_vec(data: &[u8], format: Format, level: Compression)
+                       -> Result<Vec<u8>, CompressError> {
+    let mut c = match Compress::try_new_with_format(level, format) {
+        Ok(c) => c,
+        Err(e) => {
+            return Err(CompressError::new(CompressErrorKind::Other,
+                                          Some(e.to_string())))
+        }
+    };
+    let bound = Compress::bound(data.len(), format);
+    let mut output = Vec::with_capacity(cmp::min(bound, data.len() / 2 + 64));
+    loop {
+        let input = &data[c.total_in() as usize..];
+        let status = try!(c.compress_vec(input, &mut output, Flush::Finish));
+        if status == Status::StreamEnd {
+            return Ok(output)
+        }
+        let len = output.len();
+        output.reserve(cmp::max(len, 64));
+    }
+}
+
+/// Decompresses a complete stream in the given format from `data`, returning
+/// the decompressed bytes.
+///
+/// The output buffer is grown as needed, up to `max_output` bytes if a limit
+/// is given. Anything following the end of the compressed stream is ignored.
+///
+/// # Errors
+///
+/// Returns an error if the input is invalid, with the kind
+/// `DataErrorKind::Truncated` if it ends before the compressed stream does,
+/// and with the kind `DataErrorKind::OutputLimit` if the decompressed data
+/// would be larger than `max_output`. Streams which need a preset dictionary
+/// are reported as errors of kind `DataErrorKind::Other`.
+pub fn decompress_to_vec(data: &[u8],
+                         format: DecompressFormat,
+                         max_output: Option<usize>)
+                         -> Result<Vec<u8>, DataError> {
+    let mut d = match Decompress::try_new_with_format(format) {
+        Ok(d) => d,
+        Err(e) => {
+            return Err(DataError::new(DataErrorKind::Other,
+                                      Some(e.to_string()),
+                                      0))
+        }
+    };
+    let limit = max_output.unwrap_or(usize::max_value());
+    // Room for one byte more than the limit tells apart output that exactly
+    // fits from output that is too large.
+    let initial = data.len().saturating_mul(2).saturating_add(64);
+    let mut output = Vec::with_capacity(cmp::min(initial,
+                                                 limit.saturating_add(1)));
+    loop {
+        let input = &data[d.total_in() as usize..];
+        let (len, total_in) = (output.len(), d.total_in());
+        // miniz can't resume after `Flush::Finish` runs out of output space,
+        // so truncation is detected here instead.
+        match try!(d.decompress_vec(input, &mut output, Flush::None)) {
+            Status::StreamEnd if output.len() <= limit => return Ok(output),
+            Status::NeedDictionary(_) => {
+                return Err(DataError::new(DataErrorKind::Other,
+                                          Some("a preset dictionary is \
+                                                needed".to_string()),
+                                          d.total_in()))
+            }
+            _ => {}
+        }
+        if output.len() > limit {
+            return Err(DataError::new(DataErrorKind::OutputLimit,
+                                      None,
+                                      d.total_in()))
+        }
+        if output.len() == len && d.total_in() == total_in &&
+           output.len() < output.capacity() {
+            return Err(DataError::new(DataErrorKind::Truncated,
+                                      None,
+                                      d.total_in()))
+        }
+        if output.len() == output.capacity() {
+            let room = limit.saturating_add(1) - output.len();
+            let len = output.len();
+            output.reserve_exact(cmp::min(cmp::max(len, 64), room));
+        }
+    }
+}
+
+impl DataError {
+    fn new(kind: DataErrorKind, message: Option<String>, offset: u64)
+           -> DataError {
+        DataError {
+            kind: kind,
+            message: message,
+            offset: offset,
+        }
+    }
+
+    fn from_message(message: Option<String>, offset: u64) -> DataError {
+        let kind = match message {
+            Some(ref msg) => DataErrorKind::from_message(msg),
+            None => DataErrorKind::Other,
+        };
+        DataError::new(kind, message, offset)
+    }
+
+    /// Returns the kind of problem that was found in the input.
+    pub fn kind(&self) -> DataErrorKind {
+        self.kind
+    }
+
+    /// Returns the message the backend reported for this error, if any.
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_ref().map(|s| &s[..])
+    }
+
+    /// Returns the offset into the compressed stream, in bytes, at which the
+    /// problem was found.
+    ///
+    /// This counts all input consumed by the `Decompress` object since it was
+    /// created or last reset.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // The backend's message if there is one, and otherwise a description of
+    // the kind of error.
+    fn detail(&self) -> &str {
+        match self.message {
+            Some(ref msg) => msg,
+            None => self.kind.description(),
+        }
+    }
+}
+
+impl DataErrorKind {
+    // Both backends report zlib's messages, so classify errors by those.
+    fn from_message(msg: &str) -> DataErrorKind {
+        match msg {
+            "incorrect header check" |
+            "unknown compression method" |
+            "invalid window size" |
+            "unknown header flags set" |
+            "header crc mismatch" => DataErrorKind::Header,
+            "invalid block type" => DataErrorKind::BlockType,
+            "invalid stored block lengths" => DataErrorKind::StoredLength,
+            "too many length or distance symbols" |
+            "invalid code lengths set" |
+            "invalid bit length repeat" |
+            "invalid literal/lengths set" |
+            "invalid distances set" |
+            "invalid code -- missing end-of-block" => {
+                DataErrorKind::CodeLengths
+            }
+            "invalid literal/length code" |
+            "invalid distance code" => DataErrorKind::Code,
+            "invalid distance too far back" => DataErrorKind::Distance,
+            "incorrect data check" |
+            "incorrect length check" => DataErrorKind::Checksum,
+            "unexpected end of stream" => DataErrorKind::Truncated,
+            _ => DataErrorKind::Other,
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match *self {
+            DataErrorKind::Header => "invalid zlib header",
+            DataErrorKind::BlockType => "invalid block type",
+            DataErrorKind::StoredLength => "invalid stored block lengths",
+            DataErrorKind::CodeLengths => "invalid huffman code lengths",
+            DataErrorKind::Code => "invalid literal/length or distance code",
+            DataErrorKind::Distance => "invalid distance too far back",
+            DataErrorKind::Checksum => "checksum mismatch",
+            DataErrorKind::Truncated => "unexpected end of stream",
+            DataErrorKind::Memory => "out of memory",
+            DataErrorKind::OutputLimit => "output size limit exceeded",
+            DataErrorKind::Other => "deflate data error",
+        }
     }
 }
 
 impl Error for DataError {
-    fn description(&self) -> &str { "deflate data error" }
+    fn description(&self) -> &str {
+        self.detail()
+    }
 }
 
 impl From<DataError> for io::Error {
     fn from(data: DataError) -> io::Error {
-        io::Error::new(io::ErrorKind::Other, data)
+        io::Error::new(io::ErrorKind::InvalidData, data)
     }
 }
 
 impl fmt::Display for DataError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.description().fmt(f)
+        write!(f, "corrupt deflate stream: {} at byte {}",
+               self.detail(), self.offset)
+    }
+}
+
+impl CompressError {
+    fn new(kind: CompressErrorKind, message: Option<String>) -> CompressError {
+        CompressError {
+            kind: kind,
+            message: message,
+        }
+    }
+
+    /// Returns the kind of failure that occurred.
+    pub fn kind(&self) -> CompressErrorKind {
+        self.kind
+    }
+
+    /// Returns the message the backend reported for this error, if any.
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_ref().map(|s| &s[..])
+    }
+
+    // The backend's message if there is one, and otherwise a description of
+    // the kind of error.
+    fn detail(&self) -> &str {
+        if let Some(ref msg) = self.message {
+            return msg
+        }
+        match self.kind {
+            CompressErrorKind::Stream => "invalid use of compression stream",
+            CompressErrorKind::Memory => "out of memory",
+            CompressErrorKind::Other => "deflate compression error",
+        }
     }
 }
 
-impl Direction for DirCompress {
-    unsafe fn destroy(stream: *mut ffi::mz_stream) -> c_int {
-        ffi::mz_deflateEnd(stream)
+impl Error for CompressError {
+    fn description(&self) -> &str {
+        self.detail()
     }
 }
-impl Direction for DirDecompress {
-    unsafe fn destroy(stream: *mut ffi::mz_stream) -> c_int {
-        ffi::mz_inflateEnd(stream)
+
+impl From<CompressError> for io::Error {
+    fn from(data: CompressError) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, data)
     }
 }
 
-impl<D: Direction> Drop for Stream<D> {
-    fn drop(&mut self) {
-        unsafe {
-            let _ = D::destroy(&mut *self.stream_wrapper);
+impl fmt::Display for CompressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "compression failed: {}", self.detail())
+    }
+}
+
+impl GzipEncoder {
+    fn new(header: Vec<u8>) -> GzipEncoder {
+        GzipEncoder {
+            pending: header.clone(),
+            header: header,
+            crc: Crc::new(),
+            finished: false,
         }
     }
+
+    fn reset(&mut self) {
+        self.pending = self.header.clone();
+        self.crc.reset();
+        self.finished = false;
+    }
+
+    fn finish(&mut self) {
+        let (sum, amt) = (self.crc.sum(), self.crc.amount() as u32);
+        self.pending.extend(&[(sum >> 0) as u8,
+                              (sum >> 8) as u8,
+                              (sum >> 16) as u8,
+                              (sum >> 24) as u8,
+                              (amt >> 0) as u8,
+                              (amt >> 8) as u8,
+                              (amt >> 16) as u8,
+                              (amt >> 24) as u8]);
+        self.finished = true;
+    }
+
+    fn write_pending(&mut self, output: &mut [u8]) -> usize {
+        let n = cmp::min(self.pending.len(), output.len());
+        output[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        n
+    }
+}
+
+impl GzipDecoder {
+    fn new() -> GzipDecoder {
+        GzipDecoder {
+            state: GzipState::Header,
+            header: gz::HeaderParser::new(),
+            buf: Vec::new(),
+            crc: Crc::new(),
+            check: true,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = GzipState::Header;
+        self.header.reset();
+        self.buf.truncate(0);
+        self.crc.reset();
+        self.check = true;
+    }
+
+    // Consumes header bytes from `input`, returning how many were used.
+    fn read_header(&mut self, input: &[u8], offset: u64)
+                   -> Result<usize, DataError> {
+        match self.header.parse(input) {
+            Ok(n) => {
+                if self.header.is_done() {
+                    self.header.reset();
+                    self.state = GzipState::Body;
+                }
+                Ok(n)
+            }
+            Err(msg) => {
+                Err(DataError::new(DataErrorKind::Header,
+                                   Some(msg.to_string()),
+                                   offset))
+            }
+        }
+    }
+
+    // Consumes trailer bytes from `input`, returning how many were used.
+    fn read_trailer(&mut self, input: &[u8], offset: u64)
+                    -> Result<usize, DataError> {
+        let n = cmp::min(8 - self.buf.len(), input.len());
+        self.buf.extend_from_slice(&input[..n]);
+        if self.buf.len() < 8 {
+            return Ok(n)
+        }
+        let b = &self.buf;
+        let crc = (b[0] as u32) | (b[1] as u32) << 8 |
+                  (b[2] as u32) << 16 | (b[3] as u32) << 24;
+        let amt = (b[4] as u32) | (b[5] as u32) << 8 |
+                  (b[6] as u32) << 16 | (b[7] as u32) << 24;
+        let msg = if !self.check {
+            self.state = GzipState::Done;
+            return Ok(n)
+        } else if crc != self.crc.sum() {
+            "incorrect data check"
+        } else if amt != self.crc.amount() as u32 {
+            "incorrect length check"
+        } else {
+            self.state = GzipState::Done;
+            return Ok(n)
+        };
+        Err(DataError::new(DataErrorKind::Checksum,
+                           Some(msg.to_string()),
+                           offset + n as u64))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Write;
+    use std::cmp;
+    use std::io::{self, Read, Write};
 
-    use write;
-    use {Compression, Decompress, Flush};
+    use {backend, read, write};
+    use {Compression, Compress, Decompress, Flush, Status};
+    use {DecompressFormat, Format};
+    use {DataError, DataErrorKind};
+    use {compress_to_vec, decompress_to_vec, parse_zlib_header};
+    use {CompressBuilder, DecompressBuilder, MemoryLimit, Strategy};
 
     #[test]
     fn issue51() {
@@ -498,7 +2233,7 @@ mod tests {
         let mut zlib = Vec::new();
         let mut deflate = Vec::new();
 
-        let comp = Compression::Default;
+        let comp = Compression::default();
         write::ZlibEncoder::new(&mut zlib, comp).write_all(string).unwrap();
         write::DeflateEncoder::new(&mut deflate, comp).write_all(string).unwrap();
 
@@ -513,4 +2248,923 @@ mod tests {
         assert_eq!(decoder.total_out(), string.len() as u64);
         assert!(dst.starts_with(string));
     }
+
+    #[test]
+    fn try_reset() {
+        let mut c = Compress::try_new(Compression::default(), true).unwrap();
+        let mut zlib = Vec::with_capacity(1024);
+        c.compress_vec(b"garbage", &mut zlib, Flush::Sync).unwrap();
+        c.try_reset().unwrap();
+        zlib.truncate(0);
+        c.compress_vec(b"hello", &mut zlib, Flush::Finish).unwrap();
+
+        let mut d = Decompress::try_new_with_format(DecompressFormat::Raw)
+            .unwrap();
+        let mut out = Vec::with_capacity(1024);
+        assert!(d.decompress_vec(&zlib, &mut out, Flush::Finish).is_err());
+        d.try_reset_format(DecompressFormat::Auto).unwrap();
+        out.truncate(0);
+        d.decompress_vec(&zlib, &mut out, Flush::Finish).unwrap();
+        assert_eq!(out, b"hello");
+    }
+
+    #[test]
+    fn strategies() {
+        let string = "hello hello hello world".repeat(100);
+        let strategies = [Strategy::Default,
+                          Strategy::Filtered,
+                          Strategy::HuffmanOnly,
+                          Strategy::Rle,
+                          Strategy::Fixed];
+        for &strategy in strategies.iter() {
+            let builder = CompressBuilder::new(Compression::default())
+                .strategy(strategy);
+            let mut c = builder.build(true).unwrap();
+            let mut compressed = Vec::with_capacity(string.len() * 2);
+            c.compress_vec(string.as_bytes(), &mut compressed, Flush::Finish)
+                .unwrap();
+
+            let mut d = Decompress::new(true);
+            let mut out = Vec::with_capacity(string.len());
+            d.decompress_vec(&compressed, &mut out, Flush::Finish).unwrap();
+            assert_eq!(out, string.as_bytes());
+        }
+    }
+
+    #[test]
+    fn bad_params() {
+        let c = CompressBuilder::new(Compression::default());
+        assert!(c.clone().window_bits(8).build(true).is_err());
+        assert!(c.clone().window_bits(16).build(true).is_err());
+        assert!(c.clone().mem_level(0).build(true).is_err());
+        assert!(c.clone().mem_level(10).build(true).is_err());
+        assert!(DecompressBuilder::new().window_bits(8).build(true).is_err());
+    }
+
+    #[cfg(feature = "zlib")]
+    #[test]
+    fn small_window() {
+        let string = "hello world".repeat(1000);
+        let mut c = CompressBuilder::new(Compression::best())
+            .window_bits(9)
+            .mem_level(1)
+            .build(false)
+            .unwrap();
+        let mut compressed = Vec::with_capacity(string.len());
+        c.compress_vec(string.as_bytes(), &mut compressed, Flush::Finish)
+            .unwrap();
+
+        let builder = DecompressBuilder::new().window_bits(9);
+        let mut d = builder.build(false).unwrap();
+        let mut out = Vec::with_capacity(string.len());
+        d.decompress_vec(&compressed, &mut out, Flush::Finish).unwrap();
+        assert_eq!(out, string.as_bytes());
+    }
+
+    #[cfg(not(feature = "zlib"))]
+    #[test]
+    fn miniz_unsupported_params() {
+        let level = Compression::default();
+        let err = CompressBuilder::new(level).window_bits(9).build(true);
+        assert!(err.is_err());
+        let err = CompressBuilder::new(level).mem_level(8).build(true);
+        assert!(err.is_err());
+        let err = DecompressBuilder::new().window_bits(12).build(true);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn data_errors() {
+        fn error(input: &[u8], zlib_header: bool) -> DataError {
+            let mut d = Decompress::new(zlib_header);
+            let mut out = Vec::with_capacity(1024);
+            d.decompress_vec(input, &mut out, Flush::Finish).unwrap_err()
+        }
+
+        let mut c = Compress::new(Compression::default(), true);
+        let mut data = Vec::with_capacity(1024);
+        c.compress_vec(b"hello world", &mut data, Flush::Finish).unwrap();
+
+        let err = error(&[0x78, 0x00], true);
+        assert_eq!(err.kind(), DataErrorKind::Header);
+        assert_eq!(err.message(), Some("incorrect header check"));
+
+        // miniz_oxide doesn't say what was wrong with a deflate block.
+        if !cfg!(all(feature = "rust_backend", not(feature = "zlib"))) {
+            let err = error(&[0x07, 0x00], false);
+            assert_eq!(err.kind(), DataErrorKind::BlockType);
+            assert_eq!(err.message(), Some("invalid block type"));
+
+            let err = error(&[0x01, 0x05, 0x00, 0x05, 0x00], false);
+            assert_eq!(err.kind(), DataErrorKind::StoredLength);
+        }
+
+        let err = error(&data[..data.len() - 5], true);
+        assert_eq!(err.kind(), DataErrorKind::Truncated);
+
+        let mut bad_check = data.clone();
+        *bad_check.last_mut().unwrap() ^= 1;
+        let err = error(&bad_check, true);
+        assert_eq!(err.kind(), DataErrorKind::Checksum);
+        assert_eq!(err.offset(), data.len() as u64);
+
+        let err = io::Error::from(err);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let inner = err.get_ref().unwrap().downcast_ref::<DataError>();
+        assert_eq!(inner.unwrap().kind(), DataErrorKind::Checksum);
+        assert!(err.to_string().contains("incorrect data check"));
+    }
+
+    #[cfg(feature = "zlib")]
+    #[test]
+    fn compress_errors() {
+        use CompressErrorKind;
+
+        let mut c = Compress::new(Compression::default(), true);
+        let mut out = Vec::with_capacity(1024);
+        let status = c.compress_vec(b"hello", &mut out, Flush::Finish);
+        assert_eq!(status.unwrap(), Status::StreamEnd);
+
+        // zlib refuses to accept more data once the stream is finished.
+        let err = c.compress_vec(b"world", &mut out, Flush::None).unwrap_err();
+        assert_eq!(err.kind(), CompressErrorKind::Stream);
+        let err = io::Error::from(err);
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+
+        let mut w = write::ZlibEncoder::new(Vec::new(), Compression::default());
+        w.write_all(b"hello").unwrap();
+        w.try_finish().unwrap();
+        assert!(w.write(b"world").is_err());
+        assert!(w.flush().is_err());
+    }
+
+    #[test]
+    fn set_params() {
+        let text = "hello world, hello world".repeat(100);
+        let noise = (0..4096u32).map(|i| (i * 7919 >> 3) as u8)
+                                .collect::<Vec<_>>();
+
+        let mut expected = text.clone().into_bytes();
+        expected.extend(&noise);
+        expected.extend(text.as_bytes());
+
+        for &b in backend::tests::backends().iter() {
+            let mut c = CompressBuilder::new(Compression::fast()).backend(b)
+                .build(true)
+                .unwrap();
+            let mut compressed = Vec::with_capacity(16 * 1024);
+            c.compress_vec(text.as_bytes(), &mut compressed, Flush::None)
+                .unwrap();
+
+            // Pending input isn't flushed for us.
+            let err = c.set_params(Compression::none(), Strategy::Default)
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{:?}", b);
+
+            // A flush which runs out of room is finished by repeating it.
+            let mut out = [0; 8];
+            loop {
+                let before = c.total_out();
+                c.compress(&[], &mut out, Flush::Sync).unwrap();
+                let n = (c.total_out() - before) as usize;
+                compressed.extend_from_slice(&out[..n]);
+                if n < out.len() {
+                    break
+                }
+            }
+            c.set_params(Compression::none(), Strategy::Default).unwrap();
+            c.compress_vec(&noise, &mut compressed, Flush::Sync).unwrap();
+            c.set_params(Compression::best(), Strategy::Filtered).unwrap();
+            c.compress_vec(text.as_bytes(), &mut compressed, Flush::Finish)
+                .unwrap();
+            assert!(c.set_params(Compression::best(), Strategy::Default)
+                     .is_err());
+
+            let mut d = Decompress::new(true);
+            let mut out = Vec::with_capacity(expected.len());
+            d.decompress_vec(&compressed, &mut out, Flush::Finish).unwrap();
+            assert!(out == expected, "{:?}", b);
+        }
+    }
+
+    #[test]
+    fn zlib_dictionary() {
+        let dict = b"{\"name\": \"value\", \"items\": []}";
+        let msg = b"{\"name\": \"flate2\", \"items\": [1, 2, 3]}";
+
+        let mut c = Compress::new(Compression::default(), true);
+        let id = c.set_dictionary(dict).unwrap();
+        let mut compressed = Vec::with_capacity(1024);
+        c.compress_vec(msg, &mut compressed, Flush::Finish).unwrap();
+
+        // Without a dictionary inflate stops and asks for the right one.
+        let mut d = Decompress::new(true);
+        let mut out = Vec::with_capacity(1024);
+        let status = d.decompress_vec(&compressed, &mut out, Flush::Finish);
+        assert_eq!(status.unwrap(), Status::NeedDictionary(id));
+        assert!(d.set_dictionary(b"wrong").is_err());
+        assert_eq!(d.set_dictionary(dict).unwrap(), id);
+        let consumed = d.total_in() as usize;
+        let status = d.decompress_vec(&compressed[consumed..], &mut out,
+                                      Flush::Finish);
+        assert_eq!(status.unwrap(), Status::StreamEnd);
+        assert_eq!(out, &msg[..]);
+
+        // With a dictionary configured it is installed automatically, even
+        // after a reset.
+        let mut d = DecompressBuilder::new().dictionary(&dict[..])
+            .build(true)
+            .unwrap();
+        for _ in 0..2 {
+            let mut out = Vec::with_capacity(1024);
+            let status = d.decompress_vec(&compressed, &mut out, Flush::Finish);
+            assert_eq!(status.unwrap(), Status::StreamEnd);
+            assert_eq!(out, &msg[..]);
+            d.reset(true);
+        }
+    }
+
+    #[test]
+    fn raw_dictionary() {
+        let dict = b"hello world";
+        let msg = b"hello world, hello world";
+
+        let mut c = CompressBuilder::new(Compression::default())
+            .dictionary(&dict[..])
+            .build(false)
+            .unwrap();
+        let mut compressed = Vec::with_capacity(1024);
+        c.compress_vec(msg, &mut compressed, Flush::Finish).unwrap();
+
+        // The dictionary is installed again after a reset.
+        c.reset();
+        let mut again = Vec::with_capacity(1024);
+        c.compress_vec(msg, &mut again, Flush::Finish).unwrap();
+        assert_eq!(compressed, again);
+
+        let mut d = DecompressBuilder::new().dictionary(&dict[..])
+            .build(false)
+            .unwrap();
+        for _ in 0..2 {
+            let mut out = Vec::with_capacity(1024);
+            let status = d.decompress_vec(&compressed, &mut out, Flush::Finish);
+            assert_eq!(status.unwrap(), Status::StreamEnd);
+            assert_eq!(out, &msg[..]);
+            d.reset(false);
+        }
+    }
+
+    #[test]
+    fn dictionary_out_of_place() {
+        // A zlib stream's dictionary has to come before any data is written,
+        // and only once it's been asked for when reading.
+        let mut c = Compress::new(Compression::default(), true);
+        let mut out = Vec::with_capacity(1024);
+        c.compress_vec(b"hello", &mut out, Flush::Sync).unwrap();
+        assert!(c.set_dictionary(b"hello").is_err());
+        let mut d = Decompress::new(true);
+        assert!(d.set_dictionary(b"hello").is_err());
+    }
+
+    fn compress_all(c: &mut Compress, input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len() + 64);
+        c.compress_vec(input, &mut out, Flush::Finish).unwrap();
+        out
+    }
+
+    #[test]
+    fn gzip_format() {
+        let input = b"hello hello hello gzip";
+        let mut c = Compress::new_with_format(Compression::default(),
+                                              Format::Gzip);
+        let gz = compress_all(&mut c, input);
+        assert_eq!(c.total_out(), gz.len() as u64);
+        assert_eq!(&gz[..2], &[0x1f, 0x8b]);
+
+        let mut s = Vec::new();
+        read::GzDecoder::new(&gz[..]).unwrap().read_to_end(&mut s).unwrap();
+        assert_eq!(s, &input[..]);
+
+        let mut e = write::GzEncoder::new(Vec::new(), Compression::fast());
+        e.write_all(input).unwrap();
+        let theirs = e.finish().unwrap();
+        let mut d = Decompress::new_with_format(DecompressFormat::Gzip);
+        let mut out = Vec::with_capacity(input.len());
+        let status = d.decompress_vec(&theirs, &mut out, Flush::Finish);
+        assert_eq!(status.unwrap(), Status::StreamEnd);
+        assert_eq!(out, &input[..]);
+        assert_eq!(d.total_in(), theirs.len() as u64);
+
+        // one byte at a time, including the header and trailer
+        let mut d = Decompress::new_with_format(DecompressFormat::Gzip);
+        let mut out = Vec::with_capacity(input.len());
+        for (i, byte) in gz.iter().enumerate() {
+            let flush = if i + 1 == gz.len() {Flush::Finish} else {Flush::None};
+            d.decompress_vec(&[*byte], &mut out, flush).unwrap();
+        }
+        assert_eq!(out, &input[..]);
+        assert_eq!(d.total_in(), gz.len() as u64);
+
+        // and with optional header fields split up as well
+        let mut e = ::GzBuilder::new()
+            .extra(&b"extra"[..])
+            .filename("name")
+            .comment("comment")
+            .write(Vec::new(), Compression::fast());
+        e.write_all(input).unwrap();
+        let theirs = e.finish().unwrap();
+        let mut d = Decompress::new_with_format(DecompressFormat::Gzip);
+        let mut out = Vec::with_capacity(input.len());
+        for chunk in theirs.chunks(3) {
+            d.decompress_vec(chunk, &mut out, Flush::None).unwrap();
+        }
+        assert_eq!(out, &input[..]);
+        assert_eq!(d.total_in(), theirs.len() as u64);
+
+        c.reset();
+        assert_eq!(compress_all(&mut c, input), gz);
+    }
+
+    #[test]
+    fn gzip_format_small_output() {
+        let input = b"hello hello hello gzip";
+        let mut c = Compress::new_with_format(Compression::default(),
+                                              Format::Gzip);
+        let mut gz = Vec::new();
+        loop {
+            let mut buf = [0; 3];
+            let rest = &input[c.total_in() as usize..];
+            let status = c.compress(rest, &mut buf, Flush::Finish).unwrap();
+            let n = (c.total_out() - gz.len() as u64) as usize;
+            gz.extend_from_slice(&buf[..n]);
+            if status == Status::StreamEnd {
+                break
+            }
+        }
+        let mut s = Vec::new();
+        read::GzDecoder::new(&gz[..]).unwrap().read_to_end(&mut s).unwrap();
+        assert_eq!(s, &input[..]);
+    }
+
+    #[test]
+    fn gzip_format_errors() {
+        let input = b"hello hello hello gzip";
+        let mut c = Compress::new_with_format(Compression::default(),
+                                              Format::Gzip);
+        let gz = compress_all(&mut c, input);
+        let decode = |data: &[u8]| {
+            let mut d = Decompress::new_with_format(DecompressFormat::Gzip);
+            let mut out = Vec::with_capacity(64);
+            d.decompress_vec(data, &mut out, Flush::Finish)
+        };
+
+        let mut bad = gz.clone();
+        let n = bad.len();
+        bad[n - 8] ^= 1;
+        let err = decode(&bad).unwrap_err();
+        assert_eq!(err.kind(), DataErrorKind::Checksum);
+        assert_eq!(err.message(), Some("incorrect data check"));
+
+        let mut bad = gz.clone();
+        bad[n - 1] ^= 1;
+        let err = decode(&bad).unwrap_err();
+        assert_eq!(err.message(), Some("incorrect length check"));
+        assert_eq!(err.offset(), n as u64);
+
+        let mut bad = gz.clone();
+        bad[2] = 7;
+        let err = decode(&bad).unwrap_err();
+        assert_eq!(err.kind(), DataErrorKind::Header);
+        assert_eq!(err.message(), Some("unknown compression method"));
+
+        let err = decode(&gz[..5]).unwrap_err();
+        assert_eq!(err.kind(), DataErrorKind::Truncated);
+        let err = decode(&gz[..n - 3]).unwrap_err();
+        assert_eq!(err.kind(), DataErrorKind::Truncated);
+    }
+
+    #[test]
+    fn auto_format() {
+        let input = b"hello hello hello auto";
+        for &format in [Format::Zlib, Format::Gzip].iter() {
+            let mut c = Compress::new_with_format(Compression::default(),
+                                                  format);
+            let data = compress_all(&mut c, input);
+
+            let mut d = Decompress::new_with_format(DecompressFormat::Auto);
+            assert_eq!(d.format(), None);
+            let mut out = Vec::with_capacity(input.len());
+            let status = d.decompress_vec(&data, &mut out, Flush::Finish);
+            assert_eq!(status.unwrap(), Status::StreamEnd);
+            assert_eq!(out, &input[..]);
+            assert_eq!(d.format(), Some(format));
+
+            d.reset_format(DecompressFormat::Auto);
+            assert_eq!(d.format(), None);
+            let mut out = Vec::with_capacity(input.len());
+            d.decompress_vec(&data, &mut out, Flush::Finish).unwrap();
+            assert_eq!(out, &input[..]);
+        }
+    }
+
+    // Compresses three parts separated by full flushes, then damages the
+    // block after the first flush point by giving it an invalid block type.
+    fn damaged_stream(format: Format) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let parts: [&[u8]; 3] = [b"one one one one ", b"two two two two ",
+                                 b"three three three "];
+        let mut c = Compress::new_with_format(Compression::default(), format);
+        let mut data = Vec::with_capacity(1024);
+        let mut flush_point = 0;
+        for (i, part) in parts.iter().enumerate() {
+            let flush = if i == 2 {Flush::Finish} else {Flush::Full};
+            c.compress_vec(part, &mut data, flush).unwrap();
+            if i == 0 {
+                flush_point = data.len();
+            }
+        }
+        assert_eq!(&data[flush_point - 4..flush_point], &[0, 0, 0xff, 0xff]);
+        data[flush_point] = 0x06;
+        (data, parts[0].to_vec(), parts[2].to_vec())
+    }
+
+    #[test]
+    fn sync() {
+        let (data, first, last) = damaged_stream(Format::Raw);
+        let mut d = Decompress::new(false);
+        let mut out = Vec::with_capacity(1024);
+        let err = d.decompress_vec(&data, &mut out, Flush::Finish).unwrap_err();
+        if !cfg!(all(feature = "rust_backend", not(feature = "zlib"))) {
+            assert_eq!(err.kind(), DataErrorKind::BlockType);
+        }
+        assert_eq!(out, first);
+
+        // feed the rest a byte at a time to split up the marker
+        let mut pos = d.total_in() as usize;
+        while !d.sync(&data[pos..pos + 1]).unwrap() {
+            pos += 1;
+        }
+        let rest = &data[d.total_in() as usize..];
+        let mut tail = Vec::with_capacity(1024);
+        let status = d.decompress_vec(rest, &mut tail, Flush::Finish);
+        assert_eq!(status.unwrap(), Status::StreamEnd);
+        assert_eq!(tail, last);
+        assert_eq!(d.total_in(), data.len() as u64);
+        assert_eq!(d.total_out(), (first.len() + last.len()) as u64);
+
+        // the gzip trailer can't be verified after skipping data
+        let (data, _, last) = damaged_stream(Format::Gzip);
+        let mut d = Decompress::new_with_format(DecompressFormat::Gzip);
+        let mut out = Vec::with_capacity(1024);
+        assert!(d.decompress_vec(&data, &mut out, Flush::Finish).is_err());
+        assert!(d.sync(&data[d.total_in() as usize..]).unwrap());
+        let rest = &data[d.total_in() as usize..];
+        let mut tail = Vec::with_capacity(1024);
+        let status = d.decompress_vec(rest, &mut tail, Flush::Finish);
+        assert_eq!(status.unwrap(), Status::StreamEnd);
+        assert_eq!(tail, last);
+
+        // a zlib trailer is skipped unchecked, even when split up
+        let (data, _, last) = damaged_stream(Format::Zlib);
+        let mut d = Decompress::new(true);
+        let mut out = Vec::with_capacity(1024);
+        assert!(d.decompress_vec(&data, &mut out, Flush::Finish).is_err());
+        assert!(d.sync(&data[d.total_in() as usize..]).unwrap());
+        let rest = &data[d.total_in() as usize..data.len() - 2];
+        let mut tail = Vec::with_capacity(1024);
+        let status = d.decompress_vec(rest, &mut tail, Flush::None);
+        assert_eq!(status.unwrap(), Status::Ok);
+        assert_eq!(tail, last);
+        assert_eq!(d.total_in(), data.len() as u64 - 2);
+        let rest = &data[data.len() - 2..];
+        let status = d.decompress_vec(rest, &mut tail, Flush::Finish);
+        assert_eq!(status.unwrap(), Status::StreamEnd);
+        assert_eq!(d.total_in(), data.len() as u64);
+
+        let mut d = Decompress::new(false);
+        assert!(!d.sync(b"no flush points here").unwrap());
+        assert_eq!(d.total_in(), 20);
+    }
+
+    #[test]
+    fn sync_after_partial_marker() {
+        for &b in backend::tests::backends().iter() {
+            let mut c = CompressBuilder::new(Compression::default())
+                .backend(b)
+                .build(false)
+                .unwrap();
+            let mut data = Vec::with_capacity(1024);
+            c.compress_vec(b"one one one", &mut data, Flush::Full).unwrap();
+            let first = data.len();
+            // a stored block whose length doesn't match its complement, the
+            // last two bytes of which start the marker of a full flush
+            data.extend_from_slice(&[0, 0, 0, 0, 0, 0xff, 0xff]);
+            let marker_end = data.len();
+            let mut c = CompressBuilder::new(Compression::default())
+                .backend(b)
+                .build(false)
+                .unwrap();
+            c.compress_vec(b"two two two", &mut data, Flush::Finish).unwrap();
+
+            let mut d = DecompressBuilder::new()
+                .backend(b)
+                .build(false)
+                .unwrap();
+            let mut out = Vec::with_capacity(1024);
+            let err = d.decompress_vec(&data[..first + 5], &mut out,
+                                       Flush::None).unwrap_err();
+            assert_eq!(out, b"one one one");
+            if err.kind() != DataErrorKind::Other {
+                assert_eq!(err.kind(), DataErrorKind::StoredLength);
+            }
+            let pos = d.total_in() as usize;
+            assert!(d.sync(&data[pos..]).unwrap(), "{:?}", b);
+            assert_eq!(d.total_in(), marker_end as u64, "{:?}", b);
+            let mut tail = Vec::with_capacity(1024);
+            let status = d.decompress_vec(&data[marker_end..], &mut tail,
+                                          Flush::Finish);
+            assert_eq!(status.unwrap(), Status::StreamEnd);
+            assert_eq!(tail, b"two two two");
+        }
+    }
+
+    #[test]
+    fn try_clone_compress() {
+        let prefix = b"a shared prefix, a shared prefix, a shared prefix; ";
+        let suffixes: [&[u8]; 2] = [b"and one ending", b"and another ending"];
+        for &format in [Format::Raw, Format::Zlib, Format::Gzip].iter() {
+            let mut c = Compress::new_with_format(Compression::default(),
+                                                  format);
+            let mut head = Vec::with_capacity(1024);
+            c.compress_vec(prefix, &mut head, Flush::None).unwrap();
+
+            let forks = suffixes.iter().map(|_| c.try_clone())
+                .collect::<Vec<_>>();
+            if cfg!(all(feature = "rust_backend", not(feature = "zlib"))) {
+                assert!(forks.iter().all(|f| f.is_err()));
+                continue
+            }
+            for (fork, suffix) in forks.into_iter().zip(suffixes.iter()) {
+                let mut fork = fork.unwrap();
+                assert_eq!(fork.total_in(), c.total_in());
+                let mut data = head.clone();
+                data.reserve(1024);
+                let status = fork.compress_vec(suffix, &mut data,
+                                               Flush::Finish).unwrap();
+                assert_eq!(status, Status::StreamEnd);
+
+                let mut d = Decompress::new_with_format(format.into());
+                let mut out = Vec::with_capacity(1024);
+                d.decompress_vec(&data, &mut out, Flush::Finish).unwrap();
+                assert_eq!(out, [&prefix[..], suffix].concat());
+            }
+        }
+    }
+
+    #[test]
+    fn block_boundaries() {
+        let input = (0..100000u64).map(|i| (i * i % 251) as u8)
+            .collect::<Vec<u8>>();
+        let mut c = Compress::new(Compression::default(), true);
+        let mut data = Vec::with_capacity(200000);
+        for chunk in input.chunks(20000) {
+            c.compress_vec(chunk, &mut data, Flush::Sync).unwrap();
+        }
+        c.compress_vec(&[], &mut data, Flush::Finish).unwrap();
+
+        let mut d = Decompress::new(true);
+        let mut out = Vec::with_capacity(input.len());
+        if !backend::default().supports_block_flush() {
+            assert!(d.decompress_vec(&data, &mut out, Flush::Block).is_err());
+            assert_eq!(d.block_info(), None);
+            return
+        }
+
+        let mut boundaries = 0;
+        let mut last_block = false;
+        loop {
+            let pos = d.total_in() as usize;
+            let status = d.decompress_vec(&data[pos..], &mut out, Flush::Block)
+                .unwrap();
+            if status == Status::StreamEnd {
+                break
+            }
+            let info = d.block_info().unwrap();
+            assert!(info.unused_bits < 8);
+            if info.block_boundary {
+                boundaries += 1;
+                assert!(!last_block);
+                last_block = info.last_block;
+                let window = d.window().unwrap();
+                let start = out.len() - cmp::min(out.len(), 1 << 15);
+                assert_eq!(window, &out[start..]);
+            }
+        }
+        // at least one boundary per sync flush, plus the one after the header,
+        // and only the end of the final block is flagged as the last one
+        assert!(boundaries > 5, "only {} boundaries", boundaries);
+        assert!(last_block);
+        assert_eq!(out, input);
+    }
+
+    #[test]
+    fn prime() {
+        let mut d = Decompress::new(false);
+        assert!(d.prime(9, 0).is_err());
+        assert_eq!(d.prime(3, 5).is_ok(),
+                   backend::default().supports_block_flush());
+    }
+
+    #[test]
+    fn try_clone_decompress() {
+        let input = (0..2000).map(|i| (i % 13) as u8).collect::<Vec<u8>>();
+        for &format in [Format::Raw, Format::Zlib, Format::Gzip].iter() {
+            let mut c = Compress::new_with_format(Compression::default(),
+                                                  format);
+            let mut data = Vec::with_capacity(4096);
+            c.compress_vec(&input, &mut data, Flush::Finish).unwrap();
+
+            let mut d = Decompress::new_with_format(format.into());
+            let mut out = Vec::with_capacity(4096);
+            let half = data.len() / 2;
+            d.decompress_vec(&data[..half], &mut out, Flush::None).unwrap();
+            if cfg!(all(feature = "rust_backend", not(feature = "zlib"))) {
+                assert!(d.try_clone().is_err());
+                continue
+            }
+            let snapshot = d.try_clone().unwrap();
+
+            // speculatively feed garbage, then go back to the snapshot
+            let mut junk = Vec::with_capacity(4096);
+            drop(d.decompress_vec(&[0xff; 64], &mut junk, Flush::None));
+
+            let mut d = snapshot;
+            let rest = &data[d.total_in() as usize..];
+            let status = d.decompress_vec(rest, &mut out, Flush::Finish);
+            assert_eq!(status.unwrap(), Status::StreamEnd);
+            assert_eq!(out, input);
+            assert_eq!(d.format(), Some(format));
+        }
+    }
+
+    #[test]
+    fn memory_limit() {
+        if cfg!(all(feature = "rust_backend", not(feature = "zlib"))) {
+            let limit = MemoryLimit::new(1 << 24);
+            assert!(DecompressBuilder::new().memory_limit(limit)
+                        .build(true).is_err());
+            assert_eq!(Compress::new(Compression::default(), true)
+                           .memory_usage(), None);
+            return
+        }
+
+        // far too small for any backend's state
+        let limit = MemoryLimit::new(1024);
+        let err = CompressBuilder::new(Compression::default())
+            .memory_limit(limit.clone())
+            .build(true)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(limit.used(), 0);
+
+        let limit = MemoryLimit::new(4 << 20);
+        let mut c = CompressBuilder::new(Compression::default())
+            .memory_limit(limit.clone())
+            .build(true)
+            .unwrap();
+        let mut d = DecompressBuilder::new()
+            .memory_limit(limit.clone())
+            .build(true)
+            .unwrap();
+        let usage = c.memory_usage().unwrap() + d.memory_usage().unwrap();
+        assert!(c.memory_usage().unwrap() > 0);
+        assert_eq!(limit.used(), usage);
+
+        let input = b"memory limits".iter().cycle().take(5000)
+            .cloned().collect::<Vec<u8>>();
+        let mut data = Vec::with_capacity(1024);
+        c.compress_vec(&input, &mut data, Flush::Finish).unwrap();
+        let mut out = Vec::with_capacity(input.len());
+        d.decompress_vec(&data, &mut out, Flush::Finish).unwrap();
+        assert_eq!(out, input);
+        let usage = c.memory_usage().unwrap() + d.memory_usage().unwrap();
+        assert_eq!(limit.used(), usage);
+
+        // copies are charged to the same budget
+        let c2 = c.try_clone().unwrap();
+        assert_eq!(c2.memory_usage(), c.memory_usage());
+        assert_eq!(limit.used(), usage + c2.memory_usage().unwrap());
+
+        drop(c);
+        drop(c2);
+        drop(d);
+        assert_eq!(limit.used(), 0);
+    }
+
+    #[cfg(feature = "zlib")]
+    #[test]
+    fn memory_limit_exceeded_while_decompressing() {
+        let input = (0..100000u64).map(|i| (i * i % 251) as u8)
+            .collect::<Vec<u8>>();
+        let mut c = Compress::new(Compression::default(), true);
+        let mut data = Vec::with_capacity(200000);
+        c.compress_vec(&input, &mut data, Flush::Finish).unwrap();
+
+        // zlib only allocates its window once there's output, so leave
+        // enough room for the stream's state but not for the window
+        let d = DecompressBuilder::new()
+            .backend(&backend::Zlib)
+            .build(true)
+            .unwrap();
+        let limit = MemoryLimit::new(d.memory_usage().unwrap() + 1024);
+        let mut d = DecompressBuilder::new()
+            .backend(&backend::Zlib)
+            .memory_limit(limit.clone())
+            .build(true)
+            .unwrap();
+        let mut out = Vec::with_capacity(input.len());
+        let err = d.decompress_vec(&data[..100], &mut out, Flush::None)
+            .unwrap_err();
+        assert_eq!(err.kind(), DataErrorKind::Memory);
+        assert!(limit.used() <= limit.limit());
+    }
+
+    #[test]
+    fn bound() {
+        // incompressible data, from a linear congruential generator
+        let mut x = 1u32;
+        let random = (0..300000).map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 16) as u8
+        }).collect::<Vec<u8>>();
+
+        let formats = [Format::Raw, Format::Zlib, Format::Gzip];
+        for &b in backend::tests::backends().iter() {
+            for &len in [0, 1, 100, 70000, random.len()].iter() {
+                for &format in formats.iter() {
+                    for &level in [0, 1, 6, 9].iter() {
+                        let bound = Compress::bound(len, format);
+                        let level = Compression::new(level);
+                        let mut c = CompressBuilder::new(level)
+                            .backend(b)
+                            .build_format(format)
+                            .unwrap();
+                        let mut out = Vec::with_capacity(bound);
+                        let status = c.compress_vec(&random[..len], &mut out,
+                                                    Flush::Finish).unwrap();
+                        assert_eq!(status, Status::StreamEnd);
+
+                        // Flushing along the way needs 10 bytes more each.
+                        let flushes = [Flush::Partial, Flush::Sync,
+                                       Flush::Full];
+                        let mut c = CompressBuilder::new(level)
+                            .backend(b)
+                            .build_format(format)
+                            .unwrap();
+                        let mut out = Vec::with_capacity(bound + 10 * 3);
+                        for (i, &flush) in flushes.iter().enumerate() {
+                            let piece = &random[i * len / 3..(i + 1) * len / 3];
+                            c.compress_vec(piece, &mut out, flush).unwrap();
+                            let end = (i + 1) * len / 3;
+                            assert_eq!(c.total_in(), end as u64);
+                        }
+                        let rest = &random[c.total_in() as usize..len];
+                        let status = c.compress_vec(rest, &mut out,
+                                                    Flush::Finish).unwrap();
+                        assert_eq!(status, Status::StreamEnd, "{:?}", b);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn memory_estimate() {
+        for &b in backend::tests::backends().iter() {
+            for &(window_bits, mem_level) in [(9, 1), (12, 8), (15, 9)].iter() {
+                if !b.supports_window_bits() && window_bits != 15 ||
+                   !b.supports_mem_level() && mem_level != 9 {
+                    continue
+                }
+                let estimate = b.deflate_memory(window_bits, mem_level)
+                    .unwrap();
+                let c = CompressBuilder::new(Compression::default())
+                    .backend(b)
+                    .window_bits(window_bits)
+                    .mem_level(mem_level)
+                    .build(true)
+                    .unwrap();
+                if let Some(used) = c.memory_usage() {
+                    assert!(used <= estimate && estimate < used * 2,
+                            "{:?}: {} vs {}", b, used, estimate);
+                }
+
+                let estimate = b.inflate_memory(window_bits).unwrap();
+                let mut input = Vec::with_capacity(1024);
+                let mut c = CompressBuilder::new(Compression::default())
+                    .backend(b)
+                    .window_bits(window_bits)
+                    .build(true)
+                    .unwrap();
+                c.compress_vec(b"estimate", &mut input, Flush::Sync).unwrap();
+                let mut d = DecompressBuilder::new()
+                    .backend(b)
+                    .window_bits(window_bits)
+                    .build(true)
+                    .unwrap();
+                let mut out = Vec::with_capacity(1024);
+                d.decompress_vec(&input, &mut out, Flush::None).unwrap();
+                if let Some(used) = d.memory_usage() {
+                    assert!(used <= estimate, "{:?}: {} vs {}", b, used,
+                            estimate);
+                }
+            }
+        }
+        assert_eq!(Compress::memory_estimate(15, 9).unwrap(),
+                   backend::default().deflate_memory(15, 9).unwrap());
+        assert_eq!(Decompress::memory_estimate(15).unwrap(),
+                   backend::default().inflate_memory(15).unwrap());
+        for &(window_bits, mem_level) in [(8, 9), (16, 9), (15, 0)].iter() {
+            let err = Compress::memory_estimate(window_bits, mem_level)
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(Compress::memory_estimate(15, 10).is_err());
+        assert!(Decompress::memory_estimate(8).is_err());
+        assert!(Decompress::memory_estimate(16).is_err());
+    }
+
+    #[test]
+    fn zlib_header() {
+        let header = parse_zlib_header(&[0x78, 0x01]).unwrap();
+        assert_eq!((header.cmf(), header.flg()), (0x78, 0x01));
+        assert_eq!(header.window_size(), 1 << 15);
+        assert_eq!(header.level(), 0);
+        assert_eq!(header.header_len(), 2);
+        assert_eq!(parse_zlib_header(&[0x78, 0xda]).unwrap().level(), 3);
+
+        let header = parse_zlib_header(&[0x78, 0xbb, 1, 2, 3, 4]).unwrap();
+        assert!(header.has_dictionary());
+        assert_eq!(header.dictionary_id(), Some(0x01020304));
+        assert_eq!(header.level(), 2);
+
+        let kind = |data: &[u8]| parse_zlib_header(data).unwrap_err().kind();
+        assert_eq!(kind(&[0x78]), DataErrorKind::Truncated);
+        assert_eq!(kind(&[0x78, 0xbb, 1, 2]), DataErrorKind::Truncated);
+        assert_eq!(kind(&[0x78, 0x9d]), DataErrorKind::Header);
+        assert_eq!(kind(&[0x1f, 0x8b]), DataErrorKind::Header);
+        assert_eq!(kind(&[0x88, 0x1c]), DataErrorKind::Header);
+
+        let data = compress_to_vec(b"hello", Format::Zlib,
+                                   Compression::best()).unwrap();
+        let mut d = Decompress::new_with_format(DecompressFormat::Auto);
+        assert_eq!(d.zlib_header(), None);
+        let mut out = [0; 16];
+        d.decompress(&data[..1], &mut out, Flush::None).unwrap();
+        assert_eq!(d.zlib_header(), None);
+        d.decompress(&data[1..], &mut out, Flush::None).unwrap();
+        assert_eq!(d.zlib_header(), Some(parse_zlib_header(&data).unwrap()));
+        d.reset(false);
+        assert_eq!(d.zlib_header(), None);
+    }
+
+    #[test]
+    fn to_vec() {
+        let data = (0..200000u64).map(|i| (i * i % 251) as u8)
+            .collect::<Vec<u8>>();
+        for &format in [Format::Raw, Format::Zlib, Format::Gzip].iter() {
+            for &len in [0, 10, data.len()].iter() {
+                let data = &data[..len];
+                let compressed = compress_to_vec(data, format,
+                                                 Compression::default())
+                    .unwrap();
+                let out = decompress_to_vec(&compressed, format.into(), None)
+                    .unwrap();
+                assert!(out == data);
+
+                // exactly at the limit is fine, one byte over is not
+                let out = decompress_to_vec(&compressed, format.into(),
+                                            Some(len)).unwrap();
+                assert!(out == data);
+                if len > 0 {
+                    let err = decompress_to_vec(&compressed, format.into(),
+                                                Some(len - 1)).unwrap_err();
+                    assert_eq!(err.kind(), DataErrorKind::OutputLimit);
+                }
+
+                let half = &compressed[..compressed.len() / 2];
+                let err = decompress_to_vec(half, format.into(), None)
+                    .unwrap_err();
+                assert_eq!(err.kind(), DataErrorKind::Truncated);
+            }
+        }
+
+        let compressed = compress_to_vec(&data, Format::Gzip,
+                                         Compression::fast()).unwrap();
+        let out = decompress_to_vec(&compressed, DecompressFormat::Auto,
+                                    Some(data.len())).unwrap();
+        assert!(out == data);
+        let err = decompress_to_vec(&compressed, DecompressFormat::Zlib,
+                                    None).unwrap_err();
+        assert_eq!(err.kind(), DataErrorKind::Header);
+    }
 }
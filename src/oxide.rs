@@ -0,0 +1,621 @@
+// The pure Rust backend, built directly on miniz_oxide and crc32fast.
+//
+// miniz_oxide is always driven as a raw deflate stream, with the zlib header
+// and trailer handled here. That way the strategy can be changed mid-stream
+// by swapping in a new compressor, a preset dictionary can be announced in
+// the header, and header errors are reported with the same messages zlib
+// uses. Decompression goes through a window of our own rather than
+// miniz_oxide's `InflateState`, so that a preset dictionary can be placed in
+// it and the window can be handed out.
+
+extern crate crc32fast;
+extern crate miniz_oxide;
+
+use std::cmp;
+use std::fmt;
+use std::io;
+
+use {Compression, Flush, Strategy};
+use backend::{Backend, DeflateStream, InflateStream, MemoryLimit, Progress};
+use backend::ReturnCode;
+use self::crc32fast::Hasher;
+use self::miniz_oxide::{MZError, MZFlush, MZStatus, mz_adler32_oxide};
+use self::miniz_oxide::deflate::core::{compress_to_output,
+                                       create_comp_flags_from_zip_params,
+                                       CompressorOxide, TDEFLFlush,
+                                       TDEFLStatus};
+use self::miniz_oxide::inflate::TINFLStatus;
+use self::miniz_oxide::inflate::core::{decompress, inflate_flags,
+                                       DecompressorOxide};
+
+/// The pure Rust miniz_oxide library, available with the `rust_backend`
+/// feature.
+///
+/// Like miniz it only supports the default window size and memory level.
+/// Unlike miniz it can't stop decompressing at block boundaries, so
+/// `Flush::Block`, `Decompress::block_info` and `Decompress::prime` aren't
+/// available with it, and its streams can't be duplicated with `try_clone`.
+/// It allocates through the global allocator, so streams can't be given a
+/// `MemoryLimit`. Each of these returns an error rather than being ignored.
+///
+/// miniz_oxide only reports that deflate data is invalid, not why, so errors
+/// in the middle of a stream have the kind `DataErrorKind::Other` instead of
+/// one of the more specific kinds the other backends give. Truncated input
+/// and bad zlib headers and trailers are still told apart.
+#[derive(Copy, Clone, Debug)]
+pub struct MinizOxide;
+
+const WINDOW_BITS: u8 = 15;
+const WINDOW_SIZE: usize = 1 << WINDOW_BITS;
+
+impl Backend for MinizOxide {
+    fn supports_window_bits(&self) -> bool {
+        false
+    }
+
+    fn supports_mem_level(&self) -> bool {
+        false
+    }
+
+    fn supports_dictionary(&self) -> bool {
+        true
+    }
+
+    // miniz_oxide's buffers are laid out like those of miniz, so they take up
+    // about as much memory regardless of the parameters.
+    fn deflate_memory(&self, _window_bits: u8, _mem_level: u8)
+                      -> Option<usize> {
+        Some(320 * 1024)
+    }
+
+    fn inflate_memory(&self, _window_bits: u8) -> Option<usize> {
+        Some(44 * 1024)
+    }
+
+    fn deflate_init(&self,
+                    level: Compression,
+                    zlib_header: bool,
+                    window_bits: u8,
+                    _mem_level: u8,
+                    strategy: Strategy,
+                    limit: Option<&MemoryLimit>)
+                    -> io::Result<Box<dyn DeflateStream>> {
+        try!(check_params(window_bits, limit));
+        Ok(Box::new(Deflate {
+            compressor: compressor(level.level(), strategy),
+            level: level.level(),
+            zlib: zlib_header,
+            adler: 1,
+            pending: Vec::new(),
+            started: false,
+            finished: false,
+            flushed: true,
+            dict_id: None,
+        }))
+    }
+
+    fn inflate_init(&self,
+                    zlib_header: bool,
+                    window_bits: u8,
+                    limit: Option<&MemoryLimit>)
+                    -> io::Result<Box<dyn InflateStream>> {
+        try!(check_params(window_bits, limit));
+        Ok(Box::new(Inflate {
+            decomp: Box::new(DecompressorOxide::new()),
+            window: vec![0; WINDOW_SIZE].into_boxed_slice(),
+            ofs: 0,
+            avail: 0,
+            history: 0,
+            last_status: TINFLStatus::NeedsMoreInput,
+            zlib: zlib_header,
+            phase: if zlib_header {Phase::Header} else {Phase::Body},
+            buf: Vec::new(),
+            adler: 1,
+            msg: None,
+        }))
+    }
+
+    fn crc32(&self, crc: u32, data: &[u8]) -> u32 {
+        let mut hasher = Hasher::new_with_initial(crc);
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+// miniz_oxide allocates through the global allocator, so there's no way to
+// account for or limit its memory.
+fn check_params(window_bits: u8, limit: Option<&MemoryLimit>)
+                -> io::Result<()> {
+    if limit.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "memory limits are not supported by this \
+                                   compression backend"))
+    }
+    if window_bits != WINDOW_BITS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "invalid compression stream parameters"))
+    }
+    Ok(())
+}
+
+fn compressor(level: u32, strategy: Strategy) -> CompressorOxide {
+    let flags = create_comp_flags_from_zip_params(level as i32,
+                                                  -(WINDOW_BITS as i32),
+                                                  strategy as i32);
+    CompressorOxide::new(flags)
+}
+
+fn return_code(err: MZError) -> ReturnCode {
+    match err {
+        MZError::Data => ReturnCode::DataError,
+        MZError::Mem => ReturnCode::MemError,
+        MZError::Buf => ReturnCode::BufError,
+        MZError::Param => ReturnCode::Other(-10000),
+        _ => ReturnCode::StreamError,
+    }
+}
+
+// The message for a failure reported by miniz_oxide's decompressor, in
+// zlib's words where there's an equivalent. A plain `Failed` doesn't say what
+// was wrong with the data, so it can only be given a generic message.
+fn status_message(status: TINFLStatus) -> &'static str {
+    match status {
+        TINFLStatus::FailedCannotMakeProgress => "unexpected end of stream",
+        TINFLStatus::Adler32Mismatch => "incorrect data check",
+        TINFLStatus::BadParam => "invalid decompression parameters",
+        _ => "invalid deflate data",
+    }
+}
+
+fn progress(consumed: usize, written: usize, code: ReturnCode) -> Progress {
+    Progress {
+        consumed: consumed,
+        written: written,
+        code: code,
+    }
+}
+
+// Copies as much of `pending` as fits into `output`.
+fn write_pending(pending: &mut Vec<u8>, output: &mut [u8]) -> usize {
+    let n = cmp::min(pending.len(), output.len());
+    output[..n].copy_from_slice(&pending[..n]);
+    pending.drain(..n);
+    n
+}
+
+fn zlib_header(level: u32, dict_id: Option<u32>) -> Vec<u8> {
+    // A 32KiB window, and the FLEVEL field zlib would use for the level.
+    let cmf = 0x78;
+    let flevel = match level {
+        0 | 1 => 0,
+        2..=5 => 1,
+        6 => 2,
+        _ => 3,
+    };
+    let flg = flevel << 6 | if dict_id.is_some() {0x20} else {0};
+    let check = 31 - ((cmf as u16 * 256 + flg as u16) % 31) as u8;
+    let mut header = vec![cmf, flg + check];
+    if let Some(id) = dict_id {
+        header.extend_from_slice(&be32(id));
+    }
+    header
+}
+
+fn be32(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+struct Deflate {
+    compressor: CompressorOxide,
+    level: u32,
+    zlib: bool,
+    adler: u32,
+    // Header or trailer bytes which haven't been written out yet.
+    pending: Vec<u8>,
+    started: bool,
+    finished: bool,
+    // Whether everything given to the compressor has been flushed out, which
+    // `set_params` requires, as miniz does.
+    flushed: bool,
+    // The Adler-32 of the preset dictionary of a zlib stream.
+    dict_id: Option<u32>,
+}
+
+impl fmt::Debug for Deflate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Deflate")
+            .field("level", &self.level)
+            .field("zlib", &self.zlib)
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+impl DeflateStream for Deflate {
+    fn deflate(&mut self, input: &[u8], output: &mut [u8], flush: Flush)
+               -> Progress {
+        let flush = match flush {
+            Flush::None => MZFlush::None,
+            Flush::Partial => MZFlush::Partial,
+            Flush::Sync => MZFlush::Sync,
+            Flush::Full => MZFlush::Full,
+            Flush::Finish => MZFlush::Finish,
+            Flush::Block => return progress(0, 0, ReturnCode::StreamError),
+        };
+        if output.is_empty() {
+            return progress(0, 0, ReturnCode::BufError)
+        }
+        if !self.started {
+            self.started = true;
+            if self.zlib {
+                let header = zlib_header(self.level, self.dict_id);
+                self.pending.extend_from_slice(&header);
+            }
+        }
+        let mut written = write_pending(&mut self.pending, output);
+        if !self.pending.is_empty() {
+            return progress(0, written, ReturnCode::Ok)
+        }
+        if self.finished {
+            let code = match flush {
+                MZFlush::Finish => ReturnCode::StreamEnd,
+                _ if written > 0 => ReturnCode::Ok,
+                _ => ReturnCode::BufError,
+            };
+            return progress(0, written, code)
+        }
+        if written == output.len() {
+            return progress(0, written, ReturnCode::Ok)
+        }
+
+        let res = self::miniz_oxide::deflate::stream::deflate(
+            &mut self.compressor, input, &mut output[written..], flush);
+        let consumed = res.bytes_consumed;
+        written += res.bytes_written;
+        self.adler = mz_adler32_oxide(self.adler, &input[..consumed]);
+        self.flushed = match flush {
+            MZFlush::None => self.flushed && consumed == 0,
+            _ => consumed == input.len() && written < output.len(),
+        };
+        match res.status {
+            Ok(MZStatus::StreamEnd) => {}
+            Ok(..) => return progress(consumed, written, ReturnCode::Ok),
+            Err(MZError::Buf) if written > 0 => {
+                return progress(consumed, written, ReturnCode::Ok)
+            }
+            Err(e) => return progress(consumed, written, return_code(e)),
+        }
+
+        self.finished = true;
+        if self.zlib {
+            self.pending.extend_from_slice(&be32(self.adler));
+            written += write_pending(&mut self.pending, &mut output[written..]);
+        }
+        if self.pending.is_empty() {
+            progress(consumed, written, ReturnCode::StreamEnd)
+        } else {
+            progress(consumed, written, ReturnCode::Ok)
+        }
+    }
+
+    fn reset(&mut self) -> ReturnCode {
+        self.compressor.reset();
+        self.adler = 1;
+        self.pending.truncate(0);
+        self.started = false;
+        self.finished = false;
+        self.flushed = true;
+        self.dict_id = None;
+        ReturnCode::Ok
+    }
+
+    fn set_params(&mut self, level: Compression, strategy: Strategy)
+                  -> ReturnCode {
+        if self.finished {
+            return ReturnCode::StreamError
+        }
+        if !self.flushed {
+            return ReturnCode::BufError
+        }
+        match strategy {
+            Strategy::Default => {
+                self.compressor.set_compression_level_raw(level.level() as u8);
+            }
+            // miniz_oxide can only change the strategy by starting over with
+            // a new compressor. The data so far has been flushed to a block
+            // boundary, so the only cost is forgetting the sliding window.
+            _ => self.compressor = compressor(level.level(), strategy),
+        }
+        if !self.started {
+            self.level = level.level();
+        }
+        ReturnCode::Ok
+    }
+
+    // miniz_oxide has no way to fill its window directly, so the dictionary
+    // is run through the compressor like any other input, up to a sync flush
+    // point, and the output is dropped as it's produced. The compressor is
+    // on a byte boundary both before and after (`flushed` is checked above),
+    // so what's dropped is a whole number of non-final blocks, and what it
+    // produces next is a valid continuation of the stream whose matches may
+    // reach back into the dictionary, just as with zlib.
+    fn set_dictionary(&mut self, dictionary: &[u8]) -> ReturnCode {
+        if self.finished || (self.zlib && self.started) || !self.flushed {
+            return ReturnCode::StreamError
+        }
+        let (status, consumed) = compress_to_output(&mut self.compressor,
+                                                    dictionary,
+                                                    TDEFLFlush::Sync,
+                                                    |_| true);
+        if status != TDEFLStatus::Okay || consumed != dictionary.len() {
+            return ReturnCode::StreamError
+        }
+        if self.zlib {
+            let adler = self.dict_id.unwrap_or(1);
+            self.dict_id = Some(mz_adler32_oxide(adler, dictionary));
+        }
+        ReturnCode::Ok
+    }
+
+    fn message(&self) -> Option<String> {
+        None
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Phase {
+    Header,
+    Body,
+    Trailer,
+    Done,
+}
+
+struct Inflate {
+    decomp: Box<DecompressorOxide>,
+    // The most recent output, which the deflate data can refer back to. Data
+    // is decompressed into it, starting at `ofs`, and copied out from there,
+    // with `avail` bytes still to be copied. The `history` bytes before `ofs`
+    // have been copied out already (or were a preset dictionary).
+    window: Box<[u8]>,
+    ofs: usize,
+    avail: usize,
+    history: usize,
+    last_status: TINFLStatus,
+    zlib: bool,
+    phase: Phase,
+    // Header or trailer bytes collected so far.
+    buf: Vec<u8>,
+    // The Adler-32 of the output so far, or of the dictionary asked for.
+    adler: u32,
+    msg: Option<&'static str>,
+}
+
+impl fmt::Debug for Inflate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Inflate")
+            .field("zlib", &self.zlib)
+            .field("phase", &self.phase)
+            .field("last_status", &self.last_status)
+            .finish()
+    }
+}
+
+impl Inflate {
+    // Parses as much of the zlib header as is available, returning `None`
+    // once it's complete.
+    fn inflate_header(&mut self, input: &[u8], consumed: &mut usize)
+                      -> Option<ReturnCode> {
+        let need = if self.buf.len() >= 2 && self.buf[1] & 0x20 != 0 {
+            6
+        } else {
+            2
+        };
+        let n = cmp::min(need - self.buf.len(), input.len());
+        self.buf.extend_from_slice(&input[..n]);
+        *consumed += n;
+        if self.buf.len() < 2 {
+            return Some(ReturnCode::Ok)
+        }
+        let (cmf, flg) = (self.buf[0], self.buf[1]);
+        if (cmf as u16 * 256 + flg as u16) % 31 != 0 {
+            return Some(self.error("incorrect header check"))
+        }
+        if cmf & 0xf != 8 {
+            return Some(self.error("unknown compression method"))
+        }
+        if (cmf >> 4) + 8 > WINDOW_BITS {
+            return Some(self.error("invalid window size"))
+        }
+        if flg & 0x20 != 0 {
+            // A preset dictionary, which has to be supplied through
+            // `set_dictionary` before going on.
+            if self.buf.len() < 6 {
+                let rest = cmp::min(6 - self.buf.len(), input.len() - n);
+                self.buf.extend_from_slice(&input[n..n + rest]);
+                *consumed += rest;
+            }
+            if self.buf.len() < 6 {
+                return Some(ReturnCode::Ok)
+            }
+            let b = &self.buf;
+            self.adler = (b[2] as u32) << 24 | (b[3] as u32) << 16 |
+                         (b[4] as u32) << 8 | b[5] as u32;
+            return Some(ReturnCode::NeedDict(self.adler))
+        }
+        self.buf.truncate(0);
+        self.phase = Phase::Body;
+        None
+    }
+
+    // Decompresses the deflate data into the window and copies it out from
+    // there, returning `ReturnCode::StreamEnd` once the end of the deflate
+    // data has been reached and all of it has been copied.
+    fn inflate_body(&mut self, input: &[u8], output: &mut [u8], flush: Flush)
+                    -> (ReturnCode, usize, usize) {
+        let flags = match flush {
+            Flush::Finish => 0,
+            _ => inflate_flags::TINFL_FLAG_HAS_MORE_INPUT,
+        };
+        let mut consumed = 0;
+        let mut written = 0;
+        loop {
+            let n = cmp::min(self.avail, output.len() - written);
+            output[written..written + n]
+                .copy_from_slice(&self.window[self.ofs..self.ofs + n]);
+            written += n;
+            self.avail -= n;
+            self.ofs = (self.ofs + n) & (WINDOW_SIZE - 1);
+            self.history = cmp::min(self.history + n, WINDOW_SIZE);
+            if self.avail > 0 {
+                return (ReturnCode::Ok, consumed, written)
+            }
+            // Whatever was decompressed before an error is still handed out
+            // first, as miniz does.
+            match self.last_status {
+                s if (s as i32) < 0 => {
+                    return (ReturnCode::DataError, consumed, written)
+                }
+                TINFLStatus::Done => {
+                    return (ReturnCode::StreamEnd, consumed, written)
+                }
+                TINFLStatus::NeedsMoreInput if consumed == input.len() => {
+                    return (ReturnCode::Ok, consumed, written)
+                }
+                _ => {}
+            }
+
+            let (status, n, m) = decompress(&mut self.decomp,
+                                            &input[consumed..],
+                                            &mut self.window,
+                                            self.ofs,
+                                            flags);
+            self.last_status = status;
+            consumed += n;
+            self.avail = m;
+        }
+    }
+
+    fn error(&mut self, msg: &'static str) -> ReturnCode {
+        self.msg = Some(msg);
+        ReturnCode::DataError
+    }
+
+    fn inflate_phases(&mut self, input: &[u8], output: &mut [u8],
+                      flush: Flush) -> Progress {
+        let mut consumed = 0;
+        let mut written = 0;
+
+        if self.phase == Phase::Header {
+            if let Some(code) = self.inflate_header(input, &mut consumed) {
+                return progress(consumed, written, code)
+            }
+        }
+
+        if self.phase == Phase::Body {
+            let (code, n, m) = self.inflate_body(&input[consumed..], output,
+                                                 flush);
+            consumed += n;
+            written += m;
+            self.adler = mz_adler32_oxide(self.adler, &output[..m]);
+            match code {
+                ReturnCode::StreamEnd => {}
+                ReturnCode::DataError => {
+                    let msg = status_message(self.last_status);
+                    return progress(consumed, written, self.error(msg))
+                }
+                code => return progress(consumed, written, code),
+            }
+            self.phase = if self.zlib {Phase::Trailer} else {Phase::Done};
+        }
+
+        if self.phase == Phase::Trailer {
+            let n = cmp::min(4 - self.buf.len(), input.len() - consumed);
+            self.buf.extend_from_slice(&input[consumed..consumed + n]);
+            consumed += n;
+            if self.buf.len() < 4 {
+                return progress(consumed, written, ReturnCode::Ok)
+            }
+            let b = &self.buf;
+            let adler = (b[0] as u32) << 24 | (b[1] as u32) << 16 |
+                        (b[2] as u32) << 8 | b[3] as u32;
+            if adler != self.adler {
+                let code = self.error("incorrect data check");
+                return progress(consumed, written, code)
+            }
+            self.phase = Phase::Done;
+        }
+        progress(consumed, written, ReturnCode::StreamEnd)
+    }
+}
+
+impl InflateStream for Inflate {
+    fn inflate(&mut self, input: &[u8], output: &mut [u8], flush: Flush)
+               -> Progress {
+        if flush == Flush::Block {
+            return progress(0, 0, ReturnCode::StreamError)
+        }
+        self.msg = None;
+        let mut p = self.inflate_phases(input, output, flush);
+
+        // Like zlib, anything short of the end of the stream is an error when
+        // finishing, and so is making no progress at all.
+        if p.code == ReturnCode::Ok &&
+           (flush == Flush::Finish || (p.consumed == 0 && p.written == 0)) {
+            p.code = ReturnCode::BufError;
+        }
+        p
+    }
+
+    fn reset(&mut self, zlib_header: bool) -> ReturnCode {
+        self.decomp.init();
+        self.ofs = 0;
+        self.avail = 0;
+        self.history = 0;
+        self.last_status = TINFLStatus::NeedsMoreInput;
+        self.zlib = zlib_header;
+        self.phase = if zlib_header {Phase::Header} else {Phase::Body};
+        self.buf.truncate(0);
+        self.adler = 1;
+        self.msg = None;
+        ReturnCode::Ok
+    }
+
+    fn set_dictionary(&mut self, dictionary: &[u8]) -> ReturnCode {
+        if self.zlib {
+            // The header has to have asked for the dictionary first, and it
+            // has to be the one asked for.
+            if self.phase != Phase::Header || self.buf.len() < 6 {
+                return ReturnCode::StreamError
+            }
+            if mz_adler32_oxide(1, dictionary) != self.adler {
+                return ReturnCode::DataError
+            }
+            self.buf.truncate(0);
+            self.phase = Phase::Body;
+            self.adler = 1;
+        } else if self.phase != Phase::Body || self.avail > 0 {
+            return ReturnCode::StreamError
+        }
+
+        // The dictionary goes right before where the next output will be
+        // decompressed to, as if it had been output itself.
+        let skip = dictionary.len().saturating_sub(WINDOW_SIZE);
+        for &byte in dictionary[skip..].iter() {
+            self.window[self.ofs] = byte;
+            self.ofs = (self.ofs + 1) & (WINDOW_SIZE - 1);
+        }
+        self.history = cmp::min(self.history + dictionary.len(), WINDOW_SIZE);
+        ReturnCode::Ok
+    }
+
+    fn message(&self) -> Option<String> {
+        self.msg.map(|msg| msg.to_string())
+    }
+
+    fn window(&self) -> io::Result<Vec<u8>> {
+        let start = self.ofs.wrapping_sub(self.history) & (WINDOW_SIZE - 1);
+        Ok((0..self.history)
+            .map(|i| self.window[(start + i) & (WINDOW_SIZE - 1)])
+            .collect())
+    }
+}
@@ -0,0 +1,513 @@
+//! Multi-threaded gzip compression, in the style of pigz
+//!
+//! The input is cut into blocks which are compressed independently on a pool
+//! of threads. Every block but the last ends with a sync flush, so that it
+//! ends on a byte boundary and the compressed blocks can simply be
+//! concatenated into a single deflate stream. Each block is primed with the
+//! 32 KiB of input preceding it as a preset dictionary, when the backend
+//! supports that, so hardly any compression is lost at the block boundaries.
+//! The CRC-32 of the whole stream is stitched together from those of the
+//! blocks with `Crc::combine`.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::prelude::*;
+use std::io;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use {Compress, Compression, Flush, Format, Status};
+use backend;
+use crc::Crc;
+use gz;
+
+const WINDOW_SIZE: usize = 32 * 1024;
+
+/// A builder for a [`ParallelGzEncoder`], configuring the number of threads,
+/// the size of the blocks handed to them, and the gzip header.
+///
+/// [`ParallelGzEncoder`]: write/struct.ParallelGzEncoder.html
+///
+/// # Examples
+///
+/// ```
+/// use std::io::prelude::*;
+/// use flate2::{Compression, GzBuilder, ParallelGzBuilder};
+/// use flate2::read::GzDecoder;
+///
+/// let mut e = ParallelGzBuilder::new()
+///     .threads(2)
+///     .block_size(64 * 1024)
+///     .header(GzBuilder::new().filename("dump.sql"))
+///     .write(Vec::new(), Compression::default());
+/// e.write_all(b"INSERT INTO t VALUES (1);\n").unwrap();
+/// let compressed = e.finish().unwrap();
+///
+/// let mut s = String::new();
+/// GzDecoder::new(&compressed[..]).unwrap().read_to_string(&mut s).unwrap();
+/// assert_eq!(s, "INSERT INTO t VALUES (1);\n");
+/// ```
+#[derive(Debug)]
+pub struct Builder {
+    threads: usize,
+    block_size: usize,
+    header: gz::Builder,
+}
+
+/// A gzip encoder which compresses on several threads at once
+///
+/// This structure exposes a [`Write`] interface that will emit a single gzip
+/// member to the underlying writer `W`, which can be decoded by any gzip
+/// decoder. Data written to the encoder is collected into blocks, which are
+/// compressed in the background while more data is written.
+///
+/// [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
+#[derive(Debug)]
+pub struct EncoderWriter<W: Write> {
+    inner: Option<W>,
+    level: Compression,
+    block_size: usize,
+    pool: Pool,
+    // Input for the next block, and the input that preceded it.
+    block: Vec<u8>,
+    window: Vec<u8>,
+    // Results of the blocks handed to the pool, in order. Receivers aren't
+    // `Sync`, so each is wrapped in a mutex, which is never locked since
+    // they're only used through `&mut self`.
+    pending: VecDeque<Mutex<Receiver<io::Result<Chunk>>>>,
+    crc: Crc,
+    // Output that is ready but hasn't been written yet, starting with the
+    // header.
+    buf: Vec<u8>,
+    // Whether the last block has been submitted, and whether the trailer has
+    // been queued after it.
+    finished: bool,
+    trailer: bool,
+}
+
+// A fixed set of threads running jobs in the background, which send their
+// results back over channels of their own.
+#[derive(Debug)]
+pub struct Pool {
+    jobs: Option<Sender<Box<dyn FnOnce() + Send>>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct Chunk {
+    data: Vec<u8>,
+    crc: Crc,
+}
+
+impl Builder {
+    /// Creates a new builder using 4 threads, blocks of 128 KiB and a blank
+    /// header.
+    pub fn new() -> Builder {
+        Builder {
+            threads: 4,
+            block_size: 128 * 1024,
+            header: gz::Builder::new(),
+        }
+    }
+
+    /// Configures the number of threads compressing blocks.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `threads` is zero.
+    pub fn threads(mut self, threads: usize) -> Builder {
+        assert!(threads > 0, "at least one thread is needed");
+        self.threads = threads;
+        self
+    }
+
+    /// Configures the amount of input compressed as one block.
+    ///
+    /// Larger blocks lose less compression at block boundaries, especially
+    /// with backends which can't prime a block with the preceding data, but
+    /// need more memory. The encoder holds up to two blocks per thread.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_size` is zero.
+    pub fn block_size(mut self, block_size: usize) -> Builder {
+        assert!(block_size > 0, "blocks can't be empty");
+        self.block_size = block_size;
+        self
+    }
+
+    /// Configures the gzip header to emit, as built by a `GzBuilder`.
+    pub fn header(mut self, header: gz::Builder) -> Builder {
+        self.header = header;
+        self
+    }
+
+    /// Consumes this builder, creating a writer encoder in the process.
+    ///
+    /// The data written to the returned encoder will be compressed at the
+    /// given level and then written out to the supplied parameter `w`.
+    pub fn write<W: Write>(self, w: W, lvl: Compression) -> EncoderWriter<W> {
+        EncoderWriter {
+            inner: Some(w),
+            level: lvl,
+            block_size: self.block_size,
+            pool: Pool::new(self.threads),
+            block: Vec::with_capacity(self.block_size),
+            window: Vec::new(),
+            pending: VecDeque::new(),
+            crc: Crc::new(),
+            buf: gz::builder_header(self.header, lvl),
+            finished: false,
+            trailer: false,
+        }
+    }
+}
+
+impl<W: Write> EncoderWriter<W> {
+    /// Creates a new encoder which will use the given compression level, with
+    /// the default settings of `ParallelGzBuilder`.
+    ///
+    /// The data written to the returned encoder will be compressed and then
+    /// written to the stream `w`.
+    pub fn new(w: W, level: Compression) -> EncoderWriter<W> {
+        Builder::new().write(w, level)
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Note that mutation of the writer may result in surprising results if
+    /// this encoder is continued to be used.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.as_mut().unwrap()
+    }
+
+    /// Attempt to finish this output stream, compressing the remaining data
+    /// and writing out the final blocks and the gzip trailer.
+    ///
+    /// # Panics
+    ///
+    /// Attempts to write data to this stream may result in a panic after this
+    /// function is called.
+    ///
+    /// # Errors
+    ///
+    /// This function will perform I/O to complete this stream, and any I/O
+    /// errors which occur will be returned from this function.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        if !self.finished {
+            self.submit(true);
+            self.finished = true;
+        }
+        while !self.pending.is_empty() {
+            try!(self.collect());
+        }
+        if !self.trailer {
+            let sum = self.crc.sum() as u32;
+            let amt = self.crc.amount() as u32;
+            self.buf.extend_from_slice(&[(sum >> 0) as u8,
+                                         (sum >> 8) as u8,
+                                         (sum >> 16) as u8,
+                                         (sum >> 24) as u8,
+                                         (amt >> 0) as u8,
+                                         (amt >> 8) as u8,
+                                         (amt >> 16) as u8,
+                                         (amt >> 24) as u8]);
+            self.trailer = true;
+        }
+        self.write_buf()
+    }
+
+    /// Finish encoding this stream, returning the underlying writer once the
+    /// encoding is done.
+    ///
+    /// # Errors
+    ///
+    /// This function will perform I/O to complete this stream, and any I/O
+    /// errors which occur will be returned from this function.
+    pub fn finish(mut self) -> io::Result<W> {
+        try!(self.try_finish());
+        Ok(self.inner.take().unwrap())
+    }
+
+    // Hands the current block to the pool, primed with the input before it.
+    fn submit(&mut self, last: bool) {
+        let data = mem::replace(&mut self.block,
+                                Vec::with_capacity(self.block_size));
+        let dictionary = if backend::default().supports_dictionary() {
+            self.window.clone()
+        } else {
+            Vec::new()
+        };
+        let keep = WINDOW_SIZE - cmp::min(data.len(), WINDOW_SIZE);
+        let stale = self.window.len() - cmp::min(self.window.len(), keep);
+        self.window.drain(..stale);
+        self.window.extend_from_slice(&data[data.len() -
+                                            cmp::min(data.len(),
+                                                     WINDOW_SIZE)..]);
+
+        let (tx, rx) = mpsc::channel();
+        let level = self.level;
+        self.pool.run(move || {
+            drop(tx.send(compress_block(level, &dictionary, &data, last)));
+        });
+        self.pending.push_back(Mutex::new(rx));
+    }
+
+    // Waits for the oldest block and queues its output.
+    fn collect(&mut self) -> io::Result<()> {
+        let rx = self.pending.pop_front().unwrap();
+        let rx = rx.into_inner().unwrap_or_else(|e| e.into_inner());
+        let chunk = match rx.recv() {
+            Ok(result) => try!(result),
+            Err(_) => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "compression thread panicked"))
+            }
+        };
+        self.crc.combine(&chunk.crc);
+        self.buf.extend_from_slice(&chunk.data);
+        self.write_buf()
+    }
+
+    fn write_buf(&mut self) -> io::Result<()> {
+        while self.buf.len() > 0 {
+            let n = try!(self.inner.as_mut().unwrap().write(&self.buf));
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                          "failed to write compressed data"))
+            }
+            self.buf.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncoderWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        assert!(!self.finished);
+        try!(self.write_buf());
+        // Keep at most two blocks per thread in flight.
+        while self.pending.len() >= 2 * self.pool.threads() {
+            try!(self.collect());
+        }
+        let n = cmp::min(buf.len(), self.block_size - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        if self.block.len() == self.block_size {
+            self.submit(false);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        assert!(!self.finished);
+        if self.block.len() > 0 {
+            self.submit(false);
+        }
+        while !self.pending.is_empty() {
+            try!(self.collect());
+        }
+        try!(self.write_buf());
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for EncoderWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+impl Pool {
+    pub fn new(threads: usize) -> Pool {
+        let (tx, rx) = mpsc::channel::<Box<dyn FnOnce() + Send>>();
+        let rx = Arc::new(Mutex::new(rx));
+        let threads = (0..threads).map(|_| {
+            let rx = rx.clone();
+            thread::spawn(move || {
+                loop {
+                    let job = match rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    // A panicking job mustn't take its worker down with it,
+                    // or once every worker had died no other job would run.
+                    drop(panic::catch_unwind(AssertUnwindSafe(job)));
+                }
+            })
+        }).collect();
+        Pool {
+            jobs: Some(tx),
+            threads: threads,
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads.len()
+    }
+
+    // A job which panics drops the sender of its result as it unwinds, which
+    // is reported as an error when the result is collected.
+    pub fn run<F: FnOnce() + Send + 'static>(&self, job: F) {
+        drop(self.jobs.as_ref().unwrap().send(Box::new(job)));
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        // Closing the channel stops the workers once they're idle.
+        self.jobs.take();
+        for thread in self.threads.drain(..) {
+            drop(thread.join());
+        }
+    }
+}
+
+// Compresses a block as a piece of raw deflate data ending on a byte
+// boundary.
+fn compress_block(level: Compression, dictionary: &[u8], input: &[u8],
+                  last: bool) -> io::Result<Chunk> {
+    let mut c = try!(Compress::try_new_with_format(level, Format::Raw));
+    if dictionary.len() > 0 {
+        try!(c.set_dictionary(dictionary));
+    }
+
+    // The bound doesn't cover the sync flush, which adds up to 10 bytes.
+    let flush = if last { Flush::Finish } else { Flush::Sync };
+    let mut data = Vec::with_capacity(Compress::bound(input.len(),
+                                                      Format::Raw) + 10);
+    loop {
+        let rest = &input[c.total_in() as usize..];
+        let status = try!(c.compress_vec(rest, &mut data, flush));
+        let done = if last {
+            status == Status::StreamEnd
+        } else {
+            rest.len() == 0 && data.len() < data.capacity()
+        };
+        if done {
+            break
+        }
+        let len = data.len();
+        data.reserve(cmp::max(len, 64));
+    }
+
+    let mut crc = Crc::new();
+    crc.update(input);
+    Ok(Chunk {
+        data: data,
+        crc: crc,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use std::sync::mpsc;
+
+    use super::{Builder, EncoderWriter, Pool};
+    use {backend, Compression, GzBuilder};
+    use read::GzDecoder;
+
+    fn decode(data: &[u8]) -> Vec<u8> {
+        let mut d = GzDecoder::new(data).unwrap();
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = (0..300000u64).map(|i| (i * i % 251) as u8)
+            .collect::<Vec<u8>>();
+        for &threads in [1, 3].iter() {
+            for &block_size in [1000, 40000, 1 << 20].iter() {
+                let mut e = Builder::new()
+                    .threads(threads)
+                    .block_size(block_size)
+                    .write(Vec::new(), Compression::default());
+                for chunk in data.chunks(7777) {
+                    e.write_all(chunk).unwrap();
+                }
+                let compressed = e.finish().unwrap();
+                assert!(decode(&compressed) == data);
+            }
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let e = EncoderWriter::new(Vec::new(), Compression::default());
+        let compressed = e.finish().unwrap();
+        assert_eq!(decode(&compressed), b"");
+    }
+
+    #[test]
+    fn flush_and_header() {
+        let mut e = Builder::new()
+            .block_size(100)
+            .header(GzBuilder::new().filename("foo.txt").mtime(1))
+            .write(Vec::new(), Compression::best());
+        e.write_all(b"hello ").unwrap();
+        e.flush().unwrap();
+        assert!(e.get_ref().len() > 10);
+        e.write_all(b"world").unwrap();
+        e.flush().unwrap();
+        let compressed = e.finish().unwrap();
+
+        let mut d = GzDecoder::new(&compressed[..]).unwrap();
+        assert_eq!(d.header().filename(), Some(&b"foo.txt"[..]));
+        assert_eq!(d.header().mtime(), 1);
+        let mut s = String::new();
+        d.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "hello world");
+    }
+
+    #[test]
+    fn matches_serial_size() {
+        // Priming blocks with the preceding data keeps the output close to
+        // what a single stream produces, if the backend can do it.
+        let text = b"The quick brown fox jumps over the lazy dog. ";
+        let data = text.iter().cycle().take(500000).cloned()
+            .collect::<Vec<u8>>();
+        let mut e = Builder::new().block_size(50000)
+            .write(Vec::new(), Compression::default());
+        e.write_all(&data).unwrap();
+        let parallel = e.finish().unwrap();
+        let serial = ::compress_to_vec(&data, ::Format::Gzip,
+                                       Compression::default()).unwrap();
+        assert!(decode(&parallel) == data);
+        if backend::default().supports_dictionary() {
+            assert!(parallel.len() < serial.len() + 10 * 64);
+        }
+    }
+
+    #[test]
+    fn panicking_jobs() {
+        let pool = Pool::new(2);
+        let mut results = Vec::new();
+        for i in 0..6 {
+            let (tx, rx) = mpsc::channel();
+            pool.run(move || {
+                if i % 2 == 0 {
+                    panic!("job {} failed", i);
+                }
+                tx.send(i).unwrap();
+            });
+            results.push(rx);
+        }
+        // More jobs panicked than there are threads, and the rest still ran.
+        for (i, rx) in results.iter().enumerate() {
+            assert_eq!(rx.recv().ok(), if i % 2 == 0 {None} else {Some(i)});
+        }
+    }
+}
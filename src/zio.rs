@@ -2,7 +2,7 @@ use std::io::prelude::*;
 use std::io;
 use std::mem;
 
-use {Decompress, Compress, Status, Flush, DataError};
+use {Decompress, Compress, Status, Flush};
 
 #[derive(Debug)]
 pub struct Writer<W: Write, D: Ops> {
@@ -15,21 +15,21 @@ pub trait Ops {
     fn total_in(&self) -> u64;
     fn total_out(&self) -> u64;
     fn run(&mut self, input: &[u8], output: &mut [u8], flush: Flush)
-           -> Result<Status, DataError>;
+           -> io::Result<Status>;
     fn run_vec(&mut self, input: &[u8], output: &mut Vec<u8>, flush: Flush)
-               -> Result<Status, DataError>;
+               -> io::Result<Status>;
 }
 
 impl Ops for Compress {
     fn total_in(&self) -> u64 { self.total_in() }
     fn total_out(&self) -> u64 { self.total_out() }
     fn run(&mut self, input: &[u8], output: &mut [u8], flush: Flush)
-           -> Result<Status, DataError> {
-        Ok(self.compress(input, output, flush))
+           -> io::Result<Status> {
+        self.compress(input, output, flush).map_err(io::Error::from)
     }
     fn run_vec(&mut self, input: &[u8], output: &mut Vec<u8>, flush: Flush)
-               -> Result<Status, DataError> {
-        Ok(self.compress_vec(input, output, flush))
+               -> io::Result<Status> {
+        self.compress_vec(input, output, flush).map_err(io::Error::from)
     }
 }
 
@@ -37,17 +37,26 @@ impl Ops for Decompress {
     fn total_in(&self) -> u64 { self.total_in() }
     fn total_out(&self) -> u64 { self.total_out() }
     fn run(&mut self, input: &[u8], output: &mut [u8], flush: Flush)
-           -> Result<Status, DataError> {
-        self.decompress(input, output, flush)
+           -> io::Result<Status> {
+        self.decompress(input, output, flush).map_err(io::Error::from)
     }
     fn run_vec(&mut self, input: &[u8], output: &mut Vec<u8>, flush: Flush)
-               -> Result<Status, DataError> {
-        self.decompress_vec(input, output, flush)
+               -> io::Result<Status> {
+        self.decompress_vec(input, output, flush).map_err(io::Error::from)
     }
 }
 
 pub fn read<R, D>(obj: &mut R, data: &mut D, dst: &mut [u8]) -> io::Result<usize>
     where R: BufRead, D: Ops
+{
+    read_with(obj, data, dst, Flush::None)
+}
+
+// The same as `read`, but passing `flush` rather than `Flush::None` until the
+// input runs out.
+pub fn read_with<R, D>(obj: &mut R, data: &mut D, dst: &mut [u8], flush: Flush)
+                       -> io::Result<usize>
+    where R: BufRead, D: Ops
 {
     loop {
         let (read, consumed, ret, eof);
@@ -56,7 +65,7 @@ pub fn read<R, D>(obj: &mut R, data: &mut D, dst: &mut [u8]) -> io::Result<usize
             eof = input.is_empty();
             let before_out = data.total_out();
             let before_in = data.total_in();
-            let flush = if eof {Flush::Finish} else {Flush::None};
+            let flush = if eof {Flush::Finish} else {flush};
             ret = data.run(input, dst, flush);
             read = (data.total_out() - before_out) as usize;
             consumed = (data.total_in() - before_in) as usize;
@@ -76,12 +85,17 @@ pub fn read<R, D>(obj: &mut R, data: &mut D, dst: &mut [u8]) -> io::Result<usize
             Ok(Status::BufError) |
             Ok(Status::StreamEnd) => return Ok(read),
 
-            Err(..) => return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                                 "corrupt deflate stream"))
+            Ok(Status::NeedDictionary(..)) => return Err(need_dictionary()),
+            Err(e) => return Err(e),
         }
     }
 }
 
+fn need_dictionary() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput,
+                   "deflate stream requires a preset dictionary")
+}
+
 impl<W: Write, D: Ops> Writer<W, D> {
     pub fn new(w: W, d: D) -> Writer<W, D> {
         Writer {
@@ -128,19 +142,35 @@ impl<W: Write, D: Ops> Writer<W, D> {
         self.obj.is_some()
     }
 
-    fn dump(&mut self) -> io::Result<()> {
-        // TODO: should manage this buffer not with `drain` but probably more of
-        // a deque-like strategy.
-        while self.buf.len() > 0 {
-            let n = try!(self.obj.as_mut().unwrap().write(&self.buf));
-            self.buf.drain(..n);
+    // Performs a sync flush of the stream and writes everything it produced
+    // to the underlying writer, without flushing the writer itself.
+    pub fn flush_data(&mut self) -> io::Result<()> {
+        self.flush_data_with(Flush::Sync)
+    }
+
+    // The same as `flush_data`, but with the given kind of flush.
+    pub fn flush_data_with(&mut self, flush: Flush) -> io::Result<()> {
+        // A flush which runs out of output space is only completed by asking
+        // for the same flush again, so start with an empty buffer and keep
+        // going for as long as the stream fills it up. The stream doesn't
+        // tell us when it's done, so a buffer which isn't filled all the way
+        // is taken to mean that everything has been pulled out.
+        try!(self.dump());
+        loop {
+            try!(self.data.run_vec(&[], &mut self.buf, flush));
+            let full = self.buf.len() == self.buf.capacity();
+            try!(self.dump());
+            if !full {
+                return Ok(())
+            }
         }
-        Ok(())
     }
-}
 
-impl<W: Write, D: Ops> Write for Writer<W, D> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    // The same as `write`, but also returns the status of the stream, so
+    // callers can tell when it has ended. Once it has, no more input is
+    // consumed.
+    pub fn write_with_status(&mut self, buf: &[u8])
+                             -> io::Result<(usize, Status)> {
         // miniz isn't guaranteed to actually write any of the buffer provided,
         // it may be in a flushing mode where it's just giving us data before
         // we're actually giving it any data. We don't want to spuriously return
@@ -154,37 +184,45 @@ impl<W: Write, D: Ops> Write for Writer<W, D> {
             let ret = self.data.run_vec(buf, &mut self.buf, Flush::None);
             let written = (self.data.total_in() - before_in) as usize;
 
-            if buf.len() > 0 && written == 0 && ret.is_ok() {
+            // Only retry when the stream could make progress the next time
+            // around, not when it has ended or is stuck waiting for a
+            // dictionary.
+            let retry = match ret {
+                Ok(Status::Ok) | Ok(Status::BufError) => true,
+                _ => false,
+            };
+            if buf.len() > 0 && written == 0 && retry {
                 continue
             }
             return match ret {
-                Ok(Status::Ok) |
-                Ok(Status::BufError) |
-                Ok(Status::StreamEnd) => Ok(written),
+                Ok(status @ Status::Ok) |
+                Ok(status @ Status::BufError) |
+                Ok(status @ Status::StreamEnd) => Ok((written, status)),
 
-                Err(..) => Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                              "corrupt deflate stream"))
+                Ok(Status::NeedDictionary(..)) => Err(need_dictionary()),
+                Err(e) => Err(e),
             }
         }
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.data.run_vec(&[], &mut self.buf, Flush::Sync).unwrap();
-
-        // Unfortunately miniz doesn't actually tell us when we're done with
-        // pulling out all the data from the internal stream. To remedy this we
-        // have to continually ask the stream for more memory until it doesn't
-        // give us a chunk of memory the same size as our own internal buffer,
-        // at which point we assume it's reached the end.
-        loop {
-            try!(self.dump());
-            let before = self.data.total_out();
-            self.data.run_vec(&[], &mut self.buf, Flush::None).unwrap();
-            if before == self.data.total_out() {
-                break
-            }
+    fn dump(&mut self) -> io::Result<()> {
+        // TODO: should manage this buffer not with `drain` but probably more of
+        // a deque-like strategy.
+        while self.buf.len() > 0 {
+            let n = try!(self.obj.as_mut().unwrap().write(&self.buf));
+            self.buf.drain(..n);
         }
+        Ok(())
+    }
+}
 
+impl<W: Write, D: Ops> Write for Writer<W, D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_with_status(buf).map(|(written, _)| written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.flush_data());
         self.obj.as_mut().unwrap().flush()
     }
 }
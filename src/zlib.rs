@@ -11,7 +11,9 @@ use tokio_io::{AsyncRead, AsyncWrite};
 
 use bufreader::BufReader;
 use zio;
-use {Compress, Decompress};
+use {Compress, CompressBuilder, Decompress, DecompressBuilder, Flush};
+use Strategy;
+use ZlibHeader;
 
 /// A ZLIB encoder, or compressor.
 ///
@@ -68,6 +70,9 @@ pub struct DecoderReader<R> {
 pub struct DecoderReaderBuf<R> {
     obj: R,
     data: Decompress,
+    // Passed to the decompressor while there's input left, which is
+    // `Flush::Block` while `zran` indexes the stream.
+    flush: Flush,
 }
 
 /// A ZLIB decoder, or decompressor.
@@ -93,6 +98,51 @@ impl<W: Write> EncoderWriter<W> {
         }
     }
 
+    /// Creates a new encoder which will write compressed data to the stream
+    /// given, using the compression parameters configured in `builder`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the parameters can't be honored, as described in
+    /// `CompressBuilder::build`.
+    pub fn new_with_builder(w: W, builder: CompressBuilder)
+                            -> io::Result<EncoderWriter<W>> {
+        let data = try!(builder.build(true));
+        Ok(EncoderWriter {
+            inner: zio::Writer::new(w, data),
+        })
+    }
+
+    /// Creates a new encoder which will write compressed data to the stream
+    /// given, primed with a preset dictionary.
+    ///
+    /// The same dictionary must be provided to the decoder.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend doesn't support preset dictionaries.
+    pub fn new_with_dictionary(w: W, level: ::Compression, dictionary: &[u8])
+                               -> io::Result<EncoderWriter<W>> {
+        let builder = CompressBuilder::new(level).dictionary(dictionary);
+        EncoderWriter::new_with_builder(w, builder)
+    }
+
+    /// Changes the compression level and strategy used for data written from
+    /// now on.
+    ///
+    /// All data written so far is first flushed to the underlying writer, as
+    /// with `flush`, except that the underlying writer itself is not flushed.
+    ///
+    /// # Errors
+    ///
+    /// This function will perform I/O to flush pending data, and any I/O
+    /// errors which occur will be returned from this function.
+    pub fn set_params(&mut self, level: ::Compression, strategy: Strategy)
+                      -> io::Result<()> {
+        try!(self.inner.flush_data());
+        self.inner.data.set_params(level, strategy)
+    }
+
     /// Acquires a reference to the underlying writer.
     pub fn get_ref(&self) -> &W {
         self.inner.get_ref()
@@ -123,7 +173,7 @@ impl<W: Write> EncoderWriter<W> {
     /// errors which occur will be returned from this function.
     pub fn reset(&mut self, w: W) -> io::Result<W> {
         try!(self.inner.finish());
-        self.inner.data.reset();
+        try!(self.inner.data.try_reset());
         Ok(self.inner.replace(w))
     }
 
@@ -236,6 +286,36 @@ impl<R: Read> EncoderReader<R> {
             inner: EncoderReaderBuf::new(BufReader::new(r), level),
         }
     }
+
+    /// Creates a new encoder which will read uncompressed data from the given
+    /// stream and emit the compressed stream, using the compression
+    /// parameters configured in `builder`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the parameters can't be honored, as described in
+    /// `CompressBuilder::build`.
+    pub fn new_with_builder(r: R, builder: CompressBuilder)
+                            -> io::Result<EncoderReader<R>> {
+        let r = BufReader::new(r);
+        EncoderReaderBuf::new_with_builder(r, builder).map(|inner| {
+            EncoderReader { inner: inner }
+        })
+    }
+
+    /// Creates a new encoder which will read uncompressed data from the given
+    /// stream, primed with a preset dictionary.
+    ///
+    /// The same dictionary must be provided to the decoder.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend doesn't support preset dictionaries.
+    pub fn new_with_dictionary(r: R, level: ::Compression, dictionary: &[u8])
+                               -> io::Result<EncoderReader<R>> {
+        let builder = CompressBuilder::new(level).dictionary(dictionary);
+        EncoderReader::new_with_builder(r, builder)
+    }
 }
 
 impl<R> EncoderReader<R> {
@@ -249,9 +329,20 @@ impl<R> EncoderReader<R> {
     ///
     /// Note that there may be currently buffered data when this function is
     /// called, and in that case the buffered data is discarded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the compression stream can't be reset. Use `try_reset`
+    /// to get an error instead.
     pub fn reset(&mut self, r: R) -> R {
-        self.inner.data.reset();
-        self.inner.obj.reset(r)
+        self.try_reset(r).expect("failed to reset compression stream")
+    }
+
+    /// Like `reset`, but returns an error if the compression stream
+    /// can't be reset, in which case the input stream isn't replaced.
+    pub fn try_reset(&mut self, r: R) -> io::Result<R> {
+        try!(self.inner.data.try_reset());
+        Ok(self.inner.obj.reset(r))
     }
 
     /// Acquires a reference to the underlying stream
@@ -329,6 +420,37 @@ impl<R: BufRead> EncoderReaderBuf<R> {
             data: Compress::new(level, true),
         }
     }
+
+    /// Creates a new encoder which will read uncompressed data from the given
+    /// stream and emit the compressed stream, using the compression
+    /// parameters configured in `builder`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the parameters can't be honored, as described in
+    /// `CompressBuilder::build`.
+    pub fn new_with_builder(r: R, builder: CompressBuilder)
+                            -> io::Result<EncoderReaderBuf<R>> {
+        let data = try!(builder.build(true));
+        Ok(EncoderReaderBuf {
+            obj: r,
+            data: data,
+        })
+    }
+
+    /// Creates a new encoder which will read uncompressed data from the given
+    /// stream, primed with a preset dictionary.
+    ///
+    /// The same dictionary must be provided to the decoder.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend doesn't support preset dictionaries.
+    pub fn new_with_dictionary(r: R, level: ::Compression, dictionary: &[u8])
+                               -> io::Result<EncoderReaderBuf<R>> {
+        let builder = CompressBuilder::new(level).dictionary(dictionary);
+        EncoderReaderBuf::new_with_builder(r, builder)
+    }
 }
 
 impl<R> EncoderReaderBuf<R> {
@@ -339,9 +461,20 @@ impl<R> EncoderReaderBuf<R> {
     /// the input stream with the one provided, returning the previous input
     /// stream. Future data read from this encoder will be the compressed
     /// version of `r`'s data.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the compression stream can't be reset. Use `try_reset`
+    /// to get an error instead.
     pub fn reset(&mut self, r: R) -> R {
-        self.data.reset();
-        mem::replace(&mut self.obj, r)
+        self.try_reset(r).expect("failed to reset compression stream")
+    }
+
+    /// Like `reset`, but returns an error if the compression stream
+    /// can't be reset, in which case the input stream isn't replaced.
+    pub fn try_reset(&mut self, r: R) -> io::Result<R> {
+        try!(self.data.try_reset());
+        Ok(mem::replace(&mut self.obj, r))
     }
 
     /// Acquires a reference to the underlying reader
@@ -422,6 +555,33 @@ impl<R: Read> DecoderReader<R> {
             inner: DecoderReaderBuf::new(BufReader::with_buf(buf, r)),
         }
     }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, using the decompression parameters configured in `builder`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the parameters can't be honored, as described in
+    /// `DecompressBuilder::build`.
+    pub fn new_with_builder(r: R, builder: DecompressBuilder)
+                            -> io::Result<DecoderReader<R>> {
+        let r = BufReader::new(r);
+        DecoderReaderBuf::new_with_builder(r, builder).map(|inner| {
+            DecoderReader { inner: inner }
+        })
+    }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, using the preset dictionary the data was compressed with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend doesn't support preset dictionaries.
+    pub fn new_with_dictionary(r: R, dictionary: &[u8])
+                               -> io::Result<DecoderReader<R>> {
+        let builder = DecompressBuilder::new().dictionary(dictionary);
+        DecoderReader::new_with_builder(r, builder)
+    }
 }
 
 impl<R> DecoderReader<R> {
@@ -435,9 +595,20 @@ impl<R> DecoderReader<R> {
     ///
     /// Note that there may be currently buffered data when this function is
     /// called, and in that case the buffered data is discarded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the decompression stream can't be reset. Use `try_reset`
+    /// to get an error instead.
     pub fn reset(&mut self, r: R) -> R {
-        self.inner.data = Decompress::new(true);
-        self.inner.obj.reset(r)
+        self.try_reset(r).expect("failed to reset decompression stream")
+    }
+
+    /// Like `reset`, but returns an error if the decompression stream
+    /// can't be reset, in which case the input stream isn't replaced.
+    pub fn try_reset(&mut self, r: R) -> io::Result<R> {
+        try!(self.inner.data.try_reset(true));
+        Ok(self.inner.obj.reset(r))
     }
 
     /// Acquires a reference to the underlying stream
@@ -474,6 +645,11 @@ impl<R> DecoderReader<R> {
     pub fn total_out(&self) -> u64 {
         self.inner.total_out()
     }
+
+    /// Returns the zlib header of the stream, once it has been read.
+    pub fn header(&self) -> Option<ZlibHeader> {
+        self.inner.header()
+    }
 }
 
 impl<R: Read> Read for DecoderReader<R> {
@@ -510,8 +686,38 @@ impl<R: BufRead> DecoderReaderBuf<R> {
         DecoderReaderBuf {
             obj: r,
             data: Decompress::new(true),
+            flush: Flush::None,
         }
     }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, using the decompression parameters configured in `builder`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the parameters can't be honored, as described in
+    /// `DecompressBuilder::build`.
+    pub fn new_with_builder(r: R, builder: DecompressBuilder)
+                            -> io::Result<DecoderReaderBuf<R>> {
+        let data = try!(builder.build(true));
+        Ok(DecoderReaderBuf {
+            obj: r,
+            data: data,
+            flush: Flush::None,
+        })
+    }
+
+    /// Creates a new decoder which will decompress data read from the given
+    /// stream, using the preset dictionary the data was compressed with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend doesn't support preset dictionaries.
+    pub fn new_with_dictionary(r: R, dictionary: &[u8])
+                               -> io::Result<DecoderReaderBuf<R>> {
+        let builder = DecompressBuilder::new().dictionary(dictionary);
+        DecoderReaderBuf::new_with_builder(r, builder)
+    }
 }
 
 impl<R> DecoderReaderBuf<R> {
@@ -522,9 +728,20 @@ impl<R> DecoderReaderBuf<R> {
     /// input stream with the one provided, returning the previous input
     /// stream. Future data read from this decoder will be the decompressed
     /// version of `r`'s data.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the decompression stream can't be reset. Use `try_reset`
+    /// to get an error instead.
     pub fn reset(&mut self, r: R) -> R {
-        self.data = Decompress::new(true);
-        mem::replace(&mut self.obj, r)
+        self.try_reset(r).expect("failed to reset decompression stream")
+    }
+
+    /// Like `reset`, but returns an error if the decompression stream
+    /// can't be reset, in which case the input stream isn't replaced.
+    pub fn try_reset(&mut self, r: R) -> io::Result<R> {
+        try!(self.data.try_reset(true));
+        Ok(mem::replace(&mut self.obj, r))
     }
 
     /// Acquires a reference to the underlying stream
@@ -557,14 +774,26 @@ impl<R> DecoderReaderBuf<R> {
     pub fn total_out(&self) -> u64 {
         self.data.total_out()
     }
+
+    /// Returns the zlib header of the stream, once it has been read.
+    pub fn header(&self) -> Option<ZlibHeader> {
+        self.data.zlib_header()
+    }
 }
 
 impl<R: BufRead> Read for DecoderReaderBuf<R> {
     fn read(&mut self, into: &mut [u8]) -> io::Result<usize> {
-        zio::read(&mut self.obj, &mut self.data, into)
+        zio::read_with(&mut self.obj, &mut self.data, into, self.flush)
     }
 }
 
+// Makes `r` return from every read at the next deflate block boundary, and
+// gives access to its decompressor, for `zran` to index the stream with.
+pub fn stop_at_blocks<R>(r: &mut DecoderReaderBuf<R>) -> &mut Decompress {
+    r.flush = Flush::Block;
+    &mut r.data
+}
+
 #[cfg(feature = "tokio")]
 impl<R: AsyncRead + BufRead> AsyncRead for DecoderReaderBuf<R> {
 }
@@ -597,6 +826,33 @@ impl<W: Write> DecoderWriter<W> {
         }
     }
 
+    /// Creates a new decoder which will write uncompressed data to the stream,
+    /// using the decompression parameters configured in `builder`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the parameters can't be honored, as described in
+    /// `DecompressBuilder::build`.
+    pub fn new_with_builder(w: W, builder: DecompressBuilder)
+                            -> io::Result<DecoderWriter<W>> {
+        let data = try!(builder.build(true));
+        Ok(DecoderWriter {
+            inner: zio::Writer::new(w, data),
+        })
+    }
+
+    /// Creates a new decoder which will write uncompressed data to the stream,
+    /// using the preset dictionary the data was compressed with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the backend doesn't support preset dictionaries.
+    pub fn new_with_dictionary(w: W, dictionary: &[u8])
+                               -> io::Result<DecoderWriter<W>> {
+        let builder = DecompressBuilder::new().dictionary(dictionary);
+        DecoderWriter::new_with_builder(w, builder)
+    }
+
     /// Acquires a reference to the underlying writer.
     pub fn get_ref(&self) -> &W {
         self.inner.get_ref()
@@ -624,7 +880,7 @@ impl<W: Write> DecoderWriter<W> {
     /// errors which occur will be returned from this function.
     pub fn reset(&mut self, w: W) -> io::Result<W> {
         try!(self.inner.finish());
-        self.inner.data = Decompress::new(true);
+        try!(self.inner.data.try_reset(true));
         Ok(self.inner.replace(w))
     }
 
@@ -681,6 +937,11 @@ impl<W: Write> DecoderWriter<W> {
     pub fn total_out(&self) -> u64 {
         self.inner.data.total_out()
     }
+
+    /// Returns the zlib header of the stream, once it has been read.
+    pub fn header(&self) -> Option<ZlibHeader> {
+        self.inner.data.zlib_header()
+    }
 }
 
 impl<W: Write> Write for DecoderWriter<W> {
@@ -719,12 +980,13 @@ mod tests {
     use rand::{thread_rng, Rng};
 
     use zlib::{EncoderWriter, EncoderReader, DecoderReader, DecoderWriter};
-    use Compression::Default;
+    use {Compression, CompressBuilder, DecompressBuilder, Strategy};
+    use {Decompress, Flush, Status};
 
     #[test]
     fn roundtrip() {
         let mut real = Vec::new();
-        let mut w = EncoderWriter::new(Vec::new(), Default);
+        let mut w = EncoderWriter::new(Vec::new(), Compression::default());
         let v = thread_rng().gen_iter::<u8>().take(1024).collect::<Vec<_>>();
         for _ in 0..200 {
             let to_write = &v[..thread_rng().gen_range(0, v.len())];
@@ -741,7 +1003,9 @@ mod tests {
     #[test]
     fn drop_writes() {
         let mut data = Vec::new();
-        EncoderWriter::new(&mut data, Default).write_all(b"foo").unwrap();
+        EncoderWriter::new(&mut data, Compression::default())
+            .write_all(b"foo")
+            .unwrap();
         let mut r = DecoderReader::new(&data[..]);
         let mut ret = Vec::new();
         r.read_to_end(&mut ret).unwrap();
@@ -751,7 +1015,7 @@ mod tests {
     #[test]
     fn total_in() {
         let mut real = Vec::new();
-        let mut w = EncoderWriter::new(Vec::new(), Default);
+        let mut w = EncoderWriter::new(Vec::new(), Compression::default());
         let v = thread_rng().gen_iter::<u8>().take(1024).collect::<Vec<_>>();
         for _ in 0..200 {
             let to_write = &v[..thread_rng().gen_range(0, v.len())];
@@ -779,7 +1043,8 @@ mod tests {
                     .gen_iter::<u8>()
                     .take(1024 * 1024)
                     .collect::<Vec<_>>();
-        let mut r = DecoderReader::new(EncoderReader::new(&v[..], Default));
+        let r = EncoderReader::new(&v[..], Compression::default());
+        let mut r = DecoderReader::new(r);
         let mut ret = Vec::new();
         r.read_to_end(&mut ret).unwrap();
         assert_eq!(ret, v);
@@ -791,7 +1056,8 @@ mod tests {
                     .gen_iter::<u8>()
                     .take(1024 * 1024)
                     .collect::<Vec<_>>();
-        let mut w = EncoderWriter::new(DecoderWriter::new(Vec::new()), Default);
+        let mut w = EncoderWriter::new(DecoderWriter::new(Vec::new()),
+                                       Compression::default());
         w.write_all(&v).unwrap();
         let w = w.finish().unwrap().finish().unwrap();
         assert!(w == v);
@@ -803,7 +1069,7 @@ mod tests {
                     .gen_iter::<u8>()
                     .take(1024 * 1024)
                     .collect::<Vec<_>>();
-        let mut w = EncoderWriter::new(Vec::new(), Default);
+        let mut w = EncoderWriter::new(Vec::new(), Compression::default());
         w.write_all(&v).unwrap();
         let data = w.finish().unwrap();
 
@@ -833,6 +1099,97 @@ mod tests {
         }
     }
 
+    #[test]
+    fn all_levels() {
+        let v = thread_rng().gen_iter::<u8>().take(1024).collect::<Vec<_>>();
+        for level in 0..10 {
+            let mut w = EncoderWriter::new(Vec::new(), Compression::new(level));
+            w.write_all(&v).unwrap();
+            let data = w.finish().unwrap();
+
+            // The FLEVEL bits of the header must describe the level used.
+            let flevel = match level {
+                0 | 1 => 0,
+                2..=5 => 1,
+                6 => 2,
+                _ => 3,
+            };
+            assert_eq!(data[0], 0x78);
+            assert_eq!(data[1] >> 6, flevel);
+            assert_eq!(((data[0] as u16) << 8 | data[1] as u16) % 31, 0);
+
+            let mut r = DecoderReader::new(&data[..]);
+            let mut ret = Vec::new();
+            r.read_to_end(&mut ret).unwrap();
+            assert_eq!(ret, v);
+        }
+    }
+
+    #[test]
+    fn builder() {
+        let v = thread_rng().gen_iter::<u8>().take(1024).collect::<Vec<_>>();
+        let builder = CompressBuilder::new(Compression::new(3))
+            .strategy(Strategy::Filtered);
+        let mut w = EncoderWriter::new_with_builder(Vec::new(), builder)
+            .unwrap();
+        w.write_all(&v).unwrap();
+        let data = w.finish().unwrap();
+
+        let builder = DecompressBuilder::new();
+        let mut r = DecoderReader::new_with_builder(&data[..], builder)
+            .unwrap();
+        let mut ret = Vec::new();
+        r.read_to_end(&mut ret).unwrap();
+        assert_eq!(ret, v);
+    }
+
+    #[test]
+    fn dictionary() {
+        let dict = b"{\"id\": 0, \"name\": \"\", \"tags\": []}";
+        let msg = b"{\"id\": 7, \"name\": \"flate2\", \"tags\": [\"zlib\"]}";
+        let level = Compression::best();
+
+        let mut w = EncoderWriter::new_with_dictionary(Vec::new(), level, dict)
+            .unwrap();
+        w.write_all(msg).unwrap();
+        let data = w.finish().unwrap();
+        let r = EncoderReader::new_with_dictionary(&msg[..], level, dict)
+            .unwrap();
+        let mut data2 = Vec::new();
+        r.take(1024).read_to_end(&mut data2).unwrap();
+        assert_eq!(data, data2);
+
+        let mut r = DecoderReader::new_with_dictionary(&data[..], dict)
+            .unwrap();
+        let mut ret = Vec::new();
+        r.read_to_end(&mut ret).unwrap();
+        assert_eq!(ret, &msg[..]);
+
+        let mut w = DecoderWriter::new_with_dictionary(Vec::new(), dict)
+            .unwrap();
+        w.write_all(&data).unwrap();
+        assert_eq!(w.finish().unwrap(), &msg[..]);
+
+        // Without the dictionary the stream can't be decoded.
+        let mut r = DecoderReader::new(&data[..]);
+        assert!(r.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn missing_dictionary_writer() {
+        let dict = b"a preset dictionary";
+        let mut w = EncoderWriter::new_with_dictionary(Vec::new(),
+                                                       Compression::default(),
+                                                       dict).unwrap();
+        w.write_all(b"a preset dictionary, used").unwrap();
+        let data = w.finish().unwrap();
+
+        // Every write keeps failing rather than retrying forever.
+        let mut w = DecoderWriter::new(Vec::new());
+        assert!(w.write(&data).is_err());
+        assert!(w.write(&data[2..]).is_err());
+    }
+
     #[test]
     fn bad_input() {
         // regress tests: previously caused a panic on drop
@@ -841,7 +1198,7 @@ mod tests {
         let mut w = DecoderWriter::new(&mut out);
         match w.write_all(&data[..]) {
             Ok(_) => panic!("Expected an error to be returned!"),
-            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
         }
     }
 
@@ -850,7 +1207,8 @@ mod tests {
         ::quickcheck::quickcheck(test as fn(_) -> _);
 
         fn test(v: Vec<u8>) -> bool {
-            let mut r = DecoderReader::new(EncoderReader::new(&v[..], Default));
+            let r = EncoderReader::new(&v[..], Compression::default());
+            let mut r = DecoderReader::new(r);
             let mut v2 = Vec::new();
             r.read_to_end(&mut v2).unwrap();
             v == v2
@@ -862,9 +1220,49 @@ mod tests {
         ::quickcheck::quickcheck(test as fn(_) -> _);
 
         fn test(v: Vec<u8>) -> bool {
-            let mut w = EncoderWriter::new(DecoderWriter::new(Vec::new()), Default);
+            let mut w = EncoderWriter::new(DecoderWriter::new(Vec::new()),
+                                           Compression::default());
             w.write_all(&v).unwrap();
             v == w.finish().unwrap().finish().unwrap()
         }
     }
+
+    #[test]
+    fn header() {
+        let mut w = EncoderWriter::new(Vec::new(), Compression::default());
+        w.write_all(b"hello").unwrap();
+        let data = w.finish().unwrap();
+
+        let mut r = DecoderReader::new(&data[..]);
+        assert_eq!(r.header(), None);
+        r.read_to_end(&mut Vec::new()).unwrap();
+        let header = r.header().unwrap();
+        assert_eq!(header.window_bits(), 15);
+        assert_eq!(header.dictionary_id(), None);
+
+        let mut w = DecoderWriter::new(Vec::new());
+        w.write_all(&data[..1]).unwrap();
+        assert_eq!(w.header(), None);
+        w.write_all(&data[1..]).unwrap();
+        assert_eq!(w.header(), Some(header));
+
+        let dictionary = b"hello world";
+        let mut w = EncoderWriter::new_with_dictionary(Vec::new(),
+                                                       Compression::default(),
+                                                       dictionary).unwrap();
+        w.write_all(b"hello").unwrap();
+        let data = w.finish().unwrap();
+        let mut r = DecoderReader::new_with_dictionary(&data[..], dictionary)
+            .unwrap();
+        r.read_to_end(&mut Vec::new()).unwrap();
+        let header = r.header().unwrap();
+        assert!(header.has_dictionary());
+        assert_eq!(header.header_len(), 6);
+        let mut out = [0; 16];
+        let status = Decompress::new(true)
+            .decompress(&data, &mut out, Flush::None)
+            .unwrap();
+        assert_eq!(status, Status::NeedDictionary(header.dictionary_id()
+                                                      .unwrap()));
+    }
 }
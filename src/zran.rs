@@ -0,0 +1,788 @@
+//! Random access to ordinary gzip and zlib streams, in the style of zlib's
+//! `zran.c` example
+//!
+//! Deflate data can't be decompressed from an arbitrary position, as every
+//! block may refer back to the 32 KiB of output before it and blocks needn't
+//! start on a byte boundary. An [`Index`] is built with one pass over the
+//! whole stream, recording a [`Checkpoint`] at a block boundary every so
+//! often: the position of the boundary in the compressed data, down to the
+//! bit, the corresponding position in the uncompressed data, and the window
+//! preceding it. A [`SeekableGzReader`] then resumes decompression from the
+//! checkpoint closest to the position sought, so at most the distance
+//! between two checkpoints has to be decompressed and thrown away.
+//!
+//! The miniz_oxide backend doesn't report block boundaries, so indexes can't
+//! be built with it. A gzip file written by a [`GzEncoder`] with a flush
+//! interval doesn't need to be indexed this way, though: decompression can
+//! start afresh at each of its full flush points, so an index of those,
+//! which can also be embedded at the end of the file, works with any backend.
+//!
+//! [`Index`]: struct.Index.html
+//! [`Checkpoint`]: struct.Checkpoint.html
+//! [`SeekableGzReader`]: ../read/struct.SeekableGzReader.html
+//! [`GzEncoder`]: ../write/struct.GzEncoder.html#method.set_flush_interval
+
+use std::cmp;
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+
+use {Decompress, DecompressFormat, Flush, Status};
+use backend;
+use bufreader::BufReader;
+use deflate;
+use gz;
+use zlib;
+
+const BUF_SIZE: usize = 32 * 1024;
+
+const MAGIC: &'static [u8; 4] = b"ZIDX";
+const VERSION: u8 = 1;
+
+/// A builder for an [`Index`], configuring how far apart its checkpoints
+/// are.
+///
+/// [`Index`]: struct.Index.html
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::fs::File;
+/// use std::io::prelude::*;
+/// use std::io::SeekFrom;
+/// use flate2::DecompressFormat;
+/// use flate2::read::SeekableGzReader;
+/// use flate2::zran;
+///
+/// # fn run() -> std::io::Result<()> {
+/// let index = try!(zran::Builder::new()
+///     .spacing(4 << 20)
+///     .build(try!(File::open("access.log.gz")), DecompressFormat::Gzip));
+/// try!(index.write_to(try!(File::create("access.log.gz.zidx"))));
+///
+/// let file = try!(File::open("access.log.gz"));
+/// let mut r = try!(SeekableGzReader::new(file, index));
+/// try!(r.seek(SeekFrom::Start(1 << 30)));
+/// let mut buf = [0; 4096];
+/// try!(r.read_exact(&mut buf));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Builder {
+    spacing: u64,
+}
+
+/// An index of checkpoints within a single zlib stream or gzip member.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Index {
+    format: DecompressFormat,
+    length: u64,
+    checkpoints: Vec<Checkpoint>,
+}
+
+/// A position at a block boundary from which decompression can be resumed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    input: u64,
+    bits: u8,
+    output: u64,
+    window: Vec<u8>,
+}
+
+/// A gzip or zlib decoder which can seek to any position in the
+/// decompressed data, with the help of an [`Index`]
+///
+/// The compressed stream must start at the beginning of the underlying
+/// reader, which must be the same stream the index was built from. Seeking
+/// resumes decompression from the closest checkpoint before the position
+/// sought, unless that position is a little ahead of the current one.
+///
+/// [`Index`]: ../zran/struct.Index.html
+#[derive(Debug)]
+pub struct SeekableGzReader<R: Read + Seek> {
+    inner: R,
+    index: Index,
+    decompress: Decompress,
+    buf: Vec<u8>,
+    pos: usize,
+    end: usize,
+    // The position in the decompressed data.
+    position: u64,
+    done: bool,
+}
+
+impl Builder {
+    /// Creates a new builder placing checkpoints about 1 MiB of decompressed
+    /// data apart.
+    pub fn new() -> Builder {
+        Builder { spacing: 1 << 20 }
+    }
+
+    /// Configures the minimum distance between checkpoints in the
+    /// decompressed data.
+    ///
+    /// Every checkpoint holds a window of 32 KiB, so closer checkpoints make
+    /// seeking faster at the cost of a larger index.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `spacing` is zero.
+    pub fn spacing(mut self, spacing: u64) -> Builder {
+        assert!(spacing > 0, "checkpoints need to be some distance apart");
+        self.spacing = spacing;
+        self
+    }
+
+    /// Builds the index of the stream read from `r`, which is decompressed
+    /// all the way through in the given format, with the same decoders as
+    /// `read::GzDecoder`, `read::ZlibDecoder` and `read::DeflateDecoder`.
+    ///
+    /// For gzip files only the first member is indexed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading fails, if the stream is invalid or
+    /// truncated, or if the compression backend doesn't report block
+    /// boundaries (the miniz_oxide backend doesn't).
+    pub fn build<R: Read>(&self, r: R, format: DecompressFormat)
+                          -> io::Result<Index> {
+        if !backend::default().supports_block_flush() {
+            return Err(unsupported())
+        }
+        let mut r = Counter {
+            inner: BufReader::with_buf(vec![0; BUF_SIZE], r),
+            consumed: 0,
+        };
+        // The first byte of a zlib header always has 8 in its low four bits,
+        // so it can't be mistaken for gzip's 0x1f.
+        let detected = match format {
+            DecompressFormat::Auto => {
+                match try!(r.fill_buf()).first() {
+                    Some(&0x1f) => DecompressFormat::Gzip,
+                    Some(_) => DecompressFormat::Zlib,
+                    None => return Err(truncated()),
+                }
+            }
+            format => format,
+        };
+        let (length, checkpoints) = match detected {
+            DecompressFormat::Gzip => {
+                let d = try!(gz::DecoderReaderBuf::new(r));
+                try!(self.index(d))
+            }
+            DecompressFormat::Zlib => {
+                try!(self.index(zlib::DecoderReaderBuf::new(r)))
+            }
+            _ => try!(self.index(deflate::DecoderReaderBuf::new(r))),
+        };
+        Ok(Index {
+            format: format,
+            length: length,
+            checkpoints: checkpoints,
+        })
+    }
+
+    // Reads `d` to the end, noting a checkpoint at a block boundary whenever
+    // the last one is far enough behind, and returns the length of the
+    // decompressed data along with the checkpoints.
+    fn index<D: Decoder>(&self, mut d: D)
+                         -> io::Result<(u64, Vec<Checkpoint>)> {
+        let mut output = vec![0; BUF_SIZE];
+        let mut checkpoints = Vec::new();
+        let mut length = 0;
+        let mut last = 0;
+        loop {
+            match d.read(&mut output) {
+                Ok(0) => break,
+                Ok(n) => length += n as u64,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+            let info = d.decompress().block_info().unwrap();
+            if info.block_boundary && !info.last_block &&
+               length - last >= self.spacing {
+                checkpoints.push(Checkpoint {
+                    input: d.consumed(),
+                    bits: info.unused_bits,
+                    output: length,
+                    window: try!(d.decompress().window()),
+                });
+                last = length;
+            }
+        }
+
+        // The zlib and deflate decoders stop quietly when the input runs out
+        // early, so make sure the end of the stream was actually reached.
+        match d.decompress().decompress(&[], &mut [], Flush::Finish) {
+            Ok(Status::StreamEnd) => Ok((length, checkpoints)),
+            _ => Err(truncated()),
+        }
+    }
+}
+
+// The decoders an index is built with, reading through a `Counter`.
+trait Decoder: Read {
+    // Returns the number of bytes of the compressed stream read so far.
+    fn consumed(&self) -> u64;
+
+    // Returns the decompressor, which stops at every block boundary.
+    fn decompress(&mut self) -> &mut Decompress;
+}
+
+impl<R: Read> Decoder for gz::DecoderReaderBuf<Counter<R>> {
+    fn consumed(&self) -> u64 {
+        self.get_ref().consumed
+    }
+
+    fn decompress(&mut self) -> &mut Decompress {
+        gz::stop_at_blocks(self)
+    }
+}
+
+impl<R: Read> Decoder for zlib::DecoderReaderBuf<Counter<R>> {
+    fn consumed(&self) -> u64 {
+        self.get_ref().consumed
+    }
+
+    fn decompress(&mut self) -> &mut Decompress {
+        zlib::stop_at_blocks(self)
+    }
+}
+
+impl<R: Read> Decoder for deflate::DecoderReaderBuf<Counter<R>> {
+    fn consumed(&self) -> u64 {
+        self.get_ref().consumed
+    }
+
+    fn decompress(&mut self) -> &mut Decompress {
+        deflate::stop_at_blocks(self)
+    }
+}
+
+// Buffers the compressed stream, counting the bytes consumed from the
+// buffer, which is where the decoder reading from it is in the stream.
+struct Counter<R> {
+    inner: BufReader<R>,
+    consumed: u64,
+}
+
+impl<R: Read> Read for Counter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let available = try!(self.fill_buf());
+            let n = cmp::min(available.len(), buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read> BufRead for Counter<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consumed += amt as u64;
+        self.inner.consume(amt)
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+impl Index {
+    /// Creates an index of a stream which was compressed with a full flush at
+    /// each of `points`, given as pairs of the offset in the uncompressed data
+    /// and the offset in the compressed stream, in order.
+    ///
+    /// `length` is the length of the uncompressed data.
+    pub fn from_flush_points(format: DecompressFormat,
+                             length: u64,
+                             points: &[(u64, u64)])
+                             -> Index {
+        Index {
+            format: format,
+            length: length,
+            checkpoints: points.iter().map(|&(output, input)| {
+                Checkpoint {
+                    input: input,
+                    bits: 0,
+                    output: output,
+                    window: Vec::new(),
+                }
+            }).collect(),
+        }
+    }
+
+    /// Reads the index of flush points embedded at the end of a gzip file by
+    /// a [`GzEncoder`], leaving `r` at an unspecified position.
+    ///
+    /// [`GzEncoder`]: ../write/struct.GzEncoder.html#method.set_embed_index
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the file doesn't end with an
+    /// embedded index.
+    pub fn read_embedded<R: Read + Seek>(r: &mut R) -> io::Result<Index> {
+        let (length, points) = try!(gz::read_index_member(r));
+        Ok(Index::from_flush_points(DecompressFormat::Gzip, length, &points))
+    }
+
+    /// Returns the format of the indexed stream.
+    pub fn format(&self) -> DecompressFormat {
+        self.format
+    }
+
+    /// Returns the length of the decompressed data.
+    pub fn uncompressed_len(&self) -> u64 {
+        self.length
+    }
+
+    /// Returns the checkpoints in the index, in order.
+    pub fn checkpoints(&self) -> &[Checkpoint] {
+        &self.checkpoints
+    }
+
+    /// Reads an index written by `write_to`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the data read isn't an
+    /// index in the format written by this version of the crate.
+    pub fn read_from<R: Read>(mut r: R) -> io::Result<Index> {
+        let mut header = [0; 6];
+        try!(r.read_exact(&mut header));
+        if &header[..4] != MAGIC || header[4] != VERSION {
+            return Err(invalid_index())
+        }
+        let format = match header[5] {
+            0 => DecompressFormat::Raw,
+            1 => DecompressFormat::Zlib,
+            2 => DecompressFormat::Gzip,
+            3 => DecompressFormat::Auto,
+            _ => return Err(invalid_index()),
+        };
+        let length = try!(read_u64(&mut r));
+        let count = try!(read_u64(&mut r));
+        let mut checkpoints = Vec::new();
+        for _ in 0..count {
+            let input = try!(read_u64(&mut r));
+            let output = try!(read_u64(&mut r));
+            let mut b = [0; 5];
+            try!(r.read_exact(&mut b));
+            let len = b[1..].iter().rev().fold(0, |n, &b| n << 8 | b as usize);
+            if b[0] > 7 || len > BUF_SIZE {
+                return Err(invalid_index())
+            }
+            let mut window = vec![0; len];
+            try!(r.read_exact(&mut window));
+            checkpoints.push(Checkpoint {
+                input: input,
+                bits: b[0],
+                output: output,
+                window: window,
+            });
+        }
+        Ok(Index {
+            format: format,
+            length: length,
+            checkpoints: checkpoints,
+        })
+    }
+
+    /// Writes this index in a compact binary format, which can be read back
+    /// with `read_from`.
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let format = match self.format {
+            DecompressFormat::Raw => 0,
+            DecompressFormat::Zlib => 1,
+            DecompressFormat::Gzip => 2,
+            DecompressFormat::Auto => 3,
+        };
+        try!(w.write_all(MAGIC));
+        try!(w.write_all(&[VERSION, format]));
+        try!(write_u64(&mut w, self.length));
+        try!(write_u64(&mut w, self.checkpoints.len() as u64));
+        for checkpoint in self.checkpoints.iter() {
+            try!(write_u64(&mut w, checkpoint.input));
+            try!(write_u64(&mut w, checkpoint.output));
+            let len = checkpoint.window.len();
+            try!(w.write_all(&[checkpoint.bits, len as u8, (len >> 8) as u8,
+                               (len >> 16) as u8, (len >> 24) as u8]));
+            try!(w.write_all(&checkpoint.window));
+        }
+        Ok(())
+    }
+
+    // Finds the last checkpoint at or before `position`.
+    fn find(&self, position: u64) -> Option<&Checkpoint> {
+        let i = match self.checkpoints.binary_search_by_key(&position,
+                                                             |c| c.output) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        };
+        if i == 0 {
+            None
+        } else {
+            Some(&self.checkpoints[i - 1])
+        }
+    }
+}
+
+impl Checkpoint {
+    /// Returns the offset in the compressed stream of the byte holding the
+    /// first bit after the block boundary, plus one if that bit isn't the
+    /// first in its byte.
+    pub fn input(&self) -> u64 {
+        self.input
+    }
+
+    /// Returns the number of bits of the byte before `input` which come
+    /// after the block boundary, from 0 to 7.
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    /// Returns the position in the decompressed data.
+    pub fn output(&self) -> u64 {
+        self.output
+    }
+
+    /// Returns the decompressed data preceding the checkpoint, up to 32 KiB.
+    pub fn window(&self) -> &[u8] {
+        &self.window
+    }
+}
+
+impl<R: Read + Seek> SeekableGzReader<R> {
+    /// Creates a new decoder for the stream read from `r`, which is described
+    /// by `index`, positioned at the start of the decompressed data.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `r` can't be rewound to its start.
+    pub fn new(mut r: R, index: Index) -> io::Result<SeekableGzReader<R>> {
+        try!(r.seek(SeekFrom::Start(0)));
+        let decompress = try!(Decompress::try_new_with_format(index.format));
+        Ok(SeekableGzReader {
+            inner: r,
+            index: index,
+            decompress: decompress,
+            buf: vec![0; BUF_SIZE],
+            pos: 0,
+            end: 0,
+            position: 0,
+            done: false,
+        })
+    }
+
+    /// Creates a new decoder for the gzip file read from `r`, using the index
+    /// embedded at its end by a [`GzEncoder`].
+    ///
+    /// [`GzEncoder`]: ../write/struct.GzEncoder.html#method.set_embed_index
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the index can't be read.
+    pub fn with_embedded_index(mut r: R) -> io::Result<SeekableGzReader<R>> {
+        let index = try!(Index::read_embedded(&mut r));
+        SeekableGzReader::new(r, index)
+    }
+
+    /// Returns the index this decoder seeks with.
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    /// Acquires a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Acquires a mutable reference to the underlying reader.
+    ///
+    /// Note that mutation of the reader may result in surprising results if
+    /// this decoder is continued to be used.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Consumes this decoder, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    // Restarts decompression from a checkpoint, or from the start of the
+    // stream.
+    fn resume(&mut self, checkpoint: Option<Checkpoint>) -> io::Result<()> {
+        self.pos = 0;
+        self.end = 0;
+        self.done = false;
+        let checkpoint = match checkpoint {
+            Some(checkpoint) => checkpoint,
+            None => {
+                try!(self.inner.seek(SeekFrom::Start(0)));
+                self.decompress =
+                    try!(Decompress::try_new_with_format(self.index.format));
+                self.position = 0;
+                return Ok(())
+            }
+        };
+
+        let mut d =
+            try!(Decompress::try_new_with_format(DecompressFormat::Raw));
+        if checkpoint.bits > 0 {
+            try!(self.inner.seek(SeekFrom::Start(checkpoint.input - 1)));
+            let mut byte = [0];
+            try!(self.inner.read_exact(&mut byte));
+            try!(d.prime(checkpoint.bits, byte[0] >> (8 - checkpoint.bits)));
+        } else {
+            try!(self.inner.seek(SeekFrom::Start(checkpoint.input)));
+        }
+        if checkpoint.window.len() > 0 {
+            try!(d.set_dictionary(&checkpoint.window));
+        }
+        self.decompress = d;
+        self.position = checkpoint.output;
+        Ok(())
+    }
+
+    // Decompresses and throws away data up to `target`, or the end of the
+    // stream.
+    fn skip_to(&mut self, target: u64) -> io::Result<()> {
+        let mut scratch = vec![0; BUF_SIZE];
+        while self.position < target {
+            let n = (target - self.position) as usize;
+            let n = if n < scratch.len() { n } else { scratch.len() };
+            if try!(self.read(&mut scratch[..n])) == 0 {
+                break
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for SeekableGzReader<R> {
+    fn read(&mut self, into: &mut [u8]) -> io::Result<usize> {
+        if self.done || into.len() == 0 {
+            return Ok(0)
+        }
+        loop {
+            let mut eof = false;
+            if self.pos == self.end {
+                self.end = try!(self.inner.read(&mut self.buf));
+                self.pos = 0;
+                eof = self.end == 0;
+            }
+            let (before_in, before_out) = (self.decompress.total_in(),
+                                           self.decompress.total_out());
+            let status = try!(self.decompress.decompress(
+                &self.buf[self.pos..self.end], into, Flush::None));
+            let consumed = (self.decompress.total_in() - before_in) as usize;
+            let written = (self.decompress.total_out() - before_out) as usize;
+            self.pos += consumed;
+            self.position += written as u64;
+            match status {
+                Status::StreamEnd => {
+                    self.done = true;
+                    return Ok(written)
+                }
+                Status::NeedDictionary(_) => return Err(needs_dictionary()),
+                Status::Ok | Status::BufError => {}
+            }
+            if written > 0 {
+                return Ok(written)
+            }
+            if eof && consumed == 0 {
+                return Err(truncated())
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableGzReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => add_offset(self.position, n),
+            SeekFrom::End(n) => add_offset(self.index.length, n),
+        };
+        let target = match target {
+            Some(target) => target,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "invalid seek to a negative or \
+                                           overflowing position"))
+            }
+        };
+        let checkpoint = self.index.find(target).cloned();
+        let start = checkpoint.as_ref().map(|c| c.output).unwrap_or(0);
+        if target < self.position || start > self.position {
+            try!(self.resume(checkpoint));
+        }
+        try!(self.skip_to(target));
+        Ok(target)
+    }
+}
+
+fn add_offset(position: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        position.checked_add(offset as u64)
+    } else {
+        position.checked_sub(offset.wrapping_neg() as u64)
+    }
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut b = [0; 8];
+    try!(r.read_exact(&mut b));
+    Ok(b.iter().rev().fold(0, |n, &b| n << 8 | b as u64))
+}
+
+fn write_u64<W: Write>(w: &mut W, n: u64) -> io::Result<()> {
+    let mut b = [0; 8];
+    for (i, b) in b.iter_mut().enumerate() {
+        *b = (n >> (8 * i)) as u8;
+    }
+    w.write_all(&b)
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Other,
+                   "random access indexes need a compression backend which \
+                    reports block boundaries")
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof,
+                   "compressed stream ended unexpectedly")
+}
+
+fn needs_dictionary() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   "streams with a preset dictionary can't be indexed")
+}
+
+fn invalid_index() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid random access index")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use rand::{thread_rng, Rng};
+
+    use {Compression, DecompressFormat};
+    use write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use super::*;
+
+    fn data() -> Vec<u8> {
+        let mut rng = thread_rng();
+        let mut data = Vec::new();
+        for i in 0..60_000 {
+            let line = format!("{} GET /{}/{} {}\n", i, rng.gen::<u8>(),
+                               rng.gen::<u16>(), i % 7);
+            data.extend_from_slice(line.as_bytes());
+        }
+        data
+    }
+
+    fn files(data: &[u8]) -> Vec<(Vec<u8>, DecompressFormat)> {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(data).unwrap();
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::fast());
+        zlib.write_all(data).unwrap();
+        let mut raw = DeflateEncoder::new(Vec::new(), Compression::best());
+        raw.write_all(data).unwrap();
+        let gz = gz.finish().unwrap();
+        vec![(gz.clone(), DecompressFormat::Gzip),
+             (gz, DecompressFormat::Auto),
+             (zlib.finish().unwrap(), DecompressFormat::Auto),
+             (raw.finish().unwrap(), DecompressFormat::Raw)]
+    }
+
+    #[test]
+    #[cfg(all(feature = "rust_backend", not(feature = "zlib")))]
+    fn unsupported() {
+        let data = data();
+        for (file, format) in files(&data) {
+            assert!(Builder::new().build(&file[..], format).is_err());
+        }
+    }
+
+    #[test]
+    #[cfg(not(all(feature = "rust_backend", not(feature = "zlib"))))]
+    fn seek() {
+        use std::io::{Cursor, SeekFrom};
+
+        let data = data();
+        for (file, format) in files(&data) {
+            let builder = Builder::new().spacing(64 * 1024);
+            let index = builder.build(&file[..], format).unwrap();
+            assert_eq!(index.uncompressed_len(), data.len() as u64);
+            assert!(index.checkpoints().len() > 5);
+            assert!(index.checkpoints().iter().any(|c| c.bits() > 0));
+
+            let mut r = SeekableGzReader::new(Cursor::new(file), index)
+                .unwrap();
+            let len = data.len();
+            let positions = [len / 2, 10, 0, len / 3, len / 3 + 100_000,
+                             len / 3 + 100, len - 50];
+            for &pos in positions.iter() {
+                assert_eq!(r.seek(SeekFrom::Start(pos as u64)).unwrap(),
+                           pos as u64);
+                let mut buf = [0; 50];
+                r.read_exact(&mut buf).unwrap();
+                assert_eq!(&buf[..], &data[pos..pos + 50]);
+            }
+
+            r.seek(SeekFrom::End(-20)).unwrap();
+            r.seek(SeekFrom::Current(-10)).unwrap();
+            let mut rest = Vec::new();
+            r.read_to_end(&mut rest).unwrap();
+            assert_eq!(rest, &data[len - 30..]);
+            assert!(r.seek(SeekFrom::Current(-(len as i64) - 1)).is_err());
+
+            r.seek(SeekFrom::Start(0)).unwrap();
+            let mut all = Vec::new();
+            r.read_to_end(&mut all).unwrap();
+            assert!(all == data);
+        }
+    }
+
+    #[test]
+    #[cfg(not(all(feature = "rust_backend", not(feature = "zlib"))))]
+    fn serialize() {
+        let data = data();
+        for (file, format) in files(&data) {
+            let index = Builder::new().spacing(100_000)
+                .build(&file[..], format)
+                .unwrap();
+            let mut buf = Vec::new();
+            index.write_to(&mut buf).unwrap();
+            assert_eq!(Index::read_from(&buf[..]).unwrap(), index);
+            buf[0] = b'X';
+            assert!(Index::read_from(&buf[..]).is_err());
+        }
+    }
+
+    #[test]
+    #[cfg(not(all(feature = "rust_backend", not(feature = "zlib"))))]
+    fn truncated() {
+        let data = data();
+        for (file, format) in files(&data) {
+            let half = &file[..file.len() / 2];
+            assert!(Builder::new().build(half, format).is_err());
+            assert!(Builder::new().build(&[][..], format).is_err());
+        }
+    }
+}
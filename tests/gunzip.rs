@@ -29,7 +29,7 @@ fn test_extract_success_partial_multi() {
 #[test]
 fn test_extract_failure() {
     let result = extract_file(Path::new("tests/corrupt-file.gz"));
-    assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
 }
 
 //test complete extraction of a multistream gzipped file